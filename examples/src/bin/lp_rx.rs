@@ -9,7 +9,9 @@ use embassy_stm32::low_power::Executor;
 use s2lp::S2lp;
 use s2lp::{
     ll::{CrcMode, LenWid},
-    packet_format::{Basic, BasicConfig, PacketFilteringOptions, PreamblePattern},
+    packet_format::{
+        Basic, BasicConfig, PacketFilteringOptions, PostambleLength, PreamblePattern, SyncWord,
+    },
     states::{rx::RxResult, shutdown::Config},
 };
 use stm32u0_examples::{init_board_lp, BoardLp};
@@ -45,10 +47,10 @@ async fn async_main(_spawner: Spawner) -> ! {
             preamble_length: 128,
             preamble_pattern: PreamblePattern::Pattern0,
             sync_length: 32,
-            sync_pattern: 0x12345678,
+            sync_pattern: SyncWord::msb_first(0x12345678),
             include_address: true,
             packet_length_encoding: LenWid::Bytes1,
-            postamble_length: 0,
+            postamble_length: PostambleLength::NONE,
             crc_mode: CrcMode::CrcPoly0X1021,
             packet_filter: PacketFilteringOptions {
                 source_address: Some(0xAA),
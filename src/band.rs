@@ -0,0 +1,131 @@
+//! Switching between frequency bands at runtime, for products that ship one firmware for multiple bands
+//! or that listen on more than one band.
+//!
+//! A [BandProfile] holds the band dependent registers of a [CompiledConfig]: the synthesizer word,
+//! the band select, the charge pump, the frequency deviation and the channel filter.
+//! Compute the profiles at compile time and switch with [S2lp::switch_band]:
+//!
+//! ```rust,ignore
+//! const BAND_433: BandProfile = Config { base_frequency: 433_920_000, ..CONFIG }.compile().band_profile();
+//! const BAND_868: BandProfile = Config { base_frequency: 868_300_000, ..CONFIG }.compile().band_profile();
+//!
+//! let mut radio = radio.init_compiled(&CONFIG.compile()).await?;
+//! radio.switch_band(&BAND_433).await?;
+//! ```
+//!
+//! The datarate, the modulation and the crystal must be the same for all profiles, since they aren't switched.
+
+use embedded_hal::{
+    digital::{InputPin, OutputPin},
+    spi::SpiDevice,
+};
+use embedded_hal_async::{delay::DelayNs, digital::Wait};
+
+use crate::{
+    states::{ready::LockDirection, shutdown::CompiledConfig, Ready},
+    ErrorOf, S2lp,
+};
+
+/// The band dependent registers of a config. Create it with [CompiledConfig::band_profile].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct BandProfile {
+    pub(crate) middle_band: bool,
+    pub(crate) synt: u32,
+    pub(crate) cp_isel: u8,
+    pub(crate) pfd_split: bool,
+    pub(crate) fdev_mantissa: u8,
+    pub(crate) fdev_exponent: u8,
+    pub(crate) ch_flt_mantissa: u8,
+    pub(crate) ch_flt_exponent: u8,
+}
+
+impl CompiledConfig {
+    /// The band dependent part of the config, to switch to with [S2lp::switch_band]
+    pub const fn band_profile(&self) -> BandProfile {
+        BandProfile {
+            middle_band: self.middle_band,
+            synt: self.synt,
+            cp_isel: self.cp_isel,
+            pfd_split: self.pfd_split,
+            fdev_mantissa: self.fdev_mantissa,
+            fdev_exponent: self.fdev_exponent,
+            ch_flt_mantissa: self.ch_flt_mantissa,
+            ch_flt_exponent: self.ch_flt_exponent,
+        }
+    }
+}
+
+impl<PF, Spi, Sdn, Gpio, Delay> S2lp<Ready<PF>, Spi, Sdn, Gpio, Delay>
+where
+    Spi: SpiDevice,
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    /// Switch to another band and check that the PLL locks on it for TX and RX.
+    ///
+    /// Returns true if the band is switched. If the PLL doesn't lock, the previous band is restored and false is returned.
+    ///
+    /// The VCO is calibrated on every lock, so don't use fixed words from [S2lp::set_vco_calibration]
+    /// when switching bands.
+    pub async fn switch_band(&mut self, profile: &BandProfile) -> Result<bool, ErrorOf<Self>> {
+        let previous = self.band_profile()?;
+        self.write_band_profile(profile)?;
+
+        if self.test_lock(LockDirection::Tx).await? && self.test_lock(LockDirection::Rx).await? {
+            #[cfg(feature = "defmt-03")]
+            defmt::debug!("{=str}: Switched to band {}", self.label, profile);
+
+            return Ok(true);
+        }
+
+        #[cfg(feature = "defmt-03")]
+        defmt::warn!("{=str}: No lock on band {}", self.label, profile);
+
+        self.write_band_profile(&previous)?;
+        Ok(false)
+    }
+
+    /// Read the band the radio is on
+    pub fn band_profile(&mut self) -> Result<BandProfile, ErrorOf<Self>> {
+        let synt = self.ll().synt().read()?;
+        let mod_0 = self.ll().mod_0().read()?;
+        let mod_1 = self.ll().mod_1().read()?;
+        let ch_flt = self.ll().ch_flt().read()?;
+
+        Ok(BandProfile {
+            middle_band: synt.bs(),
+            synt: synt.synt(),
+            cp_isel: synt.pll_cp_isel(),
+            pfd_split: self.ll().synth_config_2().read()?.pll_pfd_split_en(),
+            fdev_mantissa: mod_0.fdev_m(),
+            fdev_exponent: mod_1.fdev_e(),
+            ch_flt_mantissa: ch_flt.ch_flt_m(),
+            ch_flt_exponent: ch_flt.ch_flt_e(),
+        })
+    }
+
+    fn write_band_profile(&mut self, profile: &BandProfile) -> Result<(), ErrorOf<Self>> {
+        self.ll()
+            .mod_1()
+            .modify(|reg| reg.set_fdev_e(profile.fdev_exponent))?;
+        self.ll()
+            .mod_0()
+            .write(|reg| reg.set_fdev_m(profile.fdev_mantissa))?;
+        self.ll().ch_flt().write(|reg| {
+            reg.set_ch_flt_e(profile.ch_flt_exponent);
+            reg.set_ch_flt_m(profile.ch_flt_mantissa);
+        })?;
+        self.ll()
+            .synth_config_2()
+            .modify(|reg| reg.set_pll_pfd_split_en(profile.pfd_split))?;
+        self.ll().synt().modify(|reg| {
+            reg.set_bs(profile.middle_band);
+            reg.set_synt(profile.synt);
+            reg.set_pll_cp_isel(profile.cp_isel);
+        })?;
+
+        Ok(())
+    }
+}
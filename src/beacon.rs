@@ -0,0 +1,242 @@
+//! Receive windows that are synchronized to a periodic beacon, for beacon-based low-power networks.
+//!
+//! The [BeaconSync] keeps track of when the beacons arrive by the local clock and estimates the drift between
+//! the local clock and the clock of the beacon sender. Packets scheduled at a time relative to the beacon can then be
+//! received by only turning on the receiver for a short window with [S2lp::receive_window].
+//!
+//! The driver has no clock, so all times are passed in as the time since any fixed moment (e.g. boot) by the local clock.
+//! The times wrap around after ~71 minutes, which is fine as long as the beacon period is shorter than that.
+//!
+//! Timestamp the beacons as close to the reception as possible, e.g. right when [S2lp::wait] returns.
+//! A constant latency doesn't matter since it's the same for the beacons and the packets.
+
+use embedded_hal::{
+    digital::{InputPin, OutputPin},
+    spi::SpiDevice,
+};
+use embedded_hal_async::{delay::DelayNs, digital::Wait};
+
+use crate::{
+    packet_format::PacketFormat,
+    states::{
+        rx::{RxMode, RxTimeout, RxTimeoutMask},
+        Ready, Rx,
+    },
+    time::Duration,
+    ErrorOf, S2lp,
+};
+
+/// The configuration of a [BeaconSync]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct BeaconSyncConfig {
+    /// The time between two beacons by the clock of the beacon sender
+    pub beacon_period: Duration,
+    /// How far off the drift estimate can be in ppm. The windows are widened to account for it.
+    /// Before the drift is known, the windows are widened by twice this.
+    pub drift_uncertainty_ppm: u32,
+    /// Extra time the window is opened before and after, e.g. for the radio to turn on
+    pub guard_time: Duration,
+}
+
+/// When the receiver should be turned on and for how long, by the local clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct RxWindow {
+    /// The local time at which the receiver turns on
+    pub opens_at: Duration,
+    /// How long the receiver stays on
+    pub length: Duration,
+}
+
+/// Tracks the timing of a periodic beacon. See the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct BeaconSync {
+    config: BeaconSyncConfig,
+    last_beacon: Option<Duration>,
+    /// The drift of the local clock relative to the beacon clock in parts per billion. None until two beacons have been seen.
+    drift_ppb: Option<i64>,
+}
+
+impl BeaconSync {
+    /// Create a new tracker that hasn't seen any beacons yet
+    pub const fn new(config: BeaconSyncConfig) -> Self {
+        Self {
+            config,
+            last_beacon: None,
+            drift_ppb: None,
+        }
+    }
+
+    /// Record the local time at which a beacon was received.
+    ///
+    /// Missed beacons in between are fine as long as the drift is small compared to the beacon period.
+    pub fn on_beacon(&mut self, local_time: Duration) {
+        if let Some(last_beacon) = self.last_beacon {
+            let measured = local_time.as_micros().wrapping_sub(last_beacon.as_micros()) as i64;
+            let period = self.config.beacon_period.as_micros().max(1) as i64;
+            let beacon_count = ((measured + period / 2) / period).max(1);
+            let expected = period * beacon_count;
+
+            let drift_ppb = (measured - expected) * 1_000_000_000 / expected;
+            self.drift_ppb = Some(match self.drift_ppb {
+                // Smooth out the jitter of the timestamps
+                Some(previous) => (previous * 3 + drift_ppb) / 4,
+                None => drift_ppb,
+            });
+        }
+
+        self.last_beacon = Some(local_time);
+    }
+
+    /// Forget all beacons, e.g. after the network has been lost
+    pub fn reset(&mut self) {
+        self.last_beacon = None;
+        self.drift_ppb = None;
+    }
+
+    /// The estimated drift of the local clock relative to the beacon clock in ppm.
+    /// Positive means the local clock runs fast. None until two beacons have been seen.
+    pub fn drift_ppm(&self) -> Option<i32> {
+        self.drift_ppb.map(|drift| (drift / 1_000) as i32)
+    }
+
+    /// Get the receive window for a packet that's sent at `offset` after the last beacon by the beacon clock.
+    /// The window is `window` long plus the widening for the drift and the guard time.
+    ///
+    /// Returns None if no beacon has been seen yet.
+    pub fn expect_packet_at(&self, offset: Duration, window: Duration) -> Option<RxWindow> {
+        let last_beacon = self.last_beacon?;
+        let offset_us = offset.as_micros() as i64;
+
+        let corrected = offset_us + offset_us * self.drift_ppb.unwrap_or(0) / 1_000_000_000;
+        let uncertainty_ppm = match self.drift_ppb {
+            Some(_) => self.config.drift_uncertainty_ppm,
+            None => self.config.drift_uncertainty_ppm * 2,
+        } as i64;
+        let widening =
+            offset_us * uncertainty_ppm / 1_000_000 + self.config.guard_time.as_micros() as i64;
+
+        let start = corrected - window.as_micros() as i64 / 2 - widening;
+        let length = window.as_micros() as i64 + 2 * widening;
+
+        Some(RxWindow {
+            opens_at: Duration::from_micros(
+                last_beacon.as_micros().wrapping_add_signed(start as i32),
+            ),
+            length: Duration::from_micros(length.clamp(0, u32::MAX as i64) as u32),
+        })
+    }
+
+    /// Get the receive window for the next beacon (after the given amount of periods, usually 1).
+    pub fn next_beacon_window(&self, periods: u32, window: Duration) -> Option<RxWindow> {
+        self.expect_packet_at(
+            Duration::from_micros(
+                self.config
+                    .beacon_period
+                    .as_micros()
+                    .saturating_mul(periods),
+            ),
+            window,
+        )
+    }
+}
+
+impl<Format, Spi, Sdn, Gpio, Delay> S2lp<Ready<Format>, Spi, Sdn, Gpio, Delay>
+where
+    Format: PacketFormat,
+    Spi: SpiDevice,
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    /// Wait until the window opens and start receiving for the length of the window.
+    ///
+    /// `now` is the current local time. If the window has already opened, the receiver is started right away
+    /// for what's left of the window. A packet that's being received when the window closes is still received.
+    pub async fn receive_window<'b>(
+        mut self,
+        buffer: &'b mut [u8],
+        window: RxWindow,
+        now: Duration,
+    ) -> Result<S2lp<Rx<'b, Format>, Spi, Sdn, Gpio, Delay>, ErrorOf<Self>> {
+        let until_open = window.opens_at.as_micros().wrapping_sub(now.as_micros()) as i32;
+
+        let length = if until_open > 0 {
+            self.delay.delay_us(until_open as u32).await;
+            window.length
+        } else {
+            Duration::from_micros(
+                window
+                    .length
+                    .as_micros()
+                    .saturating_sub(until_open.unsigned_abs())
+                    .max(1),
+            )
+        };
+
+        self.start_receive(
+            buffer,
+            RxMode::Normal {
+                timeout: Some(RxTimeout {
+                    timeout: length,
+                    mask: RxTimeoutMask::Sqi,
+                }),
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: BeaconSyncConfig = BeaconSyncConfig {
+        beacon_period: Duration::from_secs(1),
+        drift_uncertainty_ppm: 10,
+        guard_time: Duration::from_micros(500),
+    };
+
+    #[test]
+    fn drift_estimation() {
+        let mut sync = BeaconSync::new(CONFIG);
+        assert_eq!(sync.expect_packet_at(Duration::ZERO, Duration::ZERO), None);
+
+        // The local clock runs 100 ppm fast
+        sync.on_beacon(Duration::from_micros(5_000_000));
+        assert_eq!(sync.drift_ppm(), None);
+        sync.on_beacon(Duration::from_micros(6_000_100));
+        assert_eq!(sync.drift_ppm(), Some(100));
+
+        // A missed beacon
+        sync.on_beacon(Duration::from_micros(8_000_300));
+        assert_eq!(sync.drift_ppm(), Some(100));
+
+        // Half a period later by the beacon clock, with a 2ms window
+        let window = sync
+            .expect_packet_at(Duration::from_millis(500), Duration::from_millis(2))
+            .unwrap();
+        // 500_050 corrected, -1000 for half the window, -5 for the uncertainty and -500 guard
+        assert_eq!(window.opens_at, Duration::from_micros(8_000_300 + 498_545));
+        assert_eq!(window.length, Duration::from_micros(2_000 + 2 * 505));
+
+        let beacon = sync.next_beacon_window(1, Duration::ZERO).unwrap();
+        assert_eq!(beacon.opens_at, Duration::from_micros(8_000_300 + 999_590));
+    }
+
+    #[test]
+    fn wrapping_clock() {
+        let mut sync = BeaconSync::new(CONFIG);
+        sync.on_beacon(Duration::from_micros(u32::MAX - 499_999));
+        sync.on_beacon(Duration::from_micros(500_000));
+        assert_eq!(sync.drift_ppm(), Some(0));
+
+        let window = sync
+            .expect_packet_at(Duration::from_secs(1), Duration::ZERO)
+            .unwrap();
+        assert_eq!(
+            window.opens_at,
+            Duration::from_micros(500_000 + 1_000_000 - 510)
+        );
+    }
+}
@@ -0,0 +1,464 @@
+//! A compact binary framing of received packets and transmit requests, to bridge the radio to a host over a serial port.
+//!
+//! This is the wire format for 'dongle' firmware: the firmware sends every [RxResult] to the host with [encode_rx]
+//! and hands the frames it gets from the host to [decode_tx] to find out what to transmit.
+//! A host written in Rust can use [decode_rx] and [encode_tx] for the other side.
+//!
+//! Every frame starts with a [HEADER_LEN] byte header: the [FrameKind] and the length of the body as a little endian `u16`.
+//! Read the header first and use [frame_len] to know how many bytes the whole frame takes.
+//!
+//! | Kind | Body |
+//! |------|------|
+//! | [FrameKind::Packet] | RSSI register value, metadata, payload |
+//! | [FrameKind::RxStatus] | The [RxResult] that isn't a packet, see [rx_status_code] |
+//! | [FrameKind::TxRequest] | Metadata, payload |
+//!
+//! The metadata is encoded by [BridgeMetaData], so both sides have to agree on the packet format.
+//! There's no checksum or resynchronization. The serial link is assumed to be reliable, like USB.
+
+#[cfg(feature = "basic")]
+use crate::packet_format::{BasicRxMetaData, BasicTxMetaData};
+#[cfg(feature = "fixed-length")]
+use crate::packet_format::{FixedLengthRxMetaData, FixedLengthTxMetaData};
+#[cfg(feature = "raw-fifo")]
+use crate::packet_format::{RawFifoRxMetaData, RawFifoTxMetaData};
+#[cfg(feature = "uart-ota")]
+use crate::packet_format::{UartOtaRxMetaData, UartOtaTxMetaData};
+#[cfg(feature = "wmbus")]
+use crate::packet_format::{WMBusRxMetaData, WMBusTxMetaData};
+#[cfg(feature = "stack")]
+use crate::{
+    packet_format::{StackRxMetaData, StackTxMetaData},
+    time::Duration,
+};
+use crate::{rssi::Rssi, states::rx::RxResult};
+
+/// The size of the frame header: the kind and the body length
+pub const HEADER_LEN: usize = 3;
+/// The biggest body a frame can carry
+pub const MAX_BODY_LEN: usize = u16::MAX as usize;
+
+/// The kind of a frame, the first byte of the header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[repr(u8)]
+pub enum FrameKind {
+    /// A received packet (radio to host)
+    Packet = 0x01,
+    /// A reception that ended without a packet (radio to host)
+    RxStatus = 0x02,
+    /// A packet to transmit (host to radio)
+    TxRequest = 0x81,
+}
+
+impl TryFrom<u8> for FrameKind {
+    type Error = BridgeError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x01 => Ok(Self::Packet),
+            0x02 => Ok(Self::RxStatus),
+            0x81 => Ok(Self::TxRequest),
+            kind => Err(BridgeError::UnknownKind(kind)),
+        }
+    }
+}
+
+/// The errors of encoding and decoding frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum BridgeError {
+    /// The frame is shorter than its header says. Wait for more bytes.
+    Incomplete,
+    /// The first byte is not a [FrameKind]
+    UnknownKind(u8),
+    /// The frame is of a kind that the decode function doesn't take
+    UnexpectedKind(FrameKind),
+    /// The body is too short for the metadata or holds metadata that doesn't decode
+    BadMetaData,
+    /// The [RxStatus](FrameKind::RxStatus) body isn't a single known code
+    BadStatus(u8),
+    /// The output buffer can't hold the frame
+    BufferTooSmall,
+    /// The payload doesn't fit in [MAX_BODY_LEN]
+    TooLong,
+}
+
+/// Packet metadata that can be carried in a frame
+pub trait BridgeMetaData: Sized {
+    /// The amount of bytes the encoded metadata takes
+    const LEN: usize;
+
+    /// Encode into the buffer, which is [Self::LEN] long
+    fn encode(&self, buffer: &mut [u8]);
+    /// Decode from the buffer, which is [Self::LEN] long
+    fn decode(buffer: &[u8]) -> Option<Self>;
+}
+
+macro_rules! impl_empty_bridge_meta_data {
+    ($($(#[$attr:meta])* $meta_data:ident),*) => {
+        $(
+            $(#[$attr])*
+            impl BridgeMetaData for $meta_data {
+                const LEN: usize = 0;
+
+                fn encode(&self, _buffer: &mut [u8]) {}
+
+                fn decode(_buffer: &[u8]) -> Option<Self> {
+                    Some(Self)
+                }
+            }
+        )*
+    };
+}
+
+impl_empty_bridge_meta_data!(
+    #[cfg(feature = "fixed-length")]
+    FixedLengthRxMetaData,
+    #[cfg(feature = "fixed-length")]
+    FixedLengthTxMetaData,
+    #[cfg(feature = "wmbus")]
+    WMBusRxMetaData,
+    #[cfg(feature = "wmbus")]
+    WMBusTxMetaData,
+    #[cfg(feature = "uart-ota")]
+    UartOtaRxMetaData,
+    #[cfg(feature = "uart-ota")]
+    UartOtaTxMetaData,
+    #[cfg(feature = "raw-fifo")]
+    RawFifoRxMetaData,
+    #[cfg(feature = "raw-fifo")]
+    RawFifoTxMetaData
+);
+
+/// An optional address as a presence byte and the address
+#[cfg(any(feature = "basic", feature = "stack"))]
+fn encode_address(address: Option<u8>, buffer: &mut [u8]) {
+    buffer[0] = address.is_some() as u8;
+    buffer[1] = address.unwrap_or_default();
+}
+
+#[cfg(any(feature = "basic", feature = "stack"))]
+fn decode_address(buffer: &[u8]) -> Option<Option<u8>> {
+    match buffer[0] {
+        0 => Some(None),
+        1 => Some(Some(buffer[1])),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "stack")]
+fn decode_bool(value: u8) -> Option<bool> {
+    match value {
+        0 => Some(false),
+        1 => Some(true),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "basic")]
+impl BridgeMetaData for BasicRxMetaData {
+    const LEN: usize = 2;
+
+    fn encode(&self, buffer: &mut [u8]) {
+        encode_address(self.destination_address, buffer);
+    }
+
+    fn decode(buffer: &[u8]) -> Option<Self> {
+        Some(Self {
+            destination_address: decode_address(buffer)?,
+        })
+    }
+}
+
+#[cfg(feature = "basic")]
+impl BridgeMetaData for BasicTxMetaData {
+    const LEN: usize = 2;
+
+    fn encode(&self, buffer: &mut [u8]) {
+        encode_address(self.destination_address, buffer);
+    }
+
+    fn decode(buffer: &[u8]) -> Option<Self> {
+        Some(Self {
+            destination_address: decode_address(buffer)?,
+        })
+    }
+}
+
+#[cfg(feature = "stack")]
+impl BridgeMetaData for StackRxMetaData {
+    const LEN: usize = 4;
+
+    fn encode(&self, buffer: &mut [u8]) {
+        buffer[0] = self.destination_address;
+        buffer[1] = self.source_address;
+        buffer[2] = self.sequence_number;
+        buffer[3] = self.ack_requested as u8;
+    }
+
+    fn decode(buffer: &[u8]) -> Option<Self> {
+        Some(Self {
+            destination_address: buffer[0],
+            source_address: buffer[1],
+            sequence_number: buffer[2],
+            ack_requested: decode_bool(buffer[3])?,
+        })
+    }
+}
+
+/// The ack timeout is sent in microseconds
+#[cfg(feature = "stack")]
+impl BridgeMetaData for StackTxMetaData {
+    const LEN: usize = 8;
+
+    fn encode(&self, buffer: &mut [u8]) {
+        buffer[0] = self.destination_address;
+        encode_address(self.source_address, &mut buffer[1..3]);
+        buffer[3] = self.request_ack as u8;
+        buffer[4..8].copy_from_slice(&self.ack_timeout.as_micros().to_le_bytes());
+    }
+
+    fn decode(buffer: &[u8]) -> Option<Self> {
+        Some(Self {
+            destination_address: buffer[0],
+            source_address: decode_address(&buffer[1..3])?,
+            request_ack: decode_bool(buffer[3])?,
+            ack_timeout: Duration::from_micros(u32::from_le_bytes(buffer[4..8].try_into().ok()?)),
+        })
+    }
+}
+
+/// The total length of the frame that starts with the header, or None if the header isn't complete yet
+pub fn frame_len(header: &[u8]) -> Option<usize> {
+    match header {
+        [_, low, high, ..] => Some(HEADER_LEN + u16::from_le_bytes([*low, *high]) as usize),
+        _ => None,
+    }
+}
+
+/// The status code of an [RxResult] that isn't a packet, as sent in a [FrameKind::RxStatus] frame.
+/// Returns None for [RxResult::Ok].
+pub fn rx_status_code<M>(result: &RxResult<M>) -> Option<u8> {
+    match result {
+        RxResult::Ok { .. } => None,
+        RxResult::RxAlreadyDone => Some(1),
+        RxResult::Fifo => Some(2),
+        RxResult::OverflowWhileDetached => Some(3),
+        RxResult::Discarded => Some(4),
+        RxResult::CrcError => Some(5),
+        RxResult::TooBigForBuffer => Some(6),
+        RxResult::Timeout => Some(7),
+    }
+}
+
+fn rx_status_from_code<M>(code: u8) -> Result<RxResult<M>, BridgeError> {
+    match code {
+        1 => Ok(RxResult::RxAlreadyDone),
+        2 => Ok(RxResult::Fifo),
+        3 => Ok(RxResult::OverflowWhileDetached),
+        4 => Ok(RxResult::Discarded),
+        5 => Ok(RxResult::CrcError),
+        6 => Ok(RxResult::TooBigForBuffer),
+        7 => Ok(RxResult::Timeout),
+        code => Err(BridgeError::BadStatus(code)),
+    }
+}
+
+/// Write the header and return the body part of the output
+fn write_header(
+    kind: FrameKind,
+    body_len: usize,
+    out: &mut [u8],
+) -> Result<&mut [u8], BridgeError> {
+    let body_len_u16 = u16::try_from(body_len).map_err(|_| BridgeError::TooLong)?;
+    let frame = out
+        .get_mut(..HEADER_LEN + body_len)
+        .ok_or(BridgeError::BufferTooSmall)?;
+
+    frame[0] = kind as u8;
+    frame[1..HEADER_LEN].copy_from_slice(&body_len_u16.to_le_bytes());
+    Ok(&mut frame[HEADER_LEN..])
+}
+
+/// Check the header and return the kind and the body of the frame. Bytes after the frame are ignored.
+fn read_header(frame: &[u8]) -> Result<(FrameKind, &[u8]), BridgeError> {
+    let len = frame_len(frame).ok_or(BridgeError::Incomplete)?;
+    let kind = FrameKind::try_from(frame[0])?;
+    let body = frame.get(HEADER_LEN..len).ok_or(BridgeError::Incomplete)?;
+    Ok((kind, body))
+}
+
+/// Encode the result of a reception into `out`. The buffer is the one given to the receive call,
+/// of which the first `packet_size` bytes are sent.
+///
+/// Returns the length of the frame.
+pub fn encode_rx<M: BridgeMetaData>(
+    result: &RxResult<M>,
+    buffer: &[u8],
+    out: &mut [u8],
+) -> Result<usize, BridgeError> {
+    match result {
+        RxResult::Ok {
+            packet_size,
+            rssi_value,
+            meta_data,
+        } => {
+            let payload = buffer
+                .get(..*packet_size)
+                .ok_or(BridgeError::BufferTooSmall)?;
+            let body_len = 1 + M::LEN + payload.len();
+            let body = write_header(FrameKind::Packet, body_len, out)?;
+
+            body[0] = rssi_value.register();
+            meta_data.encode(&mut body[1..][..M::LEN]);
+            body[1 + M::LEN..].copy_from_slice(payload);
+            Ok(HEADER_LEN + body_len)
+        }
+        result => {
+            let body = write_header(FrameKind::RxStatus, 1, out)?;
+            body[0] = rx_status_code(result).unwrap_or_default();
+            Ok(HEADER_LEN + 1)
+        }
+    }
+}
+
+/// Decode a frame made by [encode_rx]. For a packet, the payload is returned as well.
+pub fn decode_rx<M: BridgeMetaData>(frame: &[u8]) -> Result<(RxResult<M>, &[u8]), BridgeError> {
+    match read_header(frame)? {
+        (FrameKind::Packet, body) => {
+            if body.len() < 1 + M::LEN {
+                return Err(BridgeError::BadMetaData);
+            }
+
+            let meta_data = M::decode(&body[1..][..M::LEN]).ok_or(BridgeError::BadMetaData)?;
+            let payload = &body[1 + M::LEN..];
+            let result = RxResult::Ok {
+                packet_size: payload.len(),
+                rssi_value: Rssi::from_register(body[0]),
+                meta_data,
+            };
+            Ok((result, payload))
+        }
+        (FrameKind::RxStatus, [code]) => Ok((rx_status_from_code(*code)?, &[])),
+        (FrameKind::RxStatus, body) => Err(BridgeError::BadStatus(
+            body.first().copied().unwrap_or_default(),
+        )),
+        (kind, _) => Err(BridgeError::UnexpectedKind(kind)),
+    }
+}
+
+/// Encode a request to transmit the payload with the metadata into `out`.
+///
+/// Returns the length of the frame.
+pub fn encode_tx<M: BridgeMetaData>(
+    meta_data: &M,
+    payload: &[u8],
+    out: &mut [u8],
+) -> Result<usize, BridgeError> {
+    let body_len = M::LEN + payload.len();
+    let body = write_header(FrameKind::TxRequest, body_len, out)?;
+
+    meta_data.encode(&mut body[..M::LEN]);
+    body[M::LEN..].copy_from_slice(payload);
+    Ok(HEADER_LEN + body_len)
+}
+
+/// Decode a frame made by [encode_tx] into the metadata and the payload to hand to
+/// [S2lp::send_packet](crate::S2lp::send_packet).
+pub fn decode_tx<M: BridgeMetaData>(frame: &[u8]) -> Result<(M, &[u8]), BridgeError> {
+    match read_header(frame)? {
+        (FrameKind::TxRequest, body) => {
+            if body.len() < M::LEN {
+                return Err(BridgeError::BadMetaData);
+            }
+
+            let meta_data = M::decode(&body[..M::LEN]).ok_or(BridgeError::BadMetaData)?;
+            Ok((meta_data, &body[M::LEN..]))
+        }
+        (kind, _) => Err(BridgeError::UnexpectedKind(kind)),
+    }
+}
+
+#[cfg(all(test, feature = "basic", feature = "stack"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rx_frames() {
+        let mut out = [0; 32];
+
+        let result = RxResult::Ok {
+            packet_size: 3,
+            rssi_value: Rssi::from_dbm(-80),
+            meta_data: StackRxMetaData {
+                destination_address: 1,
+                source_address: 2,
+                sequence_number: 3,
+                ack_requested: true,
+            },
+        };
+        let len = encode_rx(&result, &[10, 11, 12, 13], &mut out).unwrap();
+        assert_eq!(&out[..len], &[0x01, 8, 0, 66, 1, 2, 3, 1, 10, 11, 12]);
+        assert_eq!(frame_len(&out[..HEADER_LEN]), Some(len));
+        assert_eq!(frame_len(&out[..2]), None);
+        assert_eq!(
+            decode_rx::<StackRxMetaData>(&out[..len]),
+            Ok((result.clone(), &[10, 11, 12][..]))
+        );
+        assert_eq!(
+            decode_rx::<StackRxMetaData>(&out[..len - 1]),
+            Err(BridgeError::Incomplete)
+        );
+
+        let len = encode_rx::<BasicRxMetaData>(&RxResult::CrcError, &[], &mut out).unwrap();
+        assert_eq!(&out[..len], &[0x02, 1, 0, 5]);
+        assert_eq!(
+            decode_rx::<BasicRxMetaData>(&out[..len]),
+            Ok((RxResult::CrcError, &[][..]))
+        );
+
+        assert_eq!(
+            encode_rx(&result, &[10, 11, 12], &mut out[..10]),
+            Err(BridgeError::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn tx_frames() {
+        let mut out = [0; 32];
+
+        let meta_data = StackTxMetaData {
+            destination_address: 5,
+            source_address: None,
+            request_ack: true,
+            ack_timeout: Duration::from_millis(2),
+        };
+        let len = encode_tx(&meta_data, &[1, 2], &mut out).unwrap();
+        assert_eq!(
+            &out[..len],
+            &[0x81, 10, 0, 5, 0, 0, 1, 0xD0, 0x07, 0, 0, 1, 2]
+        );
+
+        let (decoded, payload) = decode_tx::<StackTxMetaData>(&out[..len]).unwrap();
+        assert_eq!(decoded.destination_address, 5);
+        assert_eq!(decoded.source_address, None);
+        assert!(decoded.request_ack);
+        assert_eq!(decoded.ack_timeout, Duration::from_millis(2));
+        assert_eq!(payload, &[1, 2]);
+
+        out[4] = 2;
+        assert!(matches!(
+            decode_tx::<StackTxMetaData>(&out[..len]),
+            Err(BridgeError::BadMetaData)
+        ));
+        assert!(matches!(
+            decode_tx::<BasicTxMetaData>(&[0x01, 0, 0]),
+            Err(BridgeError::UnexpectedKind(FrameKind::Packet))
+        ));
+        assert!(matches!(
+            decode_tx::<BasicTxMetaData>(&[0x55, 0, 0]),
+            Err(BridgeError::UnknownKind(0x55))
+        ));
+    }
+}
@@ -0,0 +1,166 @@
+//! Channel tables of common regional plans, to refer to channels by name instead of by frequency.
+//!
+//! Use the center frequency of a channel as the [base frequency](crate::states::shutdown::Config::base_frequency)
+//! of the radio config, or compile a [BandProfile](crate::band::BandProfile) per channel to switch between them.
+//!
+//! The limits are the common ones of the regulations (ETSI EN 300 220 / ERC 70-03 for Europe, FCC part 15.247 for the US).
+//! They depend on the use case and change over time, so check the regulations that apply to the product.
+//! The radio has no notion of EIRP. The antenna gain has to be taken off before setting the output power.
+
+use crate::time::Duration;
+
+/// A set of equally spaced channels with the limits that apply to them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct ChannelPlan {
+    /// A short name of the plan
+    pub name: &'static str,
+    /// The lower edge of the band in Hz
+    pub lower_edge: u32,
+    /// The upper edge of the band in Hz
+    pub upper_edge: u32,
+    /// The center frequency of channel 0 in Hz
+    pub first_channel: u32,
+    /// The distance between the channels in Hz
+    pub spacing: u32,
+    /// The amount of channels
+    pub channels: u16,
+    /// The maximum radiated power in dBm EIRP
+    pub max_eirp_dbm: i8,
+    /// The maximum duty cycle in per mille (10 is 1%), if the plan limits it
+    pub max_duty_cycle_permille: Option<u16>,
+    /// The maximum time spent on one channel in a row, if the plan limits it
+    pub max_dwell_time: Option<Duration>,
+}
+
+impl ChannelPlan {
+    /// The center frequency of the channel in Hz, or None if the plan doesn't have the channel
+    pub const fn center_frequency(&self, channel: u16) -> Option<u32> {
+        if channel < self.channels {
+            Some(self.first_channel + channel as u32 * self.spacing)
+        } else {
+            None
+        }
+    }
+
+    /// The channel of which the center frequency is the given frequency in Hz, if any
+    pub const fn channel_of(&self, frequency: u32) -> Option<u16> {
+        if frequency < self.first_channel
+            || !(frequency - self.first_channel).is_multiple_of(self.spacing)
+        {
+            return None;
+        }
+
+        let channel = (frequency - self.first_channel) / self.spacing;
+        if channel < self.channels as u32 {
+            Some(channel as u16)
+        } else {
+            None
+        }
+    }
+
+    /// Iterate over the center frequencies of all channels in Hz
+    pub fn center_frequencies(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.channels).map(|channel| self.first_channel + channel as u32 * self.spacing)
+    }
+}
+
+/// EU 868 MHz sub-band g1 (868.0 - 868.6 MHz): 25 mW ERP at 1% duty cycle
+pub const EU_868_G1: ChannelPlan = ChannelPlan {
+    name: "EU 868 g1",
+    lower_edge: 868_000_000,
+    upper_edge: 868_600_000,
+    first_channel: 868_100_000,
+    spacing: 200_000,
+    channels: 3,
+    max_eirp_dbm: 16,
+    max_duty_cycle_permille: Some(10),
+    max_dwell_time: None,
+};
+
+/// EU 868 MHz sub-band g2 (868.7 - 869.2 MHz): 25 mW ERP at 0.1% duty cycle
+pub const EU_868_G2: ChannelPlan = ChannelPlan {
+    name: "EU 868 g2",
+    lower_edge: 868_700_000,
+    upper_edge: 869_200_000,
+    first_channel: 868_800_000,
+    spacing: 200_000,
+    channels: 2,
+    max_eirp_dbm: 16,
+    max_duty_cycle_permille: Some(1),
+    max_dwell_time: None,
+};
+
+/// EU 868 MHz sub-band g3 (869.4 - 869.65 MHz): a single wide channel with 500 mW ERP at 10% duty cycle
+pub const EU_868_G3: ChannelPlan = ChannelPlan {
+    name: "EU 868 g3",
+    lower_edge: 869_400_000,
+    upper_edge: 869_650_000,
+    first_channel: 869_525_000,
+    spacing: 250_000,
+    channels: 1,
+    max_eirp_dbm: 29,
+    max_duty_cycle_permille: Some(100),
+    max_dwell_time: None,
+};
+
+/// US 902 - 928 MHz frequency hopping: 64 channels, 1 W with a 6 dBi antenna and 400 ms per channel.
+///
+/// A hopping system has to use at least 50 of the channels.
+pub const US_915_HOPPING: ChannelPlan = ChannelPlan {
+    name: "US 915 hopping",
+    lower_edge: 902_000_000,
+    upper_edge: 928_000_000,
+    first_channel: 902_300_000,
+    spacing: 200_000,
+    channels: 64,
+    max_eirp_dbm: 36,
+    max_duty_cycle_permille: None,
+    max_dwell_time: Some(Duration::from_millis(400)),
+};
+
+/// EU 433 MHz ISM band (433.05 - 434.79 MHz) in 25 kHz channels: 10 mW ERP at 10% duty cycle
+pub const ISM_433: ChannelPlan = ChannelPlan {
+    name: "ISM 433",
+    lower_edge: 433_050_000,
+    upper_edge: 434_790_000,
+    first_channel: 433_075_000,
+    spacing: 25_000,
+    channels: 69,
+    max_eirp_dbm: 12,
+    max_duty_cycle_permille: Some(100),
+    max_dwell_time: None,
+};
+
+/// All the plans in this module
+pub const CHANNEL_PLANS: [ChannelPlan; 5] =
+    [EU_868_G1, EU_868_G2, EU_868_G3, US_915_HOPPING, ISM_433];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channels_fit_in_their_band() {
+        for plan in CHANNEL_PLANS {
+            for frequency in plan.center_frequencies() {
+                assert!(
+                    frequency - plan.spacing / 2 >= plan.lower_edge,
+                    "{}",
+                    plan.name
+                );
+                assert!(
+                    frequency + plan.spacing / 2 <= plan.upper_edge,
+                    "{}",
+                    plan.name
+                );
+            }
+        }
+
+        assert_eq!(EU_868_G1.center_frequency(2), Some(868_500_000));
+        assert_eq!(EU_868_G1.center_frequency(3), None);
+        assert_eq!(US_915_HOPPING.channel_of(914_900_000), Some(63));
+        assert_eq!(US_915_HOPPING.channel_of(915_000_000), None);
+        assert_eq!(ISM_433.channel_of(433_050_000), None);
+    }
+}
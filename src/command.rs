@@ -0,0 +1,144 @@
+//! The command set of the radio, checked against the state it's in.
+//!
+//! Sending commands through [S2lp::ll] skips all bookkeeping of the driver. [S2lp::strobe] only allows the commands
+//! that the radio accepts in its current state and that keep it in a state the driver can work with.
+//! The commands that change the typestate have their own methods, like [S2lp::standby] and [S2lp::send_packet].
+
+use embedded_hal::{
+    digital::{InputPin, OutputPin},
+    spi::SpiDevice,
+};
+use embedded_hal_async::{delay::DelayNs, digital::Wait};
+
+use crate::{diagnostics::FatalError, ll::State, states::Ready, Error, ErrorOf, S2lp};
+
+/// The time between two polls of the state while waiting for a command to take effect
+const STROBE_POLL_INTERVAL_US: u32 = 10;
+/// The amount of polls before giving up. The slowest transition (locking the PLL) takes well under a millisecond.
+const STROBE_POLLS: u32 = 100;
+
+/// A command of the radio
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum Command {
+    /// Start transmitting
+    Tx,
+    /// Start receiving
+    Rx,
+    /// Go to ready
+    Ready,
+    /// Go to standby
+    Standby,
+    /// Go to sleep. The fifo is retained, since the driver configures the sleep mode for it.
+    Sleep,
+    /// Lock the synthesizer with the RX configuration
+    LockRx,
+    /// Lock the synthesizer with the TX configuration
+    LockTx,
+    /// Exit TX, RX or a lock state and go to ready
+    Abort,
+    /// Reload the LDC timer
+    LdcReload,
+    /// Reset the state machine and all registers
+    Reset,
+    /// Empty the RX fifo
+    FlushRxFifo,
+    /// Empty the TX fifo
+    FlushTxFifo,
+    /// Reload the packet sequence counter
+    SequenceUpdate,
+}
+
+impl Command {
+    /// Whether the radio accepts the command in the given state, as listed in the datasheet
+    pub const fn is_valid_from(self, state: State) -> bool {
+        match self {
+            Self::Tx | Self::Rx | Self::Standby | Self::LockRx | Self::LockTx => {
+                matches!(state, State::Ready | State::SynthSetup)
+            }
+            Self::Ready => matches!(
+                state,
+                State::Ready | State::Standby | State::SleepA | State::SleepB | State::Lockon
+            ),
+            Self::Sleep => matches!(state, State::Ready | State::SynthSetup | State::WaitSleep),
+            // The lock error state can only be left with an abort
+            Self::Abort => matches!(state, State::Tx | State::Rx | State::Lockon | State::Lockst),
+            Self::LdcReload
+            | Self::Reset
+            | Self::FlushRxFifo
+            | Self::FlushTxFifo
+            | Self::SequenceUpdate => true,
+        }
+    }
+
+    /// Whether the radio has finished the command when it's in the given state
+    const fn is_done_in(self, state: State) -> bool {
+        match self {
+            Self::Ready | Self::Abort => matches!(state, State::Ready),
+            Self::Sleep => matches!(state, State::SleepA | State::SleepB),
+            // Lockst is the end of a failed lock
+            Self::LockRx | Self::LockTx => matches!(state, State::Lockon | State::Lockst),
+            _ => true,
+        }
+    }
+}
+
+impl<Format, Spi, Sdn, Gpio, Delay> S2lp<Ready<Format>, Spi, Sdn, Gpio, Delay>
+where
+    Spi: SpiDevice,
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    /// Send a command to the radio and wait until it's in the state the command leads to. Returns that state.
+    ///
+    /// The commands that would take the radio out of the ready typestate (TX, RX, standby and reset) aren't allowed,
+    /// just like the commands the radio doesn't accept in its current state. Those return [Error::IllegalCommand].
+    /// The radio can go to sleep or lock the synthesizer, but must be brought back to ready with
+    /// [Command::Ready] or [Command::Abort] before the other methods of the driver are used.
+    ///
+    /// A failed lock returns [State::Lockst]. If the state isn't reached in time, [Error::BadState] is returned.
+    pub async fn strobe(&mut self, command: Command) -> Result<State, ErrorOf<Self>> {
+        let state = self.ll().mc_state_0().read()?.state()?;
+
+        let leaves_typestate = matches!(
+            command,
+            Command::Tx | Command::Rx | Command::Standby | Command::Reset
+        );
+        if leaves_typestate || !command.is_valid_from(state) {
+            return Err(Error::IllegalCommand { command, state });
+        }
+
+        #[cfg(feature = "defmt-03")]
+        defmt::debug!("{=str}: Strobing {} in {}", self.label, command, state);
+
+        match command {
+            Command::Tx => self.ll().tx().dispatch()?,
+            Command::Rx => self.ll().rx().dispatch()?,
+            Command::Ready => self.ll().ready().dispatch()?,
+            Command::Standby => self.ll().standby().dispatch()?,
+            Command::Sleep => self.ll().sleep().dispatch()?,
+            Command::LockRx => self.ll().lock_rx().dispatch()?,
+            Command::LockTx => self.ll().lock_tx().dispatch()?,
+            Command::Abort => self.ll().abort().dispatch()?,
+            Command::LdcReload => self.ll().ldc_reload().dispatch()?,
+            Command::Reset => self.ll().reset().dispatch()?,
+            Command::FlushRxFifo => self.ll().flush_rx_fifo().dispatch()?,
+            Command::FlushTxFifo => self.ll().flush_tx_fifo().dispatch()?,
+            Command::SequenceUpdate => self.ll().sequence_update().dispatch()?,
+        }
+
+        for _ in 0..STROBE_POLLS {
+            let state = self.ll().mc_state_0().read()?.state();
+            if let Ok(state) = state {
+                if command.is_done_in(state) {
+                    return Ok(state);
+                }
+            }
+
+            self.delay.delay_us(STROBE_POLL_INTERVAL_US).await;
+        }
+
+        Err(self.fatal(FatalError::BadState))
+    }
+}
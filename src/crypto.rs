@@ -0,0 +1,449 @@
+//! Payload encryption with any AEAD cipher.
+//!
+//! The S2-LP has no crypto engine, so encryption is done in software before the payload goes into the fifo.
+//! Implement [PayloadCipher] for the AEAD of choice (e.g. a RustCrypto `AeadInPlace` like AES-CCM or ChaCha20Poly1305)
+//! and wrap it in a [LinkCipher], which manages the nonces.
+//!
+//! An encrypted payload looks like this: `[frame counter (4 bytes, BE)][ciphertext][tag]`.
+//!
+//! ## Nonces
+//!
+//! An AEAD breaks down when a nonce is used twice with the same key. The nonce is made of a prefix unique to the sender
+//! followed by the frame counter, which is incremented for every packet. So:
+//! - Give every device that shares a key a different nonce prefix, e.g. derived from its address or serial number.
+//! - Don't let the frame counter start over with the same key, e.g. after a reboot.
+//!   Persist it (or a value ahead of it) with [LinkCipher::tx_counter] and restore it with [LinkCipher::set_tx_counter].
+//! - When the counter runs out, sending fails with [CryptoError::CounterExhausted]. Change the key before that.
+//!
+//! The receiver needs the nonce prefix of the sender to open a payload.
+//! To reject replayed packets, it should only accept frame counters higher than the last one seen from that sender.
+//! A [ReplayFilter] keeps track of that, and [S2lp::receive_encrypted_packet] uses one to receive and open a payload in one go.
+
+use embedded_hal::{
+    digital::{InputPin, OutputPin},
+    spi::SpiDevice,
+};
+use embedded_hal_async::{delay::DelayNs, digital::Wait};
+
+use crate::{
+    mac::MacAddress,
+    packet_format::PacketFormat,
+    states::{
+        rx::{RxMode, RxResult},
+        Ready, Tx,
+    },
+    Error, ErrorOf, S2lp,
+};
+
+/// The size of the frame counter in front of every encrypted payload
+pub const COUNTER_SIZE: usize = 4;
+/// The biggest nonce a [PayloadCipher] can use
+pub const MAX_NONCE_SIZE: usize = 16;
+
+/// An AEAD cipher that encrypts and authenticates payloads in place.
+///
+/// This maps directly on the detached in-place functions of the RustCrypto `AeadInPlace` trait.
+pub trait PayloadCipher {
+    /// The size of the nonce in bytes. Must be more than [COUNTER_SIZE] and at most [MAX_NONCE_SIZE].
+    const NONCE_SIZE: usize;
+    /// The size of the authentication tag in bytes
+    const TAG_SIZE: usize;
+
+    /// Encrypt the buffer in place and write the authentication tag
+    fn encrypt(
+        &mut self,
+        nonce: &[u8],
+        buffer: &mut [u8],
+        tag: &mut [u8],
+    ) -> Result<(), CryptoError>;
+
+    /// Check the authentication tag and decrypt the buffer in place.
+    /// Must return [CryptoError::Authentication] if the tag doesn't match.
+    fn decrypt(&mut self, nonce: &[u8], buffer: &mut [u8], tag: &[u8]) -> Result<(), CryptoError>;
+}
+
+/// The errors of the encryption layer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum CryptoError {
+    /// The buffer can't hold the payload with the counter and the tag
+    BufferTooSmall,
+    /// The received payload is too short or has been tampered with
+    Authentication,
+    /// All frame counter values have been used. The key must be changed.
+    CounterExhausted,
+    /// The nonce prefix has the wrong length for the cipher
+    BadNoncePrefix,
+    /// The frame counter isn't higher than the last one seen from the sender, so the payload was replayed
+    Replayed,
+    /// The sender of a received payload isn't known, so there's no nonce prefix to open it with
+    UnknownSender,
+}
+
+/// Encrypts and decrypts payloads with a [PayloadCipher] while managing the nonces. See the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct LinkCipher<C: PayloadCipher> {
+    cipher: C,
+    nonce_prefix: [u8; MAX_NONCE_SIZE],
+    tx_counter: u32,
+}
+
+impl<C: PayloadCipher> LinkCipher<C> {
+    /// The amount of bytes the encryption adds to a payload
+    pub const OVERHEAD: usize = COUNTER_SIZE + C::TAG_SIZE;
+
+    /// Create a new link cipher. The nonce prefix must be unique to this device
+    /// and be `C::NONCE_SIZE - COUNTER_SIZE` long.
+    pub fn new(cipher: C, nonce_prefix: &[u8]) -> Result<Self, CryptoError> {
+        let nonce_prefix = Self::check_prefix(nonce_prefix).ok_or(CryptoError::BadNoncePrefix)?;
+        let mut prefix = [0; MAX_NONCE_SIZE];
+        prefix[..nonce_prefix.len()].copy_from_slice(nonce_prefix);
+
+        Ok(Self {
+            cipher,
+            nonce_prefix: prefix,
+            tx_counter: 0,
+        })
+    }
+
+    /// The frame counter the next payload will be sent with
+    pub fn tx_counter(&self) -> u32 {
+        self.tx_counter
+    }
+
+    /// Set the frame counter, e.g. to restore it after a reboot
+    pub fn set_tx_counter(&mut self, tx_counter: u32) {
+        self.tx_counter = tx_counter;
+    }
+
+    fn check_prefix(nonce_prefix: &[u8]) -> Option<&[u8]> {
+        (C::NONCE_SIZE > COUNTER_SIZE
+            && C::NONCE_SIZE <= MAX_NONCE_SIZE
+            && nonce_prefix.len() == C::NONCE_SIZE - COUNTER_SIZE)
+            .then_some(nonce_prefix)
+    }
+
+    fn nonce(nonce_prefix: &[u8], counter: u32) -> [u8; MAX_NONCE_SIZE] {
+        let mut nonce = [0; MAX_NONCE_SIZE];
+        nonce[..nonce_prefix.len()].copy_from_slice(nonce_prefix);
+        nonce[nonce_prefix.len()..][..COUNTER_SIZE].copy_from_slice(&counter.to_be_bytes());
+        nonce
+    }
+
+    /// Encrypt the payload into the buffer. Returns the part of the buffer that holds the encrypted payload.
+    pub fn seal<'b>(
+        &mut self,
+        payload: &[u8],
+        buffer: &'b mut [u8],
+    ) -> Result<&'b [u8], CryptoError> {
+        let len = payload.len() + Self::OVERHEAD;
+        if buffer.len() < len {
+            return Err(CryptoError::BufferTooSmall);
+        }
+
+        let counter = self.tx_counter;
+        self.tx_counter = counter
+            .checked_add(1)
+            .ok_or(CryptoError::CounterExhausted)?;
+
+        let nonce = Self::nonce(&self.nonce_prefix[..C::NONCE_SIZE - COUNTER_SIZE], counter);
+
+        let (header, rest) = buffer[..len].split_at_mut(COUNTER_SIZE);
+        header.copy_from_slice(&counter.to_be_bytes());
+        let (data, tag) = rest.split_at_mut(payload.len());
+        data.copy_from_slice(payload);
+        self.cipher.encrypt(&nonce[..C::NONCE_SIZE], data, tag)?;
+
+        Ok(&buffer[..len])
+    }
+
+    /// Decrypt a received payload in place. The nonce prefix is the one of the sender.
+    ///
+    /// Returns the frame counter of the payload and the decrypted data.
+    pub fn open<'b>(
+        &mut self,
+        sender_nonce_prefix: &[u8],
+        received: &'b mut [u8],
+    ) -> Result<(u32, &'b [u8]), CryptoError> {
+        let nonce_prefix =
+            Self::check_prefix(sender_nonce_prefix).ok_or(CryptoError::BadNoncePrefix)?;
+        if received.len() < Self::OVERHEAD {
+            return Err(CryptoError::Authentication);
+        }
+
+        let (header, rest) = received.split_at_mut(COUNTER_SIZE);
+        let counter = u32::from_be_bytes(header.try_into().unwrap());
+        let (data, tag) = rest.split_at_mut(rest.len() - C::TAG_SIZE);
+
+        let nonce = Self::nonce(nonce_prefix, counter);
+        self.cipher.decrypt(&nonce[..C::NONCE_SIZE], data, tag)?;
+
+        Ok((counter, data))
+    }
+}
+
+/// Rejects replayed payloads by remembering the highest frame counter of up to `N` senders.
+///
+/// When more senders are seen, the oldest entry is replaced.
+/// The senders are identified by an address of type `A`, see [MacAddress].
+#[derive(Debug, Clone)]
+pub struct ReplayFilter<const N: usize, A: MacAddress = u8> {
+    entries: [Option<ReplayFilterEntry<A>>; N],
+    next_replace: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ReplayFilterEntry<A> {
+    sender: A,
+    counter: u32,
+}
+
+impl<const N: usize, A: MacAddress> ReplayFilter<N, A> {
+    /// Create a new, empty filter
+    pub const fn new() -> Self {
+        Self {
+            entries: [None; N],
+            next_replace: 0,
+        }
+    }
+
+    /// Check the frame counter of an opened payload. Returns [CryptoError::Replayed] if it's not higher
+    /// than the last one seen from the same sender.
+    ///
+    /// Counters that pass are recorded. Only check payloads that have been authenticated,
+    /// or a forged counter locks out the real sender.
+    pub fn check(&mut self, sender: A, counter: u32) -> Result<(), CryptoError> {
+        if let Some(entry) = self
+            .entries
+            .iter_mut()
+            .flatten()
+            .find(|entry| entry.sender == sender)
+        {
+            if counter <= entry.counter {
+                return Err(CryptoError::Replayed);
+            }
+
+            entry.counter = counter;
+            return Ok(());
+        }
+
+        if N > 0 {
+            self.entries[self.next_replace] = Some(ReplayFilterEntry { sender, counter });
+            self.next_replace = (self.next_replace + 1) % N;
+        }
+
+        Ok(())
+    }
+
+    /// Forget all recorded senders
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+}
+
+impl<const N: usize, A: MacAddress> Default for ReplayFilter<N, A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Format, Spi, Sdn, Gpio, Delay> S2lp<Ready<Format>, Spi, Sdn, Gpio, Delay>
+where
+    Format: PacketFormat,
+    Spi: SpiDevice,
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    /// Encrypt the payload into the buffer and send it. See [S2lp::send_packet].
+    ///
+    /// The buffer needs room for the payload plus the [LinkCipher::OVERHEAD].
+    /// On the receiving side, use [S2lp::receive_encrypted_packet] or decrypt the payload with [LinkCipher::open].
+    pub fn send_encrypted_packet<'b, C: PayloadCipher>(
+        self,
+        tx_meta_data: &Format::TxMetaData,
+        cipher: &mut LinkCipher<C>,
+        payload: &[u8],
+        buffer: &'b mut [u8],
+    ) -> Result<S2lp<Tx<'b, Format>, Spi, Sdn, Gpio, Delay>, ErrorOf<Self>> {
+        let encrypted = cipher.seal(payload, buffer).map_err(Error::Crypto)?;
+        self.send_packet(tx_meta_data, encrypted)
+    }
+
+    /// Receive a packet and decrypt its payload in place. See [S2lp::start_receive].
+    ///
+    /// `sender` gives the address and the nonce prefix of the sender of the received packet, e.g. by looking up
+    /// the source address in its metadata. The frame counter is checked against the last one of that sender
+    /// in the replay filter.
+    ///
+    /// Next to the [RxResult], the decrypted payload (a part of the buffer) is returned if a packet was received,
+    /// or why it was rejected. The buffer needs room for the payload plus the [LinkCipher::OVERHEAD].
+    pub async fn receive_encrypted_packet<
+        'b,
+        'p,
+        C: PayloadCipher,
+        const N: usize,
+        A: MacAddress,
+    >(
+        self,
+        buffer: &'b mut [u8],
+        mode: RxMode,
+        cipher: &mut LinkCipher<C>,
+        replay_filter: &mut ReplayFilter<N, A>,
+        sender: impl FnOnce(&Format::RxMetaData) -> Option<(A, &'p [u8])>,
+    ) -> Result<
+        (
+            Self,
+            RxResult<Format::RxMetaData>,
+            Option<Result<&'b [u8], CryptoError>>,
+        ),
+        ErrorOf<Self>,
+    > {
+        let mut rx = self.start_receive(buffer, mode)?;
+        let rx_result = rx.wait().await?;
+        let Ok(ready) = rx.finish() else {
+            unreachable!()
+        };
+
+        let RxResult::Ok {
+            packet_size,
+            ref meta_data,
+            ..
+        } = rx_result
+        else {
+            return Ok((ready, rx_result, None));
+        };
+
+        let payload = sender(meta_data)
+            .ok_or(CryptoError::UnknownSender)
+            .and_then(|(address, nonce_prefix)| {
+                let (counter, payload) = cipher.open(nonce_prefix, &mut buffer[..packet_size])?;
+                replay_filter.check(address, counter)?;
+                Ok(payload)
+            });
+
+        #[cfg(feature = "defmt-03")]
+        if let Err(e) = payload {
+            defmt::debug!("{=str}: Received payload rejected: {}", ready.label, e);
+        }
+
+        Ok((ready, rx_result, Some(payload)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Not a real cipher, but it has the same properties as far as the link cipher is concerned
+    struct XorCipher;
+
+    impl XorCipher {
+        fn apply(nonce: &[u8], buffer: &mut [u8]) {
+            for (i, byte) in buffer.iter_mut().enumerate() {
+                *byte ^= nonce[i % nonce.len()];
+            }
+        }
+    }
+
+    impl PayloadCipher for XorCipher {
+        const NONCE_SIZE: usize = 12;
+        const TAG_SIZE: usize = 2;
+
+        fn encrypt(
+            &mut self,
+            nonce: &[u8],
+            buffer: &mut [u8],
+            tag: &mut [u8],
+        ) -> Result<(), CryptoError> {
+            let sum = buffer.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+            Self::apply(nonce, buffer);
+            tag.copy_from_slice(&[sum, nonce[nonce.len() - 1]]);
+            Ok(())
+        }
+
+        fn decrypt(
+            &mut self,
+            nonce: &[u8],
+            buffer: &mut [u8],
+            tag: &[u8],
+        ) -> Result<(), CryptoError> {
+            Self::apply(nonce, buffer);
+            let sum = buffer.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+            if tag != [sum, nonce[nonce.len() - 1]] {
+                return Err(CryptoError::Authentication);
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn seal_and_open() {
+        let prefix = [1, 2, 3, 4, 5, 6, 7, 8];
+        let mut sender = LinkCipher::new(XorCipher, &prefix).unwrap();
+        let mut receiver = LinkCipher::new(XorCipher, &[9; 8]).unwrap();
+        let mut buffer = [0; 32];
+
+        let sealed = sender.seal(b"hello", &mut buffer).unwrap();
+        assert_eq!(sealed.len(), 5 + LinkCipher::<XorCipher>::OVERHEAD);
+        assert_eq!(&sealed[..4], &[0, 0, 0, 0]);
+        assert_ne!(&sealed[4..9], b"hello");
+
+        let mut received = sealed.to_vec();
+        assert_eq!(
+            receiver.open(&prefix, &mut received),
+            Ok((0, &b"hello"[..]))
+        );
+
+        // The counter moves on
+        let sealed = sender.seal(b"hello", &mut buffer).unwrap();
+        assert_eq!(&sealed[..4], &[0, 0, 0, 1]);
+
+        // Opening with the wrong prefix fails
+        let mut received = sealed.to_vec();
+        assert_eq!(
+            receiver.open(&[9; 8], &mut received),
+            Err(CryptoError::Authentication)
+        );
+    }
+
+    #[test]
+    fn replay_filter() {
+        let mut filter = ReplayFilter::<2>::new();
+
+        assert_eq!(filter.check(1, 5), Ok(()));
+        assert_eq!(filter.check(1, 5), Err(CryptoError::Replayed));
+        assert_eq!(filter.check(1, 4), Err(CryptoError::Replayed));
+        assert_eq!(filter.check(1, 6), Ok(()));
+        // Every sender has its own counter
+        assert_eq!(filter.check(2, 0), Ok(()));
+
+        // A third sender replaces the oldest entry
+        assert_eq!(filter.check(3, 0), Ok(()));
+        assert_eq!(filter.check(1, 6), Ok(()));
+    }
+
+    #[test]
+    fn limits() {
+        assert_eq!(
+            LinkCipher::new(XorCipher, &[0; 4]).err(),
+            Some(CryptoError::BadNoncePrefix)
+        );
+        assert_eq!(
+            LinkCipher::new(XorCipher, &[0; 20]).err(),
+            Some(CryptoError::BadNoncePrefix)
+        );
+
+        let mut cipher = LinkCipher::new(XorCipher, &[0; 8]).unwrap();
+        assert_eq!(
+            cipher.seal(&[0; 10], &mut [0; 15]).err(),
+            Some(CryptoError::BufferTooSmall)
+        );
+
+        cipher.set_tx_counter(u32::MAX);
+        assert_eq!(
+            cipher.seal(&[0; 10], &mut [0; 32]).err(),
+            Some(CryptoError::CounterExhausted)
+        );
+    }
+}
@@ -0,0 +1,163 @@
+//! CSMA with a binary exponential backoff in software, for when the hardware CSMA/CA doesn't fit.
+//!
+//! The hardware backoff engine does at most 7 backoffs with a fixed backoff exponent range.
+//! The [SoftCsma] follows the unslotted CSMA-CA of IEEE 802.15.4 instead, with a configurable unit backoff,
+//! exponent range and random source. Every attempt waits a random amount of unit backoffs and then does a
+//! [clear channel assessment](S2lp::channel_clear).
+//!
+//! Turn the [hardware CSMA/CA](S2lp::set_csma_ca) off when using this, or the packet is sent with both.
+
+use embedded_hal::{
+    digital::{InputPin, OutputPin},
+    spi::SpiDevice,
+};
+use embedded_hal_async::{delay::DelayNs, digital::Wait};
+
+use crate::{packet_format::PacketFormat, states::Ready, time::Duration, ErrorOf, S2lp};
+
+/// A source of random numbers for the backoff, e.g. a hardware RNG of the MCU.
+///
+/// Implemented for any `FnMut() -> u32`.
+pub trait BackoffRng {
+    /// Get the next random number
+    fn next_u32(&mut self) -> u32;
+}
+
+impl<F: FnMut() -> u32> BackoffRng for F {
+    fn next_u32(&mut self) -> u32 {
+        self()
+    }
+}
+
+/// The configuration of the [SoftCsma]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct SoftCsmaConfig {
+    /// The time of one backoff period
+    pub unit_backoff: Duration,
+    /// The backoff exponent of the first attempt. Every attempt waits up to `2^exponent - 1` unit backoffs.
+    pub min_backoff_exponent: u8,
+    /// The backoff exponent doesn't grow beyond this. Range: `min_backoff_exponent..=16`
+    pub max_backoff_exponent: u8,
+    /// How many times the channel may be found busy before giving up
+    pub max_backoffs: u8,
+    /// How long the channel is listened to in every clear channel assessment
+    pub cca_duration: Duration,
+}
+
+impl Default for SoftCsmaConfig {
+    /// The IEEE 802.15.4 defaults for the exponents and the amount of backoffs.
+    /// The times should be adjusted to the data rate.
+    fn default() -> Self {
+        Self {
+            unit_backoff: Duration::from_micros(1_000),
+            min_backoff_exponent: 3,
+            max_backoff_exponent: 5,
+            max_backoffs: 4,
+            cca_duration: Duration::from_micros(250),
+        }
+    }
+}
+
+/// Software CSMA with a binary exponential backoff. See the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct SoftCsma<R: BackoffRng> {
+    config: SoftCsmaConfig,
+    rng: R,
+}
+
+impl<R: BackoffRng> SoftCsma<R> {
+    /// Create a new software CSMA with the random source for the backoffs
+    pub fn new(config: SoftCsmaConfig, rng: R) -> Self {
+        Self { config, rng }
+    }
+
+    /// The configuration
+    pub fn config(&self) -> &SoftCsmaConfig {
+        &self.config
+    }
+
+    /// Get a random backoff time for the given attempt, starting at 0
+    pub fn backoff(&mut self, attempt: u8) -> Duration {
+        let exponent = self
+            .config
+            .min_backoff_exponent
+            .saturating_add(attempt)
+            .min(self.config.max_backoff_exponent)
+            .min(16);
+        let periods = self.rng.next_u32() & ((1 << exponent) - 1);
+
+        Duration::from_micros(periods.saturating_mul(self.config.unit_backoff.as_micros()))
+    }
+}
+
+impl<Format, Spi, Sdn, Gpio, Delay> S2lp<Ready<Format>, Spi, Sdn, Gpio, Delay>
+where
+    Format: PacketFormat,
+    Spi: SpiDevice,
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    /// Back off and assess the channel until it's clear or the maximum amount of backoffs is reached.
+    ///
+    /// Returns true if the channel is clear. Send the packet right after with [S2lp::send_packet].
+    /// On false, the channel access failed and the packet should be dropped or tried again later.
+    pub async fn csma_backoff<R: BackoffRng>(
+        &mut self,
+        csma: &mut SoftCsma<R>,
+    ) -> Result<bool, ErrorOf<Self>> {
+        for attempt in 0..=csma.config.max_backoffs {
+            let backoff = csma.backoff(attempt);
+            self.delay.delay_us(backoff.as_micros()).await;
+
+            if self.channel_clear(csma.config.cca_duration).await? {
+                return Ok(true);
+            }
+
+            #[cfg(feature = "defmt-03")]
+            defmt::debug!(
+                "{=str}: Channel busy after backoff {} of {}",
+                self.label,
+                attempt,
+                csma.config.max_backoffs
+            );
+        }
+
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_exponent_grows_to_the_max() {
+        let mut csma = SoftCsma::new(
+            SoftCsmaConfig {
+                unit_backoff: Duration::from_micros(320),
+                ..Default::default()
+            },
+            || u32::MAX,
+        );
+
+        assert_eq!(csma.backoff(0), Duration::from_micros(7 * 320));
+        assert_eq!(csma.backoff(1), Duration::from_micros(15 * 320));
+        assert_eq!(csma.backoff(2), Duration::from_micros(31 * 320));
+        assert_eq!(csma.backoff(10), Duration::from_micros(31 * 320));
+    }
+
+    #[test]
+    fn backoff_uses_the_rng() {
+        let mut next = 0;
+        let mut csma = SoftCsma::new(SoftCsmaConfig::default(), move || {
+            next += 5;
+            next
+        });
+
+        assert_eq!(csma.backoff(0), Duration::from_millis(5));
+        // 10 & 0b111
+        assert_eq!(csma.backoff(0), Duration::from_millis(2));
+    }
+}
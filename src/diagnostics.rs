@@ -0,0 +1,111 @@
+//! A snapshot of the radio taken when the driver runs into a fatal error, to find out what went wrong afterwards.
+//!
+//! When the driver returns [Error::BadState] or [Error::RcoLockError], it reads the state machine, the interrupts,
+//! the fifo levels and the key configuration registers of the radio and keeps them until the next fatal error.
+//! Get them with [S2lp::last_diagnostics]. With the `defmt-03` feature they're logged as well,
+//! which is the only place to find them when the failing call consumed the driver, like the init.
+
+use embedded_hal::{
+    digital::{InputPin, OutputPin},
+    spi::SpiDevice,
+};
+use embedded_hal_async::{delay::DelayNs, digital::Wait};
+
+use crate::{
+    irq::IrqEvents,
+    ll::{
+        field_sets::{McState0, McState1, Mod2, PcktCtrl1, PcktCtrl3, Protocol0, Protocol1, Synt},
+        DeviceError,
+    },
+    states::Addressable,
+    Error, ErrorOf, S2lp,
+};
+
+/// The fatal error a [Diagnostics] snapshot was taken for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum FatalError {
+    /// See [Error::BadState]
+    BadState,
+    /// See [Error::RcoLockError]
+    RcoLockError,
+}
+
+/// The state of the radio at the moment of a fatal error
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct Diagnostics {
+    /// The error that was returned
+    pub error: FatalError,
+    /// The state of the main state machine
+    pub mc_state_0: McState0,
+    /// The lock, calibration and oscillator flags
+    pub mc_state_1: McState1,
+    /// The pending interrupts. Reading them cleared them in the radio.
+    pub irq_status: IrqEvents,
+    /// The enabled interrupts
+    pub irq_mask: IrqEvents,
+    /// The amount of bytes in the tx fifo
+    pub tx_fifo_len: u8,
+    /// The amount of bytes in the rx fifo
+    pub rx_fifo_len: u8,
+    /// The synthesizer word (the frequency) and charge pump setting
+    pub synt: Synt,
+    /// The modulation and datarate exponent
+    pub mod_2: Mod2,
+    /// The tx source, CRC and whitening
+    pub pckt_ctrl_1: PcktCtrl1,
+    /// The packet format and rx mode
+    pub pckt_ctrl_3: PcktCtrl3,
+    /// The LDC, CSMA and packet filter settings
+    pub protocol_1: Protocol1,
+    /// The retransmission and auto ack settings
+    pub protocol_0: Protocol0,
+}
+
+#[allow(private_bounds)]
+impl<State, Spi, Sdn, Gpio, Delay> S2lp<State, Spi, Sdn, Gpio, Delay>
+where
+    State: Addressable,
+    Spi: SpiDevice,
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    /// Take a [Diagnostics] snapshot for the error and return the error.
+    /// If the radio can't be read, there's no snapshot and the previous one is kept.
+    pub(crate) fn fatal(&mut self, error: FatalError) -> ErrorOf<Self> {
+        if let Ok(diagnostics) = self.read_diagnostics(error) {
+            #[cfg(feature = "defmt-03")]
+            defmt::error!("{=str}: {}", self.label, diagnostics);
+
+            self.diagnostics = Some(diagnostics);
+        }
+
+        match error {
+            FatalError::BadState => Error::BadState,
+            FatalError::RcoLockError => Error::RcoLockError,
+        }
+    }
+
+    fn read_diagnostics(
+        &mut self,
+        error: FatalError,
+    ) -> Result<Diagnostics, DeviceError<Spi::Error>> {
+        Ok(Diagnostics {
+            error,
+            mc_state_0: self.ll().mc_state_0().read()?,
+            mc_state_1: self.ll().mc_state_1().read()?,
+            irq_status: self.ll().irq_status().read()?.into(),
+            irq_mask: self.ll().irq_mask().read()?.into(),
+            tx_fifo_len: self.ll().tx_fifo_status().read()?.n_elem_txfifo(),
+            rx_fifo_len: self.ll().rx_fifo_status().read()?.n_elem_rxfifo(),
+            synt: self.ll().synt().read()?,
+            mod_2: self.ll().mod_2().read()?,
+            pckt_ctrl_1: self.ll().pckt_ctrl_1().read()?,
+            pckt_ctrl_3: self.ll().pckt_ctrl_3().read()?,
+            protocol_1: self.ll().protocol_1().read()?,
+            protocol_0: self.ll().protocol_0().read()?,
+        })
+    }
+}
@@ -0,0 +1,309 @@
+//! Adapters for the traits of embedded-hal 0.2, for boards of which the HAL hasn't moved to 1.0 yet.
+//!
+//! Wrap the blocking spi bus with its chip select pin in an [Eh02Spi], the shutdown pin in an [Eh02OutputPin],
+//! the irq pin in an [Eh02IrqPin] and the delay in an [Eh02Delay], and hand them to [S2lp::new](crate::S2lp::new).
+//!
+//! embedded-hal 0.2 has no async traits, so the irq pin is polled and the delay blocks in short slices.
+//! That works, but the MCU can't sleep while the driver waits. Move to a 1.0 HAL for low power applications.
+
+use core::fmt::Debug;
+
+use embedded_hal::{digital, spi};
+use embedded_hal_02::{
+    blocking::{
+        delay::DelayUs,
+        spi::{Transfer, Write},
+    },
+    digital::v2 as digital_02,
+};
+use embedded_hal_async::{delay::DelayNs, digital::Wait};
+
+/// The amount of bytes a transfer with different read and write lengths is split in
+const TRANSFER_CHUNK: usize = 16;
+/// The longest time in microseconds an [Eh02Delay] blocks before yielding to the executor
+const DELAY_SLICE_US: u32 = 100;
+
+/// An error of an embedded-hal 0.2 pin
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Eh02PinError<E>(pub E);
+
+impl<E: Debug> digital::Error for Eh02PinError<E> {
+    fn kind(&self) -> digital::ErrorKind {
+        digital::ErrorKind::Other
+    }
+}
+
+/// An error of an [Eh02Spi]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Eh02SpiError<SpiError, CsError> {
+    /// The spi bus returned an error
+    Spi(SpiError),
+    /// The chip select pin returned an error
+    Cs(CsError),
+}
+
+impl<SpiError: Debug, CsError: Debug> spi::Error for Eh02SpiError<SpiError, CsError> {
+    fn kind(&self) -> spi::ErrorKind {
+        match self {
+            Self::Spi(_) => spi::ErrorKind::Other,
+            Self::Cs(_) => spi::ErrorKind::ChipSelectFault,
+        }
+    }
+}
+
+/// A blocking embedded-hal 0.2 spi bus with the chip select pin of the radio, used as a 1.0 [SpiDevice](spi::SpiDevice).
+///
+/// The bus isn't shared, so the radio must be the only device on it.
+/// Delays within a transaction aren't supported, which the driver doesn't use.
+#[derive(Debug)]
+pub struct Eh02Spi<Spi, Cs> {
+    spi: Spi,
+    cs: Cs,
+}
+
+impl<Spi, Cs> Eh02Spi<Spi, Cs> {
+    /// Create the device from the bus and the chip select pin. The pin should be high already.
+    pub fn new(spi: Spi, cs: Cs) -> Self {
+        Self { spi, cs }
+    }
+
+    /// Get back the bus and the chip select pin
+    pub fn release(self) -> (Spi, Cs) {
+        (self.spi, self.cs)
+    }
+}
+
+impl<Spi, Cs, E> Eh02Spi<Spi, Cs>
+where
+    Spi: Transfer<u8, Error = E> + Write<u8, Error = E>,
+{
+    fn run(&mut self, operation: &mut spi::Operation<'_, u8>) -> Result<(), E> {
+        match operation {
+            spi::Operation::Read(words) => {
+                words.fill(0);
+                self.spi.transfer(words)?;
+            }
+            spi::Operation::Write(words) => self.spi.write(words)?,
+            spi::Operation::Transfer(read, write) => {
+                let len = read.len().max(write.len());
+                let mut chunk = [0; TRANSFER_CHUNK];
+
+                for start in (0..len).step_by(TRANSFER_CHUNK) {
+                    let end = (start + TRANSFER_CHUNK).min(len);
+                    let chunk = &mut chunk[..end - start];
+                    chunk.fill(0);
+
+                    let write = write.get(start..end.min(write.len())).unwrap_or_default();
+                    chunk[..write.len()].copy_from_slice(write);
+
+                    let received = self.spi.transfer(chunk)?;
+                    let read = read.get_mut(start..end.min(read.len())).unwrap_or_default();
+                    read.copy_from_slice(&received[..read.len()]);
+                }
+            }
+            spi::Operation::TransferInPlace(words) => {
+                self.spi.transfer(words)?;
+            }
+            spi::Operation::DelayNs(_) => {
+                panic!(
+                    "Delays within a transaction aren't supported by the embedded-hal 0.2 adapter"
+                )
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<Spi, Cs, E> spi::ErrorType for Eh02Spi<Spi, Cs>
+where
+    Spi: Transfer<u8, Error = E> + Write<u8, Error = E>,
+    Cs: digital_02::OutputPin,
+    E: Debug,
+    Cs::Error: Debug,
+{
+    type Error = Eh02SpiError<E, Cs::Error>;
+}
+
+impl<Spi, Cs, E> spi::SpiDevice for Eh02Spi<Spi, Cs>
+where
+    Spi: Transfer<u8, Error = E> + Write<u8, Error = E>,
+    Cs: digital_02::OutputPin,
+    E: Debug,
+    Cs::Error: Debug,
+{
+    fn transaction(
+        &mut self,
+        operations: &mut [spi::Operation<'_, u8>],
+    ) -> Result<(), Self::Error> {
+        self.cs.set_low().map_err(Eh02SpiError::Cs)?;
+
+        let result = operations
+            .iter_mut()
+            .try_for_each(|operation| self.run(operation));
+
+        // Always deselect the radio, also when the bus failed
+        let cs_result = self.cs.set_high();
+
+        result.map_err(Eh02SpiError::Spi)?;
+        cs_result.map_err(Eh02SpiError::Cs)
+    }
+}
+
+/// An embedded-hal 0.2 output pin, used as a 1.0 [OutputPin](digital::OutputPin), e.g. for the shutdown pin
+#[derive(Debug)]
+pub struct Eh02OutputPin<P>(pub P);
+
+impl<P: digital_02::OutputPin> digital::ErrorType for Eh02OutputPin<P>
+where
+    P::Error: Debug,
+{
+    type Error = Eh02PinError<P::Error>;
+}
+
+impl<P: digital_02::OutputPin> digital::OutputPin for Eh02OutputPin<P>
+where
+    P::Error: Debug,
+{
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.0.set_low().map_err(Eh02PinError)
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.0.set_high().map_err(Eh02PinError)
+    }
+}
+
+/// An embedded-hal 0.2 input pin, used as the irq pin of the driver.
+///
+/// The waits poll the pin and yield to the executor in between, since embedded-hal 0.2 has no interrupt driven waits.
+#[derive(Debug)]
+pub struct Eh02IrqPin<P>(pub P);
+
+impl<P: digital_02::InputPin> Eh02IrqPin<P>
+where
+    P::Error: Debug,
+{
+    async fn wait_for_level(&mut self, high: bool) -> Result<(), Eh02PinError<P::Error>> {
+        while self.0.is_high().map_err(Eh02PinError)? != high {
+            embassy_futures::yield_now().await;
+        }
+
+        Ok(())
+    }
+}
+
+impl<P: digital_02::InputPin> digital::ErrorType for Eh02IrqPin<P>
+where
+    P::Error: Debug,
+{
+    type Error = Eh02PinError<P::Error>;
+}
+
+impl<P: digital_02::InputPin> digital::InputPin for Eh02IrqPin<P>
+where
+    P::Error: Debug,
+{
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        self.0.is_high().map_err(Eh02PinError)
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        self.0.is_low().map_err(Eh02PinError)
+    }
+}
+
+impl<P: digital_02::InputPin> Wait for Eh02IrqPin<P>
+where
+    P::Error: Debug,
+{
+    async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_level(true).await
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_level(false).await
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_level(false).await?;
+        self.wait_for_level(true).await
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_level(true).await?;
+        self.wait_for_level(false).await
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+        let high = self.0.is_high().map_err(Eh02PinError)?;
+        self.wait_for_level(!high).await
+    }
+}
+
+/// A blocking embedded-hal 0.2 delay, used as the async [DelayNs] of the driver.
+///
+/// The driver races delays against the irq pin, so the delay blocks in slices of 100 µs
+/// and yields to the executor in between. That way an [Eh02IrqPin] gets polled while the delay runs.
+#[derive(Debug)]
+pub struct Eh02Delay<D>(pub D);
+
+impl<D: DelayUs<u32>> DelayNs for Eh02Delay<D> {
+    async fn delay_ns(&mut self, ns: u32) {
+        self.delay_us(ns.div_ceil(1_000)).await;
+    }
+
+    async fn delay_us(&mut self, mut us: u32) {
+        while us > 0 {
+            let slice = us.min(DELAY_SLICE_US);
+            self.0.delay_us(slice);
+            us -= slice;
+
+            if us > 0 {
+                embassy_futures::yield_now().await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal::spi::{Operation, SpiDevice};
+    use embedded_hal_mock::eh0::{
+        digital::{Mock as PinMock, State, Transaction as PinTransaction},
+        spi::{Mock as SpiMock, Transaction as SpiTransaction},
+    };
+
+    use super::*;
+
+    #[test]
+    fn spi_transaction() {
+        let spi = SpiMock::new(&[
+            SpiTransaction::write(vec![0x01, 0x02]),
+            SpiTransaction::transfer(vec![0x00, 0x00], vec![0xAB, 0xCD]),
+            SpiTransaction::transfer(vec![0x03, 0x00], vec![0x11, 0x22]),
+        ]);
+        let cs = PinMock::new(&[
+            PinTransaction::set(State::Low),
+            PinTransaction::set(State::High),
+        ]);
+        let mut device = Eh02Spi::new(spi, cs);
+
+        let mut read = [0; 2];
+        let mut transfer_read = [0; 2];
+        device
+            .transaction(&mut [
+                Operation::Write(&[0x01, 0x02]),
+                Operation::Read(&mut read),
+                Operation::Transfer(&mut transfer_read, &[0x03]),
+            ])
+            .unwrap();
+
+        assert_eq!(read, [0xAB, 0xCD]);
+        assert_eq!(transfer_read, [0x11, 0x22]);
+
+        let (mut spi, mut cs) = device.release();
+        spi.done();
+        cs.done();
+    }
+}
@@ -0,0 +1,177 @@
+//! Random numbers from the RSSI noise, for MCUs without a hardware RNG.
+//!
+//! On an idle channel the lowest bit of the RSSI is thermal noise. It's biased though, so the bits are
+//! debiased with the von Neumann extractor: of every pair of different bits the first is kept and pairs of equal bits are dropped.
+//!
+//! The result is good for seeding a PRNG, e.g. for the [CSMA backoffs](crate::csma) or the start of a nonce.
+//! It's not a vetted cryptographic random source, so don't generate keys with it.
+
+use embedded_hal::{
+    digital::{InputPin, OutputPin},
+    spi::SpiDevice,
+};
+use embedded_hal_async::{delay::DelayNs, digital::Wait};
+
+use crate::{
+    ll::field_sets::IrqMask,
+    packet_format::PacketFormat,
+    states::{ready::CsmaCaMode, rx::RxMode, Ready, SessionRegisters},
+    Error, ErrorOf, S2lp,
+};
+
+/// The time between two RSSI samples, so the RSSI has been updated in between
+const SAMPLE_INTERVAL_US: u32 = 20;
+/// The amount of sample pairs that may be dropped in a row before giving up
+const MAX_DROPPED_PAIRS: u32 = 256;
+
+/// Turns a stream of biased bits into unbiased bytes
+#[derive(Debug, Default)]
+struct VonNeumann {
+    byte: u8,
+    bits: u8,
+}
+
+impl VonNeumann {
+    /// Feed a pair of bits. Returns a byte every time eight bits have been kept.
+    fn push(&mut self, first: bool, second: bool) -> Option<u8> {
+        if first == second {
+            return None;
+        }
+
+        self.byte = (self.byte << 1) | first as u8;
+        self.bits += 1;
+
+        if self.bits == 8 {
+            self.bits = 0;
+            Some(core::mem::take(&mut self.byte))
+        } else {
+            None
+        }
+    }
+}
+
+impl<Format, Spi, Sdn, Gpio, Delay> S2lp<Ready<Format>, Spi, Sdn, Gpio, Delay>
+where
+    Format: PacketFormat,
+    Spi: SpiDevice,
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    /// Fill the buffer with random bytes gathered from the RSSI noise. See the [module docs](crate::entropy).
+    ///
+    /// The receiver is on while this runs, which takes roughly 100 µs per random bit and longer on a noisy channel.
+    /// Use an idle channel, since a packet that's received in the meantime stops the RSSI from changing.
+    /// The radio is back in ready when this returns.
+    ///
+    /// Returns [Error::NoEntropy] if the RSSI doesn't change for a long time.
+    pub async fn random_bytes(&mut self, buffer: &mut [u8]) -> Result<(), ErrorOf<Self>> {
+        let digital_frequency = self.state.digital_frequency();
+        let sync_less = self.ll().pckt_ctrl_6().read()?.sync_len() == 0;
+        RxMode::Normal { timeout: None }.write_to_device(
+            self.ll(),
+            digital_frequency,
+            sync_less,
+        )?;
+
+        // The gpio isn't used, so no irqs are needed
+        let saved_registers = SessionRegisters::save(self.ll())?;
+        self.ll().irq_mask().write(|reg| *reg = IrqMask::new())?;
+
+        self.ll().rx().dispatch()?;
+
+        let result = self.gather_random_bytes(buffer).await;
+
+        self.ll().abort().dispatch()?;
+        self.ll().ready().dispatch()?;
+        self.ll().flush_rx_fifo().dispatch()?;
+        saved_registers.restore(self.ll())?;
+        // Read the irq status to clear it
+        self.ll().irq_status().read()?;
+
+        #[cfg(feature = "defmt-03")]
+        defmt::debug!(
+            "{=str}: Gathered {} random bytes: ok = {}",
+            self.label,
+            buffer.len(),
+            result.is_ok()
+        );
+
+        result
+    }
+
+    /// Set the CSMA/CA mode like [S2lp::set_csma_ca], but seed the backoff prng with [Self::random_bytes]
+    /// if the mode has backoffs without a custom seed.
+    ///
+    /// This keeps identical radios that power up at the same time from picking the same backoffs and colliding over and over.
+    pub async fn set_csma_ca_seeded(&mut self, mut mode: CsmaCaMode) -> Result<(), ErrorOf<Self>> {
+        if let CsmaCaMode::Backoff {
+            custom_prng_seed: custom_prng_seed @ None,
+            ..
+        } = &mut mode
+        {
+            let mut seed = [0; 2];
+            self.random_bytes(&mut seed).await?;
+            *custom_prng_seed = Some(u16::from_be_bytes(seed));
+        }
+
+        self.set_csma_ca(mode)
+    }
+
+    async fn gather_random_bytes(&mut self, buffer: &mut [u8]) -> Result<(), ErrorOf<Self>> {
+        let mut extractor = VonNeumann::default();
+        let mut dropped_pairs = 0;
+
+        for byte in buffer.iter_mut() {
+            *byte = loop {
+                let first = self.rssi_noise_bit().await?;
+                let second = self.rssi_noise_bit().await?;
+
+                if first == second {
+                    dropped_pairs += 1;
+                    if dropped_pairs > MAX_DROPPED_PAIRS {
+                        return Err(Error::NoEntropy);
+                    }
+                    continue;
+                }
+                dropped_pairs = 0;
+
+                if let Some(byte) = extractor.push(first, second) {
+                    break byte;
+                }
+            };
+        }
+
+        Ok(())
+    }
+
+    async fn rssi_noise_bit(&mut self) -> Result<bool, ErrorOf<Self>> {
+        self.delay.delay_us(SAMPLE_INTERVAL_US).await;
+        Ok(self.ll().rssi_level_run().read()?.value() & 1 == 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn von_neumann() {
+        let mut extractor = VonNeumann::default();
+
+        // Equal pairs are dropped
+        assert_eq!(extractor.push(true, true), None);
+        assert_eq!(extractor.push(false, false), None);
+
+        for _ in 0..3 {
+            assert_eq!(extractor.push(true, false), None);
+            assert_eq!(extractor.push(false, true), None);
+        }
+        assert_eq!(extractor.push(true, false), None);
+        assert_eq!(extractor.push(true, true), None);
+        assert_eq!(extractor.push(false, true), Some(0b1010_1010));
+
+        // It starts over after a byte
+        assert_eq!(extractor.bits, 0);
+    }
+}
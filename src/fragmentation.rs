@@ -0,0 +1,310 @@
+//! Splitting payloads that are too big for a single frame into fragments and putting them back together.
+//!
+//! This works on the payload bytes only, so it can be used with any packet format.
+//! Every fragment starts with a [FRAGMENT_HEADER_SIZE] byte header with the message id, the index of the fragment and the amount of fragments.
+//!
+//! Fragments must arrive in order. Retransmissions of the last received fragment are ignored,
+//! any other fragment out of order drops the message being reassembled.
+
+use crate::time::Duration;
+
+/// The size of the header in front of every fragment
+pub const FRAGMENT_HEADER_SIZE: usize = 3;
+
+/// The errors of the fragmentation helpers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum FragmentError {
+    /// The frame size doesn't leave room for data after the header
+    FrameSizeTooSmall,
+    /// The payload needs more than 255 fragments
+    TooManyFragments,
+    /// The frame is too short to be a fragment or has a bad header
+    BadFragment,
+    /// The reassembled message doesn't fit in the buffer
+    MessageTooLarge,
+    /// A fragment was missed. The message that was being reassembled has been dropped.
+    MissingFragment,
+}
+
+/// Splits a payload into fragments
+#[derive(Debug, Clone)]
+pub struct Fragmenter<'a> {
+    payload: &'a [u8],
+    fragment_data_size: usize,
+    message_id: u8,
+    count: u8,
+    next_index: u8,
+}
+
+impl<'a> Fragmenter<'a> {
+    /// Prepare the fragmentation of the payload into frames of at most `max_frame_size` bytes.
+    ///
+    /// The `message_id` should be different for every message, e.g. by incrementing it,
+    /// so the receiver can tell the fragments of subsequent messages apart.
+    pub fn new(
+        payload: &'a [u8],
+        max_frame_size: usize,
+        message_id: u8,
+    ) -> Result<Self, FragmentError> {
+        if max_frame_size <= FRAGMENT_HEADER_SIZE {
+            return Err(FragmentError::FrameSizeTooSmall);
+        }
+
+        let fragment_data_size = max_frame_size - FRAGMENT_HEADER_SIZE;
+        // An empty payload is still sent as one (empty) fragment
+        let count = payload.len().div_ceil(fragment_data_size).max(1);
+
+        Ok(Self {
+            payload,
+            fragment_data_size,
+            message_id,
+            count: count
+                .try_into()
+                .map_err(|_| FragmentError::TooManyFragments)?,
+            next_index: 0,
+        })
+    }
+
+    /// The total amount of fragments of the payload
+    pub fn fragment_count(&self) -> u8 {
+        self.count
+    }
+
+    /// The amount of fragments that haven't been taken yet
+    pub fn remaining(&self) -> u8 {
+        self.count - self.next_index
+    }
+
+    /// Write the next fragment into the buffer and return the part of the buffer that holds it.
+    /// Returns None when all fragments have been taken.
+    ///
+    /// The buffer must be at least `max_frame_size` long.
+    pub fn next_fragment<'b>(&mut self, buffer: &'b mut [u8]) -> Option<&'b [u8]> {
+        if self.next_index == self.count {
+            return None;
+        }
+
+        let start = self.next_index as usize * self.fragment_data_size;
+        let end = (start + self.fragment_data_size).min(self.payload.len());
+        let data = &self.payload[start..end];
+        let len = FRAGMENT_HEADER_SIZE + data.len();
+
+        buffer[0] = self.message_id;
+        buffer[1] = self.next_index;
+        buffer[2] = self.count;
+        buffer[FRAGMENT_HEADER_SIZE..len].copy_from_slice(data);
+
+        self.next_index += 1;
+        Some(&buffer[..len])
+    }
+}
+
+/// Puts fragments back together into messages of up to `N` bytes.
+///
+/// The reassembler has no clock of its own, so the current time is passed in.
+/// This can be any monotonic time, e.g. the time since boot.
+#[derive(Debug, Clone)]
+pub struct Reassembler<const N: usize> {
+    buffer: [u8; N],
+    len: usize,
+    timeout: Duration,
+    current: Option<PartialMessage>,
+    /// The message id and index of the last accepted fragment, to recognize retransmissions
+    last_fragment: Option<(u8, u8)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PartialMessage {
+    message_id: u8,
+    count: u8,
+    next_index: u8,
+    last_fragment_time: Duration,
+}
+
+impl<const N: usize> Reassembler<N> {
+    /// Create a reassembler. A message is dropped when no fragment of it came in for longer than the timeout.
+    pub const fn new(timeout: Duration) -> Self {
+        Self {
+            buffer: [0; N],
+            len: 0,
+            timeout,
+            current: None,
+            last_fragment: None,
+        }
+    }
+
+    /// Add a received fragment. When it completes a message, the message is returned.
+    ///
+    /// A single-fragment message is returned right away.
+    pub fn push(&mut self, fragment: &[u8], now: Duration) -> Result<Option<&[u8]>, FragmentError> {
+        let [message_id, index, count, ..] = *fragment else {
+            return Err(FragmentError::BadFragment);
+        };
+        if index >= count {
+            return Err(FragmentError::BadFragment);
+        }
+        let data = &fragment[FRAGMENT_HEADER_SIZE..];
+
+        if self.last_fragment == Some((message_id, index)) {
+            // A retransmission, e.g. because the ack got lost
+            return Ok(None);
+        }
+
+        if let Some(current) = self.current {
+            let elapsed = now
+                .as_micros()
+                .wrapping_sub(current.last_fragment_time.as_micros());
+            if elapsed > self.timeout.as_micros() {
+                #[cfg(feature = "defmt-03")]
+                defmt::debug!("Reassembly of message {} timed out", current.message_id);
+                self.current = None;
+            }
+        }
+
+        match self.current {
+            Some(current)
+                if current.message_id == message_id
+                    && current.count == count
+                    && current.next_index == index => {}
+            Some(_) if index != 0 => {
+                self.current = None;
+                return Err(FragmentError::MissingFragment);
+            }
+            None if index != 0 => return Err(FragmentError::MissingFragment),
+            _ => {
+                // The start of a new message
+                self.len = 0;
+                self.current = Some(PartialMessage {
+                    message_id,
+                    count,
+                    next_index: 0,
+                    last_fragment_time: now,
+                });
+            }
+        }
+
+        if self.len + data.len() > N {
+            self.current = None;
+            return Err(FragmentError::MessageTooLarge);
+        }
+        self.buffer[self.len..][..data.len()].copy_from_slice(data);
+        self.len += data.len();
+
+        let Some(current) = self.current.as_mut() else {
+            unreachable!()
+        };
+        current.next_index += 1;
+        current.last_fragment_time = now;
+        self.last_fragment = Some((message_id, index));
+
+        if current.next_index == current.count {
+            self.current = None;
+            return Ok(Some(&self.buffer[..self.len]));
+        }
+
+        Ok(None)
+    }
+
+    /// Returns true if a message is being reassembled
+    pub fn is_busy(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Drop the message that's being reassembled, if any
+    pub fn reset(&mut self) {
+        self.current = None;
+        self.len = 0;
+        self.last_fragment = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let payload: [u8; 50] = core::array::from_fn(|i| i as u8);
+        let mut fragmenter = Fragmenter::new(&payload, 20, 7).unwrap();
+        assert_eq!(fragmenter.fragment_count(), 3);
+
+        let mut reassembler = Reassembler::<64>::new(Duration::from_millis(100));
+        let mut frame = [0; 20];
+
+        let fragment = fragmenter.next_fragment(&mut frame).unwrap();
+        assert_eq!(fragment.len(), 20);
+        assert_eq!(&fragment[..3], &[7, 0, 3]);
+        assert_eq!(reassembler.push(fragment, Duration::ZERO), Ok(None));
+
+        // Retransmissions are ignored
+        assert_eq!(reassembler.push(fragment, Duration::ZERO), Ok(None));
+
+        let fragment = fragmenter.next_fragment(&mut frame).unwrap();
+        assert_eq!(reassembler.push(fragment, Duration::ZERO), Ok(None));
+
+        let fragment = fragmenter.next_fragment(&mut frame).unwrap();
+        assert_eq!(fragment.len(), 3 + 16);
+        assert_eq!(
+            reassembler.push(fragment, Duration::ZERO),
+            Ok(Some(&payload[..]))
+        );
+
+        assert!(!reassembler.is_busy());
+
+        // Also after the message is complete
+        assert_eq!(reassembler.push(fragment, Duration::ZERO), Ok(None));
+        assert!(fragmenter.next_fragment(&mut frame).is_none());
+    }
+
+    #[test]
+    fn missing_fragment_and_timeout() {
+        let payload = [0xAB; 30];
+        let mut reassembler = Reassembler::<64>::new(Duration::from_millis(100));
+        let mut frame = [0; 13];
+
+        let mut fragmenter = Fragmenter::new(&payload, 13, 1).unwrap();
+        let first = fragmenter.next_fragment(&mut frame).unwrap().to_vec();
+        fragmenter.next_fragment(&mut frame).unwrap();
+        let third = fragmenter.next_fragment(&mut frame).unwrap().to_vec();
+
+        assert_eq!(reassembler.push(&first, Duration::ZERO), Ok(None));
+        assert_eq!(
+            reassembler.push(&third, Duration::ZERO),
+            Err(FragmentError::MissingFragment)
+        );
+        assert!(!reassembler.is_busy());
+
+        // The second fragment comes in too late
+        let mut fragmenter = Fragmenter::new(&payload, 13, 2).unwrap();
+        let first = fragmenter.next_fragment(&mut frame).unwrap().to_vec();
+        let second = fragmenter.next_fragment(&mut frame).unwrap().to_vec();
+        assert_eq!(reassembler.push(&first, Duration::ZERO), Ok(None));
+        assert_eq!(
+            reassembler.push(&second, Duration::from_millis(200)),
+            Err(FragmentError::MissingFragment)
+        );
+    }
+
+    #[test]
+    fn limits() {
+        assert_eq!(
+            Fragmenter::new(&[0; 10], 3, 0).err(),
+            Some(FragmentError::FrameSizeTooSmall)
+        );
+        assert_eq!(
+            Fragmenter::new(&[0; 256], 4, 0).err(),
+            Some(FragmentError::TooManyFragments)
+        );
+
+        let mut reassembler = Reassembler::<4>::new(Duration::from_millis(100));
+        assert_eq!(
+            reassembler.push(&[0, 0, 1, 1, 2, 3, 4, 5], Duration::ZERO),
+            Err(FragmentError::MessageTooLarge)
+        );
+        assert_eq!(
+            reassembler.push(&[0, 1], Duration::ZERO),
+            Err(FragmentError::BadFragment)
+        );
+    }
+}
@@ -0,0 +1,187 @@
+//! Decoding of the interrupt status into typed events
+
+use core::fmt::Debug;
+
+use crate::ll::field_sets::IrqMask;
+
+/// An interrupt event of the radio. The value is the bit in the irq status and mask registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[repr(u8)]
+pub enum IrqEvent {
+    /// RX data ready
+    RxDataReady = 0,
+    /// RX data discarded (upon filtering)
+    RxDataDiscarded = 1,
+    /// TX data sent
+    TxDataSent = 2,
+    /// Max. re-TX reached
+    MaxReTxReached = 3,
+    /// CRC error
+    CrcError = 4,
+    /// TX FIFO underflow/overflow error
+    TxFifoError = 5,
+    /// RX FIFO underflow/overflow error
+    RxFifoError = 6,
+    /// TX FIFO almost full
+    TxFifoAlmostFull = 7,
+    /// TX FIFO almost empty
+    TxFifoAlmostEmpty = 8,
+    /// RX FIFO almost full
+    RxFifoAlmostFull = 9,
+    /// RX FIFO almost empty
+    RxFifoAlmostEmpty = 10,
+    /// Max. number of back-off during CCA
+    MaxBackoffReached = 11,
+    /// Valid preamble detected
+    ValidPreamble = 12,
+    /// Sync word detected
+    ValidSync = 13,
+    /// RSSI above threshold (CS)
+    RssiAboveThreshold = 14,
+    /// Wake-up timeout in LDC mode
+    WakeUpTimeoutLdc = 15,
+    /// READY state
+    Ready = 16,
+    /// STANDBY state switching in progress
+    StandbyDelayed = 17,
+    /// Low battery level
+    LowBatteryLevel = 18,
+    /// Power-on reset
+    PowerOnReset = 19,
+    /// RX timer timeout
+    RxTimeout = 28,
+    /// Sniff timer timeout
+    RxSniffTimeout = 29,
+}
+
+impl IrqEvent {
+    /// All events in order of their bit position
+    pub const ALL: [IrqEvent; 22] = [
+        IrqEvent::RxDataReady,
+        IrqEvent::RxDataDiscarded,
+        IrqEvent::TxDataSent,
+        IrqEvent::MaxReTxReached,
+        IrqEvent::CrcError,
+        IrqEvent::TxFifoError,
+        IrqEvent::RxFifoError,
+        IrqEvent::TxFifoAlmostFull,
+        IrqEvent::TxFifoAlmostEmpty,
+        IrqEvent::RxFifoAlmostFull,
+        IrqEvent::RxFifoAlmostEmpty,
+        IrqEvent::MaxBackoffReached,
+        IrqEvent::ValidPreamble,
+        IrqEvent::ValidSync,
+        IrqEvent::RssiAboveThreshold,
+        IrqEvent::WakeUpTimeoutLdc,
+        IrqEvent::Ready,
+        IrqEvent::StandbyDelayed,
+        IrqEvent::LowBatteryLevel,
+        IrqEvent::PowerOnReset,
+        IrqEvent::RxTimeout,
+        IrqEvent::RxSniffTimeout,
+    ];
+
+    /// The mask of this event in the 32-bit irq status
+    pub const fn mask(self) -> u32 {
+        1 << self as u8
+    }
+}
+
+/// A set of interrupt events, e.g. decoded from the irq status.
+///
+/// Iterate over it to get the individual [IrqEvent]s.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub struct IrqEvents(u32);
+
+impl IrqEvents {
+    /// Create the set from the raw 32-bit irq status or mask value
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    /// Get the raw 32-bit value
+    pub const fn bits(&self) -> u32 {
+        self.0
+    }
+
+    /// Returns true if the event is in the set
+    pub const fn contains(&self, event: IrqEvent) -> bool {
+        self.0 & event.mask() != 0
+    }
+
+    /// Returns true if there are no events in the set
+    pub const fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Iterate over the events in the set in order of their bit position
+    pub fn iter(&self) -> impl Iterator<Item = IrqEvent> {
+        let bits = self.0;
+        IrqEvent::ALL
+            .into_iter()
+            .filter(move |event| bits & event.mask() != 0)
+    }
+}
+
+impl From<IrqMask> for IrqEvents {
+    fn from(value: IrqMask) -> Self {
+        Self(u32::from_be_bytes(value.into()))
+    }
+}
+
+impl FromIterator<IrqEvent> for IrqEvents {
+    fn from_iter<T: IntoIterator<Item = IrqEvent>>(iter: T) -> Self {
+        Self(iter.into_iter().fold(0, |bits, event| bits | event.mask()))
+    }
+}
+
+impl Debug for IrqEvents {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(feature = "defmt-03")]
+impl defmt::Format for IrqEvents {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "[");
+        for (i, event) in self.iter().enumerate() {
+            if i != 0 {
+                defmt::write!(f, ", ");
+            }
+            defmt::write!(f, "{}", event);
+        }
+        defmt::write!(f, "]");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_irq_status() {
+        let mut status = IrqMask::new();
+        status.set_tx_data_sent(true);
+        status.set_valid_sync(true);
+        status.set_rx_timeout(true);
+
+        let events = IrqEvents::from(status);
+        assert_eq!(events.bits(), (1 << 2) | (1 << 13) | (1 << 28));
+        assert!(events.contains(IrqEvent::ValidSync));
+        assert!(!events.contains(IrqEvent::RxDataReady));
+
+        let mut iter = events.iter();
+        assert_eq!(iter.next(), Some(IrqEvent::TxDataSent));
+        assert_eq!(iter.next(), Some(IrqEvent::ValidSync));
+        assert_eq!(iter.next(), Some(IrqEvent::RxTimeout));
+        assert_eq!(iter.next(), None);
+
+        assert_eq!(events, events.iter().collect());
+        assert_eq!(
+            std::format!("{events:?}"),
+            "[TxDataSent, ValidSync, RxTimeout]"
+        );
+    }
+}
@@ -4,25 +4,85 @@
 //! Driver for the S2-LP radio chip from ST.
 //! Built fully in Rust, uses [embedded_hal] and [device_driver].
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+#[cfg(not(any(
+    feature = "basic",
+    feature = "stack",
+    feature = "fixed-length",
+    feature = "wmbus",
+    feature = "uart-ota",
+    feature = "raw-fifo"
+)))]
+compile_error!("At least one packet format feature must be enabled");
+
 use device_driver::embedded_io::ErrorKind;
+use embassy_futures::select::{select, Either};
 use embedded_hal::{
     digital::{InputPin, OutputPin},
     spi::SpiDevice,
 };
 use embedded_hal_async::{delay::DelayNs, digital::Wait};
 use ll::{Device, DeviceError, DeviceInterface};
+use states::{rx::RssiCapture, shutdown::RcoCalibrationWait};
 
+pub mod band;
+pub mod beacon;
+#[cfg(feature = "bridge")]
+pub mod bridge;
+pub mod channel_plan;
+pub mod command;
+pub mod crypto;
+pub mod csma;
+pub mod diagnostics;
+#[cfg(feature = "embedded-hal-02")]
+pub mod eh02;
+pub mod entropy;
+pub mod fragmentation;
+pub mod irq;
 pub mod ll;
+pub mod mac;
+pub mod mirror;
+#[cfg(feature = "ota")]
+pub mod ota;
 pub mod packet_format;
+pub mod power;
+pub mod prelude;
+pub mod queue;
+pub mod ranging;
+pub mod raw;
+pub mod rssi;
+#[cfg(feature = "basic")]
+pub mod serial;
 pub mod states;
+pub mod time;
+
+/// The label radios get when none is set with [S2lp::set_label]
+pub const DEFAULT_LABEL: &str = "s2lp";
 
 /// The main driver struct of the crate representing the S2-LP radio
+///
+/// Multiple radios can share an spi bus, e.g. with the `RefCellDevice` or `AtomicDevice` of `embedded-hal-bus`.
+/// Give each radio its own label with [S2lp::set_label] to tell their logs apart.
+///
+/// Dropping the driver doesn't stop the radio. A transmission or reception keeps going without an owner.
+/// End it with `abort` or `finish` first, or use [S2lp::detach] to leave the radio running on purpose
+/// and take it back later with [S2lp::recover_orphaned].
 #[derive(Debug)]
+#[must_use = "Dropping the driver doesn't stop the radio. End the session or detach it."]
 pub struct S2lp<State, Spi, Sdn: OutputPin, Gpio: InputPin + Wait, Delay: DelayNs> {
     device: Option<Device<DeviceInterface<Spi>>>,
     shutdown_pin: Sdn,
     gpio_pin: Gpio,
     gpio_number: GpioNumber,
+    irq_trigger: IrqTrigger,
+    rssi_capture: RssiCapture,
+    label: &'static str,
+    rco_calibration_wait: RcoCalibrationWait,
+    inter_packet_gap: time::Duration,
+    mirror: Option<&'static mut mirror::MirrorCapture>,
+    diagnostics: Option<diagnostics::Diagnostics>,
     delay: Delay,
     state: State,
 }
@@ -39,6 +99,13 @@ impl<State, Spi: SpiDevice, Sdn: OutputPin, Gpio: InputPin + Wait, Delay: DelayN
             shutdown_pin: self.shutdown_pin,
             gpio_pin: self.gpio_pin,
             gpio_number: self.gpio_number,
+            irq_trigger: self.irq_trigger,
+            rssi_capture: self.rssi_capture,
+            label: self.label,
+            rco_calibration_wait: self.rco_calibration_wait,
+            inter_packet_gap: self.inter_packet_gap,
+            mirror: self.mirror,
+            diagnostics: self.diagnostics,
             delay: self.delay,
             state: next_state,
         }
@@ -48,17 +115,69 @@ impl<State, Spi: SpiDevice, Sdn: OutputPin, Gpio: InputPin + Wait, Delay: DelayN
 impl<State, Spi: SpiDevice, Sdn: OutputPin, Gpio: InputPin + Wait, Delay: DelayNs>
     S2lp<State, Spi, Sdn, Gpio, Delay>
 {
+    /// Take the spi out of the driver, e.g. to let the spi peripheral power down while waiting.
+    ///
+    /// While the spi is taken, the driver can't service the fifos.
+    /// So when taking the spi during a transmission, the payload should fit in the fifo (128 bytes).
+    /// For receiving, [S2lp::start_receive_detached] enforces the same limit.
+    /// Use `wait_for_irq` in the Tx and Rx states to wait while the spi is taken.
+    /// When receiving, give the spi back with `reattach_spi` so a fifo overflow in the meantime is detected.
+    ///
+    /// The settings of the interface, like the [fifo chunk size](S2lp::set_fifo_chunk_size), are kept for when the spi is given back.
     pub fn take_spi(self) -> (S2lp<State, (), Sdn, Gpio, Delay>, Spi) {
+        self.map_spi(|spi| ((), spi))
+    }
+}
+
+impl<State, Spi, Sdn: OutputPin, Gpio: InputPin + Wait, Delay: DelayNs>
+    S2lp<State, Spi, Sdn, Gpio, Delay>
+{
+    /// Give up the driver but leave the radio in the state it's in, e.g. to keep receiving while the MCU restarts.
+    ///
+    /// The radio is driven by nothing afterwards. Take it back with [S2lp::recover_orphaned].
+    pub fn detach(self) -> RadioParts<Spi, Sdn, Gpio, Delay> {
+        #[cfg(feature = "defmt-03")]
+        defmt::debug!("{=str}: Detaching the radio", self.label);
+
+        RadioParts {
+            spi: self.device.unwrap().interface.spi,
+            shutdown_pin: self.shutdown_pin,
+            gpio_pin: self.gpio_pin,
+            gpio_number: self.gpio_number,
+            delay: self.delay,
+        }
+    }
+
+    /// Swap out the spi for another one while keeping the rest of the interface (like the fifo chunk size and the metrics) intact
+    fn map_spi<NewSpi, T>(
+        self,
+        f: impl FnOnce(Spi) -> (NewSpi, T),
+    ) -> (S2lp<State, NewSpi, Sdn, Gpio, Delay>, T) {
+        let interface = self.device.unwrap().interface;
+        let (spi, value) = f(interface.spi);
+
         (
             S2lp {
-                device: None,
+                device: Some(Device::new(DeviceInterface {
+                    spi,
+                    fifo_chunk_size: interface.fifo_chunk_size,
+                    #[cfg(feature = "metrics")]
+                    metrics: interface.metrics,
+                })),
                 shutdown_pin: self.shutdown_pin,
                 gpio_pin: self.gpio_pin,
                 gpio_number: self.gpio_number,
+                irq_trigger: self.irq_trigger,
+                rssi_capture: self.rssi_capture,
+                label: self.label,
+                rco_calibration_wait: self.rco_calibration_wait,
+                inter_packet_gap: self.inter_packet_gap,
+                mirror: self.mirror,
+                diagnostics: self.diagnostics,
                 delay: self.delay,
                 state: self.state,
             },
-            self.device.unwrap().interface.spi,
+            value,
         )
     }
 }
@@ -66,16 +185,124 @@ impl<State, Spi: SpiDevice, Sdn: OutputPin, Gpio: InputPin + Wait, Delay: DelayN
 impl<State, Sdn: OutputPin, Gpio: InputPin + Wait, Delay: DelayNs>
     S2lp<State, (), Sdn, Gpio, Delay>
 {
+    /// Give the spi back to the driver
     pub fn give_spi<Spi: SpiDevice>(self, spi: Spi) -> S2lp<State, Spi, Sdn, Gpio, Delay> {
-        S2lp {
-            device: Some(Device::new(DeviceInterface::new(spi))),
-            shutdown_pin: self.shutdown_pin,
-            gpio_pin: self.gpio_pin,
-            gpio_number: self.gpio_number,
-            delay: self.delay,
-            state: self.state,
+        self.map_spi(|()| (spi, ())).0
+    }
+}
+
+impl<State, Spi, Sdn: OutputPin, Gpio: InputPin + Wait, Delay: DelayNs>
+    S2lp<State, Spi, Sdn, Gpio, Delay>
+{
+    /// Set how the driver waits on the interrupt pin. See [IrqTrigger].
+    pub fn set_irq_trigger(&mut self, irq_trigger: IrqTrigger) {
+        self.irq_trigger = irq_trigger;
+    }
+
+    /// Get how the driver waits on the interrupt pin
+    pub fn irq_trigger(&self) -> IrqTrigger {
+        self.irq_trigger
+    }
+
+    /// Wait for the interrupt pin for as long as it takes.
+    ///
+    /// The radio keeps the pin low until the irq status is read, so with [IrqTrigger::Edge] a missed edge would
+    /// block forever. The level is checked again every [IRQ_EDGE_RECHECK] to recover from that.
+    pub(crate) async fn wait_for_irq_pin(&mut self) -> Result<(), Gpio::Error> {
+        loop {
+            match self.irq_trigger {
+                IrqTrigger::Level => return self.irq_trigger.wait(&mut self.gpio_pin).await,
+                IrqTrigger::Edge => {
+                    if let Either::First(res) = select(
+                        self.irq_trigger.wait(&mut self.gpio_pin),
+                        self.delay.delay_us(IRQ_EDGE_RECHECK.as_micros()),
+                    )
+                    .await
+                    {
+                        return res;
+                    }
+                }
+            }
         }
     }
+
+    /// Set at which moment the RSSI value of a received packet is sampled. See [RssiCapture].
+    pub fn set_rssi_capture(&mut self, rssi_capture: RssiCapture) {
+        self.rssi_capture = rssi_capture;
+    }
+
+    /// Get at which moment the RSSI value of a received packet is sampled
+    pub fn rssi_capture(&self) -> RssiCapture {
+        self.rssi_capture
+    }
+
+    /// Set the minimum time between the end of a transmission and the start of the next one, zero by default.
+    ///
+    /// The TX `wait` only returns once the gap has passed after a packet went out, so the next packet can't be sent earlier.
+    /// This is for receivers that are slow to turn around and for regulatory off times.
+    /// The delay-free `resume` leaves the gap to the host.
+    pub fn set_inter_packet_gap(&mut self, gap: time::Duration) {
+        self.inter_packet_gap = gap;
+    }
+
+    /// Get the minimum time between two transmissions
+    pub fn inter_packet_gap(&self) -> time::Duration {
+        self.inter_packet_gap
+    }
+
+    /// Set where the first bytes and the metadata of every received packet are copied to. See [mirror].
+    ///
+    /// Returns the previous capture, e.g. to read it out after giving the driver a fresh one.
+    /// `None` (the default) disables the capture.
+    pub fn set_mirror_capture(
+        &mut self,
+        capture: Option<&'static mut mirror::MirrorCapture>,
+    ) -> Option<&'static mut mirror::MirrorCapture> {
+        core::mem::replace(&mut self.mirror, capture)
+    }
+
+    /// The last received packets, if a capture is set with [Self::set_mirror_capture]
+    pub fn mirror_capture(&self) -> Option<&mirror::MirrorCapture> {
+        self.mirror.as_deref()
+    }
+
+    /// The last received packets, e.g. to clear them
+    pub fn mirror_capture_mut(&mut self) -> Option<&mut mirror::MirrorCapture> {
+        self.mirror.as_deref_mut()
+    }
+
+    /// The snapshot of the radio taken at the last [Error::BadState] or [Error::RcoLockError]. See [diagnostics].
+    pub fn last_diagnostics(&self) -> Option<&diagnostics::Diagnostics> {
+        self.diagnostics.as_ref()
+    }
+
+    /// Set the label of this radio instance, [DEFAULT_LABEL] by default.
+    ///
+    /// All log messages of the driver are prefixed with it, so the logs of multiple radios
+    /// in the same firmware can be told apart.
+    pub fn set_label(&mut self, label: &'static str) {
+        self.label = label;
+    }
+
+    /// Get the label of this radio instance
+    pub fn label(&self) -> &'static str {
+        self.label
+    }
+}
+
+/// The peripherals of a radio that isn't owned by a driver, see [S2lp::detach]
+#[derive(Debug)]
+pub struct RadioParts<Spi, Sdn, Gpio, Delay> {
+    /// The spi of the radio
+    pub spi: Spi,
+    /// The pin connected to the SDN pin of the radio
+    pub shutdown_pin: Sdn,
+    /// The pin the radio signals its irqs on
+    pub gpio_pin: Gpio,
+    /// The gpio of the radio the irq pin is connected to
+    pub gpio_number: GpioNumber,
+    /// The delay of the driver
+    pub delay: Delay,
 }
 
 pub(crate) type ErrorOf<S> = <S as ErrorType>::ErrorType;
@@ -110,6 +337,32 @@ pub enum Error<SpiError, SdnError, GpioError> {
     },
     BadState,
     RcoLockError,
+    /// The RCO calibration didn't finish within the [RcoCalibrationWait],
+    /// after waiting for the given time over all attempts
+    RcoCalibrationTimeout {
+        waited: time::Duration,
+    },
+    /// The payload being sent doesn't fit in the fifo. The fifo must be refilled, which requires the spi.
+    TxFifoRefillRequired,
+    /// The payload could not be encrypted
+    Crypto(crypto::CryptoError),
+    /// The command can't be given in the state the radio is in, or it would leave the typestate of the driver
+    IllegalCommand {
+        command: command::Command,
+        state: ll::State,
+    },
+    /// The RSSI noise didn't vary enough to gather random bits, e.g. because of a strong constant signal
+    NoEntropy,
+    /// The radio lost the configuration of the init, e.g. because of a brownout. It must be initialized again.
+    ConfigurationLost,
+    /// The radio isn't in the state the driver expects before sending or receiving.
+    /// Only checked with the `sanity-checks` feature.
+    SanityCheck {
+        reason: &'static str,
+    },
+    /// The automatic acknowledgement of a received packet wasn't sent in time, e.g. because of a lost interrupt.
+    /// The ack is aborted, but the packet is in the buffer, see [S2lp::packet]. Abort the reception to get the radio back.
+    AckTimeout,
 }
 
 impl<SpiError, SdnError, GpioError> From<ErrorKind> for Error<SpiError, SdnError, GpioError> {
@@ -143,3 +396,41 @@ pub enum GpioNumber {
     Gpio2,
     Gpio3,
 }
+
+/// How the driver waits for the interrupt pin to become active (low).
+///
+/// In both cases the level of the pin is checked first, so an interrupt that's already asserted
+/// is never missed, even when the HAL only reacts to changes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum IrqTrigger {
+    /// Use [Wait::wait_for_low]. This is the most robust option if the HAL supports it properly.
+    #[default]
+    Level,
+    /// Use [Wait::wait_for_falling_edge] for HALs that only implement edge triggered interrupts well.
+    ///
+    /// If the interrupt is asserted between checking the level and starting to wait for the edge,
+    /// the edge is missed. The pin stays low until the driver reads the irq status, so the waits check the level
+    /// again every [IRQ_EDGE_RECHECK] (or when their timeout runs out) to recover from that.
+    /// A missed edge delays the driver, so keep the margins of the fifo thresholds in mind.
+    Edge,
+}
+
+/// How often the level of the interrupt pin is checked again while waiting with [IrqTrigger::Edge]
+pub const IRQ_EDGE_RECHECK: time::Duration = time::Duration::from_millis(10);
+
+impl IrqTrigger {
+    pub(crate) async fn wait<Gpio: InputPin + Wait>(
+        self,
+        gpio: &mut Gpio,
+    ) -> Result<(), Gpio::Error> {
+        if gpio.is_low()? {
+            return Ok(());
+        }
+
+        match self {
+            IrqTrigger::Level => gpio.wait_for_low().await,
+            IrqTrigger::Edge => gpio.wait_for_falling_edge().await,
+        }
+    }
+}
@@ -7,10 +7,17 @@ device_driver::create_device!(
     manifest: "device.yaml"
 );
 
+/// The size of the tx and rx fifo in bytes
+pub(crate) const FIFO_SIZE: usize = 128;
+
 /// The SPI wrapper interface to the driver
 #[derive(Debug)]
 pub struct DeviceInterface<Spi> {
     pub(crate) spi: Spi,
+    /// Fifo transfers that are limited by the fifo are rounded down to a multiple of this
+    pub(crate) fifo_chunk_size: usize,
+    #[cfg(feature = "metrics")]
+    pub(crate) metrics: Metrics,
 }
 
 impl<Spi> DeviceInterface<Spi> {
@@ -18,7 +25,64 @@ impl<Spi> DeviceInterface<Spi> {
     ///
     /// Spi mode 0, max 8 MHz
     pub(crate) const fn new(spi: Spi) -> Self {
-        Self { spi }
+        Self {
+            spi,
+            fifo_chunk_size: 1,
+            #[cfg(feature = "metrics")]
+            metrics: Metrics::new(),
+        }
+    }
+
+    /// Round a fifo transfer length down to whole chunks.
+    /// Lengths that are limited by the buffer instead of the fifo, or that are shorter than a chunk, are kept.
+    fn chunked_len(&self, buffer_len: usize, fifo_len: usize) -> usize {
+        if buffer_len <= fifo_len || fifo_len < self.fifo_chunk_size {
+            buffer_len.min(fifo_len)
+        } else {
+            fifo_len - fifo_len % self.fifo_chunk_size
+        }
+    }
+
+    #[allow(unused_variables)]
+    fn record_transaction(&mut self, bytes_written: usize, bytes_read: usize) {
+        #[cfg(feature = "metrics")]
+        {
+            self.metrics.spi_transactions = self.metrics.spi_transactions.saturating_add(1);
+            self.metrics.bytes_written = self
+                .metrics
+                .bytes_written
+                .saturating_add(bytes_written as u32);
+            self.metrics.bytes_read = self.metrics.bytes_read.saturating_add(bytes_read as u32);
+        }
+    }
+}
+
+/// Counters of the work the driver does, to quantify the driver overhead.
+///
+/// Only available with the `metrics` feature.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct Metrics {
+    /// The amount of spi transactions
+    pub spi_transactions: u32,
+    /// The amount of bytes written over spi, including the header bytes
+    pub bytes_written: u32,
+    /// The amount of bytes read over spi
+    pub bytes_read: u32,
+    /// The amount of interrupts handled by the driver
+    pub irqs_serviced: u32,
+}
+
+#[cfg(feature = "metrics")]
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            spi_transactions: 0,
+            bytes_written: 0,
+            bytes_read: 0,
+            irqs_serviced: 0,
+        }
     }
 }
 
@@ -33,6 +97,7 @@ impl<Spi: SpiDevice> device_driver::RegisterInterface for DeviceInterface<Spi> {
         _size_bits: u32,
         data: &[u8],
     ) -> Result<(), Self::Error> {
+        self.record_transaction(2 + data.len(), 0);
         Ok(embedded_hal::spi::SpiDevice::transaction(
             &mut self.spi,
             &mut [
@@ -48,6 +113,7 @@ impl<Spi: SpiDevice> device_driver::RegisterInterface for DeviceInterface<Spi> {
         _size_bits: u32,
         data: &mut [u8],
     ) -> Result<(), Self::Error> {
+        self.record_transaction(2, data.len());
         embedded_hal::spi::SpiDevice::transaction(
             &mut self.spi,
             &mut [
@@ -72,6 +138,7 @@ impl<Spi: SpiDevice> device_driver::CommandInterface for DeviceInterface<Spi> {
         _size_bits_out: u32,
         _output: &mut [u8],
     ) -> Result<(), Self::Error> {
+        self.record_transaction(2, 0);
         Ok(embedded_hal::spi::SpiDevice::transaction(
             &mut self.spi,
             &mut [Operation::Write(&[0b1000_0000, address])],
@@ -96,15 +163,16 @@ impl<Spi: SpiDevice> device_driver::BufferInterface for DeviceInterface<Spi> {
             device_driver::RegisterInterface::read_register(self, 0x8F, 8, &mut tx_fifo_status)?;
             let tx_fifo_status: field_sets::TxFifoStatus = tx_fifo_status.into();
 
-            let space = 128 - tx_fifo_status.n_elem_txfifo();
+            let space = FIFO_SIZE as u8 - tx_fifo_status.n_elem_txfifo();
 
             if space > 0 {
                 break space;
             }
         };
 
-        let write_len = buf.len().min(tx_free_space as usize);
+        let write_len = self.chunked_len(buf.len(), tx_free_space as usize);
 
+        self.record_transaction(2 + write_len, 0);
         embedded_hal::spi::SpiDevice::transaction(
             &mut self.spi,
             &mut [
@@ -131,8 +199,9 @@ impl<Spi: SpiDevice> device_driver::BufferInterface for DeviceInterface<Spi> {
             }
         };
 
-        let read_len = buf.len().min(rx_available_space as usize);
+        let read_len = self.chunked_len(buf.len(), rx_available_space as usize);
 
+        self.record_transaction(2, read_len);
         embedded_hal::spi::SpiDevice::transaction(
             &mut self.spi,
             &mut [
@@ -149,6 +218,120 @@ impl<Spi: SpiDevice> device_driver::BufferInterface for DeviceInterface<Spi> {
     }
 }
 
+/// The first register that is kept in a [ShadowSpi]
+const SHADOW_START: u8 = 0x17;
+/// The amount of registers kept in a [ShadowSpi]. This covers all packet handler related config registers.
+const SHADOW_LEN: usize = 0x50 - SHADOW_START as usize;
+
+/// A local copy of the packet handler registers.
+///
+/// This is used to find out which registers a reconfiguration changes,
+/// so only those have to be written to the radio.
+pub(crate) struct RegisterShadow {
+    original: [u8; SHADOW_LEN],
+    current: [u8; SHADOW_LEN],
+}
+
+impl RegisterShadow {
+    /// Create the shadow by reading the current register values from the radio
+    pub(crate) fn load<Spi: SpiDevice>(
+        interface: &mut DeviceInterface<Spi>,
+    ) -> Result<Self, DeviceError<Spi::Error>> {
+        let mut registers = [0; SHADOW_LEN];
+        device_driver::RegisterInterface::read_register(
+            interface,
+            SHADOW_START,
+            SHADOW_LEN as u32 * 8,
+            &mut registers,
+        )?;
+
+        Ok(Self {
+            original: registers,
+            current: registers,
+        })
+    }
+
+    /// Write all registers that were changed to the radio
+    pub(crate) fn flush<Spi: SpiDevice>(
+        &self,
+        interface: &mut DeviceInterface<Spi>,
+    ) -> Result<(), DeviceError<Spi::Error>> {
+        let mut index = 0;
+        while index < SHADOW_LEN {
+            if !self.is_changed(index) {
+                index += 1;
+                continue;
+            }
+
+            // Collect the changed run. Small gaps are included since writing
+            // an unchanged byte is cheaper than starting a new transaction.
+            let start = index;
+            let mut end = index + 1;
+            while let Some(next) = (end..SHADOW_LEN.min(end + 3)).find(|&i| self.is_changed(i)) {
+                end = next + 1;
+            }
+
+            device_driver::RegisterInterface::write_register(
+                interface,
+                SHADOW_START + start as u8,
+                (end - start) as u32 * 8,
+                &self.current[start..end],
+            )?;
+
+            index = end;
+        }
+
+        Ok(())
+    }
+
+    fn is_changed(&self, index: usize) -> bool {
+        self.original[index] != self.current[index]
+    }
+
+    /// The range of shadow indices the access covers, if it's fully within the shadow
+    fn range(address: u8, len: usize) -> Option<core::ops::Range<usize>> {
+        let start = (address as usize).checked_sub(SHADOW_START as usize)?;
+        (start + len <= SHADOW_LEN).then_some(start..start + len)
+    }
+}
+
+/// An spi wrapper that redirects register accesses to a [RegisterShadow].
+///
+/// Accesses to registers outside of the shadow and all other transactions go to the radio directly.
+pub(crate) struct ShadowSpi<Spi> {
+    pub(crate) spi: Spi,
+    pub(crate) shadow: RegisterShadow,
+}
+
+impl<Spi: SpiDevice> embedded_hal::spi::ErrorType for ShadowSpi<Spi> {
+    type Error = Spi::Error;
+}
+
+impl<Spi: SpiDevice> SpiDevice for ShadowSpi<Spi> {
+    fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Self::Error> {
+        // Register accesses are always a header write followed by the data
+        if let [Operation::Write(&[header, address]), data] = operations {
+            match (header, data) {
+                (0b0000_0000, Operation::Write(data)) => {
+                    if let Some(range) = RegisterShadow::range(address, data.len()) {
+                        self.shadow.current[range].copy_from_slice(data);
+                        return Ok(());
+                    }
+                }
+                (0b0000_0001, Operation::Read(data)) => {
+                    if let Some(range) = RegisterShadow::range(address, data.len()) {
+                        data.copy_from_slice(&self.shadow.current[range]);
+                        return Ok(());
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        self.spi.transaction(operations)
+    }
+}
+
 /// Low level interface error that wraps the SPI error
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
@@ -178,10 +361,9 @@ impl<Spi> core::ops::DerefMut for DeviceError<Spi> {
 mod tests {
     use super::*;
     use embedded_hal_mock::eh1::spi;
-    use futures_test::test;
 
     #[test]
-    async fn read_chip_id() {
+    fn read_chip_id() {
         let mut spi_device = spi::Mock::new(&[
             spi::Transaction::transaction_start(),
             spi::Transaction::write_vec(vec![0x01, 0xF1]),
@@ -194,8 +376,8 @@ mod tests {
         ]);
         let mut s2 = Device::new(DeviceInterface::new(&mut spi_device));
 
-        let version = s2.device_info_0().read_async().await.unwrap().version();
-        let partnum = s2.device_info_1().read_async().await.unwrap().partnum();
+        let version = s2.device_info_0().read().unwrap().version();
+        let partnum = s2.device_info_1().read().unwrap().partnum();
 
         println!("Version: {:X}, partnum: {:X}", version, partnum);
         assert_eq!(version, 0xC1);
@@ -203,4 +385,20 @@ mod tests {
 
         spi_device.done();
     }
+
+    #[test]
+    fn fifo_chunking() {
+        let mut interface = DeviceInterface::new(());
+        interface.fifo_chunk_size = 32;
+
+        // Limited by the fifo
+        assert_eq!(interface.chunked_len(200, 100), 96);
+        assert_eq!(interface.chunked_len(200, 20), 20);
+        // Limited by the buffer
+        assert_eq!(interface.chunked_len(50, 100), 50);
+        assert_eq!(interface.chunked_len(100, 100), 100);
+
+        interface.fifo_chunk_size = 1;
+        assert_eq!(interface.chunked_len(200, 100), 100);
+    }
 }
@@ -0,0 +1,811 @@
+//! Helpers for building a MAC layer on top of the radio driver
+//!
+//! The helpers are generic over the width of the addresses, see [MacAddress].
+//! The 1-byte `u8` addresses are the ones the radio filters on in hardware.
+//! The 802.15.4 [ShortAddress] and [ExtendedAddress] don't fit in the packet header of the radio,
+//! so they're carried at the start of the payload with an [AddressHeader] and have to be filtered in software.
+
+use core::fmt::Debug;
+
+use embedded_hal::{
+    digital::{InputPin, OutputPin},
+    spi::SpiDevice,
+};
+use embedded_hal_async::{delay::DelayNs, digital::Wait};
+use heapless::LinearMap;
+
+#[cfg(feature = "stack")]
+use crate::packet_format::StackRxMetaData;
+use crate::{
+    ll::FIFO_SIZE,
+    packet_format::PacketFormat,
+    rssi::Rssi,
+    states::{
+        rx::{RxMode, RxResult},
+        tx::TxResult,
+        Ready,
+    },
+    time::Duration,
+    Error, ErrorOf, S2lp,
+};
+
+impl<Format, Spi, Sdn, Gpio, Delay> S2lp<Ready<Format>, Spi, Sdn, Gpio, Delay>
+where
+    Format: PacketFormat,
+    Spi: SpiDevice,
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    /// Gateway mode: keep receiving until a frame passes the filters and answer it with a prepared ack.
+    ///
+    /// The ack payload is staged in the tx fifo before the reception starts.
+    /// After a frame is received, only the packet header has to be set up before the radio goes to TX.
+    /// The `ack_meta_data` function creates the metadata of the ack from the metadata of the received frame,
+    /// e.g. to send the ack back to the source of the frame.
+    ///
+    /// If the destination of the ack is in the `peers` table, the [PeerSettings] of that peer are applied
+    /// before the ack is sent. They stay applied afterwards. Pass an empty table to leave the settings alone.
+    ///
+    /// Frames that are discarded by the filters, have a bad CRC or overflow the fifo don't stop the reception.
+    /// The radio is put back in RX for them. Any other [RxResult] is returned without sending an ack.
+    ///
+    /// This is meant for formats without hardware acknowledgements, like [Basic](crate::packet_format::Basic).
+    /// For the STack format, use [S2lp::set_ack_payload] instead.
+    pub async fn gateway_receive<MetaDataFn, Endpoint, const N: usize>(
+        mut self,
+        rx_buffer: &mut [u8],
+        ack_payload: &[u8],
+        ack_meta_data: MetaDataFn,
+        peers: &AddressTable<Endpoint, N>,
+        config: &GatewayConfig,
+    ) -> Result<(Self, GatewayReceived<Format::RxMetaData>), ErrorOf<Self>>
+    where
+        MetaDataFn: FnOnce(&Format::RxMetaData) -> Format::TxMetaData,
+    {
+        if ack_payload.len() > FIFO_SIZE {
+            return Err(Error::BufferTooLarge);
+        }
+
+        self.ll().flush_tx_fifo().dispatch()?;
+        let mut written = 0;
+        while written < ack_payload.len() {
+            written += self.ll().fifo().write(&ack_payload[written..])?;
+        }
+
+        loop {
+            let mut rx = self.start_receive(rx_buffer, config.rx_mode)?;
+            let rx_result = rx.wait().await?;
+            let Ok(ready) = rx.finish() else {
+                unreachable!()
+            };
+            self = ready;
+
+            match rx_result {
+                RxResult::Ok { ref meta_data, .. } => {
+                    let ack_meta_data = ack_meta_data(meta_data);
+                    if let Some(peer) = Format::destination_address(&ack_meta_data)
+                        .and_then(|address| peers.get(address))
+                    {
+                        self.apply_peer_settings(&peer.settings)?;
+                    }
+
+                    self.delay.delay_us(config.ack_turnaround.as_micros()).await;
+
+                    let mut tx = self.send_staged_packet(&ack_meta_data, ack_payload.len())?;
+                    let ack_result = tx.wait().await?;
+                    let Ok(ready) = tx.finish() else {
+                        unreachable!()
+                    };
+
+                    return Ok((
+                        ready,
+                        GatewayReceived {
+                            rx_result,
+                            ack_result: Some(ack_result),
+                        },
+                    ));
+                }
+                RxResult::Discarded | RxResult::CrcError | RxResult::Fifo => {
+                    #[cfg(feature = "defmt-03")]
+                    defmt::trace!(
+                        "{=str}: Gateway resuming RX after: {}",
+                        self.label,
+                        rx_result
+                    );
+                    continue;
+                }
+                _ => {
+                    return Ok((
+                        self,
+                        GatewayReceived {
+                            rx_result,
+                            ack_result: None,
+                        },
+                    ))
+                }
+            }
+        }
+    }
+}
+
+/// Configuration for [S2lp::gateway_receive]
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct GatewayConfig {
+    /// The mode used for the reception
+    pub rx_mode: RxMode,
+    /// The time between receiving a frame and starting the transmission of the ack.
+    /// This gives the sender time to switch to RX.
+    pub ack_turnaround: Duration,
+}
+
+/// The outcome of [S2lp::gateway_receive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct GatewayReceived<MetaData> {
+    /// The result of the reception
+    pub rx_result: RxResult<MetaData>,
+    /// The result of sending the ack. None if no ack was sent.
+    pub ack_result: Option<TxResult>,
+}
+
+/// An address a node can have. The helpers in this module take the type to use as a generic parameter,
+/// so the same application code can work with any address width.
+pub trait MacAddress: Copy + Eq + Debug {}
+
+/// The native 1-byte address. It's sent in the packet header and filtered by the radio.
+impl MacAddress for u8 {}
+
+/// An address that's encoded in the payload with an [AddressHeader]
+pub trait PayloadAddress: MacAddress {
+    /// The amount of bytes the address takes up in the payload
+    const LEN: usize;
+
+    /// Write the address to the start of the buffer. The buffer is at least [Self::LEN] long.
+    fn write(&self, buffer: &mut [u8]);
+    /// Read the address from the start of the buffer. The buffer is at least [Self::LEN] long.
+    fn read(buffer: &[u8]) -> Self;
+}
+
+/// An 802.15.4 short address. The PAN ID isn't part of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct ShortAddress(pub u16);
+
+impl ShortAddress {
+    /// The address all nodes listen to
+    pub const BROADCAST: Self = Self(0xFFFF);
+}
+
+impl MacAddress for ShortAddress {}
+
+impl PayloadAddress for ShortAddress {
+    const LEN: usize = 2;
+
+    fn write(&self, buffer: &mut [u8]) {
+        buffer[..Self::LEN].copy_from_slice(&self.0.to_le_bytes());
+    }
+
+    fn read(buffer: &[u8]) -> Self {
+        Self(u16::from_le_bytes([buffer[0], buffer[1]]))
+    }
+}
+
+/// An 802.15.4 extended address (EUI-64)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct ExtendedAddress(pub u64);
+
+impl MacAddress for ExtendedAddress {}
+
+impl PayloadAddress for ExtendedAddress {
+    const LEN: usize = 8;
+
+    fn write(&self, buffer: &mut [u8]) {
+        buffer[..Self::LEN].copy_from_slice(&self.0.to_le_bytes());
+    }
+
+    fn read(buffer: &[u8]) -> Self {
+        let mut bytes = [0; Self::LEN];
+        bytes.copy_from_slice(&buffer[..Self::LEN]);
+        Self(u64::from_le_bytes(bytes))
+    }
+}
+
+/// The destination and source address at the start of the payload.
+/// Like in 802.15.4, they're sent little endian with the destination first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct AddressHeader<A> {
+    /// The address of the receiver
+    pub destination: A,
+    /// The address of the sender
+    pub source: A,
+}
+
+impl<A: PayloadAddress> AddressHeader<A> {
+    /// The amount of bytes the header takes up in the payload
+    pub const LEN: usize = 2 * A::LEN;
+
+    /// Write the header to the start of the buffer and return the amount of bytes written.
+    /// Returns None if the buffer is too small.
+    pub fn write(&self, buffer: &mut [u8]) -> Option<usize> {
+        if buffer.len() < Self::LEN {
+            return None;
+        }
+
+        self.destination.write(buffer);
+        self.source.write(&mut buffer[A::LEN..]);
+        Some(Self::LEN)
+    }
+
+    /// Read the header from the start of a received payload and return it with the rest of the payload.
+    /// Returns None if the payload is too short.
+    pub fn read(payload: &[u8]) -> Option<(Self, &[u8])> {
+        if payload.len() < Self::LEN {
+            return None;
+        }
+
+        let header = Self {
+            destination: A::read(payload),
+            source: A::read(&payload[A::LEN..]),
+        };
+        Some((header, &payload[Self::LEN..]))
+    }
+
+    /// Returns true if the frame is for the node with the given address, either directly or through the broadcast address.
+    /// This is the software version of the address filter of the radio.
+    pub fn is_for(&self, address: A, broadcast: Option<A>) -> bool {
+        self.destination == address || Some(self.destination) == broadcast
+    }
+}
+
+/// Filters out duplicated frames.
+///
+/// When auto-ack with retransmissions is used, a lost acknowledgement makes the sender
+/// retransmit a frame that was already received. Retransmissions carry the same sequence number,
+/// so a frame is a duplicate if its sequence number equals the last one seen from the same source.
+///
+/// The filter remembers the last sequence number of up to `N` sources.
+/// When more sources are seen, the oldest entry is replaced.
+/// The sources are identified by an address of type `A`, see [MacAddress].
+#[derive(Debug, Clone)]
+pub struct DuplicateFilter<const N: usize, A: MacAddress = u8> {
+    entries: [Option<DuplicateFilterEntry<A>>; N],
+    next_replace: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct DuplicateFilterEntry<A> {
+    source_address: A,
+    sequence_number: u8,
+}
+
+impl<const N: usize, A: MacAddress> DuplicateFilter<N, A> {
+    /// Create a new, empty filter
+    pub const fn new() -> Self {
+        Self {
+            entries: [None; N],
+            next_replace: 0,
+        }
+    }
+
+    /// Check a received frame. Returns true if it's a duplicate of the previous frame of the same source.
+    ///
+    /// Frames that are not a duplicate are recorded.
+    pub fn is_duplicate(&mut self, source_address: A, sequence_number: u8) -> bool {
+        if let Some(entry) = self
+            .entries
+            .iter_mut()
+            .flatten()
+            .find(|entry| entry.source_address == source_address)
+        {
+            if entry.sequence_number == sequence_number {
+                return true;
+            }
+
+            entry.sequence_number = sequence_number;
+            return false;
+        }
+
+        if N > 0 {
+            self.entries[self.next_replace] = Some(DuplicateFilterEntry {
+                source_address,
+                sequence_number,
+            });
+            self.next_replace = (self.next_replace + 1) % N;
+        }
+
+        false
+    }
+
+    /// Forget all recorded sources
+    pub fn clear(&mut self) {
+        *self = Self::new();
+    }
+}
+
+#[cfg(feature = "stack")]
+impl<const N: usize> DuplicateFilter<N> {
+    /// Check the metadata of a received STack frame. See [Self::is_duplicate].
+    pub fn is_duplicate_stack(&mut self, meta_data: &StackRxMetaData) -> bool {
+        self.is_duplicate(meta_data.source_address, meta_data.sequence_number)
+    }
+}
+
+impl<const N: usize, A: MacAddress> Default for DuplicateFilter<N, A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How the [RssiTracker] smooths the RSSI of a source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct RssiAveraging {
+    /// The average follows about the last this many packets (exponential smoothing with a weight of 1/packets).
+    /// Must be at least 1.
+    pub packets: u16,
+    /// When a source hasn't been heard from for a while, its old average says less about where it is now.
+    /// A new packet gets a weight of at least `elapsed / (elapsed + time_constant)`,
+    /// so after a gap much longer than this the average starts over from the new packet.
+    pub time_constant: Duration,
+}
+
+impl Default for RssiAveraging {
+    fn default() -> Self {
+        Self {
+            packets: 8,
+            time_constant: Duration::from_secs(10),
+        }
+    }
+}
+
+/// The smoothed RSSI of a source, as kept by the [RssiTracker]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct RssiStatistics {
+    /// The smoothed RSSI
+    pub average: Rssi,
+    /// The RSSI of the last packet
+    pub last: Rssi,
+    /// The amount of packets that went into the average (saturating)
+    pub samples: u16,
+    /// The time of the last packet, as given to [RssiTracker::update]
+    pub last_update: Duration,
+}
+
+/// Averages the RSSI of received packets per source, e.g. for proximity estimation between moving nodes.
+///
+/// The tracker remembers up to `N` sources. When more sources are seen, the one that wasn't heard from
+/// the longest is replaced. The sources are identified by an address of type `A`, see [MacAddress].
+///
+/// The time of a packet is given by the application as the time since any fixed moment, like the uptime.
+/// Only differences are used, so the counter may wrap.
+#[derive(Debug, Clone)]
+pub struct RssiTracker<const N: usize, A: MacAddress = u8> {
+    averaging: RssiAveraging,
+    entries: [Option<RssiTrackerEntry<A>>; N],
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RssiTrackerEntry<A> {
+    source_address: A,
+    /// The average in 1/16 dB steps, to keep small changes from being rounded away
+    average: i32,
+    last: Rssi,
+    samples: u16,
+    last_update: Duration,
+}
+
+impl<A> RssiTrackerEntry<A> {
+    fn statistics(&self) -> RssiStatistics {
+        RssiStatistics {
+            average: Rssi::from_dbm(((self.average + 8) >> 4) as i16),
+            last: self.last,
+            samples: self.samples,
+            last_update: self.last_update,
+        }
+    }
+}
+
+impl<const N: usize, A: MacAddress> RssiTracker<N, A> {
+    /// Create a new, empty tracker
+    pub const fn new(averaging: RssiAveraging) -> Self {
+        Self {
+            averaging,
+            entries: [None; N],
+        }
+    }
+
+    /// Add the RSSI of a packet of the source received at `now` and return the new statistics of the source
+    pub fn update(&mut self, source_address: A, rssi: Rssi, now: Duration) -> RssiStatistics {
+        let sample = rssi.dbm() as i32 * 16;
+
+        let index = match self
+            .entries
+            .iter()
+            .position(|entry| entry.is_some_and(|entry| entry.source_address == source_address))
+        {
+            Some(index) => index,
+            None => {
+                let Some(index) = self
+                    .entries
+                    .iter()
+                    .position(Option::is_none)
+                    .or_else(|| self.least_recent(now))
+                else {
+                    // Without room, there's nothing to average with
+                    return RssiStatistics {
+                        average: rssi,
+                        last: rssi,
+                        samples: 1,
+                        last_update: now,
+                    };
+                };
+
+                self.entries[index] = Some(RssiTrackerEntry {
+                    source_address,
+                    average: sample,
+                    last: rssi,
+                    samples: 0,
+                    last_update: now,
+                });
+                index
+            }
+        };
+
+        let entry = self.entries[index].as_mut().unwrap();
+        entry.samples = entry.samples.saturating_add(1);
+
+        // Until there are enough packets, take the plain average so the first packet doesn't dominate
+        let packets = entry.samples.min(self.averaging.packets.max(1)) as u64;
+        let elapsed = now.as_micros().wrapping_sub(entry.last_update.as_micros()) as u64;
+        let time_constant = self.averaging.time_constant.as_micros() as u64;
+
+        // The weight of the new sample is the biggest of 1/packets and elapsed/(elapsed + time_constant)
+        let (numerator, denominator) = if elapsed * packets > elapsed + time_constant {
+            (elapsed, elapsed + time_constant)
+        } else {
+            (1, packets)
+        };
+
+        let difference = (sample - entry.average) as i64;
+        entry.average += (difference * numerator as i64 / denominator as i64) as i32;
+        entry.last = rssi;
+        entry.last_update = now;
+
+        entry.statistics()
+    }
+
+    /// Get the statistics of the source, if it's known
+    pub fn get(&self, source_address: A) -> Option<RssiStatistics> {
+        self.entries
+            .iter()
+            .flatten()
+            .find(|entry| entry.source_address == source_address)
+            .map(RssiTrackerEntry::statistics)
+    }
+
+    /// Iterate over all known sources and their statistics
+    pub fn iter(&self) -> impl Iterator<Item = (A, RssiStatistics)> + '_ {
+        self.entries
+            .iter()
+            .flatten()
+            .map(|entry| (entry.source_address, entry.statistics()))
+    }
+
+    /// Forget the source
+    pub fn remove(&mut self, source_address: A) -> Option<RssiStatistics> {
+        self.entries
+            .iter_mut()
+            .find(|entry| entry.is_some_and(|entry| entry.source_address == source_address))
+            .and_then(Option::take)
+            .map(|entry| entry.statistics())
+    }
+
+    /// Forget all sources
+    pub fn clear(&mut self) {
+        self.entries = [None; N];
+    }
+
+    /// The index of the source that wasn't heard from the longest
+    fn least_recent(&self, now: Duration) -> Option<usize> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter_map(|(index, entry)| entry.map(|entry| (index, entry)))
+            .max_by_key(|(_, entry)| now.as_micros().wrapping_sub(entry.last_update.as_micros()))
+            .map(|(index, _)| index)
+    }
+}
+
+#[cfg(feature = "stack")]
+impl<const N: usize> RssiTracker<N> {
+    /// Add the RSSI of a received STack packet. See [Self::update].
+    pub fn update_stack(
+        &mut self,
+        meta_data: &StackRxMetaData,
+        rssi: Rssi,
+        now: Duration,
+    ) -> RssiStatistics {
+        self.update(meta_data.source_address, rssi, now)
+    }
+}
+
+impl<const N: usize, A: MacAddress> Default for RssiTracker<N, A> {
+    fn default() -> Self {
+        Self::new(RssiAveraging::default())
+    }
+}
+
+/// Settings that are applied per peer before sending to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct PeerSettings {
+    /// The output power used for this peer in dBm (-30..=14).
+    /// If None, the power isn't changed.
+    pub tx_power_dbm: Option<i8>,
+    /// The max amount of retransmissions for this peer (0..=15). Only used by the STack format.
+    /// If None, the value isn't changed.
+    pub max_retransmissions: Option<u8>,
+}
+
+/// A peer in the [AddressTable]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct Peer<Endpoint> {
+    /// The application endpoint associated with the peer
+    pub endpoint: Endpoint,
+    /// The radio settings used for the peer
+    pub settings: PeerSettings,
+}
+
+/// Associates the on-air addresses with application endpoints and per-peer settings.
+///
+/// It can hold up to `N` peers. The addresses are of type `A`, see [MacAddress].
+#[derive(Debug, Clone)]
+pub struct AddressTable<Endpoint, const N: usize, A: MacAddress = u8> {
+    peers: LinearMap<A, Peer<Endpoint>, N>,
+}
+
+impl<Endpoint, const N: usize, A: MacAddress> AddressTable<Endpoint, N, A> {
+    /// Create a new, empty table
+    pub const fn new() -> Self {
+        Self {
+            peers: LinearMap::new(),
+        }
+    }
+
+    /// Add or replace a peer. If the address was already in use, the old peer is returned.
+    ///
+    /// If the table is full, the given peer is given back as the error.
+    pub fn insert(
+        &mut self,
+        address: A,
+        peer: Peer<Endpoint>,
+    ) -> Result<Option<Peer<Endpoint>>, Peer<Endpoint>> {
+        self.peers.insert(address, peer).map_err(|(_, peer)| peer)
+    }
+
+    /// Remove the peer with the given address
+    pub fn remove(&mut self, address: A) -> Option<Peer<Endpoint>> {
+        self.peers.remove(&address)
+    }
+
+    /// Get the peer with the given address
+    pub fn get(&self, address: A) -> Option<&Peer<Endpoint>> {
+        self.peers.get(&address)
+    }
+
+    /// Get the peer with the given address mutably
+    pub fn get_mut(&mut self, address: A) -> Option<&mut Peer<Endpoint>> {
+        self.peers.get_mut(&address)
+    }
+
+    /// Find the address of the peer with the given endpoint
+    pub fn address_of(&self, endpoint: &Endpoint) -> Option<A>
+    where
+        Endpoint: PartialEq,
+    {
+        self.peers
+            .iter()
+            .find(|(_, peer)| peer.endpoint == *endpoint)
+            .map(|(address, _)| *address)
+    }
+
+    /// Iterate over all addresses and peers
+    pub fn iter(&self) -> impl Iterator<Item = (A, &Peer<Endpoint>)> {
+        self.peers.iter().map(|(address, peer)| (*address, peer))
+    }
+
+    /// The amount of peers in the table
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// Returns true if there are no peers in the table
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+}
+
+impl<Endpoint, const N: usize, A: MacAddress> Default for AddressTable<Endpoint, N, A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<PF, Spi, Sdn, Gpio, Delay> S2lp<Ready<PF>, Spi, Sdn, Gpio, Delay>
+where
+    Spi: SpiDevice,
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    /// Apply the settings of a peer. Call this before sending a packet to the peer.
+    /// [S2lp::gateway_receive] does this by itself for the acks it sends.
+    pub fn apply_peer_settings(&mut self, settings: &PeerSettings) -> Result<(), ErrorOf<Self>> {
+        if let Some(tx_power_dbm) = settings.tx_power_dbm {
+            self.set_tx_power(tx_power_dbm)?;
+        }
+
+        if let Some(max_retransmissions) = settings.max_retransmissions {
+            if max_retransmissions > 15 {
+                return Err(Error::BadConfig {
+                    reason: "Max retransmissions out of range",
+                });
+            }
+
+            self.ll()
+                .protocol_0()
+                .modify(|reg| reg.set_nmax_retx(max_retransmissions))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duplicate_filter() {
+        let mut filter = DuplicateFilter::<2>::new();
+
+        assert!(!filter.is_duplicate(1, 0));
+        assert!(filter.is_duplicate(1, 0));
+        assert!(!filter.is_duplicate(1, 1));
+        assert!(!filter.is_duplicate(2, 1));
+        assert!(filter.is_duplicate(2, 1));
+        assert!(filter.is_duplicate(1, 1));
+
+        // Source 3 replaces source 1
+        assert!(!filter.is_duplicate(3, 1));
+        assert!(!filter.is_duplicate(1, 1));
+
+        filter.clear();
+        assert!(!filter.is_duplicate(2, 1));
+    }
+
+    #[test]
+    fn address_table() {
+        let mut table = AddressTable::<&str, 2>::new();
+        let settings = PeerSettings {
+            tx_power_dbm: Some(10),
+            max_retransmissions: None,
+        };
+
+        assert_eq!(
+            table.insert(
+                0x10,
+                Peer {
+                    endpoint: "a",
+                    settings
+                }
+            ),
+            Ok(None)
+        );
+        assert!(table
+            .insert(
+                0x20,
+                Peer {
+                    endpoint: "b",
+                    settings
+                }
+            )
+            .is_ok());
+        assert!(table
+            .insert(
+                0x30,
+                Peer {
+                    endpoint: "c",
+                    settings
+                }
+            )
+            .is_err());
+
+        assert_eq!(table.address_of(&"b"), Some(0x20));
+        assert_eq!(table.get(0x10).map(|peer| peer.endpoint), Some("a"));
+        assert_eq!(table.remove(0x10).map(|peer| peer.endpoint), Some("a"));
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn wide_addresses() {
+        let header = AddressHeader {
+            destination: ShortAddress(0x1234),
+            source: ShortAddress(0xABCD),
+        };
+        let mut buffer = [0; 5];
+        assert_eq!(header.write(&mut buffer), Some(4));
+        assert_eq!(buffer[..4], [0x34, 0x12, 0xCD, 0xAB]);
+        buffer[4] = 0x55;
+
+        let (read, payload) = AddressHeader::<ShortAddress>::read(&buffer).unwrap();
+        assert_eq!(read, header);
+        assert_eq!(payload, [0x55]);
+        assert!(read.is_for(ShortAddress(0x1234), None));
+        assert!(!read.is_for(ShortAddress(0x4321), Some(ShortAddress::BROADCAST)));
+        assert!(AddressHeader::<ExtendedAddress>::read(&buffer).is_none());
+
+        let mut filter = DuplicateFilter::<2, ExtendedAddress>::new();
+        assert!(!filter.is_duplicate(ExtendedAddress(u64::MAX), 1));
+        assert!(filter.is_duplicate(ExtendedAddress(u64::MAX), 1));
+
+        let mut table = AddressTable::<&str, 2, ShortAddress>::new();
+        let peer = Peer {
+            endpoint: "a",
+            settings: PeerSettings {
+                tx_power_dbm: None,
+                max_retransmissions: None,
+            },
+        };
+        assert_eq!(table.insert(read.source, peer), Ok(None));
+        assert_eq!(table.address_of(&"a"), Some(ShortAddress(0xABCD)));
+    }
+
+    #[test]
+    fn rssi_tracker() {
+        let mut tracker = RssiTracker::<2>::new(RssiAveraging {
+            packets: 4,
+            time_constant: Duration::from_secs(1),
+        });
+        let at = Duration::from_millis;
+
+        // The first packets are averaged plainly
+        assert_eq!(
+            tracker.update(1, Rssi::from_dbm(-80), at(0)).average.dbm(),
+            -80
+        );
+        assert_eq!(
+            tracker.update(1, Rssi::from_dbm(-70), at(1)).average.dbm(),
+            -75
+        );
+
+        // Then a packet weighs 1/4
+        tracker.update(1, Rssi::from_dbm(-75), at(2));
+        tracker.update(1, Rssi::from_dbm(-75), at(3));
+        let statistics = tracker.update(1, Rssi::from_dbm(-55), at(4));
+        assert_eq!(statistics.average.dbm(), -70);
+        assert_eq!(statistics.last.dbm(), -55);
+        assert_eq!(statistics.samples, 5);
+
+        // After a long gap, the new packet weighs almost everything
+        let statistics = tracker.update(1, Rssi::from_dbm(-40), at(100_004));
+        assert_eq!(statistics.average.dbm(), -40);
+
+        // The source that wasn't heard from the longest is replaced
+        tracker.update(2, Rssi::from_dbm(-90), at(100_005));
+        tracker.update(1, Rssi::from_dbm(-40), at(100_006));
+        tracker.update(3, Rssi::from_dbm(-60), at(100_007));
+        assert_eq!(tracker.get(2), None);
+        assert_eq!(tracker.get(3).unwrap().average.dbm(), -60);
+        assert_eq!(tracker.iter().count(), 2);
+
+        assert_eq!(tracker.remove(3).unwrap().samples, 1);
+        assert_eq!(tracker.get(3), None);
+    }
+}
@@ -0,0 +1,104 @@
+//! A copy of the last received frames, for post-mortem diagnostics of intermittent field issues.
+//!
+//! Give the driver a [MirrorCapture] with [S2lp::set_mirror_capture](crate::S2lp::set_mirror_capture).
+//! Every packet that's received then also gets its first bytes and its metadata copied into it,
+//! without the application having to do anything. Read it out with [S2lp::mirror_capture](crate::S2lp::mirror_capture),
+//! e.g. from a debug shell or when something went wrong.
+//!
+//! The capture lives outside of the driver so it doesn't grow the driver (and every state transition) for everyone.
+//!
+//! ```rust,ignore
+//! static MIRROR: StaticCell<MirrorCapture> = StaticCell::new();
+//! radio.set_mirror_capture(Some(MIRROR.init(MirrorCapture::new(8))));
+//! ```
+//!
+//! The metadata is read from the radio registers, so it's the same for every packet format.
+//! Capturing costs a few extra register reads per packet.
+
+use heapless::{Deque, Vec};
+
+use crate::rssi::Rssi;
+
+/// The amount of frames the mirror holds. When it's full, the oldest frame is dropped.
+pub const MIRROR_DEPTH: usize = 4;
+/// The maximum amount of bytes of each frame the mirror holds
+pub const MIRROR_HEAD_LEN: usize = 16;
+
+/// The start and metadata of a received frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct MirroredFrame {
+    /// The full size of the packet in bytes
+    pub packet_size: usize,
+    /// The RSSI of the packet, sampled like the one returned by the RX `wait`
+    pub rssi: Rssi,
+    /// The sequence number field of the packet (`RX_PCKT_INFO`)
+    pub sequence_number: u8,
+    /// The no-ack field of the packet (`RX_PCKT_INFO`)
+    pub nack: bool,
+    /// The source address field of the packet (`RX_ADDRE_FIELD1`)
+    pub source_address: u8,
+    /// The destination address field of the packet (`RX_ADDRE_FIELD0`)
+    pub destination_address: u8,
+    /// The first bytes of the packet
+    pub head: Vec<u8, MIRROR_HEAD_LEN>,
+}
+
+/// The ring buffer of mirrored frames. See the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct MirrorCapture {
+    head_len: usize,
+    frames: Deque<MirroredFrame, MIRROR_DEPTH>,
+    captured: u32,
+}
+
+impl MirrorCapture {
+    /// Create an empty capture that copies the first `head_len` bytes of every frame.
+    /// The length is clamped to [MIRROR_HEAD_LEN].
+    pub const fn new(head_len: usize) -> Self {
+        Self {
+            head_len: if head_len < MIRROR_HEAD_LEN {
+                head_len
+            } else {
+                MIRROR_HEAD_LEN
+            },
+            frames: Deque::new(),
+            captured: 0,
+        }
+    }
+
+    /// The amount of bytes copied of every frame
+    pub fn head_len(&self) -> usize {
+        self.head_len
+    }
+
+    /// The captured frames, oldest first
+    pub fn frames(&self) -> impl Iterator<Item = &MirroredFrame> {
+        self.frames.iter()
+    }
+
+    /// The last captured frame
+    pub fn last(&self) -> Option<&MirroredFrame> {
+        self.frames.back()
+    }
+
+    /// The amount of frames captured since the last clear, including the ones that were dropped from the ring
+    pub fn captured(&self) -> u32 {
+        self.captured
+    }
+
+    /// Remove all frames
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.captured = 0;
+    }
+
+    pub(crate) fn push(&mut self, frame: MirroredFrame) {
+        if self.frames.is_full() {
+            self.frames.pop_front();
+        }
+        // Can't fail, there's room now
+        let _ = self.frames.push_back(frame);
+        self.captured = self.captured.saturating_add(1);
+    }
+}
@@ -0,0 +1,574 @@
+//! A transport for firmware updates over the air.
+//!
+//! The receiving device pulls the image from the sender chunk by chunk and writes it into an [embedded_storage::Storage].
+//! Both sides only produce and consume message payloads, so any packet format can carry them.
+//! When the chunks are bigger than a frame, send the messages with the [fragmentation](crate::fragmentation) helpers.
+//!
+//! The exchange goes like this:
+//! 1. The sender offers the image with its size and CRC-32 ([OtaSender::offer]).
+//! 2. The receiver requests the first chunk it doesn't have yet. The sender answers every request with the chunk.
+//!    When a chunk or request gets lost, the receiver requests the chunk again after its own timeout ([OtaReceiver::request]).
+//! 3. After the last chunk, the receiver checks the CRC of the whole image and reports the outcome.
+//!
+//! The progress of the receiver can be saved with [OtaReceiver::progress] and restored with [OtaReceiver::resume],
+//! so an update continues where it was after a reboot when the same image is offered again.
+
+use embedded_storage::{ReadStorage, Storage};
+
+const MESSAGE_OFFER: u8 = 0x01;
+const MESSAGE_REQUEST: u8 = 0x02;
+const MESSAGE_CHUNK: u8 = 0x03;
+const MESSAGE_DONE: u8 = 0x04;
+
+/// The size of the header of a chunk message. A chunk message is this plus the chunk size.
+pub const CHUNK_HEADER_SIZE: usize = 5;
+/// The size of the biggest message that isn't a chunk
+pub const MAX_CONTROL_MESSAGE_SIZE: usize = 11;
+
+/// The errors of the OTA helpers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum OtaError<E> {
+    /// The storage returned an error
+    Storage(E),
+    /// The message is malformed or not expected by this side
+    BadMessage,
+    /// The image doesn't fit in the storage
+    ImageTooLarge,
+    /// The buffer for the outgoing message is too small
+    BufferTooSmall,
+}
+
+/// The progress of an image transfer on the receiving side
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct OtaProgress {
+    /// The size of the image
+    pub image_size: u32,
+    /// The CRC-32 of the full image as offered by the sender
+    pub image_crc: u32,
+    /// The size of the chunks that are requested
+    pub chunk_size: u16,
+    /// The amount of bytes received and written so far
+    pub received: u32,
+    /// The CRC state over the received bytes
+    pub crc_state: u32,
+}
+
+/// The state of the receiver
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum OtaStatus {
+    /// No image has been offered yet
+    Idle,
+    /// An image is being received
+    Receiving {
+        /// The amount of bytes received so far
+        received: u32,
+        /// The size of the image
+        image_size: u32,
+    },
+    /// The image has been received and its CRC is correct
+    Complete,
+    /// The image has been received, but its CRC is wrong. It must be offered again.
+    CrcMismatch,
+}
+
+/// Receives an image and writes it to the storage
+pub struct OtaReceiver<S: Storage> {
+    storage: S,
+    progress: Option<OtaProgress>,
+    status: OtaStatus,
+}
+
+impl<S: Storage> OtaReceiver<S> {
+    /// Create a receiver that writes the image into the storage, starting at offset 0
+    pub fn new(storage: S) -> Self {
+        Self {
+            storage,
+            progress: None,
+            status: OtaStatus::Idle,
+        }
+    }
+
+    /// Create a receiver that continues a transfer of which the progress was saved.
+    /// The transfer continues when the same image is offered again.
+    pub fn resume(storage: S, progress: OtaProgress) -> Self {
+        Self {
+            storage,
+            progress: Some(progress),
+            status: OtaStatus::Receiving {
+                received: progress.received,
+                image_size: progress.image_size,
+            },
+        }
+    }
+
+    /// The progress of the transfer to save, e.g. before going to sleep. None if no transfer has started.
+    pub fn progress(&self) -> Option<OtaProgress> {
+        self.progress
+    }
+
+    /// The state of the receiver
+    pub fn status(&self) -> OtaStatus {
+        self.status
+    }
+
+    /// Give back the storage
+    pub fn release(self) -> S {
+        self.storage
+    }
+
+    /// Handle a message from the sender. Returns the response that must be sent back, if any.
+    pub fn handle<'b>(
+        &mut self,
+        message: &[u8],
+        response: &'b mut [u8],
+    ) -> Result<Option<&'b [u8]>, OtaError<S::Error>> {
+        match message {
+            [MESSAGE_OFFER, rest @ ..] if rest.len() == 10 => {
+                let image_size = read_u32(&rest[0..4]);
+                let image_crc = read_u32(&rest[4..8]);
+                let chunk_size = u16::from_be_bytes([rest[8], rest[9]]);
+
+                if image_size as usize > self.storage.capacity() {
+                    return Err(OtaError::ImageTooLarge);
+                }
+                if chunk_size == 0 {
+                    return Err(OtaError::BadMessage);
+                }
+
+                match self.progress {
+                    // The same image, so we resume
+                    Some(progress)
+                        if progress.image_size == image_size
+                            && progress.image_crc == image_crc
+                            && self.status != OtaStatus::CrcMismatch =>
+                    {
+                        if let Some(progress) = self.progress.as_mut() {
+                            progress.chunk_size = chunk_size;
+                        }
+                    }
+                    _ => {
+                        self.progress = Some(OtaProgress {
+                            image_size,
+                            image_crc,
+                            chunk_size,
+                            received: 0,
+                            crc_state: CRC_INIT,
+                        })
+                    }
+                }
+
+                self.after_progress(response).map(Some)
+            }
+            [MESSAGE_CHUNK, rest @ ..] if rest.len() >= 4 => {
+                let Some(mut progress) = self.progress else {
+                    return Err(OtaError::BadMessage);
+                };
+                let offset = read_u32(&rest[0..4]);
+                let data = &rest[4..];
+
+                if matches!(self.status, OtaStatus::Receiving { .. })
+                    && offset == progress.received
+                    && data.len() as u32 <= progress.image_size - progress.received
+                {
+                    self.storage
+                        .write(offset, data)
+                        .map_err(OtaError::Storage)?;
+                    progress.received += data.len() as u32;
+                    progress.crc_state = crc32_update(progress.crc_state, data);
+                    self.progress = Some(progress);
+                }
+                // Anything else is a chunk we didn't ask for (anymore). The request is repeated.
+
+                self.after_progress(response).map(Some)
+            }
+            _ => Err(OtaError::BadMessage),
+        }
+    }
+
+    /// Create the request for the next chunk again, e.g. when no chunk came in for a while.
+    /// Returns None if there's nothing to request.
+    pub fn request<'b>(
+        &self,
+        response: &'b mut [u8],
+    ) -> Result<Option<&'b [u8]>, OtaError<S::Error>> {
+        match (self.status, self.progress) {
+            (OtaStatus::Receiving { .. }, Some(progress)) => {
+                Self::write_request(&progress, response).map(Some)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Update the status after the progress changed and create the response
+    fn after_progress<'b>(
+        &mut self,
+        response: &'b mut [u8],
+    ) -> Result<&'b [u8], OtaError<S::Error>> {
+        let Some(progress) = self.progress else {
+            return Err(OtaError::BadMessage);
+        };
+
+        if progress.received < progress.image_size {
+            self.status = OtaStatus::Receiving {
+                received: progress.received,
+                image_size: progress.image_size,
+            };
+            return Self::write_request(&progress, response);
+        }
+
+        let crc_ok = crc32_finish(progress.crc_state) == progress.image_crc;
+        self.status = if crc_ok {
+            OtaStatus::Complete
+        } else {
+            OtaStatus::CrcMismatch
+        };
+
+        write_message(response, &[MESSAGE_DONE, crc_ok as u8])
+    }
+
+    fn write_request<'b>(
+        progress: &OtaProgress,
+        response: &'b mut [u8],
+    ) -> Result<&'b [u8], OtaError<S::Error>> {
+        let len = (progress.image_size - progress.received).min(progress.chunk_size as u32) as u16;
+        let offset = progress.received.to_be_bytes();
+        let len = len.to_be_bytes();
+
+        write_message(
+            response,
+            &[
+                MESSAGE_REQUEST,
+                offset[0],
+                offset[1],
+                offset[2],
+                offset[3],
+                len[0],
+                len[1],
+            ],
+        )
+    }
+}
+
+/// The outcome of a transfer on the sending side
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum OtaOutcome {
+    /// The receiver got the image and its CRC is correct
+    Success,
+    /// The receiver got the image, but its CRC is wrong
+    CrcMismatch,
+}
+
+/// Serves an image from a storage to a receiver
+pub struct OtaSender<S: ReadStorage> {
+    storage: S,
+    image_size: u32,
+    image_crc: u32,
+    chunk_size: u16,
+    outcome: Option<OtaOutcome>,
+}
+
+impl<S: ReadStorage> OtaSender<S> {
+    /// Create a sender for the image at the start of the storage.
+    ///
+    /// The CRC of the image is calculated here, so the whole image is read once.
+    /// The chunk size is the amount of image data per chunk message, which is [CHUNK_HEADER_SIZE] bytes bigger.
+    pub fn new(
+        mut storage: S,
+        image_size: u32,
+        chunk_size: u16,
+    ) -> Result<Self, OtaError<S::Error>> {
+        if image_size as usize > storage.capacity() {
+            return Err(OtaError::ImageTooLarge);
+        }
+        if chunk_size == 0 {
+            return Err(OtaError::BadMessage);
+        }
+
+        let mut crc = CRC_INIT;
+        let mut buffer = [0; 64];
+        let mut offset = 0;
+        while offset < image_size {
+            let len = (image_size - offset).min(buffer.len() as u32) as usize;
+            storage
+                .read(offset, &mut buffer[..len])
+                .map_err(OtaError::Storage)?;
+            crc = crc32_update(crc, &buffer[..len]);
+            offset += len as u32;
+        }
+
+        Ok(Self {
+            storage,
+            image_size,
+            image_crc: crc32_finish(crc),
+            chunk_size,
+            outcome: None,
+        })
+    }
+
+    /// The CRC-32 of the image
+    pub fn image_crc(&self) -> u32 {
+        self.image_crc
+    }
+
+    /// The outcome reported by the receiver. None while the transfer is going on.
+    pub fn outcome(&self) -> Option<OtaOutcome> {
+        self.outcome
+    }
+
+    /// Give back the storage
+    pub fn release(self) -> S {
+        self.storage
+    }
+
+    /// Create the offer message that starts (or resumes) the transfer
+    pub fn offer<'b>(&self, message: &'b mut [u8]) -> Result<&'b [u8], OtaError<S::Error>> {
+        let size = self.image_size.to_be_bytes();
+        let crc = self.image_crc.to_be_bytes();
+        let chunk_size = self.chunk_size.to_be_bytes();
+
+        write_message(
+            message,
+            &[
+                MESSAGE_OFFER,
+                size[0],
+                size[1],
+                size[2],
+                size[3],
+                crc[0],
+                crc[1],
+                crc[2],
+                crc[3],
+                chunk_size[0],
+                chunk_size[1],
+            ],
+        )
+    }
+
+    /// Handle a message from the receiver. Returns the chunk that must be sent back, if any.
+    pub fn handle<'b>(
+        &mut self,
+        message: &[u8],
+        response: &'b mut [u8],
+    ) -> Result<Option<&'b [u8]>, OtaError<S::Error>> {
+        match *message {
+            [MESSAGE_REQUEST, o0, o1, o2, o3, l0, l1] => {
+                let offset = u32::from_be_bytes([o0, o1, o2, o3]);
+                let len = u16::from_be_bytes([l0, l1]) as usize;
+
+                if offset > self.image_size || len > (self.image_size - offset) as usize {
+                    return Err(OtaError::BadMessage);
+                }
+                if response.len() < CHUNK_HEADER_SIZE + len {
+                    return Err(OtaError::BufferTooSmall);
+                }
+
+                response[0] = MESSAGE_CHUNK;
+                response[1..CHUNK_HEADER_SIZE].copy_from_slice(&offset.to_be_bytes());
+                self.storage
+                    .read(offset, &mut response[CHUNK_HEADER_SIZE..][..len])
+                    .map_err(OtaError::Storage)?;
+
+                Ok(Some(&response[..CHUNK_HEADER_SIZE + len]))
+            }
+            [MESSAGE_DONE, crc_ok] => {
+                self.outcome = Some(if crc_ok != 0 {
+                    OtaOutcome::Success
+                } else {
+                    OtaOutcome::CrcMismatch
+                });
+                Ok(None)
+            }
+            _ => Err(OtaError::BadMessage),
+        }
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes.try_into().unwrap())
+}
+
+fn write_message<'b, E>(buffer: &'b mut [u8], message: &[u8]) -> Result<&'b [u8], OtaError<E>> {
+    let buffer = buffer
+        .get_mut(..message.len())
+        .ok_or(OtaError::BufferTooSmall)?;
+    buffer.copy_from_slice(message);
+    Ok(buffer)
+}
+
+const CRC_INIT: u32 = 0xFFFF_FFFF;
+
+/// Update a CRC-32 (IEEE 802.3, as used by zip and ethernet) with the data
+fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+fn crc32_finish(crc: u32) -> u32 {
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MemoryStorage(Vec<u8>);
+
+    impl ReadStorage for MemoryStorage {
+        type Error = ();
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            bytes.copy_from_slice(self.0.get(offset..offset + bytes.len()).ok_or(())?);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.0.len()
+        }
+    }
+
+    impl Storage for MemoryStorage {
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            self.0
+                .get_mut(offset..offset + bytes.len())
+                .ok_or(())?
+                .copy_from_slice(bytes);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(
+            crc32_finish(crc32_update(CRC_INIT, b"123456789")),
+            0xCBF4_3926
+        );
+    }
+
+    #[test]
+    fn transfer_with_loss_and_resume() {
+        let image: Vec<u8> = (0..250).map(|i| (i * 7) as u8).collect();
+        let mut sender = OtaSender::new(MemoryStorage(image.clone()), 250, 100).unwrap();
+        let mut receiver = OtaReceiver::new(MemoryStorage(vec![0; 1024]));
+
+        let mut to_receiver = [0; 128];
+        let mut to_sender = [0; 16];
+
+        let offer = sender.offer(&mut to_receiver).unwrap().to_vec();
+        let request = receiver
+            .handle(&offer, &mut to_sender)
+            .unwrap()
+            .unwrap()
+            .to_vec();
+        let first_chunk = sender
+            .handle(&request, &mut to_receiver)
+            .unwrap()
+            .unwrap()
+            .to_vec();
+        assert_eq!(first_chunk.len(), CHUNK_HEADER_SIZE + 100);
+        receiver.handle(&first_chunk, &mut to_sender).unwrap();
+        assert_eq!(
+            receiver.status(),
+            OtaStatus::Receiving {
+                received: 100,
+                image_size: 250
+            }
+        );
+
+        // A duplicated chunk changes nothing
+        receiver.handle(&first_chunk, &mut to_sender).unwrap();
+        assert_eq!(receiver.progress().unwrap().received, 100);
+
+        // The receiver reboots and resumes after a new offer
+        let progress = receiver.progress().unwrap();
+        let storage = receiver.release();
+        let mut receiver = OtaReceiver::resume(storage, progress);
+        let mut request = receiver
+            .handle(&offer, &mut to_sender)
+            .unwrap()
+            .unwrap()
+            .to_vec();
+        assert_eq!(&request[1..5], &100u32.to_be_bytes());
+
+        while receiver.status() != OtaStatus::Complete {
+            let chunk = sender
+                .handle(&request, &mut to_receiver)
+                .unwrap()
+                .unwrap()
+                .to_vec();
+            request = receiver
+                .handle(&chunk, &mut to_sender)
+                .unwrap()
+                .unwrap()
+                .to_vec();
+        }
+
+        // The last response is the done message
+        assert_eq!(sender.handle(&request, &mut to_receiver), Ok(None));
+        assert_eq!(sender.outcome(), Some(OtaOutcome::Success));
+        assert_eq!(&receiver.release().0[..250], &image[..]);
+    }
+
+    #[test]
+    fn corrupted_image() {
+        let image = vec![0xAB; 50];
+        let mut sender = OtaSender::new(MemoryStorage(image), 50, 64).unwrap();
+        let mut receiver = OtaReceiver::new(MemoryStorage(vec![0; 64]));
+
+        let mut to_receiver = [0; 128];
+        let mut to_sender = [0; 16];
+
+        let offer = sender.offer(&mut to_receiver).unwrap().to_vec();
+        let request = receiver
+            .handle(&offer, &mut to_sender)
+            .unwrap()
+            .unwrap()
+            .to_vec();
+        let mut chunk = sender
+            .handle(&request, &mut to_receiver)
+            .unwrap()
+            .unwrap()
+            .to_vec();
+        chunk[10] ^= 0xFF;
+
+        let done = receiver
+            .handle(&chunk, &mut to_sender)
+            .unwrap()
+            .unwrap()
+            .to_vec();
+        assert_eq!(receiver.status(), OtaStatus::CrcMismatch);
+        sender.handle(&done, &mut to_receiver).unwrap();
+        assert_eq!(sender.outcome(), Some(OtaOutcome::CrcMismatch));
+
+        // A new offer starts over
+        receiver.handle(&offer, &mut to_sender).unwrap();
+        assert_eq!(receiver.progress().unwrap().received, 0);
+    }
+
+    #[test]
+    fn image_too_large() {
+        let mut receiver = OtaReceiver::new(MemoryStorage(vec![0; 64]));
+        let sender = OtaSender::new(MemoryStorage(vec![0; 100]), 100, 32).unwrap();
+
+        let mut offer = [0; 16];
+        let offer = sender.offer(&mut offer).unwrap();
+        assert_eq!(
+            receiver.handle(offer, &mut [0; 16]),
+            Err(OtaError::ImageTooLarge)
+        );
+    }
+}
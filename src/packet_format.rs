@@ -1,4 +1,6 @@
 //! Module containing all packet format handling and setup
+//!
+//! Every format has its own cargo feature, like `fixed-length` for `FixedLength`. They're all on by default.
 
 use core::fmt::Debug;
 
@@ -9,11 +11,18 @@ use embedded_hal::{
 };
 use embedded_hal_async::{delay::DelayNs, digital::Wait};
 
+#[cfg(feature = "wmbus")]
+use crate::states::shutdown::{Config, DataRate, ModulationType};
 use crate::{
     ll::{Device, LenWid},
     states::Ready,
     Error, ErrorOf, S2lp,
 };
+#[cfg(feature = "stack")]
+use crate::{
+    states::rx::{RxMode, RxTimeout, RxTimeoutMask},
+    time::Duration,
+};
 
 /// No packet format has been configured yet
 pub struct Uninitialized;
@@ -29,6 +38,13 @@ pub trait PacketFormat: SealedPacketFormat {
     /// All transmission metada specific for the format
     type TxMetaData;
 
+    /// Whether the payload is whitened. Formats that talk to other standards turn it off.
+    const WHITENING: bool = true;
+    /// Whether the packet handler is bypassed and the fifo is sent and filled as a raw bitstream
+    const DIRECT_FIFO: bool = false;
+    /// The packet format of the radio that the format is built on
+    const RADIO_FORMAT: crate::ll::PacketFormat = crate::ll::PacketFormat::Basic;
+
     /// Configure the device to be in the correct packet format with the given config
     fn use_config<Spi, Sdn, Gpio, Delay>(
         device: &mut S2lp<Ready<Uninitialized>, Spi, Sdn, Gpio, Delay>,
@@ -51,6 +67,18 @@ pub trait PacketFormat: SealedPacketFormat {
         Sdn: OutputPin,
         Gpio: InputPin + Wait,
         Delay: DelayNs;
+
+    /// The on-air address the packet is sent to, if the format has one.
+    /// Used to look up the peer in an [AddressTable](crate::mac::AddressTable).
+    fn destination_address(_tx_meta_data: &Self::TxMetaData) -> Option<u8> {
+        None
+    }
+
+    /// The on-air address the packet is sent from, if it's not the node address.
+    /// The node address is restored when the transmission is done.
+    fn source_address(_tx_meta_data: &Self::TxMetaData) -> Option<u8> {
+        None
+    }
 }
 
 #[allow(async_fn_in_trait)]
@@ -64,14 +92,21 @@ pub(crate) trait RxMetaData: Debug + Clone {
 }
 
 /// The basic packet format
+#[cfg(feature = "basic")]
 pub struct Basic;
 
+#[cfg(feature = "basic")]
 impl SealedPacketFormat for Basic {}
+#[cfg(feature = "basic")]
 impl PacketFormat for Basic {
     type Config = BasicConfig;
     type RxMetaData = BasicRxMetaData;
     type TxMetaData = BasicTxMetaData;
 
+    fn destination_address(tx_meta_data: &Self::TxMetaData) -> Option<u8> {
+        tx_meta_data.destination_address
+    }
+
     fn use_config<Spi, Sdn, Gpio, Delay>(
         device: &mut S2lp<Ready<Uninitialized>, Spi, Sdn, Gpio, Delay>,
         config: &Self::Config,
@@ -82,6 +117,10 @@ impl PacketFormat for Basic {
         Gpio: InputPin + Wait,
         Delay: DelayNs,
     {
+        config
+            .validate()
+            .map_err(|reason| Error::BadConfig { reason })?;
+
         device.ll().pckt_ctrl_6().write(|reg| {
             reg.set_preamble_len(config.preamble_length);
             reg.set_sync_len(config.sync_length)
@@ -109,14 +148,16 @@ impl PacketFormat for Basic {
         device
             .ll()
             .sync()
-            .write(|reg| reg.set_value(config.sync_pattern.to_be()))?;
+            .write(|reg| reg.set_value(config.sync_pattern.register_value()))?;
 
         device
             .ll()
             .pckt_pstmbl()
-            .write(|reg| reg.set_value(config.postamble_length))?;
+            .write(|reg| reg.set_value(config.postamble_length.pair_count()))?;
 
-        config.packet_filter.write_to_device(device.ll())?;
+        config
+            .packet_filter
+            .write_to_device(device.ll(), config.sync_length)?;
 
         Ok(())
     }
@@ -134,14 +175,6 @@ impl PacketFormat for Basic {
     {
         let pckt_ctrl_4 = device.ll().pckt_ctrl_4().read()?;
         let address_included = pckt_ctrl_4.address_len();
-        let max_packet_len = match pckt_ctrl_4.len_wid() {
-            LenWid::Bytes1 => u8::MAX as u16,
-            LenWid::Bytes2 => u16::MAX,
-        };
-
-        if payload_len > (max_packet_len - address_included as u16) as usize {
-            return Err(Error::BufferTooLarge);
-        }
 
         if address_included != tx_meta_data.destination_address.is_some() {
             return Err(Error::BadConfig {
@@ -149,11 +182,15 @@ impl PacketFormat for Basic {
             });
         }
 
-        // Set the packet lenght
+        let packet_len =
+            packet_length_field(payload_len, address_included as u16, pckt_ctrl_4.len_wid())
+                .ok_or(Error::BufferTooLarge)?;
+
+        // Set the packet length
         device
             .ll()
             .pckt_len()
-            .write(|reg| reg.set_value(payload_len as u16 + address_included as u16))?;
+            .write(|reg| reg.set_value(packet_len))?;
 
         // Set the destination address
         if let Some(destination_address) = tx_meta_data.destination_address {
@@ -168,19 +205,90 @@ impl PacketFormat for Basic {
 }
 
 /// Configuration for the Basic packet format
+#[cfg(feature = "basic")]
 pub struct BasicConfig {
-    pub preamble_length: u16, // 0-2046
+    pub preamble_length: u16, // 0-1023 bit pairs
     pub preamble_pattern: PreamblePattern,
-    pub sync_length: u8, // 0-32
-    pub sync_pattern: u32,
+    /// 0-32. When 0, packets are detected on the preamble alone,
+    /// which requires a [PacketFilteringOptions::preamble_quality_threshold].
+    pub sync_length: u8,
+    pub sync_pattern: SyncWord,
     pub include_address: bool,
     pub packet_length_encoding: LenWid,
-    pub postamble_length: u8, // In pairs of `01`'s
+    pub postamble_length: PostambleLength,
     pub crc_mode: CrcMode,
     pub packet_filter: PacketFilteringOptions,
 }
 
+#[cfg(feature = "basic")]
+impl BasicConfig {
+    /// Check the config without a radio. In a const, an invalid config is a compile error with [Self::validated]:
+    ///
+    /// ```rust
+    /// use s2lp::{
+    ///     ll::{CrcMode, LenWid},
+    ///     packet_format::{BasicConfig, PacketFilteringOptions, PostambleLength, PreamblePattern, SyncWord},
+    /// };
+    ///
+    /// const FORMAT: BasicConfig = BasicConfig {
+    ///     preamble_length: 32,
+    ///     preamble_pattern: PreamblePattern::Pattern0,
+    ///     sync_length: 32,
+    ///     sync_pattern: SyncWord::msb_first(0x12345678),
+    ///     include_address: false,
+    ///     packet_length_encoding: LenWid::Bytes1,
+    ///     postamble_length: PostambleLength::NONE,
+    ///     crc_mode: CrcMode::CrcPoly0X07,
+    ///     packet_filter: PacketFilteringOptions::DEFAULT,
+    /// }
+    /// .validated();
+    /// ```
+    pub const fn validate(&self) -> Result<(), &'static str> {
+        if self.preamble_length > MAX_PREAMBLE_LENGTH {
+            return Err("Preamble length out of range");
+        }
+
+        if !self.include_address && self.packet_filter.filters_on_address() {
+            return Err("Address filtering requires the address field");
+        }
+
+        self.packet_filter.validate(self.sync_length)
+    }
+
+    /// Return the config if it's valid and panic with the reason if not.
+    ///
+    /// ```rust,compile_fail
+    /// # use s2lp::{
+    /// #     ll::{CrcMode, LenWid},
+    /// #     packet_format::{BasicConfig, PacketFilteringOptions, PostambleLength, PreamblePattern, SyncWord},
+    /// # };
+    /// // Filtering on an address that isn't sent
+    /// const FORMAT: BasicConfig = BasicConfig {
+    ///     preamble_length: 32,
+    ///     preamble_pattern: PreamblePattern::Pattern0,
+    ///     sync_length: 32,
+    ///     sync_pattern: SyncWord::msb_first(0x12345678),
+    ///     include_address: false,
+    ///     packet_length_encoding: LenWid::Bytes1,
+    ///     postamble_length: PostambleLength::NONE,
+    ///     crc_mode: CrcMode::CrcPoly0X07,
+    ///     packet_filter: PacketFilteringOptions {
+    ///         source_address: Some(0x42),
+    ///         ..PacketFilteringOptions::DEFAULT
+    ///     },
+    /// }
+    /// .validated();
+    /// ```
+    pub const fn validated(self) -> Self {
+        if let Err(reason) = self.validate() {
+            panic!("{}", reason);
+        }
+        self
+    }
+}
+
 /// Receiver metadata for the Basic packet format
+#[cfg(feature = "basic")]
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
 pub struct BasicRxMetaData {
@@ -188,6 +296,7 @@ pub struct BasicRxMetaData {
     pub destination_address: Option<u8>,
 }
 
+#[cfg(feature = "basic")]
 impl RxMetaData for BasicRxMetaData {
     fn read_from_device<I: RegisterInterface<AddressType = u8>>(
         device: &mut Device<I>,
@@ -208,6 +317,7 @@ impl RxMetaData for BasicRxMetaData {
 }
 
 /// Transmission metadata for the Basic packet format
+#[cfg(feature = "basic")]
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
 pub struct BasicTxMetaData {
@@ -215,87 +325,1355 @@ pub struct BasicTxMetaData {
     pub destination_address: Option<u8>,
 }
 
-pub use crate::ll::CrcMode;
+/// The STack packet format.
+///
+/// This format always carries a destination address and supports automatic acknowledgements.
+/// Acknowledgements can optionally carry a payload (piggybacking),
+/// see [S2lp::set_ack_payload](crate::S2lp::set_ack_payload).
+#[cfg(feature = "stack")]
+pub struct Stack;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
-#[repr(u8)]
-pub enum PreamblePattern {
-    /// - `0101` for 2(G)FSK or OOK/ASK
-    /// - `0010` for 4(G)FSK
-    Pattern0,
-    /// - `1010` for 2(G)FSK or OOK/ASK
-    /// - `0111` for 4(G)FSK
-    Pattern1,
-    /// - `1100` for 2(G)FSK or OOK/ASK
-    /// - `1101` for 4(G)FSK
-    Pattern2,
-    /// - `0011` for 2(G)FSK or OOK/ASK
-    /// - `1000` for 4(G)FSK
-    Pattern3,
+#[cfg(feature = "stack")]
+impl SealedPacketFormat for Stack {}
+#[cfg(feature = "stack")]
+impl PacketFormat for Stack {
+    type Config = StackConfig;
+    type RxMetaData = StackRxMetaData;
+    type TxMetaData = StackTxMetaData;
+
+    const RADIO_FORMAT: crate::ll::PacketFormat = crate::ll::PacketFormat::Stack;
+
+    fn destination_address(tx_meta_data: &Self::TxMetaData) -> Option<u8> {
+        Some(tx_meta_data.destination_address)
+    }
+
+    fn source_address(tx_meta_data: &Self::TxMetaData) -> Option<u8> {
+        tx_meta_data.source_address
+    }
+
+    fn use_config<Spi, Sdn, Gpio, Delay>(
+        device: &mut S2lp<Ready<Uninitialized>, Spi, Sdn, Gpio, Delay>,
+        config: &Self::Config,
+    ) -> Result<(), ErrorOf<S2lp<Ready<Uninitialized>, Spi, Sdn, Gpio, Delay>>>
+    where
+        Spi: SpiDevice,
+        Sdn: OutputPin,
+        Gpio: InputPin + Wait,
+        Delay: DelayNs,
+    {
+        config
+            .validate()
+            .map_err(|reason| Error::BadConfig { reason })?;
+
+        device.ll().pckt_ctrl_6().write(|reg| {
+            reg.set_preamble_len(config.preamble_length);
+            reg.set_sync_len(config.sync_length)
+        })?;
+
+        device.ll().pckt_ctrl_4().write(|reg| {
+            // The STack format always has the address field
+            reg.set_address_len(true);
+            reg.set_len_wid(config.packet_length_encoding);
+        })?;
+
+        device.ll().pckt_ctrl_3().write(|reg| {
+            reg.set_pckt_frmt(crate::ll::PacketFormat::Stack);
+            reg.set_preamble_sel(config.preamble_pattern as u8);
+        })?;
+
+        device
+            .ll()
+            .pckt_ctrl_2()
+            .write(|reg| reg.set_fix_var_len(crate::ll::FixVarLen::Variable))?;
+
+        device.ll().pckt_ctrl_1().write(|reg| {
+            reg.set_crc_mode(config.crc_mode);
+        })?;
+
+        device
+            .ll()
+            .sync()
+            .write(|reg| reg.set_value(config.sync_pattern.register_value()))?;
+
+        device
+            .ll()
+            .pckt_pstmbl()
+            .write(|reg| reg.set_value(config.postamble_length.pair_count()))?;
+
+        config
+            .packet_filter
+            .write_to_device(device.ll(), config.sync_length)?;
+
+        device.ll().protocol_0().modify(|reg| {
+            reg.set_nmax_retx(config.max_retransmissions);
+            reg.set_auto_ack(config.auto_ack);
+        })?;
+
+        // Piggybacking is only turned on when an ack payload is staged
+        device
+            .ll()
+            .protocol_1()
+            .modify(|reg| reg.set_piggybacking(false))?;
+
+        Ok(())
+    }
+
+    fn setup_packet_send<Spi, Sdn, Gpio, Delay>(
+        device: &mut S2lp<Ready<Self>, Spi, Sdn, Gpio, Delay>,
+        tx_meta_data: &Self::TxMetaData,
+        payload_len: usize,
+    ) -> Result<(), ErrorOf<S2lp<Ready<Self>, Spi, Sdn, Gpio, Delay>>>
+    where
+        Spi: SpiDevice,
+        Sdn: OutputPin,
+        Gpio: InputPin + Wait,
+        Delay: DelayNs,
+    {
+        let len_wid = device.ll().pckt_ctrl_4().read()?.len_wid();
+        let packet_len = packet_length_field(payload_len, STACK_ADDRESS_FIELDS_LEN, len_wid)
+            .ok_or(Error::BufferTooLarge)?;
+
+        // Set the packet length. This includes the destination and source address
+        device
+            .ll()
+            .pckt_len()
+            .write(|reg| reg.set_value(packet_len))?;
+
+        device
+            .ll()
+            .pckt_flt_goals_3()
+            .write(|reg| reg.set_rx_source_addr_or_dual_sync_3(tx_meta_data.destination_address))?;
+
+        if let Some(source_address) = tx_meta_data.source_address {
+            device
+                .ll()
+                .pckt_flt_goals_0()
+                .write(|reg| reg.set_tx_source_addr_or_dual_sync_0(source_address))?;
+        }
+
+        device
+            .ll()
+            .protocol_0()
+            .modify(|reg| reg.set_nack_tx(!tx_meta_data.request_ack))?;
+
+        // Sending flushes the fifo, so a staged ack payload is gone
+        device
+            .ll()
+            .protocol_1()
+            .modify(|reg| reg.set_piggybacking(false))?;
+
+        if tx_meta_data.request_ack {
+            // The radio waits for the ack with the RX timer
+            let digital_frequency = device.state.digital_frequency();
+            let sync_less = device.ll().pckt_ctrl_6().read()?.sync_len() == 0;
+            RxMode::Normal {
+                timeout: Some(RxTimeout {
+                    timeout: tx_meta_data.ack_timeout,
+                    mask: RxTimeoutMask::Sqi,
+                }),
+            }
+            .write_to_device(device.ll(), digital_frequency, sync_less)?;
+        }
+
+        Ok(())
+    }
 }
 
-/// Setup the filters.
+/// The preamble length field counts `01` pairs in 10 bits
+#[cfg(any(
+    feature = "basic",
+    feature = "stack",
+    feature = "fixed-length",
+    feature = "uart-ota"
+))]
+const MAX_PREAMBLE_LENGTH: u16 = 1023;
+
+/// The number of bytes the destination and source address take in the STack length field
+#[cfg(feature = "stack")]
+pub(crate) const STACK_ADDRESS_FIELDS_LEN: u16 = 2;
+
+/// The value of the packet length field for a payload with the given amount of address bytes in front of it.
 ///
-/// If none of the address filters are set, then no filtering will be done on the address and
-/// all packets will be received.
-pub struct PacketFilteringOptions {
-    /// If true, packets with a bad CRC will be filtered out.
-    /// Ignored if no CRC is enabled.
-    pub discard_bad_crc: bool,
-    /// The address of *this* device.
+/// Returns None if the length doesn't fit in the length field. The length field counts the address bytes,
+/// so with a 1-byte length field the biggest payload is 255 bytes minus the address bytes.
+#[cfg(any(feature = "basic", feature = "stack", feature = "uart-ota"))]
+fn packet_length_field(payload_len: usize, address_len: u16, len_wid: LenWid) -> Option<u16> {
+    let max_packet_len = match len_wid {
+        LenWid::Bytes1 => u8::MAX as u16,
+        LenWid::Bytes2 => u16::MAX,
+    };
+
+    u16::try_from(payload_len)
+        .ok()?
+        .checked_add(address_len)
+        .filter(|packet_len| *packet_len <= max_packet_len)
+}
+
+/// Configuration for the STack packet format
+#[cfg(feature = "stack")]
+pub struct StackConfig {
+    pub preamble_length: u16, // 0-1023 bit pairs
+    pub preamble_pattern: PreamblePattern,
+    /// 0-32. When 0, packets are detected on the preamble alone,
+    /// which requires a [PacketFilteringOptions::preamble_quality_threshold].
+    pub sync_length: u8,
+    pub sync_pattern: SyncWord,
+    pub packet_length_encoding: LenWid,
+    pub postamble_length: PostambleLength,
+    pub crc_mode: CrcMode,
+    pub packet_filter: PacketFilteringOptions,
+    /// If true, received packets that request an acknowledgement are automatically acked by the radio
+    pub auto_ack: bool,
+    /// The amount of times a packet is retransmitted when no ack is received.
     ///
-    /// If Some, the filtering will be turned on and packets with this destination address will not be discarded.
-    pub source_address: Option<u8>,
-    /// The address of the multicast group this device is part of.
+    /// Range: 0..=15
+    pub max_retransmissions: u8,
+}
+
+#[cfg(feature = "stack")]
+impl StackConfig {
+    /// Check the config without a radio. See [BasicConfig::validate].
+    pub const fn validate(&self) -> Result<(), &'static str> {
+        if self.preamble_length > MAX_PREAMBLE_LENGTH {
+            return Err("Preamble length out of range");
+        }
+
+        if self.max_retransmissions > 15 {
+            return Err("Max retransmissions out of range");
+        }
+
+        self.packet_filter.validate(self.sync_length)
+    }
+
+    /// Return the config if it's valid and panic with the reason if not
+    pub const fn validated(self) -> Self {
+        if let Err(reason) = self.validate() {
+            panic!("{}", reason);
+        }
+        self
+    }
+}
+
+/// Receiver metadata for the STack packet format
+#[cfg(feature = "stack")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct StackRxMetaData {
+    /// The received packet destination address
+    pub destination_address: u8,
+    /// The address of the node that sent the packet
+    pub source_address: u8,
+    /// The 2-bit sequence number of the packet. Retransmissions carry the same sequence number.
+    pub sequence_number: u8,
+    /// True if the sender asked for an acknowledgement
+    pub ack_requested: bool,
+}
+
+#[cfg(feature = "stack")]
+impl RxMetaData for StackRxMetaData {
+    fn read_from_device<I: RegisterInterface<AddressType = u8>>(
+        device: &mut Device<I>,
+    ) -> Result<Self, I::Error>
+    where
+        Self: Sized,
+    {
+        let rx_pckt_info = device.rx_pckt_info().read()?;
+
+        Ok(Self {
+            destination_address: device.rx_addre_field_0().read()?.value(),
+            source_address: device.rx_addre_field_1().read()?.value(),
+            sequence_number: rx_pckt_info.rx_seq_num(),
+            ack_requested: !rx_pckt_info.nack_rx(),
+        })
+    }
+}
+
+/// Transmission metadata for the STack packet format
+#[cfg(feature = "stack")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct StackTxMetaData {
+    /// The destination address of the packet
+    pub destination_address: u8,
+    /// The source address of the packet. When None, the [node address](crate::S2lp::set_node_address) is sent.
     ///
-    /// If Some, the filtering will be turned on and packets with this destination address will not be discarded.
-    pub multicast_address: Option<u8>,
-    /// The broadcast address.
+    /// The source address is also the node address, so it replaces the node address (and the address the packet filter
+    /// accepts, e.g. for the ack) for this packet only. The node address is restored when the transmission is done.
+    pub source_address: Option<u8>,
+    /// If true, the receiver is asked to send back an acknowledgement
+    pub request_ack: bool,
+    /// How long to wait for the acknowledgement before retransmitting. Only used when an ack is requested.
     ///
-    /// If Some, the filtering will be turned on and packets with this destination address will not be discarded.
-    pub broadcast_address: Option<u8>,
+    /// The wait ends when the sync word of the ack is received, so this must cover the turnaround time of the receiver
+    /// plus the time the ack takes on air up to and including its sync word.
+    pub ack_timeout: Duration,
 }
 
-impl PacketFilteringOptions {
-    fn write_to_device<I: RegisterInterface<AddressType = u8>>(
-        &self,
-        device: &mut Device<I>,
-    ) -> Result<(), I::Error> {
-        device.pckt_flt_options().modify(|reg| {
-            reg.set_crc_flt(self.discard_bad_crc);
-            reg.set_dest_vs_broadcast_addr(self.broadcast_address.is_some());
-            reg.set_dest_vs_multicast_addr(self.multicast_address.is_some());
-            reg.set_dest_vs_source_addr(self.source_address.is_some());
+/// Fixed length frames without length or address field on air.
+///
+/// This is useful for ultra-short frames where every bit counts.
+/// Since there's no address field, the hardware can't filter on addresses.
+/// Use [S2lp::receive_matching] to filter the received frames in software instead.
+#[cfg(feature = "fixed-length")]
+pub struct FixedLength;
+
+#[cfg(feature = "fixed-length")]
+impl SealedPacketFormat for FixedLength {}
+#[cfg(feature = "fixed-length")]
+impl PacketFormat for FixedLength {
+    type Config = FixedLengthConfig;
+    type RxMetaData = FixedLengthRxMetaData;
+    type TxMetaData = FixedLengthTxMetaData;
+
+    fn use_config<Spi, Sdn, Gpio, Delay>(
+        device: &mut S2lp<Ready<Uninitialized>, Spi, Sdn, Gpio, Delay>,
+        config: &Self::Config,
+    ) -> Result<(), ErrorOf<S2lp<Ready<Uninitialized>, Spi, Sdn, Gpio, Delay>>>
+    where
+        Spi: SpiDevice,
+        Sdn: OutputPin,
+        Gpio: InputPin + Wait,
+        Delay: DelayNs,
+    {
+        config
+            .validate()
+            .map_err(|reason| Error::BadConfig { reason })?;
+
+        device.ll().pckt_ctrl_6().write(|reg| {
+            reg.set_preamble_len(config.preamble_length);
+            reg.set_sync_len(config.sync_length)
         })?;
 
-        device.pckt_flt_goals_2().write(|reg| {
-            reg.set_broadcast_addr_or_dual_sync_2(self.broadcast_address.unwrap_or_default())
+        device.ll().pckt_ctrl_4().write(|reg| {
+            reg.set_address_len(false);
         })?;
 
-        device.pckt_flt_goals_1().write(|reg| {
-            reg.set_multicast_addr_or_dual_sync_1(self.multicast_address.unwrap_or_default())
+        device.ll().pckt_ctrl_3().write(|reg| {
+            reg.set_pckt_frmt(crate::ll::PacketFormat::Basic);
+            reg.set_preamble_sel(config.preamble_pattern as u8);
         })?;
 
-        device.pckt_flt_goals_0().write(|reg| {
-            reg.set_tx_source_addr_or_dual_sync_0(self.source_address.unwrap_or_default())
+        device
+            .ll()
+            .pckt_ctrl_2()
+            .write(|reg| reg.set_fix_var_len(crate::ll::FixVarLen::Fixed))?;
+
+        device.ll().pckt_ctrl_1().write(|reg| {
+            reg.set_crc_mode(config.crc_mode);
         })?;
 
         device
-            .protocol_1()
-            .modify(|reg| reg.set_auto_pckt_flt(true))?;
+            .ll()
+            .pckt_len()
+            .write(|reg| reg.set_value(config.packet_length))?;
+
+        device
+            .ll()
+            .sync()
+            .write(|reg| reg.set_value(config.sync_pattern.register_value()))?;
+
+        device
+            .ll()
+            .pckt_pstmbl()
+            .write(|reg| reg.set_value(config.postamble_length.pair_count()))?;
+
+        config
+            .packet_filter
+            .write_to_device(device.ll(), config.sync_length)?;
 
         Ok(())
     }
-}
 
-impl Default for PacketFilteringOptions {
-    fn default() -> Self {
-        Self {
-            discard_bad_crc: true,
-            source_address: None,
-            multicast_address: None,
-            broadcast_address: None,
+    fn setup_packet_send<Spi, Sdn, Gpio, Delay>(
+        device: &mut S2lp<Ready<Self>, Spi, Sdn, Gpio, Delay>,
+        _tx_meta_data: &Self::TxMetaData,
+        payload_len: usize,
+    ) -> Result<(), ErrorOf<S2lp<Ready<Self>, Spi, Sdn, Gpio, Delay>>>
+    where
+        Spi: SpiDevice,
+        Sdn: OutputPin,
+        Gpio: InputPin + Wait,
+        Delay: DelayNs,
+    {
+        let packet_length = device.ll().pckt_len().read()?.value();
+
+        if payload_len != packet_length as usize {
+            return Err(Error::BadConfig {
+                reason: "Payload length different from the fixed packet length",
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Configuration for the fixed length packet format
+#[cfg(feature = "fixed-length")]
+pub struct FixedLengthConfig {
+    pub preamble_length: u16, // 0-1023 bit pairs
+    pub preamble_pattern: PreamblePattern,
+    /// 0-32. When 0, packets are detected on the preamble alone,
+    /// which requires a [PacketFilteringOptions::preamble_quality_threshold].
+    pub sync_length: u8,
+    pub sync_pattern: SyncWord,
+    /// The length of every frame in bytes
+    pub packet_length: u16,
+    pub postamble_length: PostambleLength,
+    pub crc_mode: CrcMode,
+    /// The packet filter. The address filters must be left off.
+    pub packet_filter: PacketFilteringOptions,
+}
+
+#[cfg(feature = "fixed-length")]
+impl FixedLengthConfig {
+    /// Check the config without a radio. See [BasicConfig::validate].
+    pub const fn validate(&self) -> Result<(), &'static str> {
+        if self.preamble_length > MAX_PREAMBLE_LENGTH {
+            return Err("Preamble length out of range");
+        }
+
+        if self.packet_length == 0 {
+            return Err("Packet length must not be 0");
+        }
+
+        if self.packet_filter.filters_on_address() {
+            return Err("Fixed length frames have no address to filter on");
+        }
+
+        self.packet_filter.validate(self.sync_length)
+    }
+
+    /// Return the config if it's valid and panic with the reason if not
+    pub const fn validated(self) -> Self {
+        if let Err(reason) = self.validate() {
+            panic!("{}", reason);
+        }
+        self
+    }
+}
+
+/// Receiver metadata for the fixed length packet format. There's nothing but the payload.
+#[cfg(feature = "fixed-length")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct FixedLengthRxMetaData;
+
+#[cfg(feature = "fixed-length")]
+impl RxMetaData for FixedLengthRxMetaData {
+    fn read_from_device<I: RegisterInterface<AddressType = u8>>(
+        _device: &mut Device<I>,
+    ) -> Result<Self, I::Error>
+    where
+        Self: Sized,
+    {
+        Ok(Self)
+    }
+}
+
+/// Transmission metadata for the fixed length packet format. There's nothing but the payload.
+#[cfg(feature = "fixed-length")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct FixedLengthTxMetaData;
+
+/// Wireless M-Bus (EN 13757-4) frames for metering applications.
+///
+/// The radio has no wM-Bus packet format of its own. This sets up the basic packet engine with the preamble,
+/// the sync word and the chip coding of the [mode](WMBusMode), and sends the frames without CRC or whitening.
+/// Frames are sent and received as is, with the block CRCs of the standard in the payload.
+/// Checking and generating those is up to the application.
+///
+/// The length field of wM-Bus counts the blocks in a way the radio can't follow, so the frames have a fixed length.
+/// When receiving, pick a length that covers the longest frame. The L-field of the frame tells how much of it is valid.
+///
+/// The radio must be initialized with the modulation of the mode, see [WMBusMode::radio_config].
+#[cfg(feature = "wmbus")]
+pub struct WMBus;
+
+#[cfg(feature = "wmbus")]
+impl SealedPacketFormat for WMBus {}
+#[cfg(feature = "wmbus")]
+impl PacketFormat for WMBus {
+    type Config = WMBusConfig;
+    type RxMetaData = WMBusRxMetaData;
+    type TxMetaData = WMBusTxMetaData;
+
+    const WHITENING: bool = false;
+
+    fn use_config<Spi, Sdn, Gpio, Delay>(
+        device: &mut S2lp<Ready<Uninitialized>, Spi, Sdn, Gpio, Delay>,
+        config: &Self::Config,
+    ) -> Result<(), ErrorOf<S2lp<Ready<Uninitialized>, Spi, Sdn, Gpio, Delay>>>
+    where
+        Spi: SpiDevice,
+        Sdn: OutputPin,
+        Gpio: InputPin + Wait,
+        Delay: DelayNs,
+    {
+        config
+            .validate()
+            .map_err(|reason| Error::BadConfig { reason })?;
+
+        let (preamble_length, sync_length, sync_pattern) = config.mode.header();
+
+        device.ll().pckt_ctrl_6().write(|reg| {
+            reg.set_preamble_len(preamble_length);
+            reg.set_sync_len(sync_length)
+        })?;
+
+        device.ll().pckt_ctrl_4().write(|reg| {
+            reg.set_address_len(false);
+        })?;
+
+        device.ll().pckt_ctrl_3().write(|reg| {
+            reg.set_pckt_frmt(crate::ll::PacketFormat::Basic);
+            reg.set_preamble_sel(PreamblePattern::Pattern0 as u8);
+        })?;
+
+        device.ll().pckt_ctrl_2().write(|reg| {
+            reg.set_fix_var_len(crate::ll::FixVarLen::Fixed);
+            reg.set_manchester_en(matches!(config.mode, WMBusMode::S { .. }));
+            reg.set_mbus_3_of_6_en(config.mode == WMBusMode::T);
+        })?;
+
+        device.ll().pckt_ctrl_1().write(|reg| {
+            reg.set_crc_mode(CrcMode::NoCrc);
+        })?;
+
+        device
+            .ll()
+            .pckt_len()
+            .write(|reg| reg.set_value(config.frame_length))?;
+
+        device
+            .ll()
+            .sync()
+            .write(|reg| reg.set_value(sync_pattern.register_value()))?;
+
+        device
+            .ll()
+            .pckt_pstmbl()
+            .write(|reg| reg.set_value(config.postamble_length.pair_count()))?;
+
+        PacketFilteringOptions::default().write_to_device(device.ll(), sync_length)?;
+
+        Ok(())
+    }
+
+    fn setup_packet_send<Spi, Sdn, Gpio, Delay>(
+        device: &mut S2lp<Ready<Self>, Spi, Sdn, Gpio, Delay>,
+        _tx_meta_data: &Self::TxMetaData,
+        payload_len: usize,
+    ) -> Result<(), ErrorOf<S2lp<Ready<Self>, Spi, Sdn, Gpio, Delay>>>
+    where
+        Spi: SpiDevice,
+        Sdn: OutputPin,
+        Gpio: InputPin + Wait,
+        Delay: DelayNs,
+    {
+        let frame_length = device.ll().pckt_len().read()?.value();
+
+        if payload_len != frame_length as usize {
+            return Err(Error::BadConfig {
+                reason: "Payload length different from the wM-Bus frame length",
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// The wM-Bus modes the radio can do. The 'other' side is the gateway or reader.
+#[cfg(feature = "wmbus")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum WMBusMode {
+    /// Stationary mode at 32.768 kchips/s with Manchester coding
+    S {
+        /// The long preamble of mode S1 and of the S2 frames that wake up a meter.
+        /// Without it, the short preamble of S1-m and the other S2 frames is used.
+        long_preamble: bool,
+    },
+    /// Frequent transmit mode at 100 kchips/s with 3-out-of-6 coding, from the meter to the other side
+    T,
+    /// Compact mode at 100 kchips/s without coding.
+    ///
+    /// The first two bytes of the payload are the second sync word,
+    /// which tells frame format A (`0x54 0xCD`) and B (`0x54 0x3D`) apart.
+    C,
+}
+
+#[cfg(feature = "wmbus")]
+impl WMBusMode {
+    /// The preamble length in `01` pairs, the sync length in bits and the sync word
+    const fn header(self) -> (u16, u8, SyncWord) {
+        match self {
+            Self::S {
+                long_preamble: true,
+            } => (279, 18, SyncWord::msb_first(0x7696)),
+            Self::S {
+                long_preamble: false,
+            } => (15, 18, SyncWord::msb_first(0x7696)),
+            Self::T => (19, 10, SyncWord::msb_first(0x3D)),
+            Self::C => (16, 16, SyncWord::msb_first(0x543D)),
+        }
+    }
+
+    /// The radio config of the mode with the given crystal
+    pub const fn radio_config(self, xtal_frequency: u32) -> Config {
+        match self {
+            Self::S { .. } => Config {
+                xtal_frequency,
+                base_frequency: 868_300_000,
+                modulation: ModulationType::Fsk2,
+                datarate: DataRate::symbols_per_sec(32_768),
+                frequency_deviation: 50_000,
+                bandwidth: 200_000,
+            },
+            Self::T | Self::C => Config {
+                xtal_frequency,
+                base_frequency: 868_950_000,
+                modulation: ModulationType::Fsk2,
+                datarate: DataRate::symbols_per_sec(100_000),
+                frequency_deviation: if matches!(self, Self::T) {
+                    50_000
+                } else {
+                    45_000
+                },
+                bandwidth: 250_000,
+            },
+        }
+    }
+}
+
+/// Configuration for the wM-Bus packet format
+#[cfg(feature = "wmbus")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct WMBusConfig {
+    pub mode: WMBusMode,
+    /// The length of the frames in bytes, after decoding. See [WMBus] for receiving frames of different lengths.
+    pub frame_length: u16,
+    /// The standard asks for 2 to 8 postamble chips in modes S and T
+    pub postamble_length: PostambleLength,
+}
+
+#[cfg(feature = "wmbus")]
+impl WMBusConfig {
+    /// Check the config without a radio. See [BasicConfig::validate].
+    pub const fn validate(&self) -> Result<(), &'static str> {
+        if self.frame_length == 0 {
+            return Err("Frame length must not be 0");
+        }
+
+        Ok(())
+    }
+
+    /// Return the config if it's valid and panic with the reason if not
+    pub const fn validated(self) -> Self {
+        if let Err(reason) = self.validate() {
+            panic!("{}", reason);
+        }
+        self
+    }
+}
+
+/// Receiver metadata for the wM-Bus packet format. The header of the frame is in the payload.
+#[cfg(feature = "wmbus")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct WMBusRxMetaData;
+
+#[cfg(feature = "wmbus")]
+impl RxMetaData for WMBusRxMetaData {
+    fn read_from_device<I: RegisterInterface<AddressType = u8>>(
+        _device: &mut Device<I>,
+    ) -> Result<Self, I::Error>
+    where
+        Self: Sized,
+    {
+        Ok(Self)
+    }
+}
+
+/// Transmission metadata for the wM-Bus packet format. The header of the frame is in the payload.
+#[cfg(feature = "wmbus")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct WMBusTxMetaData;
+
+/// The UART over the air format, to talk to UART-to-radio bridges.
+///
+/// Every byte of the packet is framed with a start and a stop bit like on a UART.
+/// There's no address field, so the hardware can't filter on addresses.
+/// The payload isn't whitened, since the bridges send their bytes as is.
+#[cfg(feature = "uart-ota")]
+pub struct UartOta;
+
+#[cfg(feature = "uart-ota")]
+impl SealedPacketFormat for UartOta {}
+#[cfg(feature = "uart-ota")]
+impl PacketFormat for UartOta {
+    type Config = UartOtaConfig;
+    type RxMetaData = UartOtaRxMetaData;
+    type TxMetaData = UartOtaTxMetaData;
+
+    const RADIO_FORMAT: crate::ll::PacketFormat = crate::ll::PacketFormat::UartOta;
+    const WHITENING: bool = false;
+
+    fn use_config<Spi, Sdn, Gpio, Delay>(
+        device: &mut S2lp<Ready<Uninitialized>, Spi, Sdn, Gpio, Delay>,
+        config: &Self::Config,
+    ) -> Result<(), ErrorOf<S2lp<Ready<Uninitialized>, Spi, Sdn, Gpio, Delay>>>
+    where
+        Spi: SpiDevice,
+        Sdn: OutputPin,
+        Gpio: InputPin + Wait,
+        Delay: DelayNs,
+    {
+        config
+            .validate()
+            .map_err(|reason| Error::BadConfig { reason })?;
+
+        device.ll().pckt_ctrl_6().write(|reg| {
+            reg.set_preamble_len(config.preamble_length);
+            reg.set_sync_len(config.sync_length)
+        })?;
+
+        device.ll().pckt_ctrl_4().write(|reg| {
+            reg.set_address_len(false);
+            reg.set_len_wid(config.packet_length_encoding);
+        })?;
+
+        device.ll().pckt_ctrl_3().write(|reg| {
+            reg.set_pckt_frmt(crate::ll::PacketFormat::UartOta);
+            reg.set_preamble_sel(config.preamble_pattern as u8);
+        })?;
+
+        device.ll().pckt_ctrl_2().write(|reg| {
+            reg.set_fix_var_len(crate::ll::FixVarLen::Variable);
+            reg.set_int_en_4_g_or_start_bit(config.start_bit);
+            reg.set_fec_type_4_g_or_stop_bit(config.stop_bit);
+        })?;
+
+        device.ll().pckt_ctrl_1().write(|reg| {
+            reg.set_crc_mode(config.crc_mode);
+        })?;
+
+        device
+            .ll()
+            .sync()
+            .write(|reg| reg.set_value(config.sync_pattern.register_value()))?;
+
+        device
+            .ll()
+            .pckt_pstmbl()
+            .write(|reg| reg.set_value(config.postamble_length.pair_count()))?;
+
+        config
+            .packet_filter
+            .write_to_device(device.ll(), config.sync_length)?;
+
+        Ok(())
+    }
+
+    fn setup_packet_send<Spi, Sdn, Gpio, Delay>(
+        device: &mut S2lp<Ready<Self>, Spi, Sdn, Gpio, Delay>,
+        _tx_meta_data: &Self::TxMetaData,
+        payload_len: usize,
+    ) -> Result<(), ErrorOf<S2lp<Ready<Self>, Spi, Sdn, Gpio, Delay>>>
+    where
+        Spi: SpiDevice,
+        Sdn: OutputPin,
+        Gpio: InputPin + Wait,
+        Delay: DelayNs,
+    {
+        let len_wid = device.ll().pckt_ctrl_4().read()?.len_wid();
+        let packet_len =
+            packet_length_field(payload_len, 0, len_wid).ok_or(Error::BufferTooLarge)?;
+
+        device
+            .ll()
+            .pckt_len()
+            .write(|reg| reg.set_value(packet_len))?;
+
+        Ok(())
+    }
+}
+
+/// Configuration for the UART over the air packet format
+#[cfg(feature = "uart-ota")]
+pub struct UartOtaConfig {
+    pub preamble_length: u16, // 0-1023 bit pairs
+    pub preamble_pattern: PreamblePattern,
+    /// 0-32. When 0, packets are detected on the preamble alone,
+    /// which requires a [PacketFilteringOptions::preamble_quality_threshold].
+    pub sync_length: u8,
+    pub sync_pattern: SyncWord,
+    pub packet_length_encoding: LenWid,
+    pub postamble_length: PostambleLength,
+    pub crc_mode: CrcMode,
+    /// The packet filter. The address filters must be left off.
+    pub packet_filter: PacketFilteringOptions,
+    /// The value of the start bit in front of every byte. A UART uses a low start bit.
+    pub start_bit: bool,
+    /// The value of the stop bit after every byte. A UART uses a high stop bit.
+    pub stop_bit: bool,
+}
+
+#[cfg(feature = "uart-ota")]
+impl UartOtaConfig {
+    /// Check the config without a radio. See [BasicConfig::validate].
+    pub const fn validate(&self) -> Result<(), &'static str> {
+        if self.preamble_length > MAX_PREAMBLE_LENGTH {
+            return Err("Preamble length out of range");
+        }
+
+        if self.packet_filter.filters_on_address() {
+            return Err("UART over the air packets have no address to filter on");
+        }
+
+        self.packet_filter.validate(self.sync_length)
+    }
+
+    /// Return the config if it's valid and panic with the reason if not
+    pub const fn validated(self) -> Self {
+        if let Err(reason) = self.validate() {
+            panic!("{}", reason);
+        }
+        self
+    }
+}
+
+/// Receiver metadata for the UART over the air packet format. There's nothing but the payload.
+#[cfg(feature = "uart-ota")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct UartOtaRxMetaData;
+
+#[cfg(feature = "uart-ota")]
+impl RxMetaData for UartOtaRxMetaData {
+    fn read_from_device<I: RegisterInterface<AddressType = u8>>(
+        _device: &mut Device<I>,
+    ) -> Result<Self, I::Error>
+    where
+        Self: Sized,
+    {
+        Ok(Self)
+    }
+}
+
+/// Transmission metadata for the UART over the air packet format. There's nothing but the payload.
+#[cfg(feature = "uart-ota")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct UartOtaTxMetaData;
+
+/// Raw bitstreams through the fifo, with the packet handler bypassed.
+///
+/// There's no preamble, sync word, length field or CRC. What's written is sent as is at the configured
+/// modulation and datarate, so the application does its own framing.
+///
+/// Without a packet handler there's no end of a packet when receiving. The receiver starts right away and
+/// fills the fifo with everything it demodulates, noise included. A reception is done when the buffer given to
+/// [S2lp::start_receive] is full, so its length is the amount of bits to capture. Use an [RxTimeout] with the
+/// [RxTimeoutMask::None] mask to stop early, in which case [RxResult::Timeout](crate::states::rx::RxResult::Timeout) is returned.
+/// The fifo must be serviced, so the detached receive doesn't work with this format.
+#[cfg(feature = "raw-fifo")]
+pub struct RawFifo;
+
+#[cfg(feature = "raw-fifo")]
+impl SealedPacketFormat for RawFifo {}
+#[cfg(feature = "raw-fifo")]
+impl PacketFormat for RawFifo {
+    type Config = RawFifoConfig;
+    type RxMetaData = RawFifoRxMetaData;
+    type TxMetaData = RawFifoTxMetaData;
+
+    const WHITENING: bool = false;
+    const DIRECT_FIFO: bool = true;
+
+    fn use_config<Spi, Sdn, Gpio, Delay>(
+        device: &mut S2lp<Ready<Uninitialized>, Spi, Sdn, Gpio, Delay>,
+        _config: &Self::Config,
+    ) -> Result<(), ErrorOf<S2lp<Ready<Uninitialized>, Spi, Sdn, Gpio, Delay>>>
+    where
+        Spi: SpiDevice,
+        Sdn: OutputPin,
+        Gpio: InputPin + Wait,
+        Delay: DelayNs,
+    {
+        device.ll().pckt_ctrl_6().write(|reg| {
+            reg.set_preamble_len(0);
+            reg.set_sync_len(0)
+        })?;
+
+        device.ll().pckt_ctrl_4().write(|reg| {
+            reg.set_address_len(false);
+        })?;
+
+        device.ll().pckt_ctrl_3().write(|reg| {
+            reg.set_pckt_frmt(crate::ll::PacketFormat::Basic);
+        })?;
+
+        device
+            .ll()
+            .pckt_ctrl_2()
+            .write(|reg| reg.set_fix_var_len(crate::ll::FixVarLen::Fixed))?;
+
+        device.ll().pckt_ctrl_1().write(|reg| {
+            reg.set_crc_mode(CrcMode::NoCrc);
+        })?;
+
+        device.ll().pckt_pstmbl().write(|reg| reg.set_value(0))?;
+
+        // Nothing to filter on
+        PacketFilteringOptions {
+            discard_bad_crc: false,
+            sync_quality_threshold: None,
+            automatic_filtering: false,
+            ..PacketFilteringOptions::DEFAULT
+        }
+        .write_to_device(device.ll(), 0)?;
+
+        Ok(())
+    }
+
+    fn setup_packet_send<Spi, Sdn, Gpio, Delay>(
+        device: &mut S2lp<Ready<Self>, Spi, Sdn, Gpio, Delay>,
+        _tx_meta_data: &Self::TxMetaData,
+        payload_len: usize,
+    ) -> Result<(), ErrorOf<S2lp<Ready<Self>, Spi, Sdn, Gpio, Delay>>>
+    where
+        Spi: SpiDevice,
+        Sdn: OutputPin,
+        Gpio: InputPin + Wait,
+        Delay: DelayNs,
+    {
+        if payload_len > u16::MAX as usize {
+            return Err(Error::BufferTooLarge);
+        }
+
+        // The radio stops sending after this many bytes
+        device
+            .ll()
+            .pckt_len()
+            .write(|reg| reg.set_value(payload_len as u16))?;
+
+        Ok(())
+    }
+}
+
+/// Configuration for the raw fifo format. The modulation and datarate of the radio config are all there is.
+#[cfg(feature = "raw-fifo")]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RawFifoConfig;
+
+/// Receiver metadata for the raw fifo format. There's nothing but the bitstream.
+#[cfg(feature = "raw-fifo")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct RawFifoRxMetaData;
+
+#[cfg(feature = "raw-fifo")]
+impl RxMetaData for RawFifoRxMetaData {
+    fn read_from_device<I: RegisterInterface<AddressType = u8>>(
+        _device: &mut Device<I>,
+    ) -> Result<Self, I::Error>
+    where
+        Self: Sized,
+    {
+        Ok(Self)
+    }
+}
+
+/// Transmission metadata for the raw fifo format. There's nothing but the bitstream.
+#[cfg(feature = "raw-fifo")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct RawFifoTxMetaData;
+
+pub use crate::ll::CrcMode;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+#[repr(u8)]
+pub enum PreamblePattern {
+    /// - `0101` for 2(G)FSK or OOK/ASK
+    /// - `0010` for 4(G)FSK
+    Pattern0,
+    /// - `1010` for 2(G)FSK or OOK/ASK
+    /// - `0111` for 4(G)FSK
+    Pattern1,
+    /// - `1100` for 2(G)FSK or OOK/ASK
+    /// - `1101` for 4(G)FSK
+    Pattern2,
+    /// - `0011` for 2(G)FSK or OOK/ASK
+    /// - `1000` for 4(G)FSK
+    Pattern3,
+}
+
+impl PreamblePattern {
+    pub(crate) fn from_bits(bits: u8) -> Self {
+        match bits & 0b11 {
+            0 => Self::Pattern0,
+            1 => Self::Pattern1,
+            2 => Self::Pattern2,
+            _ => Self::Pattern3,
+        }
+    }
+}
+
+/// The sync word and the byte order it's sent in.
+///
+/// Some third-party S2-LP firmwares write the sync word to the registers in the opposite byte order.
+/// Use [Self::lsb_first] to match them.
+///
+/// The dual sync word detection of the radio isn't supported. The secondary sync word shares its registers with the
+/// address filter, and the radio doesn't report which of the two sync words a packet was received with.
+/// To tell frame types apart on one channel, put a type byte in the payload or use different packet formats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct SyncWord {
+    pattern: u32,
+    lsb_first: bool,
+}
+
+impl SyncWord {
+    /// The most significant byte of the pattern is sent first.
+    /// The least significant byte ends up in the SYNC3 register.
+    pub const fn msb_first(pattern: u32) -> Self {
+        Self {
+            pattern,
+            lsb_first: false,
+        }
+    }
+
+    /// The least significant byte of the pattern is sent first.
+    /// The most significant byte ends up in the SYNC3 register.
+    pub const fn lsb_first(pattern: u32) -> Self {
+        Self {
+            pattern,
+            lsb_first: true,
+        }
+    }
+
+    /// The sync pattern
+    pub const fn pattern(self) -> u32 {
+        self.pattern
+    }
+
+    /// The value of the (big endian) SYNC register
+    #[cfg(any(
+        feature = "basic",
+        feature = "stack",
+        feature = "fixed-length",
+        feature = "wmbus",
+        feature = "uart-ota"
+    ))]
+    pub(crate) const fn register_value(self) -> u32 {
+        if self.lsb_first {
+            self.pattern
+        } else {
+            self.pattern.swap_bytes()
+        }
+    }
+}
+
+/// The length of the postamble that is sent after the packet.
+///
+/// The postamble is an alternating `01` bit pattern. Some receivers need these trailing bits
+/// to flush their demodulator before the carrier goes away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct PostambleLength(u8);
+
+impl PostambleLength {
+    /// No postamble
+    pub const NONE: Self = Self(0);
+
+    /// A postamble of `n` pairs of `01` bits
+    pub const fn pairs(n: u8) -> Self {
+        Self(n)
+    }
+
+    /// The amount of `01` pairs
+    pub const fn pair_count(self) -> u8 {
+        self.0
+    }
+
+    /// The length in bits on air
+    pub const fn bits(self) -> u16 {
+        self.0 as u16 * 2
+    }
+}
+
+/// The packet handler settings as they are currently programmed in the radio.
+///
+/// This is a summary common to all formats so it can be used to verify the configuration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct ModemStatus {
+    pub packet_format: crate::ll::PacketFormat,
+    pub preamble_length: u16,
+    pub preamble_pattern: PreamblePattern,
+    pub sync_length: u8,
+    /// The sync pattern as given to [SyncWord::msb_first]
+    pub sync_pattern: u32,
+    /// If true, packets have a fixed length of [Self::packet_length] and no length field
+    pub fixed_length: bool,
+    pub packet_length_encoding: LenWid,
+    /// The programmed packet length. For variable length packets, this is the length of the last sent packet.
+    pub packet_length: u16,
+    /// If true, the packets carry an address field
+    pub address_field: bool,
+    pub postamble_length: PostambleLength,
+    pub crc_mode: CrcMode,
+    pub whitening: bool,
+    pub fec: bool,
+    pub manchester: bool,
+    pub mbus_3_of_6: bool,
+}
+
+/// Setup the filters.
+///
+/// If none of the address filters are set, then no filtering will be done on the address and
+/// all packets will be received.
+pub struct PacketFilteringOptions {
+    /// If true, packets with a bad CRC will be filtered out.
+    /// Ignored if no CRC is enabled.
+    pub discard_bad_crc: bool,
+    /// The address of *this* device.
+    ///
+    /// If Some, the filtering will be turned on and packets with this destination address will not be discarded.
+    pub source_address: Option<u8>,
+    /// The address of the multicast group this device is part of.
+    ///
+    /// If Some, the filtering will be turned on and packets with this destination address will not be discarded.
+    pub multicast_address: Option<u8>,
+    /// The broadcast address.
+    ///
+    /// If Some, the filtering will be turned on and packets with this destination address will not be discarded.
+    pub broadcast_address: Option<u8>,
+    /// The sync quality threshold (0-7). Higher values tolerate more bit errors in the sync word.
+    ///
+    /// If Some, a sync word is only accepted when its quality passes the threshold.
+    /// This prevents false sync locks on noise. Ignored when the sync length is 0.
+    pub sync_quality_threshold: Option<u8>,
+    /// The preamble quality threshold (0-15).
+    ///
+    /// The preamble is only accepted when the PQI is at least 4 times this value. 0 disables the check.
+    ///
+    /// With a sync length of 0, this is what starts the packet reception, so it must be at least 1.
+    pub preamble_quality_threshold: u8,
+    /// If true, the radio's automatic packet filtering is enabled, so packets that fail the CRC or address filters
+    /// are discarded by the radio.
+    ///
+    /// It's only turned on when at least one of those filters is active.
+    pub automatic_filtering: bool,
+}
+
+impl PacketFilteringOptions {
+    /// Whether any of the address filters is on
+    #[cfg(any(feature = "basic", feature = "fixed-length", feature = "uart-ota"))]
+    const fn filters_on_address(&self) -> bool {
+        self.source_address.is_some()
+            || self.multicast_address.is_some()
+            || self.broadcast_address.is_some()
+    }
+
+    #[cfg(any(
+        feature = "basic",
+        feature = "stack",
+        feature = "fixed-length",
+        feature = "uart-ota"
+    ))]
+    const fn validate(&self, sync_length: u8) -> Result<(), &'static str> {
+        if sync_length > 32 {
+            return Err("Sync length out of range");
+        }
+
+        if sync_length == 0 && self.preamble_quality_threshold == 0 {
+            return Err("A zero sync length requires a preamble quality threshold");
+        }
+
+        if let Some(8..) = self.sync_quality_threshold {
+            return Err("Sync quality threshold out of range");
+        }
+
+        if self.preamble_quality_threshold > 15 {
+            return Err("Preamble quality threshold out of range");
+        }
+
+        Ok(())
+    }
+
+    fn write_to_device<I: RegisterInterface<AddressType = u8>>(
+        &self,
+        device: &mut Device<I>,
+        sync_length: u8,
+    ) -> Result<(), I::Error> {
+        device.pckt_flt_options().modify(|reg| {
+            reg.set_crc_flt(self.discard_bad_crc);
+            reg.set_dest_vs_broadcast_addr(self.broadcast_address.is_some());
+            reg.set_dest_vs_multicast_addr(self.multicast_address.is_some());
+            reg.set_dest_vs_source_addr(self.source_address.is_some());
+        })?;
+
+        device.pckt_flt_goals_2().write(|reg| {
+            reg.set_broadcast_addr_or_dual_sync_2(self.broadcast_address.unwrap_or_default())
+        })?;
+
+        device.pckt_flt_goals_1().write(|reg| {
+            reg.set_multicast_addr_or_dual_sync_1(self.multicast_address.unwrap_or_default())
+        })?;
+
+        device.pckt_flt_goals_0().write(|reg| {
+            reg.set_tx_source_addr_or_dual_sync_0(self.source_address.unwrap_or_default())
+        })?;
+
+        let any_filter = self.discard_bad_crc
+            || self.broadcast_address.is_some()
+            || self.multicast_address.is_some()
+            || self.source_address.is_some();
+        device
+            .protocol_1()
+            .modify(|reg| reg.set_auto_pckt_flt(self.automatic_filtering && any_filter))?;
+
+        // Without a sync word there's no sync quality to check
+        let sync_quality_threshold = self.sync_quality_threshold.filter(|_| sync_length > 0);
+
+        device.qi().write(|reg| {
+            reg.set_sqi_en(sync_quality_threshold.is_some());
+            reg.set_sqi_th(sync_quality_threshold.unwrap_or_default());
+            reg.set_pqi_th(self.preamble_quality_threshold);
+        })?;
+
+        Ok(())
+    }
+}
+
+impl PacketFilteringOptions {
+    /// The [Default] options, usable in a const
+    pub const DEFAULT: Self = Self {
+        discard_bad_crc: true,
+        source_address: None,
+        multicast_address: None,
+        broadcast_address: None,
+        sync_quality_threshold: Some(0),
+        preamble_quality_threshold: 0,
+        automatic_filtering: true,
+    };
+}
+
+impl Default for PacketFilteringOptions {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+#[cfg(all(
+    test,
+    any(
+        feature = "basic",
+        feature = "stack",
+        feature = "fixed-length",
+        feature = "uart-ota"
+    )
+))]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "fixed-length")]
+    fn config_validation() {
+        const FIXED: FixedLengthConfig = FixedLengthConfig {
+            preamble_length: 32,
+            preamble_pattern: PreamblePattern::Pattern0,
+            sync_length: 16,
+            sync_pattern: SyncWord::msb_first(0x1234),
+            packet_length: 4,
+            postamble_length: PostambleLength::NONE,
+            crc_mode: CrcMode::CrcPoly0X07,
+            packet_filter: PacketFilteringOptions::DEFAULT,
+        }
+        .validated();
+        assert_eq!(FIXED.validate(), Ok(()));
+
+        for (config, reason) in [
+            (
+                FixedLengthConfig {
+                    preamble_length: 1024,
+                    ..FIXED
+                },
+                "Preamble length out of range",
+            ),
+            (
+                FixedLengthConfig {
+                    sync_length: 33,
+                    ..FIXED
+                },
+                "Sync length out of range",
+            ),
+            (
+                FixedLengthConfig {
+                    packet_length: 0,
+                    ..FIXED
+                },
+                "Packet length must not be 0",
+            ),
+            (
+                FixedLengthConfig {
+                    packet_filter: PacketFilteringOptions {
+                        broadcast_address: Some(0xFF),
+                        ..PacketFilteringOptions::DEFAULT
+                    },
+                    ..FIXED
+                },
+                "Fixed length frames have no address to filter on",
+            ),
+            (
+                FixedLengthConfig {
+                    packet_filter: PacketFilteringOptions {
+                        sync_quality_threshold: Some(8),
+                        ..PacketFilteringOptions::DEFAULT
+                    },
+                    ..FIXED
+                },
+                "Sync quality threshold out of range",
+            ),
+        ] {
+            assert_eq!(config.validate(), Err(reason));
+        }
+    }
+
+    #[test]
+    #[cfg(any(feature = "basic", feature = "stack", feature = "uart-ota"))]
+    fn packet_length_matrix() {
+        for (payload_len, address_len, len_wid, expected) in [
+            (0, 0, LenWid::Bytes1, Some(0)),
+            (0, 1, LenWid::Bytes1, Some(1)),
+            (254, 1, LenWid::Bytes1, Some(255)),
+            (255, 0, LenWid::Bytes1, Some(255)),
+            (255, 1, LenWid::Bytes1, None),
+            (256, 0, LenWid::Bytes1, None),
+            (253, 2, LenWid::Bytes1, Some(255)),
+            (254, 2, LenWid::Bytes1, None),
+            (255, 1, LenWid::Bytes2, Some(256)),
+            (256, 0, LenWid::Bytes2, Some(256)),
+            (256, 1, LenWid::Bytes2, Some(257)),
+            (65_534, 1, LenWid::Bytes2, Some(65_535)),
+            (65_535, 0, LenWid::Bytes2, Some(65_535)),
+            (65_535, 1, LenWid::Bytes2, None),
+            (65_536, 0, LenWid::Bytes2, None),
+            (65_533, 2, LenWid::Bytes2, Some(65_535)),
+            (65_534, 2, LenWid::Bytes2, None),
+        ] {
+            assert_eq!(
+                packet_length_field(payload_len, address_len, len_wid),
+                expected,
+                "payload: {payload_len}, address: {address_len}, {len_wid:?}"
+            );
         }
     }
 }
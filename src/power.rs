@@ -0,0 +1,200 @@
+//! Closed-loop output power calibration with an external power detector.
+//!
+//! The output power of [S2lp::set_tx_power] follows the typical curve of the datasheet,
+//! but the matching network and the antenna path differ from board to board.
+//! With a power detector on the RF output (e.g. a log detector on a 50 Ω coupler read by an ADC),
+//! [S2lp::calibrate_tx_power] transmits a carrier and adjusts the PA level until the detector reads the requested power.
+//!
+//! Calibrate once (e.g. in production) and store the [PowerCalibration] to restore it with [S2lp::set_pa_level].
+
+use embedded_hal::{
+    digital::{InputPin, OutputPin},
+    spi::SpiDevice,
+};
+use embedded_hal_async::{delay::DelayNs, digital::Wait};
+
+use crate::{
+    ll::{ModulationType, TxSource},
+    packet_format::PacketFormat,
+    states::{ready::tx_power_to_pa_level, Ready},
+    time::Duration,
+    Error, ErrorOf, S2lp,
+};
+
+/// The PA levels the radio supports. A higher level is a lower output power.
+const PA_LEVELS: core::ops::RangeInclusive<u8> = 1..=90;
+/// The change of the output power per PA level in 0.1 dB
+const DECI_DB_PER_LEVEL: i32 = 5;
+/// The maximum amount of measurements the calibration does
+const MAX_ITERATIONS: u8 = 16;
+
+/// Measures the output power of the radio, e.g. with an ADC on a power detector.
+///
+/// Implemented for any `FnMut() -> i16`.
+pub trait PowerDetector {
+    /// Measure the output power in 0.1 dBm
+    fn measure(&mut self) -> i16;
+}
+
+impl<F: FnMut() -> i16> PowerDetector for F {
+    fn measure(&mut self) -> i16 {
+        self()
+    }
+}
+
+/// The result of [S2lp::calibrate_tx_power]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct PowerCalibration {
+    /// The PA level that comes closest to the requested power. Restore it with [S2lp::set_pa_level].
+    pub pa_level: u8,
+    /// The last measured output power in 0.1 dBm
+    pub measured: i16,
+    /// False if the requested power couldn't be reached within the tolerance,
+    /// e.g. because it's beyond what the PA can do on this board
+    pub converged: bool,
+}
+
+impl<Format, Spi, Sdn, Gpio, Delay> S2lp<Ready<Format>, Spi, Sdn, Gpio, Delay>
+where
+    Format: PacketFormat,
+    Spi: SpiDevice,
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    /// Transmit an unmodulated carrier and adjust the PA level until the detector measures `target_dbm` within `tolerance` (in 0.1 dB).
+    ///
+    /// The detector is read `settling` after every change, which should cover the settling time of the detector and the ADC.
+    /// The carrier is on the air for the whole calibration, so do this in a shielded setup or on a free channel.
+    ///
+    /// Afterwards the radio is back in ready with the calibrated level set like [Self::set_tx_power] would.
+    /// The modulation and the TX source are restored.
+    pub async fn calibrate_tx_power<D: PowerDetector>(
+        &mut self,
+        target_dbm: i8,
+        tolerance: u8,
+        settling: Duration,
+        detector: &mut D,
+    ) -> Result<PowerCalibration, ErrorOf<Self>> {
+        if !(-30..=14).contains(&target_dbm) {
+            return Err(Error::BadConfig {
+                reason: "Tx power out of range",
+            });
+        }
+
+        let mod_2 = self.ll().mod_2().read()?;
+        let pckt_ctrl_1 = self.ll().pckt_ctrl_1().read()?;
+
+        self.set_pa_level(tx_power_to_pa_level(target_dbm))?;
+        self.ll()
+            .mod_2()
+            .modify(|reg| reg.set_modulation_type(ModulationType::Unmodulated))?;
+        // The PN9 source keeps the transmitter on without the fifo
+        self.ll()
+            .pckt_ctrl_1()
+            .modify(|reg| reg.set_tx_source(TxSource::Pn9))?;
+
+        self.ll().tx().dispatch()?;
+        let result = self
+            .adjust_pa_level(target_dbm, tolerance, settling, detector)
+            .await;
+
+        self.ll().abort().dispatch()?;
+        self.ll().ready().dispatch()?;
+        self.ll().flush_tx_fifo().dispatch()?;
+        self.ll().mod_2().write(|reg| *reg = mod_2)?;
+        self.ll().pckt_ctrl_1().write(|reg| *reg = pckt_ctrl_1)?;
+        // Read the irq status to clear it
+        self.ll().irq_status().read()?;
+
+        let calibration = result?;
+
+        #[cfg(feature = "defmt-03")]
+        defmt::info!(
+            "{=str}: Calibrated {} dBm: {}",
+            self.label,
+            target_dbm,
+            calibration
+        );
+
+        Ok(calibration)
+    }
+
+    async fn adjust_pa_level<D: PowerDetector>(
+        &mut self,
+        target_dbm: i8,
+        tolerance: u8,
+        settling: Duration,
+        detector: &mut D,
+    ) -> Result<PowerCalibration, ErrorOf<Self>> {
+        let target = target_dbm as i32 * 10;
+        let mut pa_level = tx_power_to_pa_level(target_dbm);
+        let mut calibration = PowerCalibration {
+            pa_level,
+            measured: 0,
+            converged: false,
+        };
+
+        for _ in 0..MAX_ITERATIONS {
+            self.delay.delay_us(settling.as_micros()).await;
+            let measured = detector.measure();
+            calibration.measured = measured;
+            calibration.pa_level = pa_level;
+
+            let error = measured as i32 - target;
+            if error.unsigned_abs() <= tolerance as u32 {
+                calibration.converged = true;
+                break;
+            }
+
+            // Too much power means a higher level. Always move at least one level.
+            let step = match error / DECI_DB_PER_LEVEL {
+                0 => error.signum(),
+                step => step,
+            };
+            let next_level = (pa_level as i32 + step)
+                .clamp(*PA_LEVELS.start() as i32, *PA_LEVELS.end() as i32)
+                as u8;
+            if next_level == pa_level {
+                // The end of the range
+                break;
+            }
+
+            pa_level = next_level;
+            self.ll()
+                .pa_power_8()
+                .write(|reg| reg.set_value(pa_level))?;
+        }
+
+        if pa_level != calibration.pa_level {
+            // Out of iterations, so the last level that was set hasn't been measured
+            self.ll()
+                .pa_power_8()
+                .write(|reg| reg.set_value(calibration.pa_level))?;
+        }
+
+        Ok(calibration)
+    }
+
+    /// Set the output power with the raw PA level (1..=90, a higher level is a lower power),
+    /// e.g. from a [PowerCalibration]. Like [Self::set_tx_power], power ramping is turned off.
+    pub fn set_pa_level(&mut self, pa_level: u8) -> Result<(), ErrorOf<Self>> {
+        if !PA_LEVELS.contains(&pa_level) {
+            return Err(Error::BadConfig {
+                reason: "The PA level must be 1..=90",
+            });
+        }
+
+        self.ll()
+            .pa_power_8()
+            .write(|reg| reg.set_value(pa_level))?;
+        self.ll().pa_power_0().modify(|reg| {
+            reg.set_pa_level_max_idx(7);
+            reg.set_pa_ramp_en(false);
+            reg.set_pa_maxdbm(false);
+        })?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+//! The types needed to use the driver, for a single `use s2lp::prelude::*;`.
+//!
+//! This includes the generated register types that show up in the high level API, like [CrcMode] and [GpioSelectInput],
+//! so they don't have to be looked up in [ll](crate::ll).
+//! The field sets of the [Diagnostics](crate::diagnostics::Diagnostics) are included as well.
+//!
+//! The helper modules (like [mac](crate::mac) and [csma](crate::csma)) are left out.
+//! So is [ll::PacketFormat](crate::ll::PacketFormat) of the [ModemStatus], because it clashes with the [PacketFormat] trait.
+
+pub use crate::{
+    command::Command,
+    irq::{IrqEvent, IrqEvents},
+    ll::{
+        field_sets::{
+            ChFlt, IrqMask, McState0, McState1, Mod2, PcktCtrl1, PcktCtrl3, Protocol0, Protocol1,
+            Synt,
+        },
+        CcaPeriod, CrcMode, GpioSelectInput, GpioSelectOutput, LenWid, ModulationType, SetBldTh,
+        SetSmpsLvl, SleepModeSel, State,
+    },
+    packet_format::{
+        ModemStatus, PacketFilteringOptions, PacketFormat, PostambleLength, PreamblePattern,
+        SyncWord, Uninitialized,
+    },
+    rssi::Rssi,
+    states::{
+        addressable::{FifoGpioMux, GpioFunction},
+        ready::{
+            CsmaCaMode, FilterVerdicts, Fsk4SymbolMapping, LockDirection, PaRamp, PowerConfig,
+            TransitionTimings, VcoCalibrationWords,
+        },
+        rx::{
+            DiscardPolicy, RssiCapture, RxMode, RxResult, RxTimeout, RxTimeoutBuilder,
+            RxTimeoutMask,
+        },
+        shutdown::{CompiledConfig, Config, DataRate, InitWarning, RcoCalibrationWait},
+        tx::{TxReport, TxResult},
+        Ready, Rx, Shutdown, Standby, TestTx, Tx,
+    },
+    time::Duration,
+    Error, GpioNumber, IrqTrigger, S2lp,
+};
+
+#[cfg(feature = "basic")]
+pub use crate::packet_format::{Basic, BasicConfig, BasicRxMetaData, BasicTxMetaData};
+#[cfg(feature = "fixed-length")]
+pub use crate::packet_format::{
+    FixedLength, FixedLengthConfig, FixedLengthRxMetaData, FixedLengthTxMetaData,
+};
+#[cfg(feature = "raw-fifo")]
+pub use crate::packet_format::{RawFifo, RawFifoConfig, RawFifoRxMetaData, RawFifoTxMetaData};
+#[cfg(feature = "stack")]
+pub use crate::packet_format::{Stack, StackConfig, StackRxMetaData, StackTxMetaData};
+#[cfg(feature = "uart-ota")]
+pub use crate::packet_format::{UartOta, UartOtaConfig, UartOtaRxMetaData, UartOtaTxMetaData};
+#[cfg(feature = "wmbus")]
+pub use crate::packet_format::{WMBus, WMBusConfig, WMBusMode, WMBusRxMetaData, WMBusTxMetaData};
@@ -0,0 +1,218 @@
+//! Fixed capacity packet queues for buffering bursty traffic without allocation
+
+use embedded_hal::{
+    digital::{InputPin, OutputPin},
+    spi::SpiDevice,
+};
+use embedded_hal_async::{delay::DelayNs, digital::Wait};
+use heapless::{Deque, Vec};
+
+use crate::{
+    packet_format::PacketFormat,
+    rssi::Rssi,
+    states::{
+        rx::{RxMode, RxResult},
+        tx::TxResult,
+        Ready,
+    },
+    ErrorOf, S2lp,
+};
+
+/// A queue of packets to be sent
+pub type TxQueue<Format, const N: usize, const MTU: usize> =
+    PacketQueue<<Format as PacketFormat>::TxMetaData, N, MTU>;
+/// A queue of packets that have been received
+pub type RxQueue<Format, const N: usize, const MTU: usize> =
+    PacketQueue<ReceivedMetaData<<Format as PacketFormat>::RxMetaData>, N, MTU>;
+
+/// A fixed capacity queue of up to `N` packets with a payload of up to `MTU` bytes
+#[derive(Debug, Clone)]
+pub struct PacketQueue<MetaData, const N: usize, const MTU: usize> {
+    packets: Deque<QueuedPacket<MetaData, MTU>, N>,
+}
+
+impl<MetaData, const N: usize, const MTU: usize> PacketQueue<MetaData, N, MTU> {
+    /// Create a new, empty queue
+    pub const fn new() -> Self {
+        Self {
+            packets: Deque::new(),
+        }
+    }
+
+    /// Add a packet to the back of the queue. The payload is copied into the queue.
+    pub fn push(&mut self, meta_data: MetaData, payload: &[u8]) -> Result<(), QueueError> {
+        if self.packets.is_full() {
+            return Err(QueueError::Full);
+        }
+
+        let payload = Vec::from_slice(payload).map_err(|_| QueueError::PayloadTooLarge)?;
+
+        self.packets
+            .push_back(QueuedPacket { meta_data, payload })
+            .map_err(|_| QueueError::Full)
+    }
+
+    /// Take the packet from the front of the queue
+    pub fn pop(&mut self) -> Option<QueuedPacket<MetaData, MTU>> {
+        self.packets.pop_front()
+    }
+
+    /// Look at the packet at the front of the queue
+    pub fn peek(&self) -> Option<&QueuedPacket<MetaData, MTU>> {
+        self.packets.front()
+    }
+
+    /// The amount of packets in the queue
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    /// Returns true if there are no packets in the queue
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// Returns true if no more packets fit in the queue
+    pub fn is_full(&self) -> bool {
+        self.packets.is_full()
+    }
+
+    /// Remove all packets from the queue
+    pub fn clear(&mut self) {
+        self.packets.clear();
+    }
+}
+
+impl<MetaData, const N: usize, const MTU: usize> Default for PacketQueue<MetaData, N, MTU> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A packet in a [PacketQueue]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct QueuedPacket<MetaData, const MTU: usize> {
+    /// The format-specific metadata of the packet
+    pub meta_data: MetaData,
+    /// The payload of the packet
+    pub payload: Vec<u8, MTU>,
+}
+
+/// The metadata of a packet in an [RxQueue]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct ReceivedMetaData<MetaData> {
+    /// The RSSI value of the packet
+    pub rssi_value: Rssi,
+    /// Format-specific metadata like addresses
+    pub meta_data: MetaData,
+}
+
+/// Errors when pushing to a [PacketQueue]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum QueueError {
+    /// The queue has no more room
+    Full,
+    /// The payload is larger than the `MTU` of the queue
+    PayloadTooLarge,
+}
+
+impl<Format, Spi, Sdn, Gpio, Delay> S2lp<Ready<Format>, Spi, Sdn, Gpio, Delay>
+where
+    Format: PacketFormat,
+    Spi: SpiDevice,
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    /// Send the packet at the front of the queue and wait for the transmission to be done.
+    ///
+    /// The packet is only taken off the queue when the transmission is done, so it stays in the queue when an error is returned.
+    /// Returns None if the queue was empty.
+    pub async fn send_from_queue<const N: usize, const MTU: usize>(
+        self,
+        queue: &mut TxQueue<Format, N, MTU>,
+    ) -> Result<(Self, Option<TxResult>), ErrorOf<Self>> {
+        let Some(packet) = queue.peek() else {
+            return Ok((self, None));
+        };
+
+        let mut tx = self.send_packet(&packet.meta_data, &packet.payload)?;
+        let tx_result = tx.wait().await?;
+        let Ok(ready) = tx.finish() else {
+            unreachable!()
+        };
+        queue.pop();
+
+        Ok((ready, Some(tx_result)))
+    }
+
+    /// Receive a packet and put it at the back of the queue.
+    ///
+    /// Packets larger than `MTU` result in [RxResult::TooBigForBuffer].
+    /// If the queue is full, nothing is received and [QueueError::Full] is returned.
+    pub async fn receive_into_queue<const N: usize, const MTU: usize>(
+        self,
+        queue: &mut RxQueue<Format, N, MTU>,
+        mode: RxMode,
+    ) -> Result<(Self, Result<RxResult<Format::RxMetaData>, QueueError>), ErrorOf<Self>> {
+        if queue.is_full() {
+            return Ok((self, Err(QueueError::Full)));
+        }
+
+        let mut buffer = [0; MTU];
+
+        let mut rx = self.start_receive(&mut buffer, mode)?;
+        let rx_result = rx.wait().await?;
+        let Ok(ready) = rx.finish() else {
+            unreachable!()
+        };
+
+        if let RxResult::Ok {
+            packet_size,
+            rssi_value,
+            ref meta_data,
+        } = rx_result
+        {
+            let pushed = queue.push(
+                ReceivedMetaData {
+                    rssi_value,
+                    meta_data: meta_data.clone(),
+                },
+                &buffer[..packet_size],
+            );
+
+            if let Err(e) = pushed {
+                return Ok((ready, Err(e)));
+            }
+        }
+
+        Ok((ready, Ok(rx_result)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packet_queue() {
+        let mut queue = PacketQueue::<u8, 2, 4>::new();
+
+        assert_eq!(
+            queue.push(0, &[1, 2, 3, 4, 5]),
+            Err(QueueError::PayloadTooLarge)
+        );
+        assert_eq!(queue.push(0, &[1, 2]), Ok(()));
+        assert_eq!(queue.push(1, &[3]), Ok(()));
+        assert_eq!(queue.push(2, &[4]), Err(QueueError::Full));
+
+        let packet = queue.pop().unwrap();
+        assert_eq!(packet.meta_data, 0);
+        assert_eq!(&packet.payload[..], &[1, 2]);
+        assert_eq!(queue.peek().map(|packet| packet.meta_data), Some(1));
+        assert_eq!(queue.len(), 1);
+    }
+}
@@ -0,0 +1,178 @@
+//! Primitives for coarse RF time-of-flight experiments.
+//!
+//! The radio can't timestamp, so the timing comes from the host: route the sync word detection to a gpio with
+//! [S2lp::set_sync_strobe] and capture its edge with a timer of the MCU. The sync detection follows the recovered
+//! bit clock, so its resolution is about one bit ([S2lp::sync_strobe_resolution_ns]). At 500 kbps that's 2 µs or 600 m,
+//! so only averaging over many exchanges gives anything useful.
+//!
+//! In a two-way exchange the clock offset of the radios cancels out: the initiator measures the round trip,
+//! the responder measures how long it took to reply, and [time_of_flight_ns] takes the difference.
+//!
+//! For a finer estimate, [S2lp::send_pn9_sequence] sends a long known [Pn9] sequence in a 2-FSK packet.
+//! Capturing it with [S2lp::capture_raw] at a multiple of the bit rate and correlating the capture with the
+//! sequence gives timing below one bit.
+
+use embedded_hal::{
+    digital::{InputPin, OutputPin},
+    spi::SpiDevice,
+};
+use embedded_hal_async::{delay::DelayNs, digital::Wait};
+
+use crate::{
+    ll::{GpioSelectOutput, ModulationType},
+    packet_format::PacketFormat,
+    states::{addressable::GpioFunction, shutdown::compute_datarate, tx::TxResult, Ready},
+    Error, ErrorOf, GpioNumber, S2lp,
+};
+
+/// The speed of light in mm per µs
+const SPEED_OF_LIGHT_MM_PER_US: i64 = 299_792;
+
+/// The PN9 sequence (x^9 + x^5 + 1, seeded with all ones). It repeats every 511 bits.
+#[derive(Debug, Clone)]
+pub struct Pn9 {
+    state: u16,
+}
+
+impl Pn9 {
+    /// Start at the beginning of the sequence
+    pub const fn new() -> Self {
+        Self { state: 0x1FF }
+    }
+
+    /// Get the next bit of the sequence
+    pub fn next_bit(&mut self) -> bool {
+        let bit = self.state & 1;
+        let feedback = (self.state ^ (self.state >> 5)) & 1;
+        self.state = (self.state >> 1) | (feedback << 8);
+        bit == 1
+    }
+
+    /// Fill the buffer with the next bits of the sequence, MSB first like the fifo sends them
+    pub fn fill(&mut self, buffer: &mut [u8]) {
+        for byte in buffer {
+            *byte = (0..8).fold(0, |byte, _| (byte << 1) | self.next_bit() as u8);
+        }
+    }
+}
+
+impl Default for Pn9 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The one-way time of flight in ns of a two-way exchange, measured by the clocks of the host.
+///
+/// `round_trip_ns` is the time between the sync strobes of the request and the reply at the initiator,
+/// `reply_delay_ns` the time between the two sync strobes at the responder.
+/// Noise can make the result negative.
+pub const fn time_of_flight_ns(round_trip_ns: u64, reply_delay_ns: u64) -> i64 {
+    (round_trip_ns as i64 - reply_delay_ns as i64) / 2
+}
+
+/// Convert a time of flight in ns to a distance in mm
+pub const fn distance_mm(time_of_flight_ns: i64) -> i64 {
+    time_of_flight_ns * SPEED_OF_LIGHT_MM_PER_US / 1_000
+}
+
+impl<Format, Spi, Sdn, Gpio, Delay> S2lp<Ready<Format>, Spi, Sdn, Gpio, Delay>
+where
+    Format: PacketFormat,
+    Spi: SpiDevice,
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    /// Output the sync word detection on the gpio, so a timer of the host can capture when a packet came in.
+    /// The pin can't be the irq pin of the driver.
+    pub fn set_sync_strobe(&mut self, gpio: GpioNumber) -> Result<(), ErrorOf<Self>> {
+        if gpio == self.gpio_number {
+            return Err(Error::BadConfig {
+                reason: "The irq pin can't be used for the sync strobe",
+            });
+        }
+
+        self.set_gpio_function(
+            gpio,
+            GpioFunction::Output {
+                high_power: true,
+                select: GpioSelectOutput::SyncWordDetected,
+            },
+        )
+    }
+
+    /// The resolution of the sync strobe in ns, which is one bit at the configured datarate
+    pub fn sync_strobe_resolution_ns(&mut self) -> Result<u32, ErrorOf<Self>> {
+        let mantissa = self.ll().mod_4().read()?.value();
+        let exponent = self.ll().mod_2().read()?.datarate_e();
+        let datarate = compute_datarate(self.state.digital_frequency(), mantissa, exponent);
+
+        Ok(1_000_000_000u32.div_ceil(datarate.max(1)))
+    }
+
+    /// Send a packet with the [Pn9] sequence as payload and wait for it to be sent.
+    ///
+    /// The scratch buffer is filled with the sequence and its length is the length of the payload.
+    /// The radio must be configured for 2-FSK, so every bit is one symbol.
+    /// The whitening is turned off for this packet so the sequence goes on air as is.
+    pub async fn send_pn9_sequence(
+        mut self,
+        tx_meta_data: &Format::TxMetaData,
+        scratch: &mut [u8],
+    ) -> Result<(Self, TxResult), ErrorOf<Self>> {
+        if self.ll().mod_2().read()?.modulation_type() != ModulationType::Fsk2 {
+            return Err(Error::BadConfig {
+                reason: "The PN9 sequence must be sent with 2-FSK",
+            });
+        }
+
+        Pn9::new().fill(scratch);
+
+        let pckt_ctrl_1 = self.ll().pckt_ctrl_1().read()?;
+        self.ll()
+            .pckt_ctrl_1()
+            .modify(|reg| reg.set_whit_en(false))?;
+
+        let mut tx = self.send_packet(tx_meta_data, scratch)?;
+        let result = tx.wait().await?;
+        let Ok(mut this) = tx.finish() else {
+            unreachable!()
+        };
+
+        this.ll().pckt_ctrl_1().write(|reg| *reg = pckt_ctrl_1)?;
+
+        Ok((this, result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pn9_is_a_maximum_length_sequence() {
+        let mut pn9 = Pn9::new();
+        let period: Vec<bool> = (0..511).map(|_| pn9.next_bit()).collect();
+
+        // 256 ones and 255 zeros in every period
+        assert_eq!(period.iter().filter(|bit| **bit).count(), 256);
+        // And then it repeats
+        assert!(period.iter().all(|bit| *bit == pn9.next_bit()));
+
+        let mut bytes = [0; 2];
+        Pn9::new().fill(&mut bytes);
+        // The seed comes out first
+        assert_eq!(bytes[0], 0xFF);
+    }
+
+    #[test]
+    fn two_way_ranging() {
+        // 100 ns of flight each way with a reply delay of 1 ms
+        let tof = time_of_flight_ns(1_000_200, 1_000_000);
+        assert_eq!(tof, 100);
+        assert_eq!(distance_mm(tof), 29_979);
+
+        assert_eq!(time_of_flight_ns(999_900, 1_000_000), -50);
+    }
+}
@@ -0,0 +1,348 @@
+//! Raw bit-level transmission and reception that bypasses the packet handler.
+//!
+//! This is meant for legacy protocols the packet handler can't produce, like the pulse trains
+//! of 433 MHz socket remotes, and for analyzing unknown protocols.
+
+use embedded_hal::{
+    digital::{InputPin, OutputPin},
+    spi::SpiDevice,
+};
+use embedded_hal_async::{delay::DelayNs, digital::Wait};
+
+use crate::{
+    ll::{GpioSelectOutput, ModulationType, RxMode, TxSource},
+    packet_format::PacketFormat,
+    states::{
+        addressable::GpioFunction,
+        shutdown::{find_datarate_mantissa_exponent, MAXIMUM_DATARATE, MINIMUM_DATARATE},
+        Ready,
+    },
+    time::Duration,
+    Error, ErrorOf, GpioNumber, S2lp,
+};
+
+/// A period of time in which the carrier is either on or off
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct OokPulse {
+    /// If true, the carrier is on
+    pub on: bool,
+    /// The length of the pulse
+    pub duration: Duration,
+}
+
+impl OokPulse {
+    /// A pulse where the carrier is on
+    pub const fn on(duration: Duration) -> Self {
+        Self { on: true, duration }
+    }
+
+    /// A pulse where the carrier is off
+    pub const fn off(duration: Duration) -> Self {
+        Self {
+            on: false,
+            duration,
+        }
+    }
+}
+
+impl<Format, Spi, Sdn, Gpio, Delay> S2lp<Ready<Format>, Spi, Sdn, Gpio, Delay>
+where
+    Format: PacketFormat,
+    Spi: SpiDevice,
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    /// Transmit a train of OOK pulses and wait for it to be sent.
+    ///
+    /// The pulses are converted to a bitstream at the given chip rate (in bps) in the `scratch` buffer,
+    /// which is then sent through the fifo with the packet handler bypassed.
+    /// So there's no preamble, sync or CRC. Pulse lengths are rounded to the nearest chip,
+    /// so pick a chip rate that divides the pulse lengths of the protocol well.
+    ///
+    /// The modulation and datarate are temporarily changed and restored afterwards.
+    pub async fn send_ook_pulses(
+        mut self,
+        pulses: &[OokPulse],
+        chip_rate: u32,
+        scratch: &mut [u8],
+    ) -> Result<Self, ErrorOf<Self>> {
+        if !(MINIMUM_DATARATE..=MAXIMUM_DATARATE as u32).contains(&chip_rate) {
+            return Err(Error::BadConfig {
+                reason: "Chip rate out of range",
+            });
+        }
+
+        let len = encode_ook_pulses(pulses, chip_rate, scratch).ok_or(Error::BufferTooSmall)?;
+
+        // Remember the settings we're going to change
+        let mod_4 = self.ll().mod_4().read()?;
+        let mod_2 = self.ll().mod_2().read()?;
+        let pa_power_0 = self.ll().pa_power_0().read()?;
+        let pa_config_1 = self.ll().pa_config_1().read()?;
+
+        let (mantissa, exponent) =
+            find_datarate_mantissa_exponent(chip_rate, self.state.digital_frequency());
+        self.ll().mod_4().write(|reg| reg.set_value(mantissa))?;
+        self.ll().mod_2().modify(|reg| {
+            reg.set_datarate_e(exponent);
+            reg.set_modulation_type(ModulationType::AskOok);
+        })?;
+        self.ll()
+            .pa_power_0()
+            .modify(|reg| reg.set_dig_smooth_en(true))?;
+        self.ll().pa_config_1().modify(|reg| reg.set_fir_en(true))?;
+        self.ll()
+            .pckt_ctrl_1()
+            .modify(|reg| reg.set_tx_source(TxSource::DirectThroughFifo))?;
+
+        let mut tx = self.send_raw(&scratch[..len])?;
+        let tx_result = tx.wait().await;
+        let mut ready = match tx.finish() {
+            Ok(ready) => ready,
+            Err(tx) => tx.abort()?,
+        };
+
+        ready
+            .ll()
+            .pckt_ctrl_1()
+            .modify(|reg| reg.set_tx_source(TxSource::Normal))?;
+        ready.ll().mod_4().write(|reg| *reg = mod_4)?;
+        ready.ll().mod_2().write(|reg| *reg = mod_2)?;
+        ready.ll().pa_power_0().write(|reg| *reg = pa_power_0)?;
+        ready.ll().pa_config_1().write(|reg| *reg = pa_config_1)?;
+
+        tx_result?;
+
+        Ok(ready)
+    }
+}
+
+impl<Format, Spi, Sdn, Gpio, Delay> S2lp<Ready<Format>, Spi, Sdn, Gpio, Delay>
+where
+    Format: PacketFormat,
+    Spi: SpiDevice,
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    /// Capture the raw demodulated bitstream into the buffer, bypassing the packet handler.
+    ///
+    /// The radio samples at the given chip rate (in bps), so every bit in the buffer (MSB first)
+    /// represents `1 / chip_rate` seconds. The configured modulation is used.
+    /// There's no sync detection, so the capture starts immediately and includes noise.
+    ///
+    /// The capture stops when the buffer is full. If the fifo overflows because the spi can't keep up,
+    /// the capture stops early. The amount of captured bytes is returned.
+    ///
+    /// The datarate is temporarily changed and restored afterwards.
+    pub async fn capture_raw(
+        mut self,
+        buffer: &mut [u8],
+        chip_rate: u32,
+    ) -> Result<(Self, usize), ErrorOf<Self>> {
+        if !(MINIMUM_DATARATE..=MAXIMUM_DATARATE as u32).contains(&chip_rate) {
+            return Err(Error::BadConfig {
+                reason: "Chip rate out of range",
+            });
+        }
+
+        // Remember the settings we're going to change
+        let mod_4 = self.ll().mod_4().read()?;
+        let mod_2 = self.ll().mod_2().read()?;
+
+        let (mantissa, exponent) =
+            find_datarate_mantissa_exponent(chip_rate, self.state.digital_frequency());
+        self.ll().mod_4().write(|reg| reg.set_value(mantissa))?;
+        self.ll()
+            .mod_2()
+            .modify(|reg| reg.set_datarate_e(exponent))?;
+        self.ll()
+            .pckt_ctrl_3()
+            .modify(|reg| reg.set_rx_mode(RxMode::DirectThroughFifo))?;
+
+        self.ll().flush_rx_fifo().dispatch()?;
+        let irq_mask = self.ll().irq_mask().read()?;
+        self.ll().irq_mask().write(|reg| {
+            reg.set_rx_fifo_almost_full(true);
+            reg.set_rx_fifo_error(true);
+        })?;
+        // Read the irq status to clear it
+        self.ll().irq_status().read()?;
+
+        #[cfg(feature = "defmt-03")]
+        defmt::debug!(
+            "{=str}: Starting raw capture of {} bytes",
+            self.label,
+            buffer.len()
+        );
+
+        self.ll().rx().dispatch()?;
+
+        let mut written = 0;
+        while written < buffer.len() {
+            self.wait_for_irq_pin().await.map_err(Error::Gpio)?;
+
+            let irq_status = self.ll().irq_status().read()?;
+            self.record_irq();
+
+            if irq_status.rx_fifo_error() {
+                #[cfg(feature = "defmt-03")]
+                defmt::warn!(
+                    "{=str}: Raw capture fifo overflow after {} bytes",
+                    self.label,
+                    written
+                );
+                break;
+            }
+
+            if irq_status.rx_fifo_almost_full() {
+                written += self.ll().fifo().read(&mut buffer[written..])?;
+            }
+        }
+
+        self.ll().abort().dispatch()?;
+        self.ll().flush_rx_fifo().dispatch()?;
+
+        self.ll()
+            .pckt_ctrl_3()
+            .modify(|reg| reg.set_rx_mode(RxMode::Normal))?;
+        self.ll().mod_4().write(|reg| *reg = mod_4)?;
+        self.ll().mod_2().write(|reg| *reg = mod_2)?;
+        self.ll().irq_mask().write(|reg| *reg = irq_mask)?;
+
+        Ok((self, written))
+    }
+}
+
+/// The gpio pins used to transmit a bitstream that's generated by an external device. See [S2lp::start_gpio_tx].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct GpioTxPins {
+    /// The radio outputs the data clock on this pin at the configured datarate
+    pub clock: GpioNumber,
+    /// The radio reads the data to send from this pin, one bit per clock period
+    pub data: GpioNumber,
+}
+
+impl<Format, Spi, Sdn, Gpio, Delay> S2lp<Ready<Format>, Spi, Sdn, Gpio, Delay>
+where
+    Format: PacketFormat,
+    Spi: SpiDevice,
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    /// Start transmitting the bitstream an external device puts on the data pin, bypassing the packet handler and the fifo.
+    ///
+    /// The radio drives the clock pin at the configured datarate and the external device must
+    /// put the bits on the data pin in sync with it. See the timing of the direct modes in the datasheet.
+    /// The configured modulation is used. There's no preamble, sync or CRC unless the external device sends them.
+    ///
+    /// The radio keeps transmitting until [Self::stop_gpio_tx] is called. The pins can't be the irq pin of the driver.
+    pub fn start_gpio_tx(&mut self, pins: GpioTxPins) -> Result<(), ErrorOf<Self>> {
+        if pins.clock == pins.data {
+            return Err(Error::BadConfig {
+                reason: "The clock and data pins must be different",
+            });
+        }
+        if pins.clock == self.gpio_number || pins.data == self.gpio_number {
+            return Err(Error::BadConfig {
+                reason: "The irq pin can't be used for the bitstream",
+            });
+        }
+
+        self.set_gpio_function(
+            pins.clock,
+            GpioFunction::Output {
+                high_power: false,
+                select: GpioSelectOutput::TxDataInternalClockOutput,
+            },
+        )?;
+
+        #[cfg(feature = "defmt-03")]
+        defmt::debug!("{=str}: Starting gpio tx with {}", self.label, pins);
+
+        self.start_direct_gpio_tx(pins.data)
+    }
+
+    /// Stop the transmission started with [Self::start_gpio_tx].
+    /// The pins are set to high impedance and the packet handler is used again for sending.
+    pub fn stop_gpio_tx(&mut self, pins: GpioTxPins) -> Result<(), ErrorOf<Self>> {
+        self.stop_direct_gpio_tx(pins.data)?;
+        self.set_gpio_function(pins.clock, GpioFunction::HiZ)
+    }
+}
+
+/// Convert the pulses to a bitstream (MSB first) where every bit is one chip.
+/// The last byte is padded with the carrier off.
+///
+/// Returns the amount of bytes used or None if the buffer is too small.
+fn encode_ook_pulses(pulses: &[OokPulse], chip_rate: u32, buffer: &mut [u8]) -> Option<usize> {
+    // Keep track of the total time so rounding errors don't add up
+    let mut time_us = 0u64;
+    let mut chip = 0usize;
+
+    for pulse in pulses {
+        time_us += pulse.duration.as_micros() as u64;
+        let end_chip = ((time_us * chip_rate as u64 + 500_000) / 1_000_000) as usize;
+
+        if end_chip.div_ceil(8) > buffer.len() {
+            return None;
+        }
+
+        for i in chip..end_chip {
+            let mask = 0x80 >> (i % 8);
+            if pulse.on {
+                buffer[i / 8] |= mask;
+            } else {
+                buffer[i / 8] &= !mask;
+            }
+        }
+
+        chip = end_chip.max(chip);
+    }
+
+    let len = chip.div_ceil(8);
+    if !chip.is_multiple_of(8) {
+        // Clear the padding bits
+        buffer[len - 1] &= 0xFF << (8 - chip % 8);
+    }
+
+    Some(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ook_encoding() {
+        let mut buffer = [0xAA; 4];
+
+        // At 10 kbps, every chip is 100us
+        let len = encode_ook_pulses(
+            &[
+                OokPulse::on(Duration::from_micros(300)),
+                OokPulse::off(Duration::from_micros(100)),
+                OokPulse::on(Duration::from_micros(140)),
+                OokPulse::off(Duration::from_micros(960)),
+            ],
+            10_000,
+            &mut buffer,
+        );
+
+        assert_eq!(len, Some(2));
+        assert_eq!(buffer[..2], [0b1110_1000, 0b0000_0000]);
+
+        assert_eq!(
+            encode_ook_pulses(
+                &[OokPulse::on(Duration::from_micros(3300))],
+                10_000,
+                &mut buffer
+            ),
+            None
+        );
+    }
+}
@@ -0,0 +1,74 @@
+//! Conversion between the raw RSSI register values and dBm
+
+use core::fmt::Debug;
+
+/// An RSSI level or threshold as used by the radio.
+///
+/// The radio represents these in 1 dB steps with an offset of 146, so a register value of 0 is -146 dBm.
+/// This is used for both the measured RSSI and the RSSI (carrier sense) threshold.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Rssi(u8);
+
+impl Rssi {
+    /// The offset between the register value and dBm
+    const OFFSET: i16 = 146;
+
+    /// The lowest level that can be represented (-146 dBm)
+    pub const MIN: Self = Self(u8::MIN);
+    /// The highest level that can be represented (109 dBm)
+    pub const MAX: Self = Self(u8::MAX);
+
+    /// Create the level from dBm. Values outside of the representable range are clamped.
+    pub const fn from_dbm(dbm: i16) -> Self {
+        let value = dbm.saturating_add(Self::OFFSET);
+
+        if value < 0 {
+            Self::MIN
+        } else if value > u8::MAX as i16 {
+            Self::MAX
+        } else {
+            Self(value as u8)
+        }
+    }
+
+    /// Create the level from the raw register value
+    pub const fn from_register(value: u8) -> Self {
+        Self(value)
+    }
+
+    /// The level in dBm
+    pub const fn dbm(self) -> i16 {
+        self.0 as i16 - Self::OFFSET
+    }
+
+    /// The raw register value
+    pub const fn register(self) -> u8 {
+        self.0
+    }
+}
+
+impl Debug for Rssi {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} dBm", self.dbm())
+    }
+}
+
+#[cfg(feature = "defmt-03")]
+impl defmt::Format for Rssi {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "{=i16} dBm", self.dbm())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dbm_conversion() {
+        assert_eq!(Rssi::from_dbm(-85).register(), 61);
+        assert_eq!(Rssi::from_register(61).dbm(), -85);
+        assert_eq!(Rssi::from_dbm(-200), Rssi::MIN);
+        assert_eq!(Rssi::from_dbm(200), Rssi::MAX);
+    }
+}
@@ -0,0 +1,321 @@
+//! A reliable byte stream over the radio, for 'wireless uart' use cases.
+//!
+//! The [SerialLink] implements the [embedded_io_async] traits on top of the [Basic] packet format.
+//! Written data is split into frames that are sent one at a time and retransmitted until the other side acknowledges them
+//! (stop-and-wait). Received frames are only acknowledged when they fit in the receive buffer,
+//! so a sender can never overrun a slow reader.
+//!
+//! Both sides need a [SerialLink] with the same radio config and [SerialConfig].
+//! The radio only listens while [Read::read] or [Write::write] is running, so the reading side should keep a read pending.
+//! Use a CRC in the [Basic] config so corrupted frames are dropped instead of being delivered.
+//!
+//! Only available with the `basic` feature.
+
+use embedded_hal::{
+    digital::{InputPin, OutputPin},
+    spi::SpiDevice,
+};
+use embedded_hal_async::{delay::DelayNs, digital::Wait};
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
+use heapless::Deque;
+
+use crate::{
+    ll::FIFO_SIZE,
+    packet_format::{Basic, BasicTxMetaData},
+    states::{
+        rx::{RxMode, RxResult, RxTimeout, RxTimeoutMask},
+        Ready,
+    },
+    time::Duration,
+    Error, S2lp,
+};
+
+/// The biggest frame that's sent. It fits the fifo, so the fifo never has to be refilled.
+const MAX_FRAME_SIZE: usize = FIFO_SIZE;
+/// The amount of data carried by one frame
+pub const MAX_FRAME_PAYLOAD: usize = MAX_FRAME_SIZE - 1;
+
+/// Set in the header of ack frames
+const HEADER_ACK: u8 = 0x80;
+/// The sequence bit in the header
+const HEADER_SEQUENCE: u8 = 0x01;
+
+/// The configuration of a [SerialLink]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct SerialConfig {
+    /// How long to wait for the acknowledgement of a frame before sending it again
+    pub ack_timeout: Duration,
+    /// How many times a frame is sent again before the write fails
+    pub max_retries: u8,
+    /// The time between receiving a frame and sending the ack. This gives the sender time to switch to RX.
+    pub ack_turnaround: Duration,
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        Self {
+            ack_timeout: Duration::from_millis(50),
+            max_retries: 5,
+            ack_turnaround: Duration::from_micros(500),
+        }
+    }
+}
+
+/// The error of a [SerialLink]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum SerialError<E> {
+    /// The radio driver returned an error
+    Radio(E),
+    /// A frame wasn't acknowledged after all retries. It's unknown whether the other side received it.
+    NoAck,
+    /// An earlier radio error left the radio in an unknown state. Create a new link with a freshly initialized radio.
+    RadioLost,
+}
+
+impl<E> From<E> for SerialError<E> {
+    fn from(value: E) -> Self {
+        Self::Radio(value)
+    }
+}
+
+impl<E: core::fmt::Debug> embedded_io_async::Error for SerialError<E> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            SerialError::Radio(_) | SerialError::RadioLost => ErrorKind::Other,
+            SerialError::NoAck => ErrorKind::TimedOut,
+        }
+    }
+}
+
+/// A reliable byte stream over the radio. See the [module docs](self).
+///
+/// Up to `N` received bytes are buffered until they're read.
+pub struct SerialLink<Spi, Sdn, Gpio, Delay, const N: usize>
+where
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    radio: Option<S2lp<Ready<Basic>, Spi, Sdn, Gpio, Delay>>,
+    config: SerialConfig,
+    /// The sequence bit of the next frame to send
+    tx_sequence: bool,
+    /// The sequence bit of the next new frame to receive
+    rx_sequence: bool,
+    rx_buffer: Deque<u8, N>,
+    /// One byte bigger than the biggest frame, so a full frame doesn't count as too big for the buffer
+    frame: [u8; MAX_FRAME_SIZE + 1],
+}
+
+impl<Spi, Sdn, Gpio, Delay, const N: usize> SerialLink<Spi, Sdn, Gpio, Delay, N>
+where
+    Spi: SpiDevice,
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    /// Create a link over a radio that has been configured with the [Basic] format
+    pub fn new(radio: S2lp<Ready<Basic>, Spi, Sdn, Gpio, Delay>, config: SerialConfig) -> Self {
+        Self {
+            radio: Some(radio),
+            config,
+            tx_sequence: false,
+            rx_sequence: false,
+            rx_buffer: Deque::new(),
+            frame: [0; MAX_FRAME_SIZE + 1],
+        }
+    }
+
+    /// Get the radio back. Returns None if it was lost to an error.
+    ///
+    /// Buffered received data that hasn't been read yet is dropped.
+    pub fn release(self) -> Option<S2lp<Ready<Basic>, Spi, Sdn, Gpio, Delay>> {
+        self.radio
+    }
+
+    /// The amount of received bytes that are buffered and can be read without waiting
+    pub fn available(&self) -> usize {
+        self.rx_buffer.len()
+    }
+
+    fn take_radio(
+        &mut self,
+    ) -> Result<S2lp<Ready<Basic>, Spi, Sdn, Gpio, Delay>, LinkError<Spi, Sdn, Gpio>> {
+        self.radio.take().ok_or(SerialError::RadioLost)
+    }
+
+    /// Send the frame that's in the frame buffer
+    async fn send_frame(&mut self, len: usize) -> Result<(), LinkError<Spi, Sdn, Gpio>> {
+        let radio = self.take_radio()?;
+        let mut tx = radio.send_packet(
+            &BasicTxMetaData {
+                destination_address: None,
+            },
+            &self.frame[..len],
+        )?;
+        tx.wait().await?;
+        let Ok(radio) = tx.finish() else {
+            unreachable!()
+        };
+        self.radio = Some(radio);
+
+        Ok(())
+    }
+
+    /// Receive a frame into the frame buffer. Returns the size of the frame or None if nothing usable was received.
+    async fn receive_frame(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<usize>, LinkError<Spi, Sdn, Gpio>> {
+        let radio = self.take_radio()?;
+        let mut rx = radio.start_receive(
+            &mut self.frame,
+            RxMode::Normal {
+                timeout: timeout.map(|timeout| RxTimeout {
+                    timeout,
+                    mask: RxTimeoutMask::Sqi,
+                }),
+            },
+        )?;
+        let rx_result = rx.wait().await?;
+        let Ok(radio) = rx.finish() else {
+            unreachable!()
+        };
+        self.radio = Some(radio);
+
+        match rx_result {
+            RxResult::Ok { packet_size, .. } if packet_size > 0 => Ok(Some(packet_size)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Handle a received data frame of the given size. Returns true if it contained new data.
+    async fn handle_data_frame(&mut self, len: usize) -> Result<bool, LinkError<Spi, Sdn, Gpio>> {
+        let sequence = self.frame[0] & HEADER_SEQUENCE != 0;
+
+        let new_data = if sequence != self.rx_sequence {
+            // A retransmission because our ack got lost. It needs to be acked again.
+            false
+        } else if self.rx_buffer.capacity() - self.rx_buffer.len() >= len - 1 {
+            for byte in &self.frame[1..len] {
+                // The space has been checked
+                let _ = self.rx_buffer.push_back(*byte);
+            }
+            self.rx_sequence = !self.rx_sequence;
+            true
+        } else {
+            // No room, don't ack so the sender tries again later
+            return Ok(false);
+        };
+
+        self.frame[0] = HEADER_ACK | sequence as u8;
+
+        let turnaround = self.config.ack_turnaround.as_micros();
+        if let Some(radio) = self.radio.as_mut() {
+            radio.delay.delay_us(turnaround).await;
+        }
+        self.send_frame(1).await?;
+
+        Ok(new_data)
+    }
+}
+
+type LinkError<Spi, Sdn, Gpio> = SerialError<
+    Error<
+        <Spi as embedded_hal::spi::ErrorType>::Error,
+        <Sdn as embedded_hal::digital::ErrorType>::Error,
+        <Gpio as embedded_hal::digital::ErrorType>::Error,
+    >,
+>;
+
+impl<Spi, Sdn, Gpio, Delay, const N: usize> ErrorType for SerialLink<Spi, Sdn, Gpio, Delay, N>
+where
+    Spi: SpiDevice,
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    type Error = LinkError<Spi, Sdn, Gpio>;
+}
+
+impl<Spi, Sdn, Gpio, Delay, const N: usize> Read for SerialLink<Spi, Sdn, Gpio, Delay, N>
+where
+    Spi: SpiDevice,
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    /// Read buffered data or, if there is none, receive until data comes in
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        while self.rx_buffer.is_empty() {
+            if let Some(len) = self.receive_frame(None).await? {
+                // Stray acks are ignored
+                if self.frame[0] & HEADER_ACK == 0 {
+                    self.handle_data_frame(len).await?;
+                }
+            }
+        }
+
+        let mut read = 0;
+        while read < buf.len() {
+            let Some(byte) = self.rx_buffer.pop_front() else {
+                break;
+            };
+            buf[read] = byte;
+            read += 1;
+        }
+
+        Ok(read)
+    }
+}
+
+impl<Spi, Sdn, Gpio, Delay, const N: usize> Write for SerialLink<Spi, Sdn, Gpio, Delay, N>
+where
+    Spi: SpiDevice,
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    /// Send one frame of data (at most [MAX_FRAME_PAYLOAD] bytes) and wait for it to be acknowledged.
+    ///
+    /// Data frames from the other side that come in while waiting are received as well.
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let len = buf.len().min(MAX_FRAME_PAYLOAD);
+
+        for _ in 0..=self.config.max_retries {
+            self.frame[0] = self.tx_sequence as u8;
+            self.frame[1..len + 1].copy_from_slice(&buf[..len]);
+            self.send_frame(len + 1).await?;
+
+            let Some(received) = self.receive_frame(Some(self.config.ack_timeout)).await? else {
+                continue;
+            };
+
+            let header = self.frame[0];
+            if header & HEADER_ACK == 0 {
+                // The other side is sending too. The frame buffer is reused, so ours is sent again after this.
+                self.handle_data_frame(received).await?;
+            } else if (header & HEADER_SEQUENCE != 0) == self.tx_sequence {
+                self.tx_sequence = !self.tx_sequence;
+                return Ok(len);
+            }
+        }
+
+        Err(SerialError::NoAck)
+    }
+
+    /// Every write is acknowledged before it returns, so there's nothing to flush
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
@@ -5,11 +5,11 @@ use embedded_hal::{
 use embedded_hal_async::{delay::DelayNs, digital::Wait};
 
 use crate::{
-    ll::{Device, DeviceInterface, GpioMode, GpioSelectInput, GpioSelectOutput},
-    ErrorOf, GpioNumber, S2lp,
+    ll::{Device, DeviceInterface, GpioMode, GpioSelectInput, GpioSelectOutput, FIFO_SIZE},
+    Error, ErrorOf, GpioNumber, S2lp,
 };
 
-use super::Addressable;
+use super::{shutdown::CONFIG_CANARY, Addressable};
 
 #[allow(private_bounds)]
 impl<State, Spi, Sdn, Gpio, Delay> S2lp<State, Spi, Sdn, Gpio, Delay>
@@ -28,6 +28,72 @@ where
         self.device.as_mut().unwrap()
     }
 
+    /// Check cheaply whether the radio still has the configuration of the init.
+    ///
+    /// A dip in the supply can reset the radio without the MCU noticing, after which it silently
+    /// doesn't receive anymore. Call this periodically, e.g. in battery powered nodes.
+    /// It reads a marker register the init writes, so it's a single register read.
+    /// See [Self::check_configured] for a version that returns an error.
+    pub fn is_configured(&mut self) -> Result<bool, ErrorOf<Self>> {
+        Ok(self.ll().pckt_flt_goals_4().read()?.rx_source_mask() == CONFIG_CANARY)
+    }
+
+    /// Like [Self::is_configured], but returns [Error::ConfigurationLost] when the configuration is gone.
+    /// The radio must then be initialized again, e.g. with [S2lp::shutdown] and `init`.
+    pub fn check_configured(&mut self) -> Result<(), ErrorOf<Self>> {
+        if self.is_configured()? {
+            return Ok(());
+        }
+
+        #[cfg(feature = "defmt-03")]
+        defmt::error!("{=str}: The radio lost its configuration", self.label);
+
+        Err(Error::ConfigurationLost)
+    }
+
+    /// Get the counters of the spi and interrupt work the driver has done.
+    /// Use [Self::reset_metrics] before an operation to measure that operation.
+    ///
+    /// The counters keep running when the spi is [taken out](S2lp::take_spi) of the driver and given back.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&self) -> crate::ll::Metrics {
+        self.device.as_ref().unwrap().interface.metrics
+    }
+
+    /// Set all metrics counters back to 0
+    #[cfg(feature = "metrics")]
+    pub fn reset_metrics(&mut self) {
+        self.device.as_mut().unwrap().interface.metrics = Default::default();
+    }
+
+    /// Set the size of the chunks the fifo is filled and drained in while sending and receiving, e.g. the burst size of the spi DMA.
+    ///
+    /// When the fifo has room for more than a chunk, only whole chunks are transferred so every transfer is
+    /// a multiple of the burst size. Less than a chunk is only transferred at the end of a packet or when the fifo has no room for a full chunk.
+    /// Bigger chunks mean fewer spi transactions per packet, but also less margin before the fifo runs empty or overflows.
+    /// The fifo thresholds aren't changed by this.
+    ///
+    /// The size must be 1..=128 and is 1 (no chunking) by default.
+    pub fn set_fifo_chunk_size(&mut self, size: usize) -> Result<(), ErrorOf<Self>> {
+        if !(1..=FIFO_SIZE).contains(&size) {
+            return Err(Error::BadConfig {
+                reason: "The fifo chunk size must be 1..=128",
+            });
+        }
+
+        self.ll().interface.fifo_chunk_size = size;
+        Ok(())
+    }
+
+    /// Record that the driver handled an interrupt
+    pub(crate) fn record_irq(&mut self) {
+        #[cfg(feature = "metrics")]
+        {
+            let metrics = &mut self.device.as_mut().unwrap().interface.metrics;
+            metrics.irqs_serviced = metrics.irqs_serviced.saturating_add(1);
+        }
+    }
+
     /// Set the function of a gpio pin.
     ///
     /// User care should be taken because making changes here can break the driver.
@@ -40,11 +106,23 @@ where
     /// - You only use output functionality
     ///
     /// The output can also be used as a gpio extender with the VDD and GND states.
+    ///
+    /// The TX and RX command inputs would change the state of the radio behind the back of the driver,
+    /// so those return [Error::BadConfig]. Use `arm_tx_on_pin` and `arm_rx_on_pin` of the ready state instead.
     pub fn set_gpio_function(
         &mut self,
         number: GpioNumber,
         function: GpioFunction,
     ) -> Result<(), ErrorOf<Self>> {
+        if let GpioFunction::Input {
+            select: GpioSelectInput::TxCommand | GpioSelectInput::RxCommand,
+        } = function
+        {
+            return Err(Error::BadConfig {
+                reason: "The command inputs must be armed through the ready state",
+            });
+        }
+
         self.ll()
             .gpio_conf(number as usize)
             .write(|reg| match function {
@@ -67,6 +145,39 @@ where
 
         Ok(())
     }
+
+    /// Select the fifo that drives the [FifoAlmostFull](GpioSelectOutput::FifoAlmostFull) and
+    /// [FifoAlmostEmpty](GpioSelectOutput::FifoAlmostEmpty) gpio outputs.
+    ///
+    /// The radio selects the TX fifo after reset. The sniff mode selects the RX fifo itself,
+    /// otherwise the first chunk of a packet is received without the almost full threshold being signalled.
+    pub fn set_fifo_gpio_mux(&mut self, fifo: FifoGpioMux) -> Result<(), ErrorOf<Self>> {
+        self.ll()
+            .protocol_2()
+            .modify(|reg| reg.set_fifo_gpio_out_mux_sel(fifo == FifoGpioMux::Rx))?;
+        Ok(())
+    }
+
+    /// The fifo that drives the almost full and almost empty gpio outputs. See [Self::set_fifo_gpio_mux].
+    pub fn fifo_gpio_mux(&mut self) -> Result<FifoGpioMux, ErrorOf<Self>> {
+        Ok(
+            match self.ll().protocol_2().read()?.fifo_gpio_out_mux_sel() {
+                true => FifoGpioMux::Rx,
+                false => FifoGpioMux::Tx,
+            },
+        )
+    }
+}
+
+/// The fifo that drives the almost full and almost empty gpio outputs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum FifoGpioMux {
+    /// The thresholds of the TX fifo (reset value)
+    #[default]
+    Tx,
+    /// The thresholds of the RX fifo
+    Rx,
 }
 
 /// The function of a gpio pin
@@ -2,11 +2,22 @@
 
 use core::marker::PhantomData;
 
+use device_driver::RegisterInterface;
+
+use crate::{
+    ll::{
+        field_sets::{AntSelectConf, GpioConf, IrqMask, Mod2, PcktCtrl1, PcktFltGoals0},
+        Device,
+    },
+    GpioNumber,
+};
+
 pub mod addressable;
 pub mod ready;
 pub mod rx;
 pub mod shutdown;
 pub mod standby;
+pub mod test_tx;
 pub mod tx;
 
 /// The radio is in shutdown mode. This is the lowest power state and the radio is effectively turned off.
@@ -31,6 +42,54 @@ impl<PF> Ready<PF> {
             _p: PhantomData,
         }
     }
+
+    pub(crate) fn digital_frequency(&self) -> u32 {
+        self.digital_frequency
+    }
+}
+
+/// The registers a tx or rx session changes. They are restored when the session is done,
+/// so user customizations survive normal operation.
+#[derive(Clone, Copy)]
+pub(crate) struct SessionRegisters {
+    pub(crate) irq_mask: IrqMask,
+    pub(crate) ant_select_conf: AntSelectConf,
+    /// The pin that gives the TX or RX command, with the config it had before
+    pub(crate) command_pin: Option<(GpioNumber, GpioConf)>,
+    /// The node address, if the packet is sent with another source address
+    pub(crate) node_address: Option<PcktFltGoals0>,
+}
+
+impl SessionRegisters {
+    pub(crate) fn save<I: RegisterInterface<AddressType = u8>>(
+        device: &mut Device<I>,
+    ) -> Result<Self, I::Error> {
+        Ok(Self {
+            irq_mask: device.irq_mask().read()?,
+            ant_select_conf: device.ant_select_conf().read()?,
+            command_pin: None,
+            node_address: None,
+        })
+    }
+
+    pub(crate) fn restore<I: RegisterInterface<AddressType = u8>>(
+        self,
+        device: &mut Device<I>,
+    ) -> Result<(), I::Error> {
+        device.irq_mask().write(|reg| *reg = self.irq_mask)?;
+        device
+            .ant_select_conf()
+            .write(|reg| *reg = self.ant_select_conf)?;
+        if let Some((pin, gpio_conf)) = self.command_pin {
+            device
+                .gpio_conf(pin as usize)
+                .write(|reg| *reg = gpio_conf)?;
+        }
+        if let Some(node_address) = self.node_address {
+            device.pckt_flt_goals_0().write(|reg| *reg = node_address)?;
+        }
+        Ok(())
+    }
 }
 
 /// The radio is in send mode. A packet is being sent or has just been sent
@@ -38,16 +97,41 @@ pub struct Tx<'buffer, PF> {
     /// The internal `fdig` of the radio
     digital_frequency: u32,
     tx_buffer: &'buffer [u8],
+    /// The amount of payload bytes written to the fifo so far
+    queued: usize,
+    /// Buffer for the payload of a received acknowledgement (piggybacking)
+    ack_buffer: &'buffer mut [u8],
     tx_done: bool,
+    saved_registers: SessionRegisters,
+    /// The amount of times the fifo was refilled during the transmission
+    fifo_refills: u16,
+    /// The amount of interrupts handled during the transmission
+    irqs_serviced: u16,
+    /// The amount of times the watchdog expired during the transmission
+    watchdog_expirations: u8,
+    /// The amount of retransmissions done, as read from the radio after the last one
+    retransmissions: u8,
     _p: PhantomData<PF>,
 }
 
 impl<'buffer, PF> Tx<'buffer, PF> {
-    fn new(digital_frequency: u32, tx_buffer: &'buffer [u8]) -> Self {
+    fn new(
+        digital_frequency: u32,
+        tx_buffer: &'buffer [u8],
+        queued: usize,
+        saved_registers: SessionRegisters,
+    ) -> Self {
         Self {
             digital_frequency,
             tx_buffer,
+            queued,
+            saved_registers,
+            ack_buffer: &mut [],
             tx_done: false,
+            fifo_refills: 0,
+            irqs_serviced: 0,
+            watchdog_expirations: 0,
+            retransmissions: 0,
             _p: PhantomData,
         }
     }
@@ -57,24 +141,128 @@ impl<'buffer, PF> Tx<'buffer, PF> {
 pub struct Rx<'buffer, PF> {
     /// The internal `fdig` of the radio
     digital_frequency: u32,
-    rx_buffer: &'buffer mut [u8],
+    rx_buffer: RxBuffer<'buffer>,
     written: usize,
     rx_done: bool,
+    /// Restart the receiver on bad packets
+    auto_restart: bool,
+    discard_policy: rx::DiscardPolicy,
+    discarded_count: u32,
+    /// Restart the receiver after every received packet
+    restart_after_packet: bool,
+    /// The size of the last received packet
+    packet_len: usize,
+    saved_registers: SessionRegisters,
+    /// The irq mask used while receiving
+    irq_mask: IrqMask,
+    /// The packets are detected without a sync word
+    sync_less: bool,
+    /// The radio cycles between sleep and RX by itself, see [rx::RxMode::LowDutyCycle]
+    low_duty_cycle: bool,
+    /// An irq status that was read, but not yet handled by the wait
+    pending_irq_status: Option<IrqMask>,
+    /// The fifo overflowed while the spi was taken
+    overflowed_while_detached: bool,
+    /// The last received packet had a bad CRC. Only happens when bad CRCs aren't filtered.
+    crc_error: bool,
     _p: PhantomData<PF>,
 }
 
 impl<'buffer, PF> Rx<'buffer, PF> {
-    fn new(digital_frequency: u32, rx_buffer: &'buffer mut [u8]) -> Self {
+    fn new(
+        digital_frequency: u32,
+        rx_buffer: RxBuffer<'buffer>,
+        saved_registers: SessionRegisters,
+        irq_mask: IrqMask,
+        sync_less: bool,
+        low_duty_cycle: bool,
+    ) -> Self {
         Self {
             digital_frequency,
             rx_buffer,
+            saved_registers,
+            irq_mask,
+            sync_less,
+            low_duty_cycle,
+            pending_irq_status: None,
+            overflowed_while_detached: false,
+            crc_error: false,
             written: 0,
             rx_done: false,
+            auto_restart: false,
+            discard_policy: rx::DiscardPolicy::Stop,
+            discarded_count: 0,
+            restart_after_packet: false,
+            packet_len: 0,
             _p: PhantomData,
         }
     }
 }
 
+/// The buffer a reception is written to
+pub(crate) enum RxBuffer<'buffer> {
+    Slice(&'buffer mut [u8]),
+    /// Grows with the received data, up to the max length
+    #[cfg(feature = "alloc")]
+    Vec {
+        vec: &'buffer mut alloc::vec::Vec<u8>,
+        max_len: usize,
+    },
+}
+
+impl RxBuffer<'_> {
+    /// The amount of bytes the buffer can take
+    fn capacity(&self) -> usize {
+        match self {
+            RxBuffer::Slice(slice) => slice.len(),
+            #[cfg(feature = "alloc")]
+            RxBuffer::Vec { max_len, .. } => *max_len,
+        }
+    }
+
+    fn is_growable(&self) -> bool {
+        match self {
+            RxBuffer::Slice(_) => false,
+            #[cfg(feature = "alloc")]
+            RxBuffer::Vec { .. } => true,
+        }
+    }
+
+    /// The room for `len` bytes at `start`. A growable buffer grows to fit them.
+    fn space(&mut self, start: usize, len: usize) -> &mut [u8] {
+        match self {
+            RxBuffer::Slice(slice) => &mut slice[start..][..len],
+            #[cfg(feature = "alloc")]
+            RxBuffer::Vec { vec, .. } => {
+                if vec.len() < start + len {
+                    vec.resize(start + len, 0);
+                }
+                &mut vec[start..][..len]
+            }
+        }
+    }
+
+    /// The first `len` bytes, which must have been written
+    fn written(&self, len: usize) -> &[u8] {
+        match self {
+            RxBuffer::Slice(slice) => &slice[..len],
+            #[cfg(feature = "alloc")]
+            RxBuffer::Vec { vec, .. } => &vec[..len],
+        }
+    }
+}
+
+/// The radio transmits a test signal, e.g. for regulatory certification. It keeps transmitting until it's aborted.
+pub struct TestTx<PF> {
+    /// The internal `fdig` of the radio
+    digital_frequency: u32,
+    /// The tx source before the test signal was started
+    pckt_ctrl_1: PcktCtrl1,
+    /// The modulation before the test signal was started
+    mod_2: Mod2,
+    _p: PhantomData<PF>,
+}
+
 /// Implemented if the state allows for spi communication
 pub(crate) trait Addressable {}
 
@@ -82,3 +270,4 @@ impl<PF> Addressable for Standby<PF> {}
 impl<PF> Addressable for Ready<PF> {}
 impl<PF> Addressable for Tx<'_, PF> {}
 impl<PF> Addressable for Rx<'_, PF> {}
+impl<PF> Addressable for TestTx<PF> {}
@@ -7,12 +7,27 @@ use embedded_hal::{
 use embedded_hal_async::{delay::DelayNs, digital::Wait};
 
 use crate::{
-    ll::CcaPeriod,
-    packet_format::{Basic, PacketFormat, Uninitialized},
-    Error, ErrorOf, S2lp,
+    diagnostics::FatalError,
+    irq::{IrqEvent, IrqEvents},
+    ll::{
+        field_sets::{IrqMask, PcktFltOptions},
+        CcaPeriod, CrcMode, FixVarLen, GpioMode, GpioSelectInput, RegisterShadow, ShadowSpi, State,
+        TxSource, FIFO_SIZE,
+    },
+    packet_format::{ModemStatus, PacketFormat, PostambleLength, PreamblePattern, Uninitialized},
+    rssi::Rssi,
+    time::Duration,
+    Error, ErrorOf, GpioNumber, S2lp,
 };
 
-use super::{rx::RxMode, Ready, Rx, Shutdown, Standby, Tx};
+#[cfg(feature = "stack")]
+use crate::packet_format::{Stack, StackTxMetaData, STACK_ADDRESS_FIELDS_LEN};
+
+use super::{
+    addressable::GpioFunction,
+    rx::{RxMode, RxResult, RxTimeout, RxTimeoutMask},
+    Ready, Rx, RxBuffer, SessionRegisters, Shutdown, Standby, Tx,
+};
 
 impl<Spi, Sdn, Gpio, Delay, PF> S2lp<Ready<PF>, Spi, Sdn, Gpio, Delay>
 where
@@ -21,11 +36,120 @@ where
     Gpio: InputPin + Wait,
     Delay: DelayNs,
 {
+    /// Change the packet format and its config, only writing the registers that differ from what's programmed now.
+    ///
+    /// The result is the same as setting the format on a freshly initialized radio, but it's a lot faster
+    /// when only a few parameters change, e.g. when switching between modes.
+    /// If the config is invalid, nothing is written to the radio.
+    pub fn reconfigure<Format: PacketFormat>(
+        mut self,
+        format_config: &Format::Config,
+    ) -> Result<S2lp<Ready<Format>, Spi, Sdn, Gpio, Delay>, ErrorOf<Self>> {
+        let shadow = RegisterShadow::load(&mut self.ll().interface)?;
+        let digital_frequency = self.state.digital_frequency;
+
+        // Run the normal configuration against the shadow registers
+        let (radio, ()) = self.map_spi(|spi| (ShadowSpi { spi, shadow }, ()));
+        let radio = radio
+            .cast_state(Ready::<Uninitialized>::new(digital_frequency))
+            .set_format::<Format>(format_config)?;
+        let (mut radio, shadow) = radio.map_spi(|shadow_spi| (shadow_spi.spi, shadow_spi.shadow));
+
+        shadow.flush(&mut radio.ll().interface)?;
+
+        Ok(radio)
+    }
+
+    /// Read back the packet handler settings that are currently programmed in the radio
+    pub fn current_format_config(&mut self) -> Result<ModemStatus, ErrorOf<Self>> {
+        let pckt_ctrl_6 = self.ll().pckt_ctrl_6().read()?;
+        let pckt_ctrl_4 = self.ll().pckt_ctrl_4().read()?;
+        let pckt_ctrl_3 = self.ll().pckt_ctrl_3().read()?;
+        let pckt_ctrl_2 = self.ll().pckt_ctrl_2().read()?;
+        let pckt_ctrl_1 = self.ll().pckt_ctrl_1().read()?;
+
+        Ok(ModemStatus {
+            packet_format: pckt_ctrl_3.pckt_frmt(),
+            preamble_length: pckt_ctrl_6.preamble_len(),
+            preamble_pattern: PreamblePattern::from_bits(pckt_ctrl_3.preamble_sel()),
+            sync_length: pckt_ctrl_6.sync_len(),
+            sync_pattern: self.ll().sync().read()?.value().swap_bytes(),
+            fixed_length: pckt_ctrl_2.fix_var_len() == FixVarLen::Fixed,
+            packet_length_encoding: pckt_ctrl_4.len_wid(),
+            packet_length: self.ll().pckt_len().read()?.value(),
+            address_field: pckt_ctrl_4.address_len(),
+            postamble_length: PostambleLength::pairs(self.ll().pckt_pstmbl().read()?.value()),
+            crc_mode: pckt_ctrl_1.crc_mode()?,
+            whitening: pckt_ctrl_1.whit_en(),
+            fec: pckt_ctrl_1.fec_en(),
+            manchester: pckt_ctrl_2.manchester_en(),
+            mbus_3_of_6: pckt_ctrl_2.mbus_3_of_6_en(),
+        })
+    }
+
+    /// Set the RSSI threshold used for carrier sense, both in the CCA of CSMA/CA and
+    /// the [RssiAboveThreshold](crate::irq::IrqEvent::RssiAboveThreshold) event.
+    ///
+    /// Setting a format resets this to [DEFAULT_RSSI_THRESHOLD].
+    pub fn set_rssi_threshold(&mut self, threshold: Rssi) -> Result<(), ErrorOf<Self>> {
+        self.ll()
+            .rssi_th()
+            .write(|reg| reg.set_value(threshold.register()))?;
+        Ok(())
+    }
+
+    /// The currently configured carrier sense RSSI threshold
+    pub fn rssi_threshold(&mut self) -> Result<Rssi, ErrorOf<Self>> {
+        Ok(Rssi::from_register(self.ll().rssi_th().read()?.value()))
+    }
+
+    /// Set the symbol mapping used for 4-(G)FSK modulation, e.g. to talk to a stack that maps the symbols differently.
+    ///
+    /// Setting a format resets this to [Fsk4SymbolMapping::Standard].
+    pub fn set_fsk4_symbol_mapping(
+        &mut self,
+        mapping: Fsk4SymbolMapping,
+    ) -> Result<(), ErrorOf<Self>> {
+        self.ll()
+            .pckt_ctrl_3()
+            .modify(|reg| reg.set_fsk_4_sym_swap(mapping == Fsk4SymbolMapping::Swapped))?;
+        Ok(())
+    }
+
+    /// Set the address of this node.
+    ///
+    /// The STack format sends it as the source address of every packet. When the packet filter checks the
+    /// [source address](crate::packet_format::PacketFilteringOptions::source_address), this is also the destination
+    /// address that's accepted. Setting a format overwrites it with the source address of the packet filter.
+    pub fn set_node_address(&mut self, address: u8) -> Result<(), ErrorOf<Self>> {
+        self.ll()
+            .pckt_flt_goals_0()
+            .write(|reg| reg.set_tx_source_addr_or_dual_sync_0(address))?;
+        Ok(())
+    }
+
+    /// The address of this node. See [Self::set_node_address].
+    pub fn node_address(&mut self) -> Result<u8, ErrorOf<Self>> {
+        Ok(self
+            .ll()
+            .pckt_flt_goals_0()
+            .read()?
+            .tx_source_addr_or_dual_sync_0())
+    }
+
     /// Set the CSMA/CA mode used for sending packets.
     pub fn set_csma_ca(&mut self, mode: CsmaCaMode) -> Result<(), ErrorOf<Self>> {
         #[cfg(feature = "defmt-03")]
         use defmt::assert;
 
+        if !mode.is_off()
+            && self.ll().pm_conf_0().read()?.sleep_mode_sel() == SleepModeSel::WithoutFifoRetention
+        {
+            return Err(Error::BadConfig {
+                reason: "CSMA/CA requires fifo retention in sleep",
+            });
+        }
+
         let seed_reload = match mode {
             CsmaCaMode::Off => false,
             CsmaCaMode::Persistent {
@@ -98,6 +222,464 @@ where
         Ok(())
     }
 
+    /// Set the power management configuration.
+    ///
+    /// The fifo can only be dropped in sleep when CSMA/CA is off,
+    /// because the CSMA/CA backoff sleeps with the packet in the fifo.
+    pub fn set_power_config(&mut self, config: &PowerConfig) -> Result<(), ErrorOf<Self>> {
+        if config.sleep_mode == SleepModeSel::WithoutFifoRetention
+            && self.ll().protocol_1().read()?.csma_on()
+        {
+            return Err(Error::BadConfig {
+                reason: "CSMA/CA requires fifo retention in sleep",
+            });
+        }
+
+        self.ll().pm_conf_0().write(|reg| {
+            reg.set_set_smps_lvl(config.smps_level);
+            reg.set_sleep_mode_sel(config.sleep_mode);
+        })?;
+        self.ll().pm_conf_1().modify(|reg| {
+            reg.set_smps_lvl_mode(config.smps_level_tx_only);
+            reg.set_battery_lvl_en(config.battery_detector_threshold.is_some());
+            if let Some(threshold) = config.battery_detector_threshold {
+                reg.set_set_bld_th(threshold);
+            }
+        })?;
+        self.ll()
+            .pm_conf_4()
+            .modify(|reg| reg.set_ext_smps(config.external_smps))?;
+
+        Ok(())
+    }
+
+    /// Set the output power of the radio in dBm.
+    ///
+    /// Range: -30..=14 dBm.
+    ///
+    /// The power is set in the highest PA slot and power ramping is turned off.
+    pub fn set_tx_power(&mut self, dbm: i8) -> Result<(), ErrorOf<Self>> {
+        if !(-30..=14).contains(&dbm) {
+            return Err(Error::BadConfig {
+                reason: "Tx power out of range",
+            });
+        }
+
+        self.ll()
+            .pa_power_8()
+            .write(|reg| reg.set_value(tx_power_to_pa_level(dbm)))?;
+        self.ll().pa_power_0().modify(|reg| {
+            reg.set_pa_level_max_idx(7);
+            reg.set_pa_ramp_en(false);
+            reg.set_pa_maxdbm(false);
+        })?;
+
+        Ok(())
+    }
+
+    /// Set the output power with power ramping at the start and the end of every transmission.
+    ///
+    /// Ramping limits the spectral splatter of switching the PA on and off, which some regional spectrum masks require.
+    /// The eight PA slots are filled with a ramp from -30 dBm up to the target power.
+    /// Use [PaRamp::for_ramp_time] to get the step width for a ramp time at the datarate in use.
+    ///
+    /// Use [Self::set_tx_power] to turn ramping off again.
+    pub fn set_tx_power_ramp(&mut self, ramp: PaRamp) -> Result<(), ErrorOf<Self>> {
+        if !(-30..=14).contains(&ramp.target_dbm) {
+            return Err(Error::BadConfig {
+                reason: "Tx power out of range",
+            });
+        }
+        if !(1..=4).contains(&ramp.step_width) {
+            return Err(Error::BadConfig {
+                reason: "The ramp step width must be 1..=4",
+            });
+        }
+
+        for slot in 0..PA_SLOTS {
+            let dbm = -30 + (ramp.target_dbm as i32 + 30) * (slot as i32 + 1) / PA_SLOTS as i32;
+            let level = tx_power_to_pa_level(dbm as i8);
+
+            match slot {
+                0 => self.ll().pa_power_1().write(|reg| reg.set_value(level))?,
+                1 => self.ll().pa_power_2().write(|reg| reg.set_value(level))?,
+                2 => self.ll().pa_power_3().write(|reg| reg.set_value(level))?,
+                3 => self.ll().pa_power_4().write(|reg| reg.set_value(level))?,
+                4 => self.ll().pa_power_5().write(|reg| reg.set_value(level))?,
+                5 => self.ll().pa_power_6().write(|reg| reg.set_value(level))?,
+                6 => self.ll().pa_power_7().write(|reg| reg.set_value(level))?,
+                _ => self.ll().pa_power_8().write(|reg| reg.set_value(level))?,
+            }
+        }
+
+        self.ll().pa_power_0().modify(|reg| {
+            reg.set_pa_level_max_idx(PA_SLOTS - 1);
+            reg.set_pa_ramp_step_len(ramp.step_width - 1);
+            reg.set_pa_ramp_en(true);
+            reg.set_pa_maxdbm(false);
+        })?;
+
+        Ok(())
+    }
+
+    /// Lock the PLL for the given direction at the configured frequency without transmitting or receiving,
+    /// and check whether it locks. The radio is back in ready when this returns.
+    ///
+    /// This can be used to quickly verify a frequency (e.g. when hopping) and in self tests.
+    /// Returns true if the PLL locked.
+    pub async fn test_lock(&mut self, direction: LockDirection) -> Result<bool, ErrorOf<Self>> {
+        Ok(self.lock_time(direction).await?.is_some())
+    }
+
+    /// Lock the PLL like [Self::test_lock] and return how long it took, rounded up to the poll interval.
+    /// Returns None if the PLL didn't lock.
+    async fn lock_time(
+        &mut self,
+        direction: LockDirection,
+    ) -> Result<Option<Duration>, ErrorOf<Self>> {
+        match direction {
+            LockDirection::Tx => self.ll().lock_tx().dispatch()?,
+            LockDirection::Rx => self.ll().lock_rx().dispatch()?,
+        }
+
+        let mut lock_time = None;
+        for poll in 0..LOCK_TEST_POLLS {
+            match self.ll().mc_state_0().read()?.state() {
+                Ok(State::Lockon) => {
+                    lock_time = Some(Duration::from_micros(poll * LOCK_TEST_POLL_INTERVAL_US));
+                    break;
+                }
+                Ok(State::Lockst) => break,
+                _ => self.delay.delay_us(LOCK_TEST_POLL_INTERVAL_US).await,
+            }
+        }
+
+        #[cfg(feature = "defmt-03")]
+        defmt::debug!(
+            "{=str}: Lock test for {}: lock time = {}",
+            self.label,
+            direction,
+            lock_time
+        );
+
+        // Lockst can only be left with an abort
+        self.ll().abort().dispatch()?;
+        self.ll().ready().dispatch()?;
+
+        Ok(lock_time)
+    }
+
+    /// The time the state transitions take with the current configuration, e.g. to compute the guard times of a MAC.
+    ///
+    /// These are the typical times from the datasheet, rounded up. They don't include the SPI traffic to start the transition.
+    /// Use [Self::measure_timings] to get the synthesizer settling time of this radio at this frequency.
+    pub fn timings(&mut self) -> Result<TransitionTimings, ErrorOf<Self>> {
+        let vco_config = self.ll().vco_config().read()?;
+        let synth_settling = if vco_config.vco_calamp_ext_sel() && vco_config.vco_calfreq_ext_sel()
+        {
+            SYNTH_SETTLING
+        } else {
+            Duration::from_micros(SYNTH_SETTLING.as_micros() + VCO_CALIBRATION.as_micros())
+        };
+
+        Ok(TransitionTimings::new(synth_settling, synth_settling))
+    }
+
+    /// Like [Self::timings], but with the synthesizer settling time measured by locking the PLL for TX and RX.
+    ///
+    /// The measurement has a resolution of 10us. The radio is back in ready when this returns.
+    pub async fn measure_timings(&mut self) -> Result<TransitionTimings, ErrorOf<Self>> {
+        let Some(tx_settling) = self.lock_time(LockDirection::Tx).await? else {
+            return Err(self.fatal(FatalError::BadState));
+        };
+        let Some(rx_settling) = self.lock_time(LockDirection::Rx).await? else {
+            return Err(self.fatal(FatalError::BadState));
+        };
+
+        Ok(TransitionTimings::new(tx_settling, rx_settling))
+    }
+
+    /// Check whether someone is sending on the channel by listening for a preamble for at most `window`.
+    ///
+    /// The receiver is only on until a preamble with a quality of at least 4 times `pqi_threshold` (1..=15) is detected
+    /// or the window runs out, so this is a lot cheaper than receiving. For a quick check,
+    /// make the window a few preamble bytes long (e.g. 16 bits at the data rate, plus some margin for the RX turn-on).
+    ///
+    /// The radio is back in ready when this returns, with the preamble quality threshold of the packet format restored.
+    /// Returns true if a preamble was detected.
+    pub async fn detect_activity(
+        &mut self,
+        window: Duration,
+        pqi_threshold: u8,
+    ) -> Result<bool, ErrorOf<Self>> {
+        if !(1..=15).contains(&pqi_threshold) {
+            return Err(Error::BadConfig {
+                reason: "The PQI threshold must be 1..=15",
+            });
+        }
+
+        let saved_qi = self.ll().qi().read()?;
+        self.ll().qi().modify(|reg| reg.set_pqi_th(pqi_threshold))?;
+
+        let detected = self
+            .listen(window, RxTimeoutMask::Pqi, IrqEvent::ValidPreamble)
+            .await?;
+
+        #[cfg(feature = "defmt-03")]
+        defmt::debug!(
+            "{=str}: Channel activity detection: detected = {}",
+            self.label,
+            detected
+        );
+
+        self.ll().qi().write(|reg| *reg = saved_qi)?;
+
+        Ok(detected)
+    }
+
+    /// Clear channel assessment: listen for `duration` and check that the RSSI stays below the
+    /// [RSSI threshold](Self::set_rssi_threshold).
+    ///
+    /// The receiver is stopped as soon as the RSSI goes over the threshold.
+    /// The duration must include the time the RSSI needs to settle, see [Self::set_rssi_settling_limit].
+    /// The radio is back in ready when this returns. Returns true if the channel is clear.
+    pub async fn channel_clear(&mut self, duration: Duration) -> Result<bool, ErrorOf<Self>> {
+        let busy = self
+            .listen(duration, RxTimeoutMask::Rssi, IrqEvent::RssiAboveThreshold)
+            .await?;
+
+        #[cfg(feature = "defmt-03")]
+        defmt::trace!(
+            "{=str}: Clear channel assessment: busy = {}",
+            self.label,
+            busy
+        );
+
+        Ok(!busy)
+    }
+
+    /// Turn on the receiver for at most `window` with the RX timer stopped by the `timeout_mask`.
+    /// Returns true if the `event` came in before the timer ran out. The radio is back in ready when this returns.
+    async fn listen(
+        &mut self,
+        window: Duration,
+        timeout_mask: RxTimeoutMask,
+        event: IrqEvent,
+    ) -> Result<bool, ErrorOf<Self>> {
+        let digital_frequency = self.state.digital_frequency;
+        let sync_less = self.ll().pckt_ctrl_6().read()?.sync_len() == 0;
+        RxMode::Normal {
+            timeout: Some(RxTimeout {
+                timeout: window,
+                mask: timeout_mask,
+            }),
+        }
+        .write_to_device(self.ll(), digital_frequency, sync_less)?;
+
+        let saved_registers = SessionRegisters::save(self.ll())?;
+
+        let irq_mask = event.mask() | IrqEvent::RxTimeout.mask();
+        self.ll()
+            .irq_mask()
+            .write(|reg| *reg = IrqMask::from(irq_mask.to_be_bytes()))?;
+        // Read the irq status to clear it
+        self.ll().irq_status().read()?;
+
+        self.ll().rx().dispatch()?;
+
+        let detected = loop {
+            self.wait_for_irq_pin().await.map_err(Error::Gpio)?;
+
+            let irq_status = IrqEvents::from(self.ll().irq_status().read()?);
+            self.record_irq();
+
+            if irq_status.contains(event) {
+                break true;
+            }
+            if irq_status.contains(IrqEvent::RxTimeout) {
+                break false;
+            }
+        };
+
+        self.ll().abort().dispatch()?;
+        self.ll().ready().dispatch()?;
+        self.ll().flush_rx_fifo().dispatch()?;
+        saved_registers.restore(self.ll())?;
+        // Clear the irqs that came in while stopping
+        self.ll().irq_status().read()?;
+
+        Ok(detected)
+    }
+
+    /// Run the VCO calibration for TX and RX at the configured frequency and read back the results.
+    ///
+    /// The words can be given to [Self::set_vco_calibration] to skip the calibration on every
+    /// TX and RX turn-on, which shortens the synthesizer settling time.
+    pub async fn calibrate_vco(&mut self) -> Result<VcoCalibrationWords, ErrorOf<Self>> {
+        self.set_vco_calibration(None)?;
+
+        if !self.test_lock(LockDirection::Tx).await? {
+            return Err(self.fatal(FatalError::BadState));
+        }
+        let tx_amplitude = self.ll().vco_calibr_out_1().read()?.vco_cal_amp_out();
+        let tx_frequency = self.ll().vco_calibr_out_0().read()?.vco_cal_freq_out();
+
+        if !self.test_lock(LockDirection::Rx).await? {
+            return Err(self.fatal(FatalError::BadState));
+        }
+        let rx_amplitude = self.ll().vco_calibr_out_1().read()?.vco_cal_amp_out();
+        let rx_frequency = self.ll().vco_calibr_out_0().read()?.vco_cal_freq_out();
+
+        Ok(VcoCalibrationWords {
+            tx_amplitude,
+            tx_frequency,
+            rx_amplitude,
+            rx_frequency,
+        })
+    }
+
+    /// Set the VCO calibration words to use. If None, the VCO is calibrated on every TX and RX turn-on (the default).
+    ///
+    /// Stored words are only valid for the frequency they were measured at, see [Self::calibrate_vco].
+    pub fn set_vco_calibration(
+        &mut self,
+        words: Option<VcoCalibrationWords>,
+    ) -> Result<(), ErrorOf<Self>> {
+        if let Some(words) = words {
+            self.ll().vco_calibr_in_2().write(|reg| {
+                reg.set_vco_cal_amp_tx(words.tx_amplitude);
+                reg.set_vco_cal_amp_rx(words.rx_amplitude);
+            })?;
+            self.ll()
+                .vco_calibr_in_1()
+                .write(|reg| reg.set_vco_cal_freq_tx(words.tx_frequency))?;
+            self.ll()
+                .vco_calibr_in_0()
+                .write(|reg| reg.set_vco_cal_freq_rx(words.rx_frequency))?;
+        }
+
+        self.ll().vco_config().modify(|reg| {
+            reg.set_vco_calamp_ext_sel(words.is_some());
+            reg.set_vco_calfreq_ext_sel(words.is_some());
+        })?;
+
+        Ok(())
+    }
+
+    /// Set the time the receiver gives the RSSI to settle before it's used for the carrier sense based
+    /// RX termination (sniff mode).
+    ///
+    /// The unit is the RX sampling clock. The reset value is 0x28.
+    pub fn set_rssi_settling_limit(&mut self, limit: u8) -> Result<(), ErrorOf<Self>> {
+        self.ll()
+            .fast_rx_timer()
+            .write(|reg| reg.set_rssi_settling_limit(limit))?;
+        Ok(())
+    }
+
+    /// The amount of bytes in the tx fifo
+    pub fn tx_fifo_len(&mut self) -> Result<usize, ErrorOf<Self>> {
+        Ok(self.ll().tx_fifo_status().read()?.n_elem_txfifo() as usize)
+    }
+
+    /// The amount of bytes in the rx fifo
+    pub fn rx_fifo_len(&mut self) -> Result<usize, ErrorOf<Self>> {
+        Ok(self.ll().rx_fifo_status().read()?.n_elem_rxfifo() as usize)
+    }
+
+    /// Write data into the tx fifo directly, e.g. to preload a payload.
+    ///
+    /// Only what fits in the fifo is written. Returns the amount of bytes written.
+    /// Note that [Self::send_packet] flushes the fifo before writing its own payload.
+    pub fn write_tx_fifo(&mut self, data: &[u8]) -> Result<usize, ErrorOf<Self>> {
+        if data.is_empty() || self.tx_fifo_len()? == FIFO_SIZE {
+            return Ok(0);
+        }
+
+        Ok(self.ll().fifo().write(data)?)
+    }
+
+    /// Read data out of the rx fifo directly.
+    ///
+    /// Only what's available in the fifo is read. Returns the amount of bytes read.
+    pub fn read_rx_fifo(&mut self, buffer: &mut [u8]) -> Result<usize, ErrorOf<Self>> {
+        if buffer.is_empty() || self.rx_fifo_len()? == 0 {
+            return Ok(0);
+        }
+
+        Ok(self.ll().fifo().read(buffer)?)
+    }
+
+    /// Clear the tx fifo
+    pub fn flush_tx_fifo(&mut self) -> Result<(), ErrorOf<Self>> {
+        self.ll().flush_tx_fifo().dispatch()?;
+        Ok(())
+    }
+
+    /// Clear the rx fifo
+    pub fn flush_rx_fifo(&mut self) -> Result<(), ErrorOf<Self>> {
+        self.ll().flush_rx_fifo().dispatch()?;
+        Ok(())
+    }
+
+    /// Key the transmitter from a gpio, e.g. for OOK with the lowest possible latency.
+    ///
+    /// The radio takes the bits to send from the pin instead of the packet handler, so there's no preamble, sync or CRC.
+    /// With OOK, a high level turns the carrier on. The pin is sampled at the configured datarate,
+    /// so a high datarate keeps the delay between the pin and the modulator short.
+    ///
+    /// The radio keeps transmitting until [Self::stop_gpio_keying] is called. The pin can't be the irq pin of the driver.
+    /// To send the bits in sync with the data clock of the radio, use [S2lp::start_gpio_tx] instead.
+    pub fn start_gpio_keying(&mut self, data: GpioNumber) -> Result<(), ErrorOf<Self>> {
+        if data == self.gpio_number {
+            return Err(Error::BadConfig {
+                reason: "The irq pin can't be used to key the transmitter",
+            });
+        }
+
+        #[cfg(feature = "defmt-03")]
+        defmt::debug!("{=str}: Starting gpio keying on {}", self.label, data);
+
+        self.start_direct_gpio_tx(data)
+    }
+
+    /// Stop the transmission started with [Self::start_gpio_keying].
+    /// The pin is set to high impedance and the packet handler is used again for sending.
+    pub fn stop_gpio_keying(&mut self, data: GpioNumber) -> Result<(), ErrorOf<Self>> {
+        self.stop_direct_gpio_tx(data)
+    }
+
+    /// Start transmitting what's on the data pin
+    pub(crate) fn start_direct_gpio_tx(&mut self, data: GpioNumber) -> Result<(), ErrorOf<Self>> {
+        self.set_gpio_function(
+            data,
+            GpioFunction::Input {
+                select: GpioSelectInput::TxDataInput,
+            },
+        )?;
+        self.ll()
+            .pckt_ctrl_1()
+            .modify(|reg| reg.set_tx_source(TxSource::DirectThroughGpio))?;
+
+        self.ll().tx().dispatch()?;
+
+        Ok(())
+    }
+
+    /// Stop transmitting from the data pin and go back to the packet handler
+    pub(crate) fn stop_direct_gpio_tx(&mut self, data: GpioNumber) -> Result<(), ErrorOf<Self>> {
+        self.ll().abort().dispatch()?;
+        self.ll().ready().dispatch()?;
+        self.ll()
+            .pckt_ctrl_1()
+            .modify(|reg| reg.set_tx_source(TxSource::Normal))?;
+        self.set_gpio_function(data, GpioFunction::HiZ)?;
+        // Read the irq status to clear it
+        self.ll().irq_status().read()?;
+
+        Ok(())
+    }
+
     /// Put the radio in shutdown mode using the shutdown pin. This is the lowest possible power state.
     ///
     /// The radio can be booted again by going through the init procedure.
@@ -120,6 +702,15 @@ where
     }
 }
 
+/// The amount of PA power slots the ramp goes through
+const PA_SLOTS: u8 = 8;
+
+/// Convert the power in dBm to a PA level register value.
+/// The approximation used in the ST driver: `level = 25.66 - 2.11 * dBm`
+pub(crate) fn tx_power_to_pa_level(dbm: i8) -> u8 {
+    ((2566 - 211 * dbm as i32) / 100).clamp(1, 90) as u8
+}
+
 pub enum CsmaCaMode {
     /// No Csma is done
     Off,
@@ -156,6 +747,9 @@ pub enum CsmaCaMode {
         /// Range: 2..=64
         backoff_prescaler: u8,
         /// The backoff time is based on a prng. This prng is automatically seeded, unless this custom seed is given.
+        ///
+        /// Identical radios that power up at the same time may pick the same backoffs.
+        /// Use [S2lp::set_csma_ca_seeded] to seed the prng from the RSSI noise instead.
         custom_prng_seed: Option<u16>,
     },
 }
@@ -178,6 +772,173 @@ impl CsmaCaMode {
     }
 }
 
+/// The amount of times the state is polled in the lock test
+const LOCK_TEST_POLLS: u32 = 100;
+/// The time between polls in the lock test. Together with [LOCK_TEST_POLLS] it gives a max lock time of 1ms.
+const LOCK_TEST_POLL_INTERVAL_US: u32 = 10;
+
+/// The typical time the synthesizer takes to lock with a calibrated VCO
+const SYNTH_SETTLING: Duration = Duration::from_micros(50);
+/// The typical time the VCO calibration adds to the lock when it runs on every turn-on
+const VCO_CALIBRATION: Duration = Duration::from_micros(60);
+/// The time from the lock until the radio is in TX or RX
+const LOCK_TO_ON: Duration = Duration::from_micros(10);
+/// The time it takes to stop the receiver and get back to ready
+const RX_TO_READY: Duration = Duration::from_micros(10);
+
+/// What the packet filters would have said about a packet received with [S2lp::receive_promiscuous]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct FilterVerdicts {
+    /// Whether the CRC of the packet is right. None if the format has no CRC.
+    pub crc_ok: Option<bool>,
+    /// Whether the destination address is one of the addresses the filter accepts.
+    /// None if the filter doesn't check addresses or the packets have no address field.
+    pub address_match: Option<bool>,
+    /// The radio would have discarded the packet with the filter settings of the format.
+    /// Source address filtering isn't judged.
+    pub would_be_discarded: bool,
+}
+
+/// The time the state transitions take. See [S2lp::timings].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct TransitionTimings {
+    /// From the TX command in ready until the first bit goes out
+    pub ready_to_tx: Duration,
+    /// From the RX command in ready until the receiver is listening
+    pub ready_to_rx: Duration,
+    /// From the end of a reception until the first bit of the next packet goes out, e.g. for an ack
+    pub rx_to_tx: Duration,
+}
+
+impl TransitionTimings {
+    fn new(tx_settling: Duration, rx_settling: Duration) -> Self {
+        let ready_to_tx = Duration::from_micros(tx_settling.as_micros() + LOCK_TO_ON.as_micros());
+        Self {
+            ready_to_tx,
+            ready_to_rx: Duration::from_micros(rx_settling.as_micros() + LOCK_TO_ON.as_micros()),
+            rx_to_tx: Duration::from_micros(ready_to_tx.as_micros() + RX_TO_READY.as_micros()),
+        }
+    }
+}
+
+/// The mapping of the 4-(G)FSK symbols to frequency deviations. See [S2lp::set_fsk4_symbol_mapping].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum Fsk4SymbolMapping {
+    /// The reset mapping of the radio
+    #[default]
+    Standard,
+    /// The alternative mapping of the radio (`FSK4_SYM_SWAP`), which assigns the symbols to other deviations.
+    /// See the datasheet for the deviation of each symbol.
+    Swapped,
+}
+
+/// The power ramping of the PA. See [S2lp::set_tx_power_ramp].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct PaRamp {
+    /// The output power at the top of the ramp in dBm. Range: -30..=14
+    pub target_dbm: i8,
+    /// How long every step of the ramp takes in 1/8th bit periods. Range: 1..=4
+    ///
+    /// The ramp has 8 steps, so the ramp takes 1 to 4 bit periods.
+    pub step_width: u8,
+}
+
+impl PaRamp {
+    /// Get the ramp that comes closest to the given ramp time at the bit rate.
+    ///
+    /// The ramp takes 1 to 4 bit periods, so the ramp time is clamped to that.
+    /// Spectrum masks usually need the ramp to take at least a couple of µs.
+    /// At high datarates the longest ramp may be shorter than that, so check the result with [Self::ramp_time].
+    pub const fn for_ramp_time(target_dbm: i8, ramp_time: Duration, bit_rate: u32) -> Self {
+        // Every step width unit adds one bit period to the ramp
+        let bit_periods = (ramp_time.as_micros() as u64 * bit_rate as u64 + 500_000) / 1_000_000;
+        let step_width = if bit_periods < 1 {
+            1
+        } else if bit_periods > 4 {
+            4
+        } else {
+            bit_periods as u8
+        };
+
+        Self {
+            target_dbm,
+            step_width,
+        }
+    }
+
+    /// The time the ramp takes at the bit rate
+    pub const fn ramp_time(&self, bit_rate: u32) -> Duration {
+        let micros = match (self.step_width as u64 * 1_000_000).checked_div(bit_rate as u64) {
+            Some(micros) => micros,
+            None => 0,
+        };
+        Duration::from_micros(micros as u32)
+    }
+}
+
+/// The synthesizer settings to use in a lock test. TX and RX use a different frequency (because of the IF).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum LockDirection {
+    Tx,
+    Rx,
+}
+
+/// The result of a VCO calibration. See [S2lp::calibrate_vco].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct VcoCalibrationWords {
+    /// Range: 0..=15
+    pub tx_amplitude: u8,
+    /// Range: 0..=127
+    pub tx_frequency: u8,
+    /// Range: 0..=15
+    pub rx_amplitude: u8,
+    /// Range: 0..=127
+    pub rx_frequency: u8,
+}
+
+pub use crate::ll::{SetBldTh, SetSmpsLvl, SleepModeSel};
+
+/// The biggest packet that can be received while the spi is taken. See [S2lp::start_receive_detached].
+pub const MAX_DETACHED_RX_PACKET_SIZE: usize = FIFO_SIZE;
+
+/// The carrier sense RSSI threshold that is set along with the packet format
+pub const DEFAULT_RSSI_THRESHOLD: Rssi = Rssi::from_dbm(-85);
+
+/// The power management configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct PowerConfig {
+    /// Whether the fifo is retained in sleep. Retention costs sleep current, but is required for CSMA/CA.
+    pub sleep_mode: SleepModeSel,
+    /// The output voltage of the internal SMPS
+    pub smps_level: SetSmpsLvl,
+    /// If true, the SMPS level is only used in TX. In RX it's fixed to 1.4V.
+    pub smps_level_tx_only: bool,
+    /// Set to true if the internal SMPS is disabled and an external supply is used
+    pub external_smps: bool,
+    /// If some, the battery level detector is enabled with the given threshold
+    pub battery_detector_threshold: Option<SetBldTh>,
+}
+
+impl Default for PowerConfig {
+    /// The configuration the radio has after init
+    fn default() -> Self {
+        Self {
+            sleep_mode: SleepModeSel::WithFifoRetention,
+            smps_level: SetSmpsLvl::V15,
+            smps_level_tx_only: true,
+            external_smps: false,
+            battery_detector_threshold: None,
+        }
+    }
+}
+
 impl<Spi, Sdn, Gpio, Delay> S2lp<Ready<Uninitialized>, Spi, Sdn, Gpio, Delay>
 where
     Spi: SpiDevice,
@@ -197,17 +958,25 @@ where
         // Set up the format specific configs
         Format::use_config(&mut self, format_config)?;
 
-        self.ll().pckt_ctrl_3().write(|reg| {
-            reg.set_rx_mode(crate::ll::RxMode::Normal);
+        self.ll().pckt_ctrl_3().modify(|reg| {
+            reg.set_rx_mode(if Format::DIRECT_FIFO {
+                crate::ll::RxMode::DirectThroughFifo
+            } else {
+                crate::ll::RxMode::Normal
+            });
             reg.set_byte_swap(false);
             reg.set_fsk_4_sym_swap(false);
         })?;
 
-        self.ll().pckt_ctrl_1().write(|reg| {
+        self.ll().pckt_ctrl_1().modify(|reg| {
             reg.set_fec_en(false);
             reg.set_second_sync_sel(false);
-            reg.set_tx_source(crate::ll::TxSource::Normal);
-            reg.set_whit_en(true);
+            reg.set_tx_source(if Format::DIRECT_FIFO {
+                crate::ll::TxSource::DirectThroughFifo
+            } else {
+                crate::ll::TxSource::Normal
+            });
+            reg.set_whit_en(Format::WHITENING);
         })?;
 
         // Set the tx fifo almost empty to the default
@@ -215,18 +984,16 @@ where
         // Set the rx fifo almost full to the default
         self.ll().fifo_config_3().write(|_| ())?;
 
-        self.ll()
-            .pm_conf_1()
-            .modify(|reg| reg.set_smps_lvl_mode(true))?;
-
         self.ll().rssi_flt().modify(|reg| {
             reg.set_cs_mode(crate::ll::CsMode::StaticCs);
             reg.set_rssi_flt(14)
         })?;
-        self.ll().rssi_th().write(|reg| reg.set_value(65))?; // -85 dB
+        self.ll()
+            .rssi_th()
+            .write(|reg| reg.set_value(DEFAULT_RSSI_THRESHOLD.register()))?;
 
         #[cfg(feature = "defmt-03")]
-        defmt::debug!("Packet type has been configured");
+        defmt::debug!("{=str}: Packet type has been configured", self.label);
 
         let digital_frequency = self.state.digital_frequency;
         Ok(self.cast_state(Ready::new(digital_frequency)))
@@ -243,81 +1010,677 @@ where
 {
     /// Start a transmission and send a packet
     pub fn send_packet<'b>(
+        self,
+        tx_meta_data: &Format::TxMetaData,
+        payload: &'b [u8],
+    ) -> Result<S2lp<Tx<'b, Format>, Spi, Sdn, Gpio, Delay>, ErrorOf<Self>> {
+        self.start_packet(tx_meta_data, payload, None)
+    }
+
+    /// Prepare the transmission of a packet, but let a high level on the gpio start it instead of the spi.
+    ///
+    /// The pin is set up as the TX command input of the radio. The returned Tx state waits for the
+    /// transmission like [Self::send_packet] does, so the payload can be bigger than the fifo.
+    /// The pin is set back to its previous function when the transmission is done or aborted.
+    /// Until then, the level must only go high once, or the radio starts sending again.
+    ///
+    /// The pin can't be the irq pin of the driver.
+    pub fn arm_tx_on_pin<'b>(
+        self,
+        pin: GpioNumber,
+        tx_meta_data: &Format::TxMetaData,
+        payload: &'b [u8],
+    ) -> Result<S2lp<Tx<'b, Format>, Spi, Sdn, Gpio, Delay>, ErrorOf<Self>> {
+        self.start_packet(tx_meta_data, payload, Some(pin))
+    }
+
+    fn start_packet<'b>(
         mut self,
         tx_meta_data: &Format::TxMetaData,
         payload: &'b [u8],
+        command_pin: Option<GpioNumber>,
     ) -> Result<S2lp<Tx<'b, Format>, Spi, Sdn, Gpio, Delay>, ErrorOf<Self>> {
-        Format::setup_packet_send(&mut self, tx_meta_data, payload.len())?;
+        self.check_command_pin(command_pin)?;
+        #[cfg(feature = "sanity-checks")]
+        self.sanity_check()?;
 
-        // Must be off to support CSMA/CA
-        self.ll()
-            .ant_select_conf()
-            .modify(|reg| reg.set_cs_blanking(false))?;
+        // Clear out anything that might still be in the fifos.
+        // The rx fifo is used for the payload of acknowledgements.
+        self.ll().flush_tx_fifo().dispatch()?;
+        self.ll().flush_rx_fifo().dispatch()?;
+
+        let mut saved_registers = self.prepare_transmission(tx_meta_data, payload.len())?;
+
+        // Write all we can of the payload into the fifo now
+        let initial_len = self.ll().fifo().write(payload)?;
+
+        #[cfg(feature = "defmt-03")]
+        defmt::debug!(
+            "{=str}: Sending packet with len: {}",
+            self.label,
+            payload.len()
+        );
+
+        // Start the tx process, or leave it to the pin
+        match command_pin {
+            Some(pin) => {
+                self.arm_command_pin(pin, GpioSelectInput::TxCommand, &mut saved_registers)?
+            }
+            None => self.ll().tx().dispatch()?,
+        }
+
+        let digital_frequency = self.state.digital_frequency;
+        Ok(self.cast_state(Tx::new(
+            digital_frequency,
+            &payload[initial_len..],
+            initial_len,
+            saved_registers,
+        )))
+    }
+
+    /// Start a transmission of a packet of which the payload has already been written to the tx fifo.
+    /// This skips the fifo writes, so the radio can go to TX faster.
+    ///
+    /// The full payload must be in the fifo.
+    pub(crate) fn send_staged_packet(
+        mut self,
+        tx_meta_data: &Format::TxMetaData,
+        payload_len: usize,
+    ) -> Result<S2lp<Tx<'static, Format>, Spi, Sdn, Gpio, Delay>, ErrorOf<Self>> {
+        self.ll().flush_rx_fifo().dispatch()?;
+
+        let saved_registers = self.prepare_transmission(tx_meta_data, payload_len)?;
+
+        #[cfg(feature = "defmt-03")]
+        defmt::debug!(
+            "{=str}: Sending staged packet with len: {}",
+            self.label,
+            payload_len
+        );
+
+        // Start the tx process
+        self.ll().tx().dispatch()?;
+
+        let digital_frequency = self.state.digital_frequency;
+        Ok(self.cast_state(Tx::new(digital_frequency, &[], 0, saved_registers)))
+    }
+
+    /// Start a transmission of raw data that bypasses the packet format.
+    ///
+    /// The caller must have put the radio in a direct tx mode.
+    pub(crate) fn send_raw<'b>(
+        mut self,
+        data: &'b [u8],
+    ) -> Result<S2lp<Tx<'b, Format>, Spi, Sdn, Gpio, Delay>, ErrorOf<Self>> {
+        if data.len() > u16::MAX as usize {
+            return Err(Error::BufferTooLarge);
+        }
 
-        // Clear out anything that might still be in the tx fifo
         self.ll().flush_tx_fifo().dispatch()?;
+        self.ll().flush_rx_fifo().dispatch()?;
+
+        self.ll()
+            .pckt_len()
+            .write(|reg| reg.set_value(data.len() as u16))?;
+        let saved_registers = self.prepare_tx_irqs(data.len())?;
+
+        let initial_len = self.ll().fifo().write(data)?;
+
+        #[cfg(feature = "defmt-03")]
+        defmt::debug!(
+            "{=str}: Sending raw data with len: {}",
+            self.label,
+            data.len()
+        );
+
+        self.ll().tx().dispatch()?;
+
+        let digital_frequency = self.state.digital_frequency;
+        Ok(self.cast_state(Tx::new(
+            digital_frequency,
+            &data[initial_len..],
+            initial_len,
+            saved_registers,
+        )))
+    }
+
+    fn check_command_pin(&self, command_pin: Option<GpioNumber>) -> Result<(), ErrorOf<Self>> {
+        if command_pin == Some(self.gpio_number) {
+            return Err(Error::BadConfig {
+                reason: "The irq pin can't be used to give commands",
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Let the pin give the command. The previous config of the pin is saved, so it's restored with the session.
+    fn arm_command_pin(
+        &mut self,
+        pin: GpioNumber,
+        command: GpioSelectInput,
+        saved_registers: &mut SessionRegisters,
+    ) -> Result<(), ErrorOf<Self>> {
+        saved_registers.command_pin = Some((pin, self.ll().gpio_conf(pin as usize).read()?));
+
+        #[cfg(feature = "defmt-03")]
+        defmt::debug!("{=str}: Arming {} on {}", self.label, command, pin);
+
+        self.ll().gpio_conf(pin as usize).write(|reg| {
+            reg.set_gpio_mode(GpioMode::Input);
+            reg.set_gpio_select_input(command);
+        })?;
+
+        Ok(())
+    }
+
+    fn prepare_transmission(
+        &mut self,
+        tx_meta_data: &Format::TxMetaData,
+        payload_len: usize,
+    ) -> Result<SessionRegisters, ErrorOf<Self>> {
+        // A source address for this packet only replaces the node address until the session is done
+        let node_address = match Format::source_address(tx_meta_data) {
+            Some(_) => Some(self.ll().pckt_flt_goals_0().read()?),
+            None => None,
+        };
+
+        Format::setup_packet_send(self, tx_meta_data, payload_len)?;
+        let mut saved_registers = self.prepare_tx_irqs(payload_len)?;
+        saved_registers.node_address = node_address;
+
+        Ok(saved_registers)
+    }
+
+    fn prepare_tx_irqs(&mut self, payload_len: usize) -> Result<SessionRegisters, ErrorOf<Self>> {
+        let saved_registers = SessionRegisters::save(self.ll())?;
+
+        // Must be off to support CSMA/CA
+        self.ll().ant_select_conf().write(|reg| {
+            *reg = saved_registers.ant_select_conf;
+            reg.set_cs_blanking(false);
+        })?;
 
         // Read the irq status to clear it
         self.ll().irq_status().read()?;
         // Set the irq mask for all the irqs we need
         self.ll().irq_mask().write(|reg| {
-            reg.set_tx_fifo_almost_empty(true);
+            // The fifo is empty before the first write, so only a payload larger than the fifo needs refills
+            reg.set_tx_fifo_almost_empty(payload_len > FIFO_SIZE);
             reg.set_tx_data_sent(true);
             reg.set_max_re_tx_reach(true);
             reg.set_tx_fifo_error(true);
             reg.set_max_bo_cca_reach(true);
+            reg.set_rx_data_ready(true);
         })?;
 
-        // Write all we can of the payload into the fifo now
-        let initial_len = self.ll().fifo().write(payload)?;
+        Ok(saved_registers)
+    }
 
-        #[cfg(feature = "defmt-03")]
-        defmt::debug!("Sending packet with len: {}", payload.len());
+    /// Start the reception to try and receive a packet
+    pub fn start_receive(
+        self,
+        buffer: &mut [u8],
+        mode: RxMode,
+    ) -> Result<S2lp<Rx<'_, Format>, Spi, Sdn, Gpio, Delay>, ErrorOf<Self>> {
+        self.start_receiver(RxBuffer::Slice(buffer), mode, true, None)
+    }
 
-        // Start the tx process
-        self.ll().tx().dispatch()?;
+    /// Prepare the reception, but let a high level on the gpio start the receiver instead of the spi.
+    ///
+    /// The pin is set up as the RX command input of the radio and is set back to its previous function
+    /// when the reception is done or aborted. The returned Rx state is used like the one of [Self::start_receive].
+    ///
+    /// The pin can't be the irq pin of the driver.
+    pub fn arm_rx_on_pin(
+        self,
+        pin: GpioNumber,
+        buffer: &mut [u8],
+        mode: RxMode,
+    ) -> Result<S2lp<Rx<'_, Format>, Spi, Sdn, Gpio, Delay>, ErrorOf<Self>> {
+        self.start_receiver(RxBuffer::Slice(buffer), mode, true, Some(pin))
+    }
 
-        let digital_frequency = self.state.digital_frequency;
-        Ok(self.cast_state(Tx::new(digital_frequency, &payload[initial_len..])))
+    /// Start the reception and take the spi out of the driver, for a low-power RX flow.
+    /// See [S2lp::take_spi].
+    ///
+    /// The fifo can't be serviced while the spi is taken, so the packet must fit in the fifo.
+    /// A buffer bigger than [MAX_DETACHED_RX_PACKET_SIZE] returns [Error::BadConfig].
+    /// The fifo almost full interrupt is turned off, so the irq only fires when the reception is done.
+    pub fn start_receive_detached(
+        self,
+        buffer: &mut [u8],
+        mode: RxMode,
+    ) -> Result<(S2lp<Rx<'_, Format>, (), Sdn, Gpio, Delay>, Spi), ErrorOf<Self>> {
+        if Format::DIRECT_FIFO {
+            return Err(Error::BadConfig {
+                reason: "A raw bitstream has no end, so the fifo must be serviced",
+            });
+        }
+        if buffer.len() > MAX_DETACHED_RX_PACKET_SIZE {
+            return Err(Error::BadConfig {
+                reason: "The buffer allows packets that don't fit in the fifo while detached",
+            });
+        }
+
+        Ok(self
+            .start_receiver(RxBuffer::Slice(buffer), mode, false, None)?
+            .take_spi())
     }
 
-    /// Start the reception to try and receive a packet
-    pub fn start_receive(
+    fn start_receiver<'b>(
         mut self,
-        buffer: &mut [u8],
+        buffer: RxBuffer<'b>,
         mode: RxMode,
-    ) -> Result<S2lp<Rx<Basic>, Spi, Sdn, Gpio, Delay>, ErrorOf<Self>> {
+        service_fifo: bool,
+        command_pin: Option<GpioNumber>,
+    ) -> Result<S2lp<Rx<'b, Format>, Spi, Sdn, Gpio, Delay>, ErrorOf<Self>> {
+        self.check_command_pin(command_pin)?;
+        #[cfg(feature = "sanity-checks")]
+        self.sanity_check()?;
+
         let digital_frequency = self.state.digital_frequency;
-        mode.write_to_device(self.ll(), digital_frequency)?;
+        let sync_less = self.ll().pckt_ctrl_6().read()?.sync_len() == 0;
+        mode.write_to_device(self.ll(), digital_frequency, sync_less)?;
+
+        let mut saved_registers = SessionRegisters::save(self.ll())?;
 
         // Make fifo more reliable
-        self.ll()
-            .ant_select_conf()
-            .modify(|reg| reg.set_cs_blanking(true))?;
+        self.ll().ant_select_conf().write(|reg| {
+            *reg = saved_registers.ant_select_conf;
+            reg.set_cs_blanking(true);
+        })?;
 
         // Clear out anything that might still be in the rx fifo
         self.ll().flush_rx_fifo().dispatch()?;
 
         // Set the irq mask for all the irqs we need
-        self.ll().irq_mask().write(|reg| {
-            reg.set_rx_data_ready(true);
-            reg.set_rx_fifo_almost_full(true);
-            reg.set_rx_fifo_error(true);
-            reg.set_rx_timeout(true);
-            reg.set_rx_data_disc(true);
-            reg.set_crc_error(true);
-            reg.set_rx_sniff_timeout(true);
-        })?;
+        let mut irq_mask = IrqMask::new();
+        irq_mask.set_rx_data_ready(true);
+        irq_mask.set_rx_fifo_almost_full(service_fifo);
+        irq_mask.set_rx_fifo_error(true);
+        irq_mask.set_rx_timeout(true);
+        irq_mask.set_rx_data_disc(true);
+        irq_mask.set_crc_error(true);
+        irq_mask.set_rx_sniff_timeout(true);
+        irq_mask.set_tx_data_sent(true);
+        self.ll().irq_mask().write(|reg| *reg = irq_mask)?;
         // Read the irq status to clear it
         self.ll().irq_status().read()?;
 
         #[cfg(feature = "defmt-03")]
-        defmt::trace!("Starting receiver");
+        defmt::trace!("{=str}: Starting receiver", self.label);
 
-        // Start the rx process
-        self.ll().rx().dispatch()?;
+        // Start the rx process, or leave it to the pin
+        match command_pin {
+            Some(pin) => {
+                self.arm_command_pin(pin, GpioSelectInput::RxCommand, &mut saved_registers)?
+            }
+            None => self.ll().rx().dispatch()?,
+        }
 
         let digital_frequency = self.state.digital_frequency;
-        Ok(self.cast_state(Rx::new(digital_frequency, buffer)))
+        Ok(self.cast_state(Rx::new(
+            digital_frequency,
+            buffer,
+            saved_registers,
+            irq_mask,
+            sync_less,
+            mode.is_low_duty_cycle(),
+        )))
+    }
+
+    /// Check that the radio is ready, still configured and set up for the packet format of the typestate
+    #[cfg(feature = "sanity-checks")]
+    fn sanity_check(&mut self) -> Result<(), ErrorOf<Self>> {
+        self.check_configured()?;
+
+        if self.ll().mc_state_0().read()?.state() != Ok(State::Ready) {
+            return Err(Error::SanityCheck {
+                reason: "The radio is not in the ready state",
+            });
+        }
+
+        let pckt_ctrl_3 = self.ll().pckt_ctrl_3().read()?;
+        if pckt_ctrl_3.pckt_frmt() != Format::RADIO_FORMAT {
+            return Err(Error::SanityCheck {
+                reason: "The packet format of the radio doesn't match the typestate",
+            });
+        }
+        if (pckt_ctrl_3.rx_mode() == Ok(crate::ll::RxMode::DirectThroughFifo))
+            != Format::DIRECT_FIFO
+        {
+            return Err(Error::SanityCheck {
+                reason: "The rx mode of the radio doesn't match the packet format",
+            });
+        }
+
+        // The whitening isn't checked, since some sends turn it off on purpose
+        let tx_source = self.ll().pckt_ctrl_1().read()?.tx_source();
+        if (tx_source == TxSource::DirectThroughFifo) != Format::DIRECT_FIFO {
+            return Err(Error::SanityCheck {
+                reason: "The tx source of the radio doesn't match the packet format",
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl<Format, Spi, Sdn, Gpio, Delay> S2lp<Ready<Format>, Spi, Sdn, Gpio, Delay>
+where
+    Format: PacketFormat,
+    Spi: SpiDevice,
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    /// Receive a packet that passes the given software filter and wait for the reception to be done.
+    ///
+    /// Received packets for which `matches` returns false are discarded and the receiver is restarted.
+    /// This emulates address filtering for formats the hardware can't filter, like [FixedLength](crate::packet_format::FixedLength).
+    /// For a prefix match, use e.g. `|frame| frame.starts_with(&[0xAB, 0xCD])`.
+    ///
+    /// Any other result, like a timeout, ends the reception.
+    pub async fn receive_matching(
+        self,
+        buffer: &mut [u8],
+        mode: RxMode,
+        mut matches: impl FnMut(&[u8]) -> bool,
+    ) -> Result<(Self, RxResult<Format::RxMetaData>), ErrorOf<Self>> {
+        let mut ready = self;
+
+        loop {
+            let mut rx = ready.start_receive(buffer, mode)?;
+            let rx_result = rx.wait().await?;
+            let Ok(next) = rx.finish() else {
+                unreachable!()
+            };
+            ready = next;
+
+            if let RxResult::Ok { packet_size, .. } = &rx_result {
+                if !matches(&buffer[..*packet_size]) {
+                    #[cfg(feature = "defmt-03")]
+                    defmt::trace!(
+                        "{=str}: Received frame didn't match the software filter",
+                        ready.label
+                    );
+                    continue;
+                }
+            }
+
+            return Ok((ready, rx_result));
+        }
+    }
+
+    /// Scan the channel for a packet: receive for at most `window` unless a preamble is detected,
+    /// in which case the packet is received to the end.
+    ///
+    /// A preamble is detected when its quality is at least 4 times `pqi_threshold` (1..=15).
+    /// Make the window a few preamble bytes long, plus some margin for the RX turn-on, to spend as little time
+    /// as possible on an empty channel. This sets up the [RxTimeoutMask::Pqi] timeout for that.
+    ///
+    /// Returns [RxResult::Timeout] if no preamble was detected. The preamble quality threshold of the packet format
+    /// is restored afterwards.
+    pub async fn scan_for_packet(
+        mut self,
+        buffer: &mut [u8],
+        window: Duration,
+        pqi_threshold: u8,
+    ) -> Result<(Self, RxResult<Format::RxMetaData>), ErrorOf<Self>> {
+        if !(1..=15).contains(&pqi_threshold) {
+            return Err(Error::BadConfig {
+                reason: "The PQI threshold must be 1..=15",
+            });
+        }
+
+        let saved_qi = self.ll().qi().read()?;
+        self.ll().qi().modify(|reg| reg.set_pqi_th(pqi_threshold))?;
+
+        let mut rx = self.start_receive(
+            buffer,
+            RxMode::Normal {
+                timeout: Some(RxTimeout {
+                    timeout: window,
+                    mask: RxTimeoutMask::Pqi,
+                }),
+            },
+        )?;
+        let rx_result = rx.wait().await?;
+        let Ok(mut ready) = rx.finish() else {
+            unreachable!()
+        };
+
+        #[cfg(feature = "defmt-03")]
+        defmt::debug!("{=str}: Scan for packet: {}", ready.label, rx_result);
+
+        ready.ll().qi().write(|reg| *reg = saved_qi)?;
+
+        Ok((ready, rx_result))
+    }
+
+    /// Receive any packet, like a sniffer: the address and CRC filters are turned off and the automatic acknowledgement
+    /// is disabled, so packets for other nodes and corrupted packets are received as well and never answered.
+    ///
+    /// For a received packet, the [FilterVerdicts] tell what the filters of the packet format would have said about it.
+    /// The whitening, coding and length field settings of the format stay on, since the packet can't be found without them.
+    /// To see the bits as they're on air, use the [RawFifo](crate::packet_format::RawFifo) format.
+    ///
+    /// The filter settings are restored afterwards.
+    pub async fn receive_promiscuous(
+        mut self,
+        buffer: &mut [u8],
+        mode: RxMode,
+    ) -> Result<(Self, RxResult<Format::RxMetaData>, Option<FilterVerdicts>), ErrorOf<Self>> {
+        let saved_pckt_flt_options = self.ll().pckt_flt_options().read()?;
+        let saved_protocol_1 = self.ll().protocol_1().read()?;
+        let saved_protocol_0 = self.ll().protocol_0().read()?;
+
+        self.ll().pckt_flt_options().modify(|reg| {
+            reg.set_crc_flt(false);
+            reg.set_dest_vs_broadcast_addr(false);
+            reg.set_dest_vs_multicast_addr(false);
+            reg.set_dest_vs_source_addr(false);
+            reg.set_source_addr_flt(false);
+        })?;
+        self.ll()
+            .protocol_1()
+            .modify(|reg| reg.set_auto_pckt_flt(false))?;
+        self.ll()
+            .protocol_0()
+            .modify(|reg| reg.set_auto_ack(false))?;
+
+        let mut rx = self.start_receive(buffer, mode)?;
+        let rx_result = rx.wait().await?;
+        let crc_error = rx.state.crc_error;
+        let Ok(mut ready) = rx.finish() else {
+            unreachable!()
+        };
+
+        let verdicts = match rx_result {
+            RxResult::Ok { .. } => Some(ready.filter_verdicts(
+                saved_pckt_flt_options,
+                saved_protocol_1.auto_pckt_flt(),
+                crc_error,
+            )?),
+            _ => None,
+        };
+
+        #[cfg(feature = "defmt-03")]
+        defmt::debug!(
+            "{=str}: Promiscuous receive: {}, {}",
+            ready.label,
+            rx_result,
+            verdicts
+        );
+
+        ready
+            .ll()
+            .pckt_flt_options()
+            .write(|reg| *reg = saved_pckt_flt_options)?;
+        ready
+            .ll()
+            .protocol_1()
+            .write(|reg| *reg = saved_protocol_1)?;
+        ready
+            .ll()
+            .protocol_0()
+            .write(|reg| *reg = saved_protocol_0)?;
+
+        Ok((ready, rx_result, verdicts))
+    }
+
+    /// Judge the packet that was just received against the filter options
+    fn filter_verdicts(
+        &mut self,
+        options: PcktFltOptions,
+        automatic_filtering: bool,
+        crc_error: bool,
+    ) -> Result<FilterVerdicts, ErrorOf<Self>> {
+        let crc_ok = match self.ll().pckt_ctrl_1().read()?.crc_mode()? {
+            CrcMode::NoCrc => None,
+            _ => Some(!crc_error),
+        };
+
+        let filters_on_address = options.dest_vs_source_addr()
+            || options.dest_vs_multicast_addr()
+            || options.dest_vs_broadcast_addr();
+        let address_match = if filters_on_address && self.ll().pckt_ctrl_4().read()?.address_len() {
+            let destination = self.ll().rx_addre_field_0().read()?.value();
+            let goals = [
+                (
+                    options.dest_vs_source_addr(),
+                    self.ll()
+                        .pckt_flt_goals_0()
+                        .read()?
+                        .tx_source_addr_or_dual_sync_0(),
+                ),
+                (
+                    options.dest_vs_multicast_addr(),
+                    self.ll()
+                        .pckt_flt_goals_1()
+                        .read()?
+                        .multicast_addr_or_dual_sync_1(),
+                ),
+                (
+                    options.dest_vs_broadcast_addr(),
+                    self.ll()
+                        .pckt_flt_goals_2()
+                        .read()?
+                        .broadcast_addr_or_dual_sync_2(),
+                ),
+            ];
+
+            Some(
+                goals
+                    .iter()
+                    .any(|(enabled, address)| *enabled && *address == destination),
+            )
+        } else {
+            None
+        };
+
+        Ok(FilterVerdicts {
+            crc_ok,
+            address_match,
+            would_be_discarded: automatic_filtering
+                && ((options.crc_flt() && crc_ok == Some(false)) || address_match == Some(false)),
+        })
+    }
+}
+
+#[cfg(feature = "stack")]
+impl<Spi, Sdn, Gpio, Delay> S2lp<Ready<Stack>, Spi, Sdn, Gpio, Delay>
+where
+    Spi: SpiDevice,
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    /// Stage a payload that will be sent along with the next automatic acknowledgement (piggybacking).
+    ///
+    /// The payload is put in the tx fifo and is used for one acknowledgement only.
+    /// It must fit in the fifo (128 bytes) since the fifo can't be refilled while acking.
+    /// Any call to [Self::send_packet] clears the staged payload.
+    pub fn set_ack_payload(&mut self, payload: &[u8]) -> Result<(), ErrorOf<Self>> {
+        if payload.len() > FIFO_SIZE {
+            return Err(Error::BufferTooLarge);
+        }
+
+        self.ll().flush_tx_fifo().dispatch()?;
+        self.ll()
+            .pckt_len()
+            .write(|reg| reg.set_value(payload.len() as u16 + STACK_ADDRESS_FIELDS_LEN))?;
+
+        let mut written = 0;
+        while written < payload.len() {
+            written += self.ll().fifo().write(&payload[written..])?;
+        }
+
+        self.ll()
+            .protocol_1()
+            .modify(|reg| reg.set_piggybacking(true))?;
+
+        Ok(())
+    }
+
+    /// Start a transmission like [Self::send_packet], but the payload of the acknowledgement
+    /// (if any) is written to the given ack buffer.
+    ///
+    /// The size of the ack payload is returned in the [TxResult](crate::states::tx::TxResult).
+    pub fn send_packet_with_ack_buffer<'b>(
+        self,
+        tx_meta_data: &StackTxMetaData,
+        payload: &'b [u8],
+        ack_buffer: &'b mut [u8],
+    ) -> Result<S2lp<Tx<'b, Stack>, Spi, Sdn, Gpio, Delay>, ErrorOf<Self>> {
+        let mut this = self.send_packet(tx_meta_data, payload)?;
+        this.state.ack_buffer = ack_buffer;
+        Ok(this)
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<Format, Spi, Sdn, Gpio, Delay> S2lp<Ready<Format>, Spi, Sdn, Gpio, Delay>
+where
+    Format: PacketFormat,
+    Spi: SpiDevice,
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    /// Receive a packet into a newly allocated vector and wait for the reception to be done.
+    ///
+    /// The vector grows as the fifo is drained, so only the received length is allocated instead of `max_len`.
+    /// Packets larger than `max_len` result in [RxResult::TooBigForBuffer].
+    /// The returned vector is sized to the received packet length and is empty if no packet was received.
+    pub async fn receive_vec(
+        self,
+        mode: RxMode,
+        max_len: usize,
+    ) -> Result<(Self, RxResult<Format::RxMetaData>, alloc::vec::Vec<u8>), ErrorOf<Self>> {
+        let mut buffer = alloc::vec::Vec::new();
+
+        let mut rx = self.start_receiver(
+            RxBuffer::Vec {
+                vec: &mut buffer,
+                max_len,
+            },
+            mode,
+            true,
+            None,
+        )?;
+        let rx_result = rx.wait().await?;
+        let Ok(ready) = rx.finish() else {
+            unreachable!()
+        };
+
+        let packet_size = match rx_result {
+            RxResult::Ok { packet_size, .. } => packet_size,
+            _ => 0,
+        };
+        buffer.truncate(packet_size);
+
+        Ok((ready, rx_result, buffer))
     }
 }
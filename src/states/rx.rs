@@ -1,17 +1,23 @@
 use device_driver::RegisterInterface;
+use embassy_futures::select::{select, Either};
 use embedded_hal::{
     digital::{InputPin, OutputPin},
     spi::SpiDevice,
 };
 use embedded_hal_async::{delay::DelayNs, digital::Wait};
 
+use heapless::Vec;
+
 use crate::{
-    ll::Device,
+    ll::{Device, LdcTimerMult, State},
+    mirror::MirroredFrame,
     packet_format::{PacketFormat, RxMetaData},
+    rssi::Rssi,
+    time::Duration,
     Error, ErrorOf, S2lp,
 };
 
-use super::{Ready, Rx};
+use super::{shutdown::CompiledConfig, tx::TX_WATCHDOG, Ready, Rx};
 
 impl<Spi, Sdn, Gpio, Delay, PF: PacketFormat> S2lp<Rx<'_, PF>, Spi, Sdn, Gpio, Delay>
 where
@@ -21,9 +27,58 @@ where
 {
     /// Just waits for the interrupt without acting on it. This is cancel-safe.
     pub async fn wait_for_irq(&mut self) -> Result<(), Error<(), Sdn::Error, Gpio::Error>> {
-        self.gpio_pin.wait_for_low().await.map_err(Error::Gpio)?;
+        self.wait_for_irq_pin().await.map_err(Error::Gpio)?;
         Ok(())
     }
+
+    /// Check without blocking whether there's something for [S2lp::wait] to handle.
+    ///
+    /// This only looks at the interrupt pin, so it's cheap and works while the spi is taken.
+    /// When this returns true, a call to `wait` will make progress right away,
+    /// e.g. by returning the [RxResult::Timeout] of a timeout that already happened.
+    pub fn has_event(&mut self) -> Result<bool, Error<(), Sdn::Error, Gpio::Error>> {
+        if self.state.rx_done || self.state.pending_irq_status.is_some() {
+            return Ok(true);
+        }
+
+        self.gpio_pin.is_low().map_err(Error::Gpio)
+    }
+}
+
+impl<'b, Sdn, Gpio, Delay, PF: PacketFormat> S2lp<Rx<'b, PF>, (), Sdn, Gpio, Delay>
+where
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    /// Give the spi back to the driver and immediately check whether the RX fifo overflowed while it was taken.
+    ///
+    /// Use this instead of [S2lp::give_spi] when receiving with the spi taken.
+    /// An overflow is then reported by [S2lp::wait] as [RxResult::OverflowWhileDetached],
+    /// which tells the packet was bigger than what the fifo can hold without servicing it (128 bytes).
+    pub fn reattach_spi<Spi: SpiDevice>(
+        self,
+        spi: Spi,
+    ) -> Result<
+        S2lp<Rx<'b, PF>, Spi, Sdn, Gpio, Delay>,
+        ErrorOf<S2lp<Rx<'b, PF>, Spi, Sdn, Gpio, Delay>>,
+    > {
+        let mut this = self.give_spi(spi);
+
+        // Reading the status clears it, so keep it around for the wait to handle
+        let irq_status = this.ll().irq_status().read()?;
+        if irq_status.rx_fifo_error() {
+            #[cfg(feature = "defmt-03")]
+            defmt::warn!(
+                "{=str}: The RX fifo overflowed while the spi was taken",
+                this.label
+            );
+            this.state.overflowed_while_detached = true;
+        }
+        this.state.pending_irq_status = Some(irq_status);
+
+        Ok(this)
+    }
 }
 
 impl<Spi, Sdn, Gpio, Delay, PF: PacketFormat> S2lp<Rx<'_, PF>, Spi, Sdn, Gpio, Delay>
@@ -33,6 +88,41 @@ where
     Gpio: InputPin + Wait,
     Delay: DelayNs,
 {
+    /// When enabled, the receiver is restarted when a packet with a bad CRC is received,
+    /// instead of [Self::wait] returning [RxResult::CrcError].
+    ///
+    /// This keeps noise that caused a false sync from ending the reception.
+    /// Note that a restart also restarts the RX timeout.
+    pub fn set_auto_restart(&mut self, enabled: bool) {
+        self.state.auto_restart = enabled;
+    }
+
+    /// Set what happens when a packet is discarded by the packet filter, e.g. because it's addressed to another node.
+    ///
+    /// Note that continuing also restarts the RX timeout.
+    pub fn set_discard_policy(&mut self, policy: DiscardPolicy) {
+        self.state.discard_policy = policy;
+    }
+
+    /// When enabled, the receiver is restarted right after a packet is received, which also restarts the RX timeout.
+    ///
+    /// [Self::wait] then returns every packet that comes in until the timeout expires,
+    /// which gives 'receive until there's N ms of silence' behavior.
+    /// Read each packet with [Self::packet] before calling [Self::wait] again.
+    pub fn set_restart_after_packet(&mut self, enabled: bool) {
+        self.state.restart_after_packet = enabled;
+    }
+
+    /// The last packet returned by [Self::wait]
+    pub fn packet(&self) -> &[u8] {
+        self.state.rx_buffer.written(self.state.packet_len)
+    }
+
+    /// The amount of packets that were discarded while using [DiscardPolicy::ContinueAndCount]
+    pub fn discarded_count(&self) -> u32 {
+        self.state.discarded_count
+    }
+
     /// Wait for the receive to be done.
     ///
     /// After this is done, call [Self::abort] to get back the radio in the ready state.
@@ -41,79 +131,426 @@ where
             return Ok(RxResult::RxAlreadyDone);
         }
 
+        self.wait_for_result(None).await.map(Option::unwrap)
+    }
+
+    /// Same as [Self::wait], but gives up and returns None when no interrupt comes in within the timeout
+    async fn wait_for_result(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<RxResult<PF::RxMetaData>>, ErrorOf<Self>> {
         loop {
-            // Wait for the interrupt
-            self.gpio_pin.wait_for_low().await.map_err(Error::Gpio)?;
+            let irq_status = match self.state.pending_irq_status.take() {
+                Some(irq_status) => irq_status,
+                None => {
+                    // Wait for the interrupt
+                    match timeout {
+                        None => self.wait_for_irq_pin().await.map_err(Error::Gpio)?,
+                        Some(timeout) => match select(
+                            self.irq_trigger.wait(&mut self.gpio_pin),
+                            self.delay.delay_us(timeout.as_micros()),
+                        )
+                        .await
+                        {
+                            Either::First(res) => res.map_err(Error::Gpio)?,
+                            Either::Second(()) => return Ok(None),
+                        },
+                    }
 
-            // Figure out what's up
-            let irq_status = self.ll().irq_status().read()?;
+                    // Figure out what's up
+                    self.ll().irq_status().read()?
+                }
+            };
 
-            #[cfg(feature = "defmt-03")]
-            defmt::trace!("RX wait interrupt: {}", irq_status);
+            #[cfg(feature = "defmt-verbose")]
+            defmt::trace!(
+                "{=str}: RX wait interrupt: {}",
+                self.label,
+                crate::irq::IrqEvents::from(irq_status)
+            );
+            self.record_irq();
 
-            if irq_status.rx_data_disc()
-                || irq_status.rx_fifo_error()
-                || self.state.written == self.state.rx_buffer.len()
-            {
-                self.ll().abort().dispatch()?;
+            if self.state.low_duty_cycle && irq_status.rx_timeout() && !irq_status.rx_data_ready() {
+                // A listen window ended without a packet. The radio goes back to sleep until the next wake-up by itself.
+                #[cfg(feature = "defmt-verbose")]
+                defmt::trace!("{=str}: Low duty cycle listen window ended", self.label);
+
+                self.ll().flush_rx_fifo().dispatch()?;
+                self.state.written = 0;
+                continue;
+            }
+
+            // On data ready, the rest of the packet is in the fifo.
+            // That can be nothing when the almost full interrupt has drained the fifo right before.
+            let remaining = if irq_status.rx_data_ready() {
+                Some(self.ll().rx_fifo_status().read()?.n_elem_rxfifo() as usize)
+            } else {
+                None
+            };
+            let space = self.state.rx_buffer.capacity() - self.state.written;
+            // A buffer that's exactly filled by the packet is fine
+            let too_big = match remaining {
+                Some(remaining) => remaining > space,
+                None => space == 0,
+            };
+
+            if irq_status.rx_data_disc() || irq_status.rx_fifo_error() || too_big {
+                self.stop_receiver()?;
                 self.ll().flush_rx_fifo().dispatch()?;
+
+                let bad_crc = irq_status.crc_error();
+                let discarded = irq_status.rx_data_disc() && !bad_crc && !irq_status.rx_timeout();
+                let restart = (self.state.auto_restart && bad_crc)
+                    || (discarded && self.state.discard_policy != DiscardPolicy::Stop);
+
+                if restart && !too_big && !irq_status.rx_fifo_error() {
+                    #[cfg(feature = "defmt-03")]
+                    defmt::debug!(
+                        "{=str}: Restarting the receiver after a bad or discarded packet",
+                        self.label
+                    );
+
+                    if discarded && self.state.discard_policy == DiscardPolicy::ContinueAndCount {
+                        self.state.discarded_count = self.state.discarded_count.saturating_add(1);
+                    }
+
+                    self.state.written = 0;
+                    self.restart_receiver()?;
+                    continue;
+                }
+
                 self.state.rx_done = true;
+                self.state.saved_registers.restore(self.ll())?;
 
-                if self.state.written == self.state.rx_buffer.len() {
-                    return Ok(RxResult::TooBigForBuffer);
+                if too_big {
+                    return Ok(Some(RxResult::TooBigForBuffer));
+                } else if irq_status.rx_fifo_error() && self.state.overflowed_while_detached {
+                    return Ok(Some(RxResult::OverflowWhileDetached));
                 } else if irq_status.rx_fifo_error() {
-                    return Ok(RxResult::Fifo);
+                    return Ok(Some(RxResult::Fifo));
                 } else if irq_status.crc_error() {
-                    return Ok(RxResult::CrcError);
+                    return Ok(Some(RxResult::CrcError));
                 } else if irq_status.rx_timeout() {
-                    return Ok(RxResult::Timeout);
+                    return Ok(Some(RxResult::Timeout));
                 } else if irq_status.rx_data_disc() {
-                    return Ok(RxResult::Discarded);
+                    return Ok(Some(RxResult::Discarded));
                 } else {
                     unreachable!()
                 }
             }
 
-            if irq_status.rx_data_ready() || irq_status.rx_fifo_almost_full() {
-                let received = self
-                    .device
-                    .as_mut()
-                    .unwrap()
-                    .fifo()
-                    .read(&mut self.state.rx_buffer[self.state.written..])?;
-                self.state.written += received;
+            let received = match remaining {
+                Some(mut remaining) => {
+                    let mut received = 0;
+                    // The chunking can take multiple reads
+                    while remaining > 0 {
+                        let read = self.device.as_mut().unwrap().fifo().read(
+                            self.state
+                                .rx_buffer
+                                .space(self.state.written + received, remaining),
+                        )?;
+                        received += read;
+                        remaining -= read;
+                    }
+                    received
+                }
+                None if irq_status.rx_fifo_almost_full() => {
+                    // A growable buffer only grows by what's in the fifo
+                    let len = if self.state.rx_buffer.is_growable() {
+                        (self.ll().rx_fifo_status().read()?.n_elem_rxfifo() as usize).min(space)
+                    } else {
+                        space
+                    };
+                    self.device
+                        .as_mut()
+                        .unwrap()
+                        .fifo()
+                        .read(self.state.rx_buffer.space(self.state.written, len))?
+                }
+                None => 0,
+            };
+            self.state.written += received;
 
-                #[cfg(feature = "defmt-03")]
+            #[cfg(feature = "defmt-verbose")]
+            if received > 0 {
                 defmt::trace!(
-                    "Received {} bytes (total = {}) {:X}",
+                    "{=str}: Received {} bytes (total = {}) {:X}",
+                    self.label,
                     received,
                     self.state.written,
-                    &self.state.rx_buffer[..self.state.written]
+                    self.state.rx_buffer.written(self.state.written)
                 );
             }
 
-            if irq_status.rx_data_ready() {
-                self.state.rx_done = true;
-                return Ok(RxResult::Ok {
+            // A raw bitstream has no end, so it's done when the buffer is full
+            let stream_done = PF::DIRECT_FIFO
+                && received > 0
+                && self.state.written == self.state.rx_buffer.capacity();
+            if stream_done {
+                self.stop_receiver()?;
+                self.ll().flush_rx_fifo().dispatch()?;
+            }
+
+            if irq_status.rx_data_ready() || stream_done {
+                self.state.crc_error = irq_status.crc_error();
+                let result = RxResult::Ok {
                     packet_size: self.state.written,
-                    rssi_value: self.ll().rssi_level().read()?.value() as i16 - 146,
+                    rssi_value: self.read_rssi()?,
                     meta_data: PF::RxMetaData::read_from_device(self.ll())?,
-                });
+                };
+
+                if let RxResult::Ok { rssi_value, .. } = result {
+                    self.mirror_packet(rssi_value)?;
+                }
+
+                self.state.packet_len = self.state.written;
+                self.wait_for_auto_ack().await?;
+
+                if self.state.restart_after_packet {
+                    #[cfg(feature = "defmt-03")]
+                    defmt::trace!(
+                        "{=str}: Restarting the receiver for the next packet",
+                        self.label
+                    );
+
+                    // The packet stays in the buffer until the next wait reads from the fifo
+                    self.state.written = 0;
+                    // With low duty cycle, the radio goes on with the next wake-up by itself
+                    if !self.state.low_duty_cycle {
+                        self.ll().rx().dispatch()?;
+                    }
+                } else {
+                    if self.state.low_duty_cycle {
+                        self.stop_receiver()?;
+                    }
+                    self.state.rx_done = true;
+                    self.state.saved_registers.restore(self.ll())?;
+                }
+
+                return Ok(Some(result));
             }
         }
     }
 
+    /// Stop the receiver. With low duty cycle, the cycling is stopped too and the radio can be asleep,
+    /// so it's woken up instead of aborted.
+    fn stop_receiver(&mut self) -> Result<(), ErrorOf<Self>> {
+        if !self.state.low_duty_cycle {
+            self.ll().abort().dispatch()?;
+            return Ok(());
+        }
+
+        self.ll()
+            .protocol_1()
+            .modify(|reg| reg.set_ldc_mode(false))?;
+        if self.ll().mc_state_0().read()?.state() == Ok(State::Rx) {
+            self.ll().abort().dispatch()?;
+        }
+        self.ll().ready().dispatch()?;
+
+        Ok(())
+    }
+
+    /// Start the receiver again after [Self::stop_receiver]
+    fn restart_receiver(&mut self) -> Result<(), ErrorOf<Self>> {
+        if self.state.low_duty_cycle {
+            self.ll()
+                .protocol_1()
+                .modify(|reg| reg.set_ldc_mode(true))?;
+        }
+        self.ll().rx().dispatch()?;
+
+        Ok(())
+    }
+
+    /// Read the RSSI of the received packet using the configured [RssiCapture]
+    fn read_rssi(&mut self) -> Result<Rssi, ErrorOf<Self>> {
+        // Without a sync word, the RSSI is never latched at sync detection
+        let value = match self.rssi_capture {
+            RssiCapture::SyncDetect if !self.state.sync_less => {
+                self.ll().rssi_level().read()?.value()
+            }
+            RssiCapture::SyncDetect | RssiCapture::PacketEnd => {
+                self.ll().rssi_level_run().read()?.value()
+            }
+        };
+
+        Ok(Rssi::from_register(value))
+    }
+
+    /// Copy the received packet into the mirror capture, if there is one
+    fn mirror_packet(&mut self, rssi: Rssi) -> Result<(), ErrorOf<Self>> {
+        if self.mirror.is_none() {
+            return Ok(());
+        }
+
+        let packet_info = self.ll().rx_pckt_info().read()?;
+        let source_address = self.ll().rx_addre_field_1().read()?.value();
+        let destination_address = self.ll().rx_addre_field_0().read()?.value();
+
+        let Some(mirror) = self.mirror.as_deref_mut() else {
+            unreachable!()
+        };
+        let packet = self.state.rx_buffer.written(self.state.written);
+        let head = &packet[..packet.len().min(mirror.head_len())];
+
+        mirror.push(MirroredFrame {
+            packet_size: packet.len(),
+            rssi,
+            sequence_number: packet_info.rx_seq_num(),
+            nack: packet_info.nack_rx(),
+            source_address,
+            destination_address,
+            // The head length is clamped to the capacity
+            head: Vec::from_slice(head).unwrap_or_default(),
+        });
+
+        Ok(())
+    }
+
+    /// If the radio is sending an automatic acknowledgement, wait for it to be sent
+    /// so it doesn't get aborted.
+    ///
+    /// If no interrupt comes in within the [TX_WATCHDOG] time, the ack is aborted and [Error::AckTimeout] is returned.
+    async fn wait_for_auto_ack(&mut self) -> Result<(), ErrorOf<Self>> {
+        let auto_ack = self.ll().protocol_0().read()?.auto_ack();
+        if !auto_ack || self.ll().rx_pckt_info().read()?.nack_rx() {
+            return Ok(());
+        }
+
+        loop {
+            match select(
+                self.irq_trigger.wait(&mut self.gpio_pin),
+                self.delay.delay_us(TX_WATCHDOG.as_micros()),
+            )
+            .await
+            {
+                Either::First(res) => res.map_err(Error::Gpio)?,
+                Either::Second(()) => {
+                    #[cfg(feature = "defmt-03")]
+                    defmt::error!("{=str}: The automatic ack wasn't sent in time", self.label);
+
+                    self.ll().abort().dispatch()?;
+                    return Err(Error::AckTimeout);
+                }
+            }
+
+            if self.ll().irq_status().read()?.tx_data_sent() {
+                break;
+            }
+        }
+
+        #[cfg(feature = "defmt-03")]
+        defmt::trace!("{=str}: Automatic ack has been sent", self.label);
+
+        // The staged ack payload (if any) has been used up
+        self.ll()
+            .protocol_1()
+            .modify(|reg| reg.set_piggybacking(false))?;
+
+        Ok(())
+    }
+
+    /// Start receiving the next packet in the same buffer and with the same configuration,
+    /// e.g. after [RxResult::CrcError] in a busy channel.
+    ///
+    /// This is a lot cheaper than finishing and starting a new reception. Any packet that's being received is dropped.
+    /// The [RX timeout](RxMode) starts over.
+    pub fn rearm(&mut self) -> Result<(), ErrorOf<Self>> {
+        if self.state.rx_done {
+            // The radio is already in ready with an empty fifo, but the session registers were restored
+            let irq_mask = self.state.irq_mask;
+            let ant_select_conf = self.state.saved_registers.ant_select_conf;
+            self.ll().irq_mask().write(|reg| *reg = irq_mask)?;
+            self.ll().ant_select_conf().write(|reg| {
+                *reg = ant_select_conf;
+                reg.set_cs_blanking(true);
+            })?;
+        } else {
+            self.stop_receiver()?;
+            self.ll().flush_rx_fifo().dispatch()?;
+        }
+
+        // Read the irq status to clear it
+        self.ll().irq_status().read()?;
+
+        #[cfg(feature = "defmt-03")]
+        defmt::debug!("{=str}: Rearming the receiver", self.label);
+
+        self.state.written = 0;
+        self.state.packet_len = 0;
+        self.state.rx_done = false;
+        self.state.pending_irq_status = None;
+        self.state.overflowed_while_detached = false;
+        self.restart_receiver()?;
+
+        Ok(())
+    }
+
     /// Aborts the transmission immediately
     pub fn abort(mut self) -> Result<S2lp<Ready<PF>, Spi, Sdn, Gpio, Delay>, ErrorOf<Self>> {
-        self.ll().abort().dispatch()?;
+        self.stop_receiver()?;
         self.ll().flush_rx_fifo().dispatch()?;
+        self.state.saved_registers.restore(self.ll())?;
 
         let digital_frequency = self.state.digital_frequency;
         Ok(self.cast_state(Ready::new(digital_frequency)))
     }
 
+    /// Stop receiving, but let a packet that's coming in right now complete first, e.g. when shutting down a node.
+    ///
+    /// A packet is coming in when some of it has been received already. If not, this stops right away like [Self::abort].
+    /// Otherwise the result of the packet is returned, unless the radio raises no interrupt within the timeout.
+    /// Then the reception is aborted and None is returned. A packet that fits in the fifo raises a single interrupt
+    /// when it's done, longer packets raise one every time the fifo needs to be read.
+    ///
+    /// Automatic restarts are off while waiting, so this doesn't go on to the next packet.
+    /// If [Self::wait] already returned the last result, there's nothing to wait for and None is returned.
+    pub async fn stop_after_current(
+        mut self,
+        timeout: Duration,
+    ) -> Result<
+        (
+            S2lp<Ready<PF>, Spi, Sdn, Gpio, Delay>,
+            Option<RxResult<PF::RxMetaData>>,
+        ),
+        ErrorOf<Self>,
+    > {
+        if self.state.rx_done {
+            let digital_frequency = self.state.digital_frequency;
+            return Ok((self.cast_state(Ready::new(digital_frequency)), None));
+        }
+
+        let in_progress = self.state.pending_irq_status.is_some()
+            || self.state.written > 0
+            || self.gpio_pin.is_low().map_err(Error::Gpio)?
+            || self.ll().rx_fifo_status().read()?.n_elem_rxfifo() > 0;
+        if !in_progress {
+            return Ok((self.abort()?, None));
+        }
+
+        #[cfg(feature = "defmt-03")]
+        defmt::debug!(
+            "{=str}: Waiting for the current packet before stopping the receiver",
+            self.label
+        );
+
+        self.state.auto_restart = false;
+        self.state.discard_policy = DiscardPolicy::Stop;
+        self.state.restart_after_packet = false;
+
+        match self.wait_for_result(Some(timeout)).await? {
+            Some(result) => {
+                let digital_frequency = self.state.digital_frequency;
+                Ok((self.cast_state(Ready::new(digital_frequency)), Some(result)))
+            }
+            None => Ok((self.abort()?, None)),
+        }
+    }
+
     /// Finish the transmission. This only returns ok when the [Self::wait] function has returned.
     /// If you need to stop the transmission before it's done, call [Self::abort].
+    #[allow(clippy::result_large_err)] // The driver is handed back as is, so it can be retried
     pub fn finish(self) -> Result<S2lp<Ready<PF>, Spi, Sdn, Gpio, Delay>, Self> {
         if self.state.rx_done {
             let digital_frequency = self.state.digital_frequency;
@@ -132,8 +569,8 @@ pub enum RxResult<MetaData> {
     Ok {
         /// The size of the received packet in bytes
         packet_size: usize,
-        /// The RSSI value in dB
-        rssi_value: i16,
+        /// The RSSI value, sampled at the moment set by [RssiCapture]
+        rssi_value: Rssi,
         /// Format-specific metadata like addresses
         meta_data: MetaData,
     },
@@ -141,6 +578,9 @@ pub enum RxResult<MetaData> {
     RxAlreadyDone,
     /// The RX fifo filled up too fast and we couldn't keep up
     Fifo,
+    /// The RX fifo overflowed while the spi was taken, so the fifo couldn't be serviced.
+    /// See [S2lp::reattach_spi].
+    OverflowWhileDetached,
     /// While receiving the packet, it got filtered out
     Discarded,
     /// The received packet has a bad CRC
@@ -151,8 +591,36 @@ pub enum RxResult<MetaData> {
     Timeout,
 }
 
+/// What the receiver does when a packet is discarded by the packet filter
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum DiscardPolicy {
+    /// Stop the reception and return [RxResult::Discarded]
+    #[default]
+    Stop,
+    /// Silently restart the receiver
+    Continue,
+    /// Restart the receiver and count the discarded packets. See [S2lp::discarded_count].
+    ContinueAndCount,
+}
+
+/// The moment at which the RSSI of a received packet is sampled
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum RssiCapture {
+    /// Use the RSSI the radio latches when the sync word is detected (`RSSI_LEVEL`).
+    ///
+    /// This is measured on the preamble and sync word only, so it's the most accurate per-packet value.
+    #[default]
+    SyncDetect,
+    /// Use the running RSSI (`RSSI_LEVEL_RUN`) as read right after the packet is received.
+    ///
+    /// This is measured at the end of the packet, so it can already include noise or other transmitters.
+    PacketEnd,
+}
+
 /// The mode of receiving
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
 pub enum RxMode {
     /// Normal, default, receiving where the receiver will just be on
@@ -161,10 +629,26 @@ pub enum RxMode {
         /// If none, the receiver will stay on until a packet has been received or the operation is aborted.
         timeout: Option<RxTimeout>,
     },
+    /// Low duty cycle receiving. The radio sleeps and wakes up every `wake_up_interval` to listen for `timeout`.
+    /// When nothing is heard, it goes back to sleep, so the average current can be in the microamps.
+    ///
+    /// The wake-up timer runs on the RCO, so the interval is approximate. Longer intervals than the radio supports (~15s) are clamped.
+    /// Use a timeout mask that keeps the receiver on for a packet that's coming in, like the default [RxTimeoutMask::Sqi].
+    /// The receiving only stops with a packet (or a bad one) or an abort, never with [RxResult::Timeout].
     LowDutyCycle {
+        /// The time between the wake-ups
+        wake_up_interval: Duration,
+        /// The time the receiver is on after every wake-up
         timeout: RxTimeout,
     },
+    /// Receive with carrier sense based termination. The receiver stops early when the RSSI stays below the
+    /// threshold once it has settled (see [S2lp::set_rssi_settling_limit](crate::S2lp::set_rssi_settling_limit)).
+    ///
+    /// The almost full/empty gpio outputs are switched to the RX fifo (see [FifoGpioMux](super::addressable::FifoGpioMux)) so the first chunk
+    /// of a sniffed packet isn't missed.
     Sniff {
+        /// The time after which the receiving stops.
+        /// Use a mask with the RSSI in it to keep the receiver on while there's a carrier.
         timeout: RxTimeout,
     },
 }
@@ -180,34 +664,69 @@ impl RxMode {
         &self,
         device: &mut Device<I>,
         digital_frequency: u32,
+        sync_less: bool,
     ) -> Result<(), I::Error> {
         match self {
             RxMode::Normal {
                 timeout: Some(timeout),
             } => {
-                timeout.write_to_device(device, digital_frequency)?;
+                timeout.write_to_device(device, digital_frequency, sync_less)?;
             }
             RxMode::Normal { timeout: None } => {
                 RxTimeout {
-                    timeout_us: 0,
+                    timeout: Duration::ZERO,
                     mask: RxTimeoutMask::_NoTimeout,
                 }
-                .write_to_device(device, digital_frequency)?;
+                .write_to_device(device, digital_frequency, sync_less)?;
+            }
+            RxMode::LowDutyCycle {
+                wake_up_interval,
+                timeout,
+            } => {
+                timeout.write_to_device(device, digital_frequency, sync_less)?;
+
+                let (multiplier, prescaler, counter) = find_ldc_timer_values(
+                    wake_up_interval.as_micros(),
+                    rco_frequency(digital_frequency),
+                );
+                device
+                    .protocol_2()
+                    .modify(|reg| reg.set_ldc_timer_mult(multiplier))?;
+                device
+                    .timers_3()
+                    .write(|reg| reg.set_ldc_timer_presc(prescaler))?;
+                device
+                    .timers_2()
+                    .write(|reg| reg.set_ldc_timer_cntr(counter))?;
+            }
+            RxMode::Sniff { timeout } => {
+                timeout.write_to_device(device, digital_frequency, sync_less)?;
+                device
+                    .protocol_2()
+                    .modify(|reg| reg.set_fifo_gpio_out_mux_sel(true))?;
             }
-            RxMode::LowDutyCycle { timeout: _ } => todo!(),
-            RxMode::Sniff { timeout: _ } => todo!(),
         }
 
+        device.protocol_1().modify(|reg| {
+            reg.set_fast_cs_term_en(matches!(self, RxMode::Sniff { .. }));
+            reg.set_ldc_mode(self.is_low_duty_cycle());
+        })?;
+
         Ok(())
     }
+
+    pub(crate) fn is_low_duty_cycle(&self) -> bool {
+        matches!(self, RxMode::LowDutyCycle { .. })
+    }
 }
 
 /// Timeout settings for the receiver
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
 pub struct RxTimeout {
-    /// The amount of time after which the RX timer timeout happens
-    pub timeout_us: u32,
+    /// The amount of time after which the RX timer timeout happens.
+    /// Longer timeouts than the radio supports (~3s) are clamped.
+    pub timeout: Duration,
     /// A mask to prevent the timout from aborting the RX
     pub mask: RxTimeoutMask,
 }
@@ -217,25 +736,32 @@ impl RxTimeout {
         &self,
         device: &mut Device<I>,
         digital_frequency: u32,
+        sync_less: bool,
     ) -> Result<(), I::Error> {
+        let mut mask = self.mask as u8;
+        if sync_less && (mask & 0b0010) > 0 {
+            // There's no sync word to stop the timer, so the preamble has to take its place
+            mask = (mask & !0b0010) | 0b0001;
+        }
+
         device
             .pckt_flt_options()
-            .modify(|reg| reg.set_rx_timeout_and_or_sel((self.mask as u8 & 0b1000) > 0))?;
+            .modify(|reg| reg.set_rx_timeout_and_or_sel((mask & 0b1000) > 0))?;
 
         device.protocol_2().modify(|reg| {
-            reg.set_cs_timeout_mask((self.mask as u8 & 0b0100) > 0);
-            reg.set_sqi_timeout_mask((self.mask as u8 & 0b0010) > 0);
-            reg.set_pqi_timeout_mask((self.mask as u8 & 0b0001) > 0);
+            reg.set_cs_timeout_mask((mask & 0b0100) > 0);
+            reg.set_sqi_timeout_mask((mask & 0b0010) > 0);
+            reg.set_pqi_timeout_mask((mask & 0b0001) > 0);
         })?;
 
         let (prescaler, counter, overflow) =
-            find_rx_timer_prescaler_and_counter(self.timeout_us, digital_frequency);
+            find_rx_timer_prescaler_and_counter(self.timeout.as_micros(), digital_frequency);
 
         if overflow {
             #[cfg(feature = "defmt-03")]
             defmt::warn!(
-                "RX timeout ({=u32}) is longer than is supported. Max value is used (~3s)",
-                self.timeout_us
+                "RX timeout ({}) is longer than is supported. Max value is used (~3s)",
+                self.timeout
             );
         }
 
@@ -250,7 +776,123 @@ impl RxTimeout {
     }
 }
 
+impl RxTimeout {
+    /// Start building a timeout with validated stop conditions. See [RxTimeoutBuilder].
+    #[allow(clippy::new_ret_no_self)] // The struct literal stays available for the unchecked way
+    pub const fn new(timeout: Duration) -> RxTimeoutBuilder {
+        RxTimeoutBuilder {
+            timeout,
+            conditions: 0,
+            require_all: false,
+        }
+    }
+
+    /// The timeout the radio actually uses with the config, after rounding to the steps of the timer.
+    /// It's never shorter than asked for, unless it's longer than the radio supports.
+    pub fn actual_timeout(&self, config: &CompiledConfig) -> Duration {
+        let (prescaler, counter, _) =
+            find_rx_timer_prescaler_and_counter(self.timeout.as_micros(), config.digital_frequency);
+
+        let ticks = (prescaler as u64 + 1) * (counter as u64).saturating_sub(1);
+        let micros = ticks * 1210 * 1_000_000 / config.digital_frequency as u64;
+        Duration::from_micros(micros.min(u32::MAX as u64) as u32)
+    }
+}
+
+/// The longest RX timeout the timer can count at any crystal frequency
+pub const MAX_RX_TIMEOUT: Duration = Duration::from_micros(3_026_116);
+
+/// Builds an [RxTimeout] from stop conditions instead of picking an [RxTimeoutMask].
+///
+/// A stop condition stops the RX timer when it passes, so a packet that's coming in isn't cut off.
+/// Without any, the timer can't be stopped. With more than one, any of them stops the timer,
+/// or all of them together with [Self::require_all].
+///
+/// ```rust
+/// # use s2lp::{states::rx::{RxTimeout, RxTimeoutMask}, time::Duration};
+/// let timeout = RxTimeout::new(Duration::from_millis(50))
+///     .stop_on_rssi()
+///     .stop_on_sqi()
+///     .build()
+///     .unwrap();
+/// assert_eq!(timeout.mask, RxTimeoutMask::RssiOrSqi);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct RxTimeoutBuilder {
+    timeout: Duration,
+    /// The condition bits of the [RxTimeoutMask]
+    conditions: u8,
+    require_all: bool,
+}
+
+impl RxTimeoutBuilder {
+    /// Stop the timer when the RSSI is above the [threshold](crate::S2lp::set_rssi_threshold)
+    pub const fn stop_on_rssi(mut self) -> Self {
+        self.conditions |= RxTimeoutMask::Rssi as u8;
+        self
+    }
+
+    /// Stop the timer when a sync word passes the
+    /// [sync quality threshold](crate::packet_format::PacketFilteringOptions::sync_quality_threshold)
+    pub const fn stop_on_sqi(mut self) -> Self {
+        self.conditions |= RxTimeoutMask::Sqi as u8;
+        self
+    }
+
+    /// Stop the timer when a preamble passes the
+    /// [preamble quality threshold](crate::packet_format::PacketFilteringOptions::preamble_quality_threshold)
+    pub const fn stop_on_pqi(mut self) -> Self {
+        self.conditions |= RxTimeoutMask::Pqi as u8;
+        self
+    }
+
+    /// Only stop the timer when all the stop conditions pass at the same time
+    pub const fn require_all(mut self) -> Self {
+        self.require_all = true;
+        self
+    }
+
+    /// Check the timeout and the stop conditions and build the [RxTimeout]
+    pub const fn build(self) -> Result<RxTimeout, &'static str> {
+        if self.timeout.as_micros() == 0 {
+            return Err("A zero RX timeout turns the timer off. Use no timeout instead");
+        }
+        if self.timeout.as_micros() > MAX_RX_TIMEOUT.as_micros() {
+            return Err("The RX timeout is longer than the radio supports");
+        }
+        if self.require_all && self.conditions == 0 {
+            return Err("Requiring all stop conditions needs at least one");
+        }
+
+        let or_bit = if self.require_all { 0 } else { 0b1000 };
+        let mask = match self.conditions | or_bit {
+            0b1000 => RxTimeoutMask::None,
+            0b0100 | 0b1100 => RxTimeoutMask::Rssi,
+            0b0010 | 0b1010 => RxTimeoutMask::Sqi,
+            0b0001 | 0b1001 => RxTimeoutMask::Pqi,
+            0b0110 => RxTimeoutMask::RssiAndSqi,
+            0b0101 => RxTimeoutMask::RssiAndPqi,
+            0b0011 => RxTimeoutMask::SqiAndPqi,
+            0b0111 => RxTimeoutMask::All,
+            0b1110 => RxTimeoutMask::RssiOrSqi,
+            0b1101 => RxTimeoutMask::RssiOrPqi,
+            0b1011 => RxTimeoutMask::SqiOrPqi,
+            _ => RxTimeoutMask::Any,
+        };
+
+        Ok(RxTimeout {
+            timeout: self.timeout,
+            mask,
+        })
+    }
+}
+
 /// The mask for the RX timer. It can prevent the timer from expiring in situations where it's not desired.
+///
+/// When the sync length is 0, the SQI can never pass, so the PQI is used in its place.
+///
+/// The values are combinations of stop conditions. [RxTimeout::new] builds them from the conditions instead.
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
 #[repr(u8)]
@@ -289,6 +931,43 @@ pub enum RxTimeoutMask {
     Any = 0b1111,
 }
 
+/// The frequency of the RCO that runs the wake-up timer in Hz.
+/// It's derived from the crystal, so it's slightly different for the 25 MHz digital domain.
+fn rco_frequency(digital_frequency: u32) -> u32 {
+    if digital_frequency.is_multiple_of(25_000_000) {
+        34_700
+    } else {
+        33_300
+    }
+}
+
+/// Find the multiplier, prescaler and counter of the wake-up timer for the given time.
+/// The time is `multiplier * (prescaler + 1) * (counter + 1) / rco_frequency`.
+///
+/// Longer times than the timer can count are clamped.
+fn find_ldc_timer_values(time_microseconds: u32, rco_frequency: u32) -> (LdcTimerMult, u8, u8) {
+    const MAX_STEPS: u64 = 256 * 256;
+
+    let ticks = (time_microseconds as u64 * rco_frequency as u64).div_ceil(1_000_000);
+
+    let (multiplier, factor) = [
+        (LdcTimerMult::X1, 1),
+        (LdcTimerMult::X2, 2),
+        (LdcTimerMult::X4, 4),
+        (LdcTimerMult::X8, 8),
+    ]
+    .into_iter()
+    .find(|(_, factor)| ticks.div_ceil(*factor) <= MAX_STEPS)
+    .unwrap_or((LdcTimerMult::X8, 8));
+
+    let steps = ticks.div_ceil(factor).clamp(2, MAX_STEPS);
+    // Like the RX timer, the prescaler isn't set below 1
+    let prescaler = steps.div_ceil(256).max(2);
+    let counter = steps.div_ceil(prescaler).clamp(1, 256);
+
+    (multiplier, (prescaler - 1) as u8, (counter - 1) as u8)
+}
+
 fn find_rx_timer_prescaler_and_counter(
     time_microseconds: u32,
     digital_frequency: u32,
@@ -323,6 +1002,7 @@ fn find_rx_timer_prescaler_and_counter(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::states::shutdown::Config;
 
     fn calculate_rx_timeout(prescaler: u8, counter: u8, digital_frequency: f64) -> f64 {
         (prescaler as f64 + 1.0) * (counter as f64 - 1.0) / (digital_frequency / 1210.0)
@@ -366,4 +1046,81 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn ldc_timer() {
+        fn wake_up_us(values: (LdcTimerMult, u8, u8), rco_frequency: u32) -> u64 {
+            let factor = match values.0 {
+                LdcTimerMult::X1 => 1,
+                LdcTimerMult::X2 => 2,
+                LdcTimerMult::X4 => 4,
+                LdcTimerMult::X8 => 8,
+            };
+            factor * (values.1 as u64 + 1) * (values.2 as u64 + 1) * 1_000_000
+                / rco_frequency as u64
+        }
+
+        for us in [1_000, 100_000, 1_000_000, 10_000_000] {
+            let values = find_ldc_timer_values(us, 33_300);
+            let actual = wake_up_us(values, 33_300);
+            // Never shorter and at most a timer step longer
+            assert!(actual >= us as u64, "{us} -> {actual}");
+            assert!(actual <= us as u64 * 101 / 100 + 1_000, "{us} -> {actual}");
+        }
+
+        // Clamped to the longest time
+        let values = find_ldc_timer_values(u32::MAX, 33_300);
+        assert!(matches!(values, (LdcTimerMult::X8, 255, 255)));
+    }
+
+    #[test]
+    fn rx_timeout_builder() {
+        let timeout = Duration::from_millis(10);
+        let build = |builder: RxTimeoutBuilder| builder.build().map(|timeout| timeout.mask);
+
+        assert_eq!(build(RxTimeout::new(timeout)), Ok(RxTimeoutMask::None));
+        assert_eq!(
+            build(RxTimeout::new(timeout).stop_on_sqi()),
+            Ok(RxTimeoutMask::Sqi)
+        );
+        assert_eq!(
+            build(RxTimeout::new(timeout).stop_on_sqi().require_all()),
+            Ok(RxTimeoutMask::Sqi)
+        );
+        assert_eq!(
+            build(RxTimeout::new(timeout).stop_on_rssi().stop_on_pqi()),
+            Ok(RxTimeoutMask::RssiOrPqi)
+        );
+        assert_eq!(
+            build(
+                RxTimeout::new(timeout)
+                    .stop_on_rssi()
+                    .stop_on_sqi()
+                    .stop_on_pqi()
+                    .require_all()
+            ),
+            Ok(RxTimeoutMask::All)
+        );
+        assert!(build(RxTimeout::new(timeout).require_all()).is_err());
+        assert!(build(RxTimeout::new(Duration::ZERO)).is_err());
+        assert!(build(RxTimeout::new(Duration::from_secs(4))).is_err());
+
+        // The longest timeout fits at the highest digital frequency
+        let config = Config {
+            xtal_frequency: 26_000_000,
+            ..Config::default()
+        }
+        .compile();
+        let longest = RxTimeout::new(MAX_RX_TIMEOUT).build().unwrap();
+        let actual = longest.actual_timeout(&config);
+        assert!(actual >= MAX_RX_TIMEOUT);
+        assert!(actual.as_micros() - MAX_RX_TIMEOUT.as_micros() < 20_000);
+
+        let actual = RxTimeout::new(timeout)
+            .build()
+            .unwrap()
+            .actual_timeout(&config);
+        assert!(actual >= timeout);
+        assert!(actual.as_micros() - timeout.as_micros() < 100);
+    }
 }
@@ -5,13 +5,15 @@ use embedded_hal::{
 use embedded_hal_async::{delay::DelayNs, digital::Wait};
 
 use crate::{
-    ll::{Device, DeviceInterface, GpioSelectOutput, SleepModeSel, State},
+    diagnostics::FatalError,
+    ll::{field_sets::IrqMask, Device, DeviceInterface, GpioSelectOutput, SleepModeSel, State},
     packet_format::Uninitialized,
     states::addressable::GpioFunction,
-    Error, ErrorOf, GpioNumber, S2lp,
+    time::Duration,
+    Error, ErrorOf, GpioNumber, IrqTrigger, RadioParts, S2lp, DEFAULT_LABEL,
 };
 
-use super::{Ready, Shutdown};
+use super::{rx::RssiCapture, Ready, Shutdown};
 
 impl<Spi, Sdn, Gpio, Delay> S2lp<Shutdown, Spi, Sdn, Gpio, Delay>
 where
@@ -40,34 +42,83 @@ where
             shutdown_pin,
             gpio_pin,
             gpio_number,
+            irq_trigger: IrqTrigger::Level,
+            rssi_capture: RssiCapture::SyncDetect,
+            label: DEFAULT_LABEL,
+            rco_calibration_wait: RcoCalibrationWait::DEFAULT,
+            inter_packet_gap: Duration::ZERO,
+            mirror: None,
+            diagnostics: None,
             delay,
             state: Shutdown,
         }
     }
 
+    /// Set how long init waits for the RCO calibration. See [RcoCalibrationWait].
+    pub fn set_rco_calibration_wait(&mut self, wait: RcoCalibrationWait) {
+        self.rco_calibration_wait = wait;
+    }
+
+    /// Get how long init waits for the RCO calibration
+    pub fn rco_calibration_wait(&self) -> RcoCalibrationWait {
+        self.rco_calibration_wait
+    }
+
     /// Initialize the radio chip
+    ///
+    /// The register values are calculated from the config at runtime.
+    /// For fixed configurations, [Self::init_compiled] can move this work to compile time.
     pub async fn init(
-        mut self,
+        self,
         config: Config,
     ) -> Result<S2lp<Ready<Uninitialized>, Spi, Sdn, Gpio, Delay>, ErrorOf<Self>> {
-        if !is_frequency_band(config.base_frequency) {
-            return Err(Error::BadConfig {
-                reason: "Base frequency out of range",
-            });
-        }
-        if !is_datarate(config.datarate, config.xtal_frequency) {
-            return Err(Error::BadConfig {
-                reason: "Datarate out of range",
-            });
-        }
-        if !is_f_dev(config.frequency_deviation, config.xtal_frequency) {
-            return Err(Error::BadConfig {
-                reason: "Frequency deviation out of range",
-            });
-        }
+        Ok(self.init_with_warnings(config).await?.0)
+    }
+
+    /// Initialize the radio chip like [Self::init] and return where the radio couldn't match the config exactly.
+    ///
+    /// For a compiled config, the warnings are available with [CompiledConfig::warnings].
+    pub async fn init_with_warnings(
+        self,
+        config: Config,
+    ) -> Result<
+        (
+            S2lp<Ready<Uninitialized>, Spi, Sdn, Gpio, Delay>,
+            heapless::Vec<InitWarning, MAX_INIT_WARNINGS>,
+        ),
+        ErrorOf<Self>,
+    > {
+        let config = config
+            .try_compile()
+            .map_err(|reason| Error::BadConfig { reason })?;
+
+        Ok((self.init_compiled(&config).await?, config.warnings()))
+    }
 
+    /// Initialize the radio chip with a config of which the register values have already been calculated.
+    ///
+    /// When the config is compiled into a const, the datarate, frequency deviation and bandwidth searches
+    /// all run at compile time and an invalid config is a compile error:
+    ///
+    /// ```rust
+    /// use s2lp::states::shutdown::{CompiledConfig, Config, DataRate, ModulationType};
+    ///
+    /// const RADIO_CONFIG: CompiledConfig = Config {
+    ///     xtal_frequency: 50_000_000,
+    ///     base_frequency: 868_000_000,
+    ///     modulation: ModulationType::Fsk2,
+    ///     datarate: DataRate::bits_per_sec(38_400),
+    ///     frequency_deviation: 20_000,
+    ///     bandwidth: 100_000,
+    /// }
+    /// .compile();
+    /// ```
+    pub async fn init_compiled(
+        mut self,
+        config: &CompiledConfig,
+    ) -> Result<S2lp<Ready<Uninitialized>, Spi, Sdn, Gpio, Delay>, ErrorOf<Self>> {
         #[cfg(feature = "defmt-03")]
-        defmt::debug!("Resetting the radio");
+        defmt::debug!("{=str}: Resetting the radio", self.label);
 
         self.shutdown_pin.set_high().map_err(Error::Sdn)?;
         self.delay.delay_us(1).await;
@@ -75,28 +126,570 @@ where
 
         if self.gpio_number == GpioNumber::Gpio0 {
             #[cfg(feature = "defmt-03")]
-            defmt::trace!("Waiting for POR");
+            defmt::trace!("{=str}: Waiting for POR", self.label);
             self.gpio_pin.wait_for_high().await.map_err(Error::Gpio)?;
         } else {
             #[cfg(feature = "defmt-03")]
-            defmt::trace!("Waiting for reset delay");
+            defmt::trace!("{=str}: Waiting for reset delay", self.label);
             self.delay.delay_ms(2).await;
         }
 
         let mut this = self.cast_state(Ready::new(0));
+        this.configure(config)?;
 
+        // Datasheet 5.7 part 2
+        this.wait_for_rco_calibration().await?;
+
+        this.finish_init(config)?;
+        Ok(this)
+    }
+
+    /// Initialize the radio chip like [Self::init_compiled], but without using the delay.
+    ///
+    /// This is for hosts that schedule the waits themselves, e.g. in a time-triggered architecture.
+    /// Every [PendingInit] says how long to wait before calling [PendingInit::resume] for the next step.
+    /// Instead of waiting for the power-on-reset on gpio 0, the worst case startup delay is always used.
+    pub fn init_stepped(
+        mut self,
+        config: &CompiledConfig,
+    ) -> Result<PendingInit<Spi, Sdn, Gpio, Delay>, ErrorOf<Self>> {
         #[cfg(feature = "defmt-03")]
-        defmt::trace!("Checking interface works");
+        defmt::debug!("{=str}: Resetting the radio", self.label);
+
+        self.shutdown_pin.set_high().map_err(Error::Sdn)?;
+
+        Ok(PendingInit {
+            config: *config,
+            phase: InitPhase::Reset(self),
+        })
+    }
+}
+
+/// The time the shutdown pin is held high to reset the radio
+const RESET_PULSE: Duration = Duration::from_micros(1);
+/// The worst case time the radio needs to boot after a reset
+const STARTUP_DELAY: Duration = Duration::from_millis(2);
+
+/// An init from [S2lp::init_stepped] that's waiting for time to pass.
+///
+/// Wait for [Self::wait_time] with the scheduler of the host and then call [Self::resume].
+#[must_use]
+pub struct PendingInit<Spi, Sdn: OutputPin, Gpio: InputPin + Wait, Delay: DelayNs> {
+    config: CompiledConfig,
+    phase: InitPhase<Spi, Sdn, Gpio, Delay>,
+}
+
+enum InitPhase<Spi, Sdn: OutputPin, Gpio: InputPin + Wait, Delay: DelayNs> {
+    /// The shutdown pin is high
+    Reset(S2lp<Shutdown, Spi, Sdn, Gpio, Delay>),
+    /// The shutdown pin is low again and the radio is booting
+    Boot(S2lp<Shutdown, Spi, Sdn, Gpio, Delay>),
+    /// The radio is configured and the RCO is calibrating
+    RcoCalibration(
+        S2lp<Ready<Uninitialized>, Spi, Sdn, Gpio, Delay>,
+        RcoProgress,
+    ),
+}
+
+/// The result of [PendingInit::resume]
+pub enum InitStep<Spi, Sdn: OutputPin, Gpio: InputPin + Wait, Delay: DelayNs> {
+    /// Wait some more
+    Pending(PendingInit<Spi, Sdn, Gpio, Delay>),
+    /// The init is done
+    Done(S2lp<Ready<Uninitialized>, Spi, Sdn, Gpio, Delay>),
+}
+
+impl<Spi, Sdn, Gpio, Delay> PendingInit<Spi, Sdn, Gpio, Delay>
+where
+    Spi: SpiDevice,
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    /// How long to wait before calling [Self::resume]
+    pub fn wait_time(&self) -> Duration {
+        match self.phase {
+            InitPhase::Reset(_) => RESET_PULSE,
+            InitPhase::Boot(_) => STARTUP_DELAY,
+            InitPhase::RcoCalibration(..) => Duration::from_micros(RCO_POLL_INTERVAL_US),
+        }
+    }
+
+    /// Do the next step of the init after at least [Self::wait_time] has passed
+    pub fn resume(
+        self,
+    ) -> Result<InitStep<Spi, Sdn, Gpio, Delay>, ErrorOf<S2lp<Shutdown, Spi, Sdn, Gpio, Delay>>>
+    {
+        let config = self.config;
+
+        let phase = match self.phase {
+            InitPhase::Reset(mut radio) => {
+                radio.shutdown_pin.set_low().map_err(Error::Sdn)?;
+                InitPhase::Boot(radio)
+            }
+            InitPhase::Boot(radio) => {
+                let mut this = radio.cast_state(Ready::new(0));
+                this.configure(&config)?;
+                InitPhase::RcoCalibration(this, RcoProgress::default())
+            }
+            InitPhase::RcoCalibration(mut this, mut progress) => {
+                if !this.poll_rco_calibration(&mut progress)? {
+                    InitPhase::RcoCalibration(this, progress)
+                } else {
+                    this.finish_init(&config)?;
+                    return Ok(InitStep::Done(this));
+                }
+            }
+        };
+
+        Ok(InitStep::Pending(PendingInit { config, phase }))
+    }
+}
+
+pub use crate::ll::ModulationType;
+
+/// The radio configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    /// The frequency of the crystal oscillator
+    pub xtal_frequency: u32,
+    /// Specifies the carrier frequency of channel 0 in Hz.
+    ///
+    /// Possible values:
+    /// - High band (860 MHz - 940 MHz)
+    /// - Middle band (430 MHz - 470 MHz)
+    pub base_frequency: u32,
+    /// The modulation the radio will use
+    pub modulation: ModulationType,
+    /// The datarate used. The radio is programmed with the symbol rate, which must be between 100 and 250k symbols per second.
+    ///
+    /// For the 4-level modulations, every symbol carries 2 bits.
+    pub datarate: DataRate,
+    /// Frequency deviation in Hz. This is used for (G)FSK.
+    ///
+    /// - Min: `F_Xo * 8 / 0x40000`
+    /// - Max: `F_Xo * 7680 / 0x40000 `
+    pub frequency_deviation: u32,
+    /// Channel (filter) bandwidth in Hz between 1100 Hz - 800100 Hz
+    pub bandwidth: u32,
+    // TODO:
+    // pub pa_info: PaInfo,
+}
+
+impl Config {
+    /// Calculate all register values of the config. Meant to be used in a const.
+    ///
+    /// Panics if the config is invalid, which in a const is a compile error.
+    pub const fn compile(self) -> CompiledConfig {
+        match self.try_compile() {
+            Ok(config) => config,
+            Err(reason) => panic!("{}", reason),
+        }
+    }
+
+    /// Calculate all register values of the config.
+    ///
+    /// If the config is invalid, the reason is returned.
+    pub const fn try_compile(self) -> Result<CompiledConfig, &'static str> {
+        if !is_frequency_band(self.base_frequency) {
+            return Err("Base frequency out of range");
+        }
+        let Some(symbol_rate) = self.datarate.symbol_rate(self.modulation) else {
+            return Err("Datarate in bits per second must be even for 4-level modulation");
+        };
+        if !is_datarate(symbol_rate, self.xtal_frequency) {
+            return Err("Datarate out of range");
+        }
+        if !is_f_dev(self.frequency_deviation, self.xtal_frequency) {
+            return Err("Frequency deviation out of range");
+        }
+
+        // Datasheet 4.7 - A slow crystal drives the digital domain directly, a fast one is divided by 2
+        let pd_clkdiv = self.xtal_frequency < DIG_DOMAIN_XTAL_THRESH;
+        let digital_frequency = self.xtal_frequency / if pd_clkdiv { 1 } else { 2 };
+
+        if !is_ch_bw(self.bandwidth, digital_frequency) {
+            return Err("Bandwidth out of range");
+        }
+
+        // Datasheet 5.5.5 - The recommended Intermediate Frequency (IF)
+        const IF: u64 = 300_000;
+        let if_offset_ana = ((IF << 13) * 3 / self.xtal_frequency as u64 - 100) as u8;
+        let if_offset_dig = ((IF << 13) * 3 / digital_frequency as u64 - 100) as u8;
+
+        let (datarate_mantissa, datarate_exponent) =
+            find_datarate_mantissa_exponent(symbol_rate, digital_frequency);
+
+        let band_factor = get_band_factor(self.base_frequency);
+        // The reference divider is never enabled, so it's at its reset value
+        let refdiv = 1;
+
+        let (fdev_mantissa, fdev_exponent) = find_fdev_mantissa_exponent(
+            self.frequency_deviation,
+            self.xtal_frequency,
+            band_factor,
+            refdiv,
+        );
+
+        let (ch_flt_mantissa, ch_flt_exponent) =
+            search_channel_filter_bandwidth(self.bandwidth, digital_frequency);
+
+        let pa_fc = match symbol_rate {
+            ..16000 => crate::ll::PaFc::Khz12P5,
+            16000..32000 => crate::ll::PaFc::Khz25,
+            32000..62500 => crate::ll::PaFc::Khz50,
+            62500.. => crate::ll::PaFc::Khz100,
+        };
+
+        let synt_target =
+            ((self.base_frequency as u64) << 20) * (band_factor / 2) as u64 * refdiv as u64;
+        let synt =
+            ((synt_target + self.xtal_frequency as u64 / 2) / self.xtal_frequency as u64) as u32;
+
+        let vco_freq = self.base_frequency as u64 * band_factor as u64;
+        let f_ref = self.xtal_frequency / refdiv;
+
+        let (cp_isel, pfd_split) =
+            match (vco_freq >= VCO_CENTER_FREQ, f_ref >= DIG_DOMAIN_XTAL_THRESH) {
+                (true, true) => (0x02, false),
+                (true, false) => (0x01, true),
+                (false, true) => (0x03, false),
+                (false, false) => (0x02, true),
+            };
+
+        let bits_per_symbol = bits_per_symbol(self.modulation);
+        let warnings = InitWarnings {
+            datarate: (
+                self.datarate.bit_rate(self.modulation),
+                compute_datarate(digital_frequency, datarate_mantissa, datarate_exponent)
+                    * bits_per_symbol,
+            ),
+            frequency_deviation: (
+                self.frequency_deviation,
+                compute_fdev(
+                    self.xtal_frequency,
+                    fdev_mantissa,
+                    fdev_exponent,
+                    band_factor,
+                    refdiv,
+                ),
+            ),
+            bandwidth: (
+                self.bandwidth,
+                compute_channel_filter_bandwidth(
+                    ch_flt_mantissa,
+                    ch_flt_exponent,
+                    digital_frequency,
+                ),
+            ),
+        };
+
+        Ok(CompiledConfig {
+            pd_clkdiv,
+            digital_frequency,
+            if_offset_ana,
+            if_offset_dig,
+            modulation: self.modulation,
+            datarate_mantissa,
+            datarate_exponent,
+            middle_band: is_frequency_band_middle(self.base_frequency),
+            fdev_mantissa,
+            fdev_exponent,
+            ch_flt_mantissa,
+            ch_flt_exponent,
+            pa_fc,
+            synt,
+            cp_isel,
+            pfd_split,
+            warnings,
+        })
+    }
+}
+
+/// The rate at which data is sent over the air
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct DataRate {
+    value: u32,
+    in_symbols: bool,
+}
+
+impl DataRate {
+    /// The rate in bits per second. For 4-level modulations, this is twice the symbol rate.
+    pub const fn bits_per_sec(value: u32) -> Self {
+        Self {
+            value,
+            in_symbols: false,
+        }
+    }
+
+    /// The rate in symbols per second. This is what is programmed into the radio.
+    pub const fn symbols_per_sec(value: u32) -> Self {
+        Self {
+            value,
+            in_symbols: true,
+        }
+    }
+
+    /// The symbol rate for the given modulation.
+    ///
+    /// Returns None if the bitrate can't be expressed in whole symbols.
+    pub const fn symbol_rate(self, modulation: ModulationType) -> Option<u32> {
+        let bits_per_symbol = bits_per_symbol(modulation);
+
+        if self.in_symbols {
+            Some(self.value)
+        } else if !self.value.is_multiple_of(bits_per_symbol) {
+            None
+        } else {
+            Some(self.value / bits_per_symbol)
+        }
+    }
+
+    /// The bit rate for the given modulation
+    pub const fn bit_rate(self, modulation: ModulationType) -> u32 {
+        if self.in_symbols {
+            self.value.saturating_mul(bits_per_symbol(modulation))
+        } else {
+            self.value
+        }
+    }
+}
+
+const fn bits_per_symbol(modulation: ModulationType) -> u32 {
+    match modulation {
+        ModulationType::Fsk4 | ModulationType::Gfsk4Bt1 | ModulationType::Gfsk4Bt05 => 2,
+        _ => 1,
+    }
+}
+
+/// A [Config] of which all register values have been calculated. Create it using [Config::compile].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompiledConfig {
+    pd_clkdiv: bool,
+    pub(crate) digital_frequency: u32,
+    if_offset_ana: u8,
+    if_offset_dig: u8,
+    modulation: ModulationType,
+    datarate_mantissa: u16,
+    datarate_exponent: u8,
+    pub(crate) middle_band: bool,
+    pub(crate) fdev_mantissa: u8,
+    pub(crate) fdev_exponent: u8,
+    pub(crate) ch_flt_mantissa: u8,
+    pub(crate) ch_flt_exponent: u8,
+    pa_fc: crate::ll::PaFc,
+    pub(crate) synt: u32,
+    pub(crate) cp_isel: u8,
+    pub(crate) pfd_split: bool,
+    warnings: InitWarnings,
+}
+
+impl CompiledConfig {
+    /// The places where the radio can't do exactly what the config asks for.
+    /// See [InitWarning].
+    pub fn warnings(&self) -> heapless::Vec<InitWarning, MAX_INIT_WARNINGS> {
+        let mut warnings = heapless::Vec::new();
+        let InitWarnings {
+            datarate,
+            frequency_deviation,
+            bandwidth,
+        } = self.warnings;
+
+        if datarate.0 != datarate.1 {
+            let _ = warnings.push(InitWarning::DataRateRounded {
+                requested: datarate.0,
+                actual: datarate.1,
+            });
+        }
+        if frequency_deviation.0 != frequency_deviation.1 {
+            let _ = warnings.push(InitWarning::FrequencyDeviationRounded {
+                requested: frequency_deviation.0,
+                actual: frequency_deviation.1,
+            });
+        }
+        if bandwidth.0 != bandwidth.1 {
+            let _ = warnings.push(InitWarning::BandwidthFromTable {
+                requested: bandwidth.0,
+                actual: bandwidth.1,
+            });
+        }
+
+        warnings
+    }
+}
+
+/// The requested and actual values of the settings the radio can't always match exactly
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct InitWarnings {
+    datarate: (u32, u32),
+    frequency_deviation: (u32, u32),
+    bandwidth: (u32, u32),
+}
+
+/// The maximum amount of [InitWarning]s a config can have
+pub const MAX_INIT_WARNINGS: usize = 3;
+
+/// A setting of the [Config] that the radio can't do exactly, so it's programmed with the closest value it can do.
+///
+/// Log these in production to catch configuration drift, e.g. a datarate that's further off than the other side tolerates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum InitWarning {
+    /// The datarate in bits per second is rounded to the resolution of the datarate registers
+    DataRateRounded { requested: u32, actual: u32 },
+    /// The frequency deviation in Hz is rounded to the resolution of the deviation registers
+    FrequencyDeviationRounded { requested: u32, actual: u32 },
+    /// The channel filter bandwidth in Hz is the closest one in the table of the radio
+    BandwidthFromTable { requested: u32, actual: u32 },
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            xtal_frequency: 50_000_000,
+            base_frequency: 868_000_000,
+            modulation: ModulationType::Fsk2,
+            datarate: DataRate::bits_per_sec(38_400),
+            frequency_deviation: 20_000,
+            bandwidth: 100_000,
+        }
+    }
+}
+
+/// How long init waits for the RCO calibration to finish before it gives up with [Error::RcoCalibrationTimeout].
+///
+/// The calibration normally takes well under a millisecond. When an attempt times out,
+/// the calibration is restarted up to `retries` times.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct RcoCalibrationWait {
+    /// How long a single calibration attempt may take
+    pub timeout: Duration,
+    /// How many times the calibration is restarted after the first attempt timed out
+    pub retries: u8,
+}
+
+impl RcoCalibrationWait {
+    /// 2 ms per attempt with 2 retries
+    pub const DEFAULT: Self = Self {
+        timeout: Duration::from_millis(2),
+        retries: 2,
+    };
+}
+
+impl Default for RcoCalibrationWait {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// The time between two polls of the RCO calibration status
+const RCO_POLL_INTERVAL_US: u32 = 10;
+/// The amount of polls [S2lp::recover_orphaned] waits for the radio to get to ready
+const RECOVER_POLLS: u32 = 100;
+/// Written to the source address mask (`PCKT_FLT_GOALS4`) by the init. The mask is only used by the
+/// source address filter (`SOURCE_ADDR_FLT`), which the driver never turns on, so it has no function otherwise.
+/// When the radio loses its configuration, the register is back at its reset value of 0.
+pub(crate) const CONFIG_CANARY: u8 = 0xA5;
+
+impl<Spi, Sdn, Gpio, Delay> S2lp<Ready<Uninitialized>, Spi, Sdn, Gpio, Delay>
+where
+    Spi: SpiDevice,
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    /// Take back a radio that was left running by [S2lp::detach] (or by a driver that was dropped), without resetting it.
+    ///
+    /// Whatever the radio was doing is aborted and it's brought to ready with empty fifos and no irqs.
+    /// The config must be the one the radio was initialized with, since it can't be read back.
+    /// The registers of a session (like the RX timeout or CSMA/CA) are left as they were,
+    /// so set up the packet format and the rest of the session again.
+    ///
+    /// Returns [Error::Init] if the radio doesn't respond and [Error::BadState] if it doesn't get to ready,
+    /// in which case a full init is needed.
+    pub async fn recover_orphaned(
+        parts: RadioParts<Spi, Sdn, Gpio, Delay>,
+        config: &CompiledConfig,
+    ) -> Result<Self, ErrorOf<Self>> {
+        let RadioParts {
+            spi,
+            shutdown_pin,
+            gpio_pin,
+            gpio_number,
+            delay,
+        } = parts;
+
+        let mut this = Self {
+            device: Some(Device::new(DeviceInterface::new(spi))),
+            shutdown_pin,
+            gpio_pin,
+            gpio_number,
+            irq_trigger: IrqTrigger::Level,
+            rssi_capture: RssiCapture::SyncDetect,
+            label: DEFAULT_LABEL,
+            rco_calibration_wait: RcoCalibrationWait::DEFAULT,
+            inter_packet_gap: Duration::ZERO,
+            mirror: None,
+            diagnostics: None,
+            delay,
+            state: Ready::new(config.digital_frequency),
+        };
+
         let version = this.ll().device_info_0().read()?.version();
         if version != 0xC1 {
             return Err(Error::Init);
         }
 
+        let state = this.ll().mc_state_0().read()?.state()?;
+
+        #[cfg(feature = "defmt-03")]
+        defmt::debug!("{=str}: Recovering the radio from {}", this.label, state);
+
+        match state {
+            State::Tx | State::Rx | State::Lockon | State::Lockst => {
+                this.ll().abort().dispatch()?
+            }
+            State::Standby | State::SleepA | State::SleepB => this.ll().ready().dispatch()?,
+            _ => {}
+        }
+
+        let mut ready = false;
+        for _ in 0..RECOVER_POLLS {
+            if matches!(this.ll().mc_state_0().read()?.state(), Ok(State::Ready)) {
+                ready = true;
+                break;
+            }
+            this.delay.delay_us(RCO_POLL_INTERVAL_US).await;
+        }
+        if !ready {
+            return Err(this.fatal(FatalError::BadState));
+        }
+
+        this.ll().flush_rx_fifo().dispatch()?;
+        this.ll().flush_tx_fifo().dispatch()?;
+        this.ll().irq_mask().write(|reg| *reg = IrqMask::new())?;
+        // Read the irq status to clear it
+        this.ll().irq_status().read()?;
+
+        Ok(this)
+    }
+
+    /// Everything of the init between the reset and the end of the RCO calibration
+    fn configure(&mut self, config: &CompiledConfig) -> Result<(), ErrorOf<Self>> {
+        #[cfg(feature = "defmt-03")]
+        defmt::trace!("{=str}: Checking interface works", self.label);
+        let version = self.ll().device_info_0().read()?.version();
+        if version != 0xC1 {
+            return Err(Error::Init);
+        }
+
         #[cfg(feature = "defmt-03")]
-        defmt::trace!("Setting correct radio config");
+        defmt::trace!("{=str}: Setting correct radio config", self.label);
         // Set the gpio pin to irq mode since we use IRQs in the driver
-        this.set_gpio_function(
-            this.gpio_number,
+        self.set_gpio_function(
+            self.gpio_number,
             GpioFunction::Output {
                 high_power: false,
                 select: GpioSelectOutput::Irq,
@@ -106,297 +699,181 @@ where
         // Datasheet 4.7 - Setting up the crystal oscillator
         // If the xtal_frequency is slow, then we can drive the chip from it directly.
         // If it is fast, we need to enable the clock divider.
-        let digital_frequency = {
-            let mut pd_clkdiv = this.ll().xo_rco_conf_1().read()?.pd_clkdiv();
-
-            if (config.xtal_frequency < DIG_DOMAIN_XTAL_THRESH && !pd_clkdiv)
-                || (config.xtal_frequency > DIG_DOMAIN_XTAL_THRESH && pd_clkdiv)
-            {
-                // Go to standby
-                this.ll().standby().dispatch()?;
-                while this.ll().mc_state_0().read()?.state()? != State::Standby {}
-
-                // Invert the pd_clkdiv
-                pd_clkdiv = !pd_clkdiv;
-                this.ll()
-                    .xo_rco_conf_1()
-                    .modify(|reg| reg.set_pd_clkdiv(pd_clkdiv))?;
-
-                // Go to ready
-                this.ll().ready().dispatch()?;
-                while this.ll().mc_state_0().read()?.state()? != State::Ready {}
-            }
-
-            config.xtal_frequency / if pd_clkdiv { 1 } else { 2 }
-        };
+        if self.ll().xo_rco_conf_1().read()?.pd_clkdiv() != config.pd_clkdiv {
+            // Go to standby
+            self.ll().standby().dispatch()?;
+            while self.ll().mc_state_0().read()?.state()? != State::Standby {}
+
+            self.ll()
+                .xo_rco_conf_1()
+                .modify(|reg| reg.set_pd_clkdiv(config.pd_clkdiv))?;
+
+            // Go to ready
+            self.ll().ready().dispatch()?;
+            while self.ll().mc_state_0().read()?.state()? != State::Ready {}
+        }
 
-        this.state.digital_frequency = digital_frequency;
+        self.state.digital_frequency = config.digital_frequency;
 
         // Datasheet 5.7 part 1
         // The clock divider is now ok, so we can turn the rco calibration on.
         // Later we must check whether it succeeded.
-        this.ll()
+        self.ll()
             .xo_rco_conf_0()
             .modify(|reg| reg.set_rco_calibration(true))?;
 
-        if !is_ch_bw(config.bandwidth, digital_frequency) {
-            return Err(Error::BadConfig {
-                reason: "Bandwidth out of range",
-            });
-        }
-
         // Datasheet 5.5.5 - Set the Intermediate Frequency (IF) to the recommended value
-        {
-            const IF: u64 = 300_000;
-            this.ll().if_offset_ana().write(|reg| {
-                reg.set_value(((IF << 13) * 3 / config.xtal_frequency as u64 - 100) as u8)
-            })?;
-            this.ll().if_offset_dig().write(|reg| {
-                reg.set_value(((IF << 13) * 3 / digital_frequency as u64 - 100) as u8)
-            })?;
-        }
+        self.ll()
+            .if_offset_ana()
+            .write(|reg| reg.set_value(config.if_offset_ana))?;
+        self.ll()
+            .if_offset_dig()
+            .write(|reg| reg.set_value(config.if_offset_dig))?;
 
         // Datasheet 5.4.5 - Configure the datarate
-        // We search for the smallest exponent where our datarate fits (for highest resolution)
-        {
-            let mut used_exponent = 0;
-            for exponent in 0..15 {
-                if compute_datarate(digital_frequency, u16::MAX, exponent) > config.datarate {
-                    used_exponent = exponent;
-                    break;
-                }
-            }
-
-            // Now calculate the best mantissa including rounding
-            let used_mantissa = if used_exponent == 0 {
-                let target = (config.datarate as u64) << 32;
-                (target + (digital_frequency as u64 / 2)) / digital_frequency as u64
-            } else {
-                let target = (config.datarate as u64) << (33 - used_exponent as u64);
-                (target + (digital_frequency as u64 / 2)) / digital_frequency as u64 - 65536
-            } as u16;
-
-            #[cfg(feature = "defmt-03")]
-            defmt::trace!(
-                "Selected datarate. Target: {}, found: {}",
-                config.datarate,
-                compute_datarate(digital_frequency, used_mantissa, used_exponent)
-            );
-
-            this.ll()
-                .mod_4()
-                .write(|reg| reg.set_value(used_mantissa))?;
-            this.ll().mod_2().write(|reg| {
-                reg.set_datarate_e(used_exponent);
-                reg.set_modulation_type(config.modulation);
-            })?;
-        }
+        self.ll()
+            .mod_4()
+            .write(|reg| reg.set_value(config.datarate_mantissa))?;
+        self.ll().mod_2().write(|reg| {
+            reg.set_datarate_e(config.datarate_exponent);
+            reg.set_modulation_type(config.modulation);
+        })?;
 
         // Datasheet 5.3.1
-        {
-            this.ll()
-                .synt()
-                .modify(|reg| reg.set_bs(is_frequency_band_middle(config.base_frequency)))?;
-        }
+        self.ll()
+            .synt()
+            .modify(|reg| reg.set_bs(config.middle_band))?;
 
         // Datasheet 5.4.1 - Configure the frequency modulation
-        {
-            let band_factor = get_band_factor(config.base_frequency);
-
-            let refdiv = if this.ll().xo_rco_conf_0().read()?.refdiv() {
-                2
-            } else {
-                1
-            };
-
-            // Search for the smallest exponent that our fdev fits in for the highest resolution
-            let mut used_exponent = 0;
-            for exponent in 0..16 {
-                let fdev = compute_fdev(
-                    config.xtal_frequency,
-                    u8::MAX,
-                    exponent,
-                    band_factor,
-                    refdiv,
-                );
-
-                if fdev > config.frequency_deviation {
-                    used_exponent = exponent;
-                    break;
-                }
-            }
-
-            let mut used_mantissa = u8::MAX;
-            let mut prev_fdev = 0;
-            for mantissa in (0..=u8::MAX).rev() {
-                let fdev = compute_fdev(
-                    config.xtal_frequency,
-                    mantissa,
-                    used_exponent,
-                    band_factor,
-                    refdiv,
-                );
-
-                if fdev < config.frequency_deviation {
-                    used_mantissa = if config.frequency_deviation.abs_diff(fdev)
-                        < config.frequency_deviation.abs_diff(prev_fdev)
-                    {
-                        #[cfg(feature = "defmt-03")]
-                        defmt::trace!(
-                            "Selected frequency deviation. Target: {}, found: {}",
-                            config.frequency_deviation,
-                            fdev
-                        );
-                        mantissa
-                    } else {
-                        #[cfg(feature = "defmt-03")]
-                        defmt::trace!(
-                            "Selected frequency deviation. Target: {}, found: {}",
-                            config.frequency_deviation,
-                            prev_fdev
-                        );
-                        mantissa + 1
-                    };
-                    break;
-                } else {
-                    prev_fdev = fdev;
-                }
-            }
-
-            this.ll()
-                .mod_1()
-                .modify(|reg| reg.set_fdev_e(used_exponent))?;
-            this.ll()
-                .mod_0()
-                .write(|reg| reg.set_fdev_m(used_mantissa))?;
-        }
+        self.ll()
+            .mod_1()
+            .modify(|reg| reg.set_fdev_e(config.fdev_exponent))?;
+        self.ll()
+            .mod_0()
+            .write(|reg| reg.set_fdev_m(config.fdev_mantissa))?;
 
         // Set the bandwidth
-        this.ll().ch_flt().write(|reg| {
-            *reg = search_channel_filter_bandwidth(config.bandwidth, digital_frequency);
+        self.ll().ch_flt().write(|reg| {
+            reg.set_ch_flt_e(config.ch_flt_exponent);
+            reg.set_ch_flt_m(config.ch_flt_mantissa);
         })?;
 
         // Set the OOK smoothing
         let is_ook = matches!(config.modulation, ModulationType::AskOok);
-        this.ll()
+        self.ll()
             .pa_power_0()
             .modify(|reg| reg.set_dig_smooth_en(is_ook))?;
-        this.ll()
+        self.ll()
             .pa_config_1()
             .modify(|reg| reg.set_fir_en(is_ook))?;
 
-        this.ll().pa_config_0().modify(|reg| {
-            reg.set_pa_fc(match config.datarate {
-                ..16000 => crate::ll::PaFc::Khz12P5,
-                16000..32000 => crate::ll::PaFc::Khz25,
-                32000..62500 => crate::ll::PaFc::Khz50,
-                62500.. => crate::ll::PaFc::Khz100,
-            })
-        })?;
+        self.ll()
+            .pa_config_0()
+            .modify(|reg| reg.set_pa_fc(config.pa_fc))?;
 
         // Enable AFC freeze on SYNC
-        this.ll()
+        self.ll()
             .afc_2()
             .modify(|reg| reg.set_afc_freeze_on_sync(true))?;
 
         // Set the synt word (base frequency) and charge pump
-        {
-            let band_factor = get_band_factor(config.base_frequency);
-
-            let refdiv = if this.ll().xo_rco_conf_0().read()?.refdiv() {
-                2
-            } else {
-                1
-            };
+        self.ll()
+            .synth_config_2()
+            .modify(|reg| reg.set_pll_pfd_split_en(config.pfd_split))?;
+        self.ll().synt().modify(|reg| {
+            reg.set_synt(config.synt);
+            reg.set_pll_cp_isel(config.cp_isel);
+        })?;
 
-            let synt_target =
-                ((config.base_frequency as u64) << 20) * (band_factor / 2) as u64 * refdiv as u64;
-            let synt = ((synt_target + config.xtal_frequency as u64 / 2)
-                / config.xtal_frequency as u64) as u32;
+        Ok(())
+    }
 
-            let vco_freq = config.base_frequency as u64 * band_factor as u64;
-            let f_ref = config.xtal_frequency / refdiv;
+    #[cfg_attr(not(feature = "defmt-03"), allow(unused_variables))]
+    fn finish_init(&mut self, config: &CompiledConfig) -> Result<(), ErrorOf<Self>> {
+        // Retain fifo on sleep. Required for CSMA/CA to work
+        self.ll()
+            .pm_conf_0()
+            .write(|reg| reg.set_sleep_mode_sel(SleepModeSel::WithFifoRetention))?;
+        self.ll()
+            .pm_conf_1()
+            .modify(|reg| reg.set_smps_lvl_mode(true))?;
 
-            let (cp_isel, pfd_split) = match (vco_freq, f_ref) {
-                (VCO_CENTER_FREQ.., DIG_DOMAIN_XTAL_THRESH..) => (0x02, false),
-                (VCO_CENTER_FREQ.., ..DIG_DOMAIN_XTAL_THRESH) => (0x01, true),
-                (..VCO_CENTER_FREQ, DIG_DOMAIN_XTAL_THRESH..) => (0x03, false),
-                (..VCO_CENTER_FREQ, ..DIG_DOMAIN_XTAL_THRESH) => (0x02, true),
-            };
+        // Mark the radio as configured for `is_configured`
+        self.ll()
+            .pckt_flt_goals_4()
+            .write(|reg| reg.set_rx_source_mask(CONFIG_CANARY))?;
 
-            this.ll()
-                .synth_config_2()
-                .modify(|reg| reg.set_pll_pfd_split_en(pfd_split))?;
-            this.ll().synt().modify(|reg| {
-                reg.set_synt(synt);
-                reg.set_pll_cp_isel(cp_isel);
-            })?;
+        #[cfg(feature = "defmt-03")]
+        for warning in config.warnings() {
+            defmt::info!("{=str}: Init: {}", self.label, warning);
         }
 
-        // Datasheet 5.7 part 2
-        loop {
-            // Wait for the RCO calibration to finish
-            let mc_state_1 = this.ll().mc_state_1().read()?;
-            if mc_state_1.rco_cal_ok() {
-                break;
-            } else if mc_state_1.error_lock() {
-                return Err(Error::RcoLockError);
-            }
-        }
+        #[cfg(feature = "defmt-03")]
+        defmt::debug!("{=str}: Init done!", self.label);
 
-        // Retain fifo on sleep. Required for CSMA/CA to work
-        this.ll()
-            .pm_conf_0()
-            .write(|reg| reg.set_sleep_mode_sel(SleepModeSel::WithFifoRetention))?;
+        Ok(())
+    }
 
-        #[cfg(feature = "defmt-03")]
-        defmt::debug!("Init done!");
+    async fn wait_for_rco_calibration(&mut self) -> Result<(), ErrorOf<Self>> {
+        let mut progress = RcoProgress::default();
+        while !self.poll_rco_calibration(&mut progress)? {
+            self.delay.delay_us(RCO_POLL_INTERVAL_US).await;
+        }
 
-        Ok(this)
+        Ok(())
     }
-}
 
-pub use crate::ll::ModulationType;
+    /// Check whether the RCO calibration is done. If not, the caller must wait [RCO_POLL_INTERVAL_US] before polling again.
+    fn poll_rco_calibration(&mut self, progress: &mut RcoProgress) -> Result<bool, ErrorOf<Self>> {
+        let mc_state_1 = self.ll().mc_state_1().read()?;
+        if mc_state_1.rco_cal_ok() {
+            return Ok(true);
+        } else if mc_state_1.error_lock() {
+            return Err(self.fatal(FatalError::RcoLockError));
+        }
 
-/// The radio configuration
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct Config {
-    /// The frequency of the crystal oscillator
-    pub xtal_frequency: u32,
-    /// Specifies the carrier frequency of channel 0 in Hz.
-    ///
-    /// Possible values:
-    /// - High band (860 MHz - 940 MHz)
-    /// - Middle band (430 MHz - 470 MHz)
-    pub base_frequency: u32,
-    /// The modulation the radio will use
-    pub modulation: ModulationType,
-    /// The datarate used in bps (100 bps - 500 kbps)
-    pub datarate: u32,
-    /// Frequency deviation in Hz. This is used for (G)FSK.
-    ///
-    /// - Min: `F_Xo * 8 / 0x40000`
-    /// - Max: `F_Xo * 7680 / 0x40000 `
-    pub frequency_deviation: u32,
-    /// Channel (filter) bandwidth in Hz between 1100 Hz - 800100 Hz
-    pub bandwidth: u32,
-    // TODO:
-    // pub pa_info: PaInfo,
-}
+        let wait = self.rco_calibration_wait;
+        if progress.attempt_us >= wait.timeout.as_micros() {
+            if progress.attempt >= wait.retries {
+                return Err(Error::RcoCalibrationTimeout {
+                    waited: Duration::from_micros(progress.waited_us),
+                });
+            }
 
-impl Default for Config {
-    fn default() -> Self {
-        Self {
-            xtal_frequency: 50_000_000,
-            base_frequency: 868_000_000,
-            modulation: ModulationType::Fsk2,
-            datarate: 38_400,
-            frequency_deviation: 20_000,
-            bandwidth: 100_000,
+            progress.attempt += 1;
+            progress.attempt_us = 0;
+
+            #[cfg(feature = "defmt-03")]
+            defmt::warn!(
+                "{=str}: RCO calibration timed out, retry {} of {}",
+                self.label,
+                progress.attempt,
+                wait.retries
+            );
+
+            // Restart the calibration
+            self.ll()
+                .xo_rco_conf_0()
+                .modify(|reg| reg.set_rco_calibration(false))?;
+            self.ll()
+                .xo_rco_conf_0()
+                .modify(|reg| reg.set_rco_calibration(true))?;
         }
+
+        progress.attempt_us += RCO_POLL_INTERVAL_US;
+        progress.waited_us = progress.waited_us.saturating_add(RCO_POLL_INTERVAL_US);
+        Ok(false)
     }
 }
 
+/// How far the RCO calibration wait is
+#[derive(Debug, Default)]
+struct RcoProgress {
+    attempt: u8,
+    attempt_us: u32,
+    waited_us: u32,
+}
+
 const fn is_frequency_band(base_frequency: u32) -> bool {
     is_frequency_band_high(base_frequency) || is_frequency_band_middle(base_frequency)
 }
@@ -449,28 +926,101 @@ const MIDDLE_BAND_LOWER_LIMIT: u32 = 412900000;
 const MIDDLE_BAND_UPPER_LIMIT: u32 = 527100000;
 
 /// Minimum datarate supported by S2LP 100 bps
-const MINIMUM_DATARATE: u32 = 100;
+pub(crate) const MINIMUM_DATARATE: u32 = 100;
 /// Maximum datarate supported by S2LP 250 ksps
-const MAXIMUM_DATARATE: u64 = 250000;
+pub(crate) const MAXIMUM_DATARATE: u64 = 250000;
 
 /// Digital domain logic threshold for XTAL in MHz
 const DIG_DOMAIN_XTAL_THRESH: u32 = 30000000;
 
-fn compute_datarate(digital_frequency: u32, mantissa: u16, exponent: u8) -> u32 {
+/// Datasheet 5.4.5 - Find the datarate mantissa and exponent for the given datarate.
+///
+/// We search for the smallest exponent where our datarate fits (for highest resolution)
+pub(crate) const fn find_datarate_mantissa_exponent(
+    datarate: u32,
+    digital_frequency: u32,
+) -> (u16, u8) {
+    let mut used_exponent = 0;
+    let mut exponent = 0;
+    while exponent < 15 {
+        if compute_datarate(digital_frequency, u16::MAX, exponent) > datarate {
+            used_exponent = exponent;
+            break;
+        }
+        exponent += 1;
+    }
+
+    // Now calculate the best mantissa including rounding
+    let used_mantissa = if used_exponent == 0 {
+        let target = (datarate as u64) << 32;
+        (target + (digital_frequency as u64 / 2)) / digital_frequency as u64
+    } else {
+        let target = (datarate as u64) << (33 - used_exponent as u64);
+        (target + (digital_frequency as u64 / 2)) / digital_frequency as u64 - 65536
+    } as u16;
+
+    (used_mantissa, used_exponent)
+}
+
+pub(crate) const fn compute_datarate(digital_frequency: u32, mantissa: u16, exponent: u8) -> u32 {
     match exponent {
         0 => ((digital_frequency as u64 * mantissa as u64) >> 32) as u32,
         e @ 1..15 => {
             ((digital_frequency as u64 * (65536 + mantissa as u64)) >> (33 - e) as u64) as u32
         }
         15 => digital_frequency / (8 * mantissa as u32),
-        #[cfg(feature = "defmt-03")]
-        _ => defmt::panic!("Illegal exponent value"),
-        #[cfg(not(feature = "defmt-03"))]
         _ => panic!("Illegal exponent value"),
     }
 }
 
-fn compute_fdev(
+/// Datasheet 5.4.1 - Find the frequency deviation mantissa and exponent for the given deviation
+const fn find_fdev_mantissa_exponent(
+    frequency_deviation: u32,
+    xtal_frequency: u32,
+    band_factor: u32,
+    refdiv: u32,
+) -> (u8, u8) {
+    // Search for the smallest exponent that our fdev fits in for the highest resolution
+    let mut used_exponent = 0;
+    let mut exponent = 0;
+    while exponent < 16 {
+        if compute_fdev(xtal_frequency, u8::MAX, exponent, band_factor, refdiv)
+            > frequency_deviation
+        {
+            used_exponent = exponent;
+            break;
+        }
+        exponent += 1;
+    }
+
+    let mut used_mantissa = u8::MAX;
+    let mut prev_fdev = 0;
+    let mut mantissa = u8::MAX;
+    loop {
+        let fdev = compute_fdev(xtal_frequency, mantissa, used_exponent, band_factor, refdiv);
+
+        if fdev < frequency_deviation {
+            used_mantissa =
+                if frequency_deviation.abs_diff(fdev) < frequency_deviation.abs_diff(prev_fdev) {
+                    mantissa
+                } else {
+                    mantissa + 1
+                };
+            break;
+        }
+
+        prev_fdev = fdev;
+
+        if mantissa == 0 {
+            break;
+        }
+        mantissa -= 1;
+    }
+
+    (used_mantissa, used_exponent)
+}
+
+const fn compute_fdev(
     xtal_freq: u32,   // fXO
     mantissa: u8,     // FDEV_M
     exponent: u8,     // FDEV_E
@@ -496,48 +1046,46 @@ fn compute_fdev(
             let denom = (1 << 19) * refdiv as u64 * band_factor as u64 * band_factor_div;
             (nom / denom) as _
         }
-        #[cfg(feature = "defmt-03")]
-        _ => defmt::panic!("Illegal exponent value"),
-        #[cfg(not(feature = "defmt-03"))]
         _ => panic!("Illegal exponent value"),
     }
 }
 
-fn search_channel_filter_bandwidth(target_bw: u32, dig_freq: u32) -> crate::ll::field_sets::ChFlt {
-    // Datasheet Table 44
-    // Every unit is 100hz
-    const CHANNEL_FILTER_WORDS: [u16; 90] = [
-        8001, 7951, 7684, 7368, 7051, 6709, 6423, 5867, 5414, 4509, 4259, 4032, 3808, 3621, 3417,
-        3254, 2945, 2703, 2247, 2124, 2015, 1900, 1807, 1706, 1624, 1471, 1350, 1123, 1062, 1005,
-        950, 903, 853, 812, 735, 675, 561, 530, 502, 474, 451, 426, 406, 367, 337, 280, 265, 251,
-        237, 226, 213, 203, 184, 169, 140, 133, 126, 119, 113, 106, 101, 92, 84, 70, 66, 63, 59,
-        56, 53, 51, 46, 42, 35, 33, 31, 30, 28, 27, 25, 23, 21, 18, 17, 16, 15, 14, 13, 13, 12, 11,
-    ];
-
-    let word_to_bandwidth = |word: u16| (word as u64 * 100 * dig_freq as u64 / 26_000_000) as u32;
-
-    let (best_index, _) = CHANNEL_FILTER_WORDS
-        .into_iter()
-        // Calculate the bandwidth we get from the table
-        .map(word_to_bandwidth)
-        // Calculate the difference to the target bw
-        .map(|possible_bw| possible_bw.abs_diff(target_bw))
-        // Run over it with the index
-        .enumerate()
-        .min_by_key(|(_, diff)| *diff)
-        .unwrap_or_default();
-
-    #[cfg(feature = "defmt-03")]
-    defmt::trace!(
-        "Selected channel bandwidth. Target: {}, found: {}",
-        target_bw,
-        word_to_bandwidth(CHANNEL_FILTER_WORDS[best_index])
-    );
-
-    let mut w = crate::ll::field_sets::ChFlt::new_zero();
-
-    w.set_ch_flt_e(best_index as u8 / 9);
-    w.set_ch_flt_m(best_index as u8 % 9);
-
-    w
+/// Datasheet Table 44 - The channel filter bandwidths at a 26 MHz digital clock, indexed by `exponent * 9 + mantissa`.
+/// Every unit is 100hz
+const CHANNEL_FILTER_WORDS: [u16; 90] = [
+    8001, 7951, 7684, 7368, 7051, 6709, 6423, 5867, 5414, 4509, 4259, 4032, 3808, 3621, 3417, 3254,
+    2945, 2703, 2247, 2124, 2015, 1900, 1807, 1706, 1624, 1471, 1350, 1123, 1062, 1005, 950, 903,
+    853, 812, 735, 675, 561, 530, 502, 474, 451, 426, 406, 367, 337, 280, 265, 251, 237, 226, 213,
+    203, 184, 169, 140, 133, 126, 119, 113, 106, 101, 92, 84, 70, 66, 63, 59, 56, 53, 51, 46, 42,
+    35, 33, 31, 30, 28, 27, 25, 23, 21, 18, 17, 16, 15, 14, 13, 13, 12, 11,
+];
+
+const fn word_to_bandwidth(word: u16, dig_freq: u32) -> u32 {
+    (word as u64 * 100 * dig_freq as u64 / 26_000_000) as u32
+}
+
+/// The bandwidth of the channel filter with the given mantissa and exponent
+const fn compute_channel_filter_bandwidth(mantissa: u8, exponent: u8, dig_freq: u32) -> u32 {
+    word_to_bandwidth(
+        CHANNEL_FILTER_WORDS[exponent as usize * 9 + mantissa as usize],
+        dig_freq,
+    )
+}
+
+/// Find the channel filter mantissa and exponent closest to the target bandwidth
+const fn search_channel_filter_bandwidth(target_bw: u32, dig_freq: u32) -> (u8, u8) {
+    // Find the table entry with the smallest difference to the target bandwidth
+    let mut best_index = 0;
+    let mut best_diff = u32::MAX;
+    let mut index = 0;
+    while index < CHANNEL_FILTER_WORDS.len() {
+        let diff = word_to_bandwidth(CHANNEL_FILTER_WORDS[index], dig_freq).abs_diff(target_bw);
+        if diff < best_diff {
+            best_index = index;
+            best_diff = diff;
+        }
+        index += 1;
+    }
+
+    (best_index as u8 % 9, best_index as u8 / 9)
 }
@@ -0,0 +1,102 @@
+use core::marker::PhantomData;
+
+use embedded_hal::{
+    digital::{InputPin, OutputPin},
+    spi::SpiDevice,
+};
+use embedded_hal_async::{delay::DelayNs, digital::Wait};
+
+use crate::{
+    ll::{ModulationType, TxSource},
+    packet_format::PacketFormat,
+    ErrorOf, S2lp,
+};
+
+use super::{Ready, TestTx};
+
+impl<PF, Spi, Sdn, Gpio, Delay> S2lp<Ready<PF>, Spi, Sdn, Gpio, Delay>
+where
+    PF: PacketFormat,
+    Spi: SpiDevice,
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    /// Transmit a continuous PN9 sequence with the configured frequency, modulation and datarate,
+    /// as required for regulatory testing.
+    ///
+    /// The radio keeps transmitting until [S2lp::abort] is called. The packet handler and the fifo aren't used.
+    pub fn start_pn9_tx(self) -> Result<S2lp<TestTx<PF>, Spi, Sdn, Gpio, Delay>, ErrorOf<Self>> {
+        #[cfg(feature = "defmt-03")]
+        defmt::info!("{=str}: Starting PN9 transmission", self.label);
+
+        self.start_test_tx(None)
+    }
+
+    /// Transmit a continuous unmodulated carrier with the configured frequency and output power,
+    /// e.g. for spectrum analyzer measurements and antenna tuning.
+    ///
+    /// The radio keeps transmitting until [S2lp::abort] is called. The modulation is restored then.
+    pub fn start_cw(self) -> Result<S2lp<TestTx<PF>, Spi, Sdn, Gpio, Delay>, ErrorOf<Self>> {
+        #[cfg(feature = "defmt-03")]
+        defmt::info!("{=str}: Starting CW transmission", self.label);
+
+        self.start_test_tx(Some(ModulationType::Unmodulated))
+    }
+
+    /// Start transmitting from the PN9 source, which keeps the transmitter on without the fifo.
+    /// The modulation is changed if given.
+    fn start_test_tx(
+        mut self,
+        modulation: Option<ModulationType>,
+    ) -> Result<S2lp<TestTx<PF>, Spi, Sdn, Gpio, Delay>, ErrorOf<Self>> {
+        let pckt_ctrl_1 = self.ll().pckt_ctrl_1().read()?;
+        let mod_2 = self.ll().mod_2().read()?;
+
+        if let Some(modulation) = modulation {
+            self.ll()
+                .mod_2()
+                .modify(|reg| reg.set_modulation_type(modulation))?;
+        }
+        self.ll()
+            .pckt_ctrl_1()
+            .modify(|reg| reg.set_tx_source(TxSource::Pn9))?;
+        self.ll().tx().dispatch()?;
+
+        let digital_frequency = self.state.digital_frequency();
+        Ok(self.cast_state(TestTx {
+            digital_frequency,
+            pckt_ctrl_1,
+            mod_2,
+            _p: PhantomData,
+        }))
+    }
+}
+
+impl<PF, Spi, Sdn, Gpio, Delay> S2lp<TestTx<PF>, Spi, Sdn, Gpio, Delay>
+where
+    Spi: SpiDevice,
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    /// Stop the test signal and go back to ready. The tx source and the modulation are restored.
+    pub fn abort(mut self) -> Result<S2lp<Ready<PF>, Spi, Sdn, Gpio, Delay>, ErrorOf<Self>> {
+        self.ll().abort().dispatch()?;
+        self.ll().ready().dispatch()?;
+        self.ll().flush_tx_fifo().dispatch()?;
+        let TestTx {
+            pckt_ctrl_1, mod_2, ..
+        } = self.state;
+        self.ll().pckt_ctrl_1().write(|reg| *reg = pckt_ctrl_1)?;
+        self.ll().mod_2().write(|reg| *reg = mod_2)?;
+        // Read the irq status to clear it
+        self.ll().irq_status().read()?;
+
+        #[cfg(feature = "defmt-03")]
+        defmt::info!("{=str}: Stopped the test transmission", self.label);
+
+        let digital_frequency = self.state.digital_frequency;
+        Ok(self.cast_state(Ready::new(digital_frequency)))
+    }
+}
@@ -5,12 +5,49 @@ use embedded_hal::{
 };
 use embedded_hal_async::{delay::DelayNs, digital::Wait};
 
-use crate::{ll::State, Error, ErrorOf, S2lp};
+use crate::{diagnostics::FatalError, ll::State, rssi::Rssi, time::Duration, Error, ErrorOf, S2lp};
 
-use super::{Ready, Tx};
+use super::{shutdown::compute_datarate, Ready, Tx};
 
-#[cfg(feature = "defmt-03")]
-use defmt::unreachable;
+/// When no interrupt comes in for this long during a transmission, [S2lp::wait] checks whether the radio got stuck
+pub const TX_WATCHDOG: Duration = Duration::from_secs(1);
+
+impl<Spi, Sdn, Gpio, Delay, PF> S2lp<Tx<'_, PF>, Spi, Sdn, Gpio, Delay>
+where
+    Sdn: OutputPin,
+    Gpio: InputPin + Wait,
+    Delay: DelayNs,
+{
+    /// Just waits for the interrupt without acting on it. This is cancel-safe.
+    ///
+    /// This allows for a low-power TX flow where the spi is taken during the transmission.
+    /// That only works for packets that fit in the fifo fully, because the fifo can't be refilled without the spi.
+    /// If the packet is too large, [Error::TxFifoRefillRequired] is returned and [Self::wait] must be used instead.
+    pub async fn wait_for_irq(&mut self) -> Result<(), Error<(), Sdn::Error, Gpio::Error>> {
+        if !self.state.tx_buffer.is_empty() {
+            return Err(Error::TxFifoRefillRequired);
+        }
+
+        self.wait_for_irq_pin().await.map_err(Error::Gpio)?;
+        Ok(())
+    }
+
+    /// Check without blocking whether there's something for [S2lp::wait] to handle.
+    ///
+    /// This only looks at the interrupt pin, so it's cheap and works while the spi is taken.
+    pub fn has_event(&mut self) -> Result<bool, Error<(), Sdn::Error, Gpio::Error>> {
+        if self.state.tx_done {
+            return Ok(true);
+        }
+
+        self.gpio_pin.is_low().map_err(Error::Gpio)
+    }
+
+    /// The amount of payload bytes written to the fifo so far
+    pub fn bytes_queued(&self) -> usize {
+        self.state.queued
+    }
+}
 
 impl<Spi, Sdn, Gpio, Delay, PF> S2lp<Tx<'_, PF>, Spi, Sdn, Gpio, Delay>
 where
@@ -23,87 +60,370 @@ where
     ///
     /// After this is done, call [Self::abort] to get back the radio in the ready state.
     pub async fn wait(&mut self) -> Result<TxResult, ErrorOf<Self>> {
+        self.wait_with_report().await.map(|report| report.result)
+    }
+
+    /// Same as [Self::wait], but `on_queued` is called with the amount of bytes every time the fifo is refilled.
+    ///
+    /// For long packets, this lets the application produce the next data while the current packet is going out.
+    /// The total is available with [Self::bytes_queued].
+    pub async fn wait_with_progress(
+        &mut self,
+        mut on_queued: impl FnMut(usize),
+    ) -> Result<TxResult, ErrorOf<Self>> {
         if self.state.tx_done {
             return Ok(TxResult::TxAlreadyDone);
         }
 
+        self.wait_for_result(&mut on_queued).await
+    }
+
+    /// Same as [Self::wait], but returns a [TxReport] with statistics of the transmission
+    /// on top of the result. These are collected along the way, so no extra register reads are needed.
+    pub async fn wait_with_report(&mut self) -> Result<TxReport, ErrorOf<Self>> {
+        if self.state.tx_done {
+            return Ok(self.report(TxResult::TxAlreadyDone));
+        }
+
+        let result = self.wait_for_result(&mut |_| {}).await?;
+        Ok(self.report(result))
+    }
+
+    /// Same as [Self::wait], but the RSSI is sampled once every CCA period while the CSMA/CA engine assesses the channel.
+    ///
+    /// This helps to tune the [RSSI threshold](S2lp::set_rssi_threshold) for a noisy environment,
+    /// e.g. when transmissions keep ending in [TxResult::MaxBackoffReached].
+    /// Instead of sleeping until the interrupt, this polls the radio every CCA period, so it uses more power than [Self::wait].
+    ///
+    /// The stats are None if the channel was never sampled, e.g. because CSMA/CA is off.
+    pub async fn wait_with_cca_stats(
+        &mut self,
+    ) -> Result<(TxResult, Option<CcaStats>), ErrorOf<Self>> {
+        if self.state.tx_done {
+            return Ok((TxResult::TxAlreadyDone, None));
+        }
+
+        let cca_period = self.cca_period()?;
+        let mut stats: Option<CcaStats> = None;
+        let mut idle = 0;
+
         loop {
-            // Wait for the interrupt
-            match select(self.gpio_pin.wait_for_low(), self.delay.delay_ms(1000)).await {
-                Either::First(res) => res.map_err(Error::Gpio)?,
-                Either::Second(()) => {
-                    // Timeout
+            let mut service = self.gpio_pin.is_low().map_err(Error::Gpio)?;
+            if !service && idle >= TX_WATCHDOG.as_micros() {
+                idle = 0;
+                service = self.on_watchdog_expired()?;
+            }
 
-                    // Check for bad state
-                    let state = self.ll().mc_state_0().read()?.state();
-                    match state {
-                        Ok(State::Lockst) | Err(_) => return Err(Error::BadState),
-                        _ => {}
-                    }
+            if service {
+                idle = 0;
 
-                    // Check for persistent CSMA/CA
-                    let protocol1 = self.ll().protocol_1().read()?;
-                    if protocol1.csma_on() && protocol1.csma_pers_on() {
-                        continue;
+                if let Some(result) = self.service_irq(&mut |_| {})? {
+                    if result.packet_sent() && self.inter_packet_gap != Duration::ZERO {
+                        self.delay.delay_us(self.inter_packet_gap.as_micros()).await;
                     }
 
                     #[cfg(feature = "defmt-03")]
-                    defmt::error!("TX wait timeout out in state: {}", state);
+                    defmt::debug!("{=str}: CCA stats: {}", self.label, stats);
+
+                    return Ok((result, stats));
                 }
+                continue;
             }
 
-            // Figure out what's up
-            let irq_status = self.ll().irq_status().read()?;
+            // The radio is in RX while it assesses the channel and sleeps during the backoffs
+            if matches!(self.ll().mc_state_0().read()?.state(), Ok(State::Rx)) {
+                let rssi = Rssi::from_register(self.ll().rssi_level_run().read()?.value());
+                match &mut stats {
+                    Some(stats) => stats.add(rssi),
+                    None => stats = Some(CcaStats::new(rssi)),
+                }
+            }
 
-            #[cfg(feature = "defmt-03")]
-            defmt::trace!("TX wait interrupt: {}", irq_status);
+            self.delay.delay_us(cca_period.as_micros()).await;
+            idle += cca_period.as_micros();
+        }
+    }
+
+    /// The length of one CCA period at the configured datarate
+    fn cca_period(&mut self) -> Result<Duration, ErrorOf<Self>> {
+        let bits = 64u32 << self.ll().csma_conf_1().read()?.cca_period() as u8;
+        let mantissa = self.ll().mod_4().read()?.value();
+        let exponent = self.ll().mod_2().read()?.datarate_e();
+        let datarate = compute_datarate(self.state.digital_frequency, mantissa, exponent).max(1);
 
-            if irq_status.tx_fifo_error() {
-                self.ll().abort().dispatch()?;
-                self.ll().flush_tx_fifo().dispatch()?;
+        Ok(Duration::from_micros(
+            (bits as u64 * 1_000_000 / datarate as u64) as u32,
+        ))
+    }
 
-                break Ok(TxResult::FifoError);
+    fn report(&self, result: TxResult) -> TxReport {
+        TxReport {
+            result,
+            fifo_refills: self.state.fifo_refills,
+            irqs_serviced: self.state.irqs_serviced,
+            watchdog_expirations: self.state.watchdog_expirations,
+            retransmissions: self.state.retransmissions,
+        }
+    }
+
+    /// Drive the transmission without the delay, for hosts that schedule the waits themselves.
+    ///
+    /// After starting the transmission, wait for the interrupt (see [IrqTrigger](crate::IrqTrigger)) or
+    /// for [TX_WATCHDOG], whatever comes first, and call this with what woke the host up.
+    /// Keep doing that for as long as [TxStep::WaitForIrq] is returned.
+    ///
+    /// The [inter-packet gap](S2lp::set_inter_packet_gap) isn't waited here.
+    /// Wait it before the next transmission if [TxResult::packet_sent].
+    pub fn resume(&mut self, wakeup: Wakeup) -> Result<TxStep, ErrorOf<Self>> {
+        if self.state.tx_done {
+            return Ok(TxStep::Done(TxResult::TxAlreadyDone));
+        }
+
+        if wakeup == Wakeup::Timeout && !self.on_watchdog_expired()? {
+            return Ok(TxStep::WaitForIrq {
+                timeout: TX_WATCHDOG,
+            });
+        }
+
+        Ok(match self.service_irq(&mut |_| {})? {
+            Some(result) => TxStep::Done(result),
+            None => TxStep::WaitForIrq {
+                timeout: TX_WATCHDOG,
+            },
+        })
+    }
+
+    async fn wait_for_result(
+        &mut self,
+        on_queued: &mut impl FnMut(usize),
+    ) -> Result<TxResult, ErrorOf<Self>> {
+        loop {
+            // Wait for the interrupt
+            match select(
+                self.irq_trigger.wait(&mut self.gpio_pin),
+                self.delay.delay_us(TX_WATCHDOG.as_micros()),
+            )
+            .await
+            {
+                Either::First(res) => res.map_err(Error::Gpio)?,
+                Either::Second(()) => {
+                    if !self.on_watchdog_expired()? {
+                        continue;
+                    }
+                }
             }
 
-            if irq_status.tx_fifo_almost_empty() && !self.state.tx_buffer.is_empty() {
-                // Refill the fifo
-                let written = self
-                    .device
-                    .as_mut()
-                    .unwrap()
-                    .fifo()
-                    .write(self.state.tx_buffer)?;
-                self.state.tx_buffer = &self.state.tx_buffer[written..];
+            if let Some(result) = self.service_irq(on_queued)? {
+                if result.packet_sent() && self.inter_packet_gap != Duration::ZERO {
+                    self.delay.delay_us(self.inter_packet_gap.as_micros()).await;
+                }
 
-                continue;
+                break Ok(result);
             }
+        }
+    }
+
+    /// Check whether the radio got stuck. Returns false if it's fine to keep waiting without looking at the irq status.
+    fn on_watchdog_expired(&mut self) -> Result<bool, ErrorOf<Self>> {
+        self.state.watchdog_expirations = self.state.watchdog_expirations.saturating_add(1);
 
-            let tx_result = if irq_status.tx_data_sent() {
-                TxResult::Ok
-            } else if irq_status.max_re_tx_reach() {
-                TxResult::MaxReTxReached
-            } else if irq_status.max_bo_cca_reach() {
-                TxResult::MaxBackoffReached
-            } else {
-                unreachable!();
-            };
+        // Check for bad state
+        let state = self.ll().mc_state_0().read()?.state();
+        match state {
+            Ok(State::Lockst) | Err(_) => return Err(self.fatal(FatalError::BadState)),
+            _ => {}
+        }
 
-            self.state.tx_done = true;
-            break Ok(tx_result);
+        // Check for persistent CSMA/CA
+        let protocol1 = self.ll().protocol_1().read()?;
+        if protocol1.csma_on() && protocol1.csma_pers_on() {
+            return Ok(false);
         }
+
+        #[cfg(feature = "defmt-03")]
+        defmt::error!(
+            "{=str}: TX wait timeout out in state: {}",
+            self.label,
+            state
+        );
+
+        Ok(true)
+    }
+
+    /// Handle the interrupt. Returns None if the transmission isn't done yet.
+    fn service_irq(
+        &mut self,
+        on_queued: &mut impl FnMut(usize),
+    ) -> Result<Option<TxResult>, ErrorOf<Self>> {
+        // Figure out what's up
+        let irq_status = self.ll().irq_status().read()?;
+
+        #[cfg(feature = "defmt-verbose")]
+        defmt::trace!(
+            "{=str}: TX wait interrupt: {}",
+            self.label,
+            crate::irq::IrqEvents::from(irq_status)
+        );
+        self.record_irq();
+        self.state.irqs_serviced = self.state.irqs_serviced.saturating_add(1);
+
+        if irq_status.tx_fifo_error() {
+            self.ll().abort().dispatch()?;
+            self.ll().flush_tx_fifo().dispatch()?;
+
+            return Ok(Some(TxResult::FifoError));
+        }
+
+        if irq_status.tx_fifo_almost_empty() && !self.state.tx_buffer.is_empty() {
+            // Refill the fifo
+            let written = self
+                .device
+                .as_mut()
+                .unwrap()
+                .fifo()
+                .write(self.state.tx_buffer)?;
+            self.state.tx_buffer = &self.state.tx_buffer[written..];
+            self.state.queued += written;
+            self.state.fifo_refills = self.state.fifo_refills.saturating_add(1);
+            on_queued(written);
+
+            return Ok(None);
+        }
+
+        // Retransmissions only happen when waiting for an ack
+        if irq_status.rx_data_ready() || irq_status.max_re_tx_reach() {
+            self.state.retransmissions = self.ll().tx_pckt_info().read()?.n_retx();
+        }
+
+        let tx_result = if irq_status.tx_data_sent() || irq_status.rx_data_ready() {
+            self.read_ack_payload()?
+        } else if irq_status.max_re_tx_reach() {
+            TxResult::MaxReTxReached
+        } else if irq_status.max_bo_cca_reach() {
+            TxResult::MaxBackoffReached
+        } else {
+            // Nothing that ends the transmission, e.g. the fifo running low after the last refill
+            return Ok(None);
+        };
+
+        self.state.tx_done = true;
+        self.state.saved_registers.restore(self.ll())?;
+        Ok(Some(tx_result))
+    }
+
+    /// An acknowledgement may have been received with a payload (piggybacking).
+    /// If so, it's in the rx fifo and we copy it to the ack buffer.
+    fn read_ack_payload(&mut self) -> Result<TxResult, ErrorOf<Self>> {
+        let ack_payload_len = self.ll().rx_fifo_status().read()?.n_elem_rxfifo() as usize;
+
+        if ack_payload_len == 0 {
+            return Ok(TxResult::Ok);
+        }
+
+        if ack_payload_len > self.state.ack_buffer.len() {
+            self.ll().flush_rx_fifo().dispatch()?;
+            return Ok(TxResult::AckPayloadTooBigForBuffer);
+        }
+
+        let received = self
+            .device
+            .as_mut()
+            .unwrap()
+            .fifo()
+            .read(&mut self.state.ack_buffer[..ack_payload_len])?;
+
+        #[cfg(feature = "defmt-03")]
+        defmt::trace!(
+            "{=str}: Received ack payload of {} bytes",
+            self.label,
+            received
+        );
+
+        Ok(TxResult::AckPayloadReceived {
+            payload_size: received,
+        })
     }
 
     /// Aborts the transmission immediately
     pub fn abort(mut self) -> Result<S2lp<Ready<PF>, Spi, Sdn, Gpio, Delay>, ErrorOf<Self>> {
         self.ll().abort().dispatch()?;
         self.ll().flush_tx_fifo().dispatch()?;
+        self.state.saved_registers.restore(self.ll())?;
 
         let digital_frequency = self.state.digital_frequency;
         Ok(self.cast_state(Ready::new(digital_frequency)))
     }
 
+    /// Stop, but let the packet that's being sent complete first, so it doesn't go out cut off.
+    /// This is for e.g. shutting down a node without corrupting the last telemetry frame.
+    ///
+    /// This waits for the transmission to be done like [Self::wait], including CSMA/CA and retries,
+    /// unless the radio raises no interrupt within the timeout. Then the transmission is aborted and None is returned.
+    /// A packet that fits in the fifo raises a single interrupt when it's done,
+    /// longer packets raise one every time the fifo is refilled.
+    /// The [inter-packet gap](S2lp::set_inter_packet_gap) isn't waited.
+    ///
+    /// If [Self::wait] already returned the result, there's nothing to wait for and None is returned.
+    pub async fn stop_after_current(
+        mut self,
+        timeout: Duration,
+    ) -> Result<(S2lp<Ready<PF>, Spi, Sdn, Gpio, Delay>, Option<TxResult>), ErrorOf<Self>> {
+        let digital_frequency = self.state.digital_frequency;
+        if self.state.tx_done {
+            return Ok((self.cast_state(Ready::new(digital_frequency)), None));
+        }
+
+        #[cfg(feature = "defmt-03")]
+        defmt::debug!(
+            "{=str}: Waiting for the current packet before stopping the transmission",
+            self.label
+        );
+
+        let mut idle = 0;
+        loop {
+            let wait = TX_WATCHDOG.as_micros().min(timeout.as_micros() - idle);
+            match select(
+                self.irq_trigger.wait(&mut self.gpio_pin),
+                self.delay.delay_us(wait),
+            )
+            .await
+            {
+                Either::First(res) => {
+                    res.map_err(Error::Gpio)?;
+                    idle = 0;
+                }
+                Either::Second(()) => {
+                    idle += wait;
+                    if idle >= timeout.as_micros() {
+                        #[cfg(feature = "defmt-03")]
+                        defmt::warn!(
+                            "{=str}: The packet didn't complete in time, aborting",
+                            self.label
+                        );
+
+                        return Ok((self.abort()?, None));
+                    }
+                    if wait < TX_WATCHDOG.as_micros() || !self.on_watchdog_expired()? {
+                        continue;
+                    }
+                }
+            }
+
+            if let Some(result) = self.service_irq(&mut |_| {})? {
+                // A fifo error ends the transmission without restoring the session registers
+                let ready = if self.state.tx_done {
+                    self.cast_state(Ready::new(digital_frequency))
+                } else {
+                    self.abort()?
+                };
+                return Ok((ready, Some(result)));
+            }
+        }
+    }
+
     /// Finish the transmission. This only returns ok when the [Self::wait] function has returned.
     /// If you need to stop the transmission before it's done, call [Self::abort].
+    #[allow(clippy::result_large_err)] // The driver is handed back as is, so it can be retried
     pub fn finish(self) -> Result<S2lp<Ready<PF>, Spi, Sdn, Gpio, Delay>, Self> {
         if self.state.tx_done {
             let digital_frequency = self.state.digital_frequency;
@@ -114,12 +434,97 @@ where
     }
 }
 
+/// The result of a transmission together with statistics about it, as returned by [S2lp::wait_with_report].
+///
+/// The radio doesn't count the CSMA/CA backoffs and the driver has no clock, so those aren't part of it.
+/// Time the transmission with the timer of the platform if needed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct TxReport {
+    /// The result of the transmission
+    pub result: TxResult,
+    /// The amount of times the fifo had to be refilled because the packet didn't fit in it
+    pub fifo_refills: u16,
+    /// The amount of interrupts handled
+    pub irqs_serviced: u16,
+    /// The amount of times no interrupt came in within the [TX_WATCHDOG] time.
+    /// This is normal when persistent CSMA/CA keeps the channel busy, but otherwise points to lost interrupts.
+    pub watchdog_expirations: u8,
+    /// The amount of retransmissions done because no ack was received (STack only)
+    pub retransmissions: u8,
+}
+
+/// The RSSI measured during the clear channel assessments of a transmission, see [S2lp::wait_with_cca_stats]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct CcaStats {
+    /// The amount of RSSI samples, one per CCA period
+    pub samples: u16,
+    /// The lowest RSSI measured
+    pub min: Rssi,
+    /// The highest RSSI measured
+    pub max: Rssi,
+    sum: u32,
+}
+
+impl CcaStats {
+    fn new(rssi: Rssi) -> Self {
+        Self {
+            samples: 1,
+            min: rssi,
+            max: rssi,
+            sum: rssi.register() as u32,
+        }
+    }
+
+    fn add(&mut self, rssi: Rssi) {
+        self.samples = self.samples.saturating_add(1);
+        self.min = self.min.min(rssi);
+        self.max = self.max.max(rssi);
+        self.sum = self.sum.saturating_add(rssi.register() as u32);
+    }
+
+    /// The average of the measured RSSI
+    pub fn average(&self) -> Rssi {
+        Rssi::from_register((self.sum / self.samples as u32) as u8)
+    }
+}
+
+/// What woke the host up, for [S2lp::resume]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum Wakeup {
+    /// The interrupt came in
+    Irq,
+    /// The timeout of the last [TxStep::WaitForIrq] passed without an interrupt
+    Timeout,
+}
+
+/// What the host should do next, as returned by [S2lp::resume]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub enum TxStep {
+    /// Wait for the interrupt, but at most for the timeout
+    WaitForIrq { timeout: Duration },
+    /// The transmission is done. Call [S2lp::finish] to get back to ready.
+    Done(TxResult),
+}
+
 /// The result of the TX operation
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
 pub enum TxResult {
     /// All went fine and the packet is sent
     Ok,
+    /// The packet is sent and the acknowledgement came back with a payload.
+    /// The payload has been written to the ack buffer given to the send function.
+    AckPayloadReceived {
+        /// The size of the ack payload in bytes
+        payload_size: usize,
+    },
+    /// The packet is sent, but the payload of the acknowledgement didn't fit in the ack buffer.
+    /// The payload has been discarded.
+    AckPayloadTooBigForBuffer,
     /// There was trouble keeping the fifo full.
     /// This may be a performance issue where polling isn't happening fast enough.
     ///
@@ -132,3 +537,16 @@ pub enum TxResult {
     /// The transmission was already done previously
     TxAlreadyDone,
 }
+
+impl TxResult {
+    /// Whether the packet went out over the air with this result
+    pub fn packet_sent(&self) -> bool {
+        matches!(
+            self,
+            Self::Ok
+                | Self::AckPayloadReceived { .. }
+                | Self::AckPayloadTooBigForBuffer
+                | Self::MaxReTxReached
+        )
+    }
+}
@@ -0,0 +1,59 @@
+//! Typed durations for the timing parameters of the driver
+
+/// A span of time with microsecond resolution.
+///
+/// Used for all timing parameters so units can't get mixed up.
+/// Conversions saturate at the maximum of ~71 minutes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(feature = "defmt-03", derive(defmt::Format))]
+pub struct Duration {
+    micros: u32,
+}
+
+impl Duration {
+    /// No time at all
+    pub const ZERO: Self = Self { micros: 0 };
+    /// The longest representable duration
+    pub const MAX: Self = Self { micros: u32::MAX };
+
+    /// Create a duration from microseconds
+    pub const fn from_micros(micros: u32) -> Self {
+        Self { micros }
+    }
+
+    /// Create a duration from milliseconds
+    pub const fn from_millis(millis: u32) -> Self {
+        Self {
+            micros: millis.saturating_mul(1_000),
+        }
+    }
+
+    /// Create a duration from seconds
+    pub const fn from_secs(secs: u32) -> Self {
+        Self {
+            micros: secs.saturating_mul(1_000_000),
+        }
+    }
+
+    /// The duration in whole microseconds
+    pub const fn as_micros(self) -> u32 {
+        self.micros
+    }
+
+    /// The duration in whole milliseconds
+    pub const fn as_millis(self) -> u32 {
+        self.micros / 1_000
+    }
+}
+
+impl From<core::time::Duration> for Duration {
+    fn from(value: core::time::Duration) -> Self {
+        Self::from_micros(value.as_micros().try_into().unwrap_or(u32::MAX))
+    }
+}
+
+impl From<Duration> for core::time::Duration {
+    fn from(value: Duration) -> Self {
+        core::time::Duration::from_micros(value.micros as u64)
+    }
+}
@@ -0,0 +1,730 @@
+//! A register level simulator of the S2-LP for host tests.
+//!
+//! It models the register file, the command strobes, the fifo fill levels and the interrupt status
+//! well enough for the driver to run through init, packet sending and receiving.
+//! Everything happens instantly, but spi traffic and delays are counted as simulated time.
+
+#![allow(dead_code)]
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+    convert::Infallible,
+    rc::Rc,
+};
+
+use embedded_hal::{
+    digital::{ErrorType, InputPin, OutputPin},
+    spi::{self, Operation, SpiBus, SpiDevice},
+};
+use embedded_hal_async::{delay::DelayNs, digital::Wait};
+use s2lp::{
+    packet_format::Uninitialized,
+    states::{shutdown::Config, Ready, Shutdown},
+    GpioNumber, S2lp,
+};
+
+/// The simulated spi clock
+const SPI_FREQUENCY: u64 = 8_000_000;
+const FIFO_SIZE: usize = 128;
+
+const ADDR_RSSI_TH: usize = 0x18;
+const ADDR_PCKT_CTRL_4: usize = 0x2D;
+const ADDR_PCKT_CTRL_3: usize = 0x2E;
+const ADDR_PCKT_CTRL_1: usize = 0x30;
+const ADDR_PCKT_LEN: usize = 0x31;
+const ADDR_PROTOCOL_2: usize = 0x39;
+const ADDR_PROTOCOL_1: usize = 0x3A;
+const ADDR_TIMERS_5: usize = 0x46;
+const ADDR_PCKT_FLT_OPTIONS: usize = 0x40;
+const ADDR_IRQ_MASK: usize = 0x50;
+const ADDR_MC_STATE_1: usize = 0x8D;
+const ADDR_MC_STATE_0: usize = 0x8E;
+const ADDR_TX_FIFO_STATUS: usize = 0x8F;
+const ADDR_RX_FIFO_STATUS: usize = 0x90;
+const ADDR_RX_PCKT_LEN: usize = 0xA4;
+const ADDR_RSSI_LEVEL_RUN: usize = 0xEF;
+const ADDR_DEVICE_INFO_1: usize = 0xF0;
+const ADDR_DEVICE_INFO_0: usize = 0xF1;
+const ADDR_IRQ_STATUS: usize = 0xFA;
+const ADDR_FIFO: u8 = 0xFF;
+
+const IRQ_RX_DATA_READY: u32 = 1 << 0;
+const IRQ_TX_DATA_SENT: u32 = 1 << 2;
+const IRQ_RX_FIFO_ERROR: u32 = 1 << 6;
+const IRQ_TX_FIFO_ERROR: u32 = 1 << 5;
+const IRQ_RX_DATA_DISCARDED: u32 = 1 << 1;
+const IRQ_CRC_ERROR: u32 = 1 << 4;
+const IRQ_RX_TIMEOUT: u32 = 1 << 28;
+const IRQ_TX_FIFO_ALMOST_EMPTY: u32 = 1 << 8;
+const IRQ_RX_FIFO_ALMOST_FULL: u32 = 1 << 9;
+const IRQ_MAX_BO_CCA_REACH: u32 = 1 << 11;
+const IRQ_VALID_PREAMBLE: u32 = 1 << 12;
+const IRQ_RSSI_ABOVE_TH: u32 = 1 << 14;
+
+const STATE_READY: u8 = 0x00;
+const STATE_LOCKON: u8 = 0x0C;
+const STATE_LOCKST: u8 = 0x14;
+const STATE_STANDBY: u8 = 0x02;
+const STATE_SLEEP_B: u8 = 0x03;
+const STATE_RX: u8 = 0x30;
+const STATE_TX: u8 = 0x5C;
+
+/// The things the simulator counts
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Counters {
+    /// The amount of spi transactions
+    pub spi_transactions: u32,
+    /// The amount of bytes sent and received over spi
+    pub spi_bytes: u32,
+    /// The amount of tx fifo writes done while transmitting
+    pub fifo_refills: u32,
+    /// The simulated time in nanoseconds, spi traffic and delays
+    pub time_ns: u64,
+}
+
+struct SimState {
+    registers: [u8; 256],
+    tx_fifo: usize,
+    tx_sent: usize,
+    rx_fifo: VecDeque<u8>,
+    /// The payloads to receive and whether their CRC is bad
+    pending_rx_packets: VecDeque<(Vec<u8>, bool)>,
+    /// The values the running RSSI takes on every read in RX
+    rssi_noise: VecDeque<u8>,
+    /// The CSMA/CA engine is assessing the channel
+    csma_active: bool,
+    /// The synthesizer doesn't lock
+    lock_fails: bool,
+    /// Waits on the irq pin only complete when an interrupt is pending
+    strict_irq_pin: bool,
+    /// The simulated time it takes a raised interrupt to show on the irq pin
+    irq_latency_ns: u64,
+    /// The simulated time at which the last raised interrupt shows on the irq pin
+    irq_visible_at_ns: u64,
+    counters: Counters,
+    /// The header of the spi transaction in progress
+    header: Option<(u8, u8)>,
+    /// The amount of bytes of the spi transaction in progress
+    transaction_bytes: usize,
+}
+
+impl SimState {
+    fn new() -> Self {
+        let mut registers = [0; 256];
+        registers[ADDR_DEVICE_INFO_1] = 0x03;
+        registers[ADDR_DEVICE_INFO_0] = 0xC1;
+        // RCO calibration done
+        registers[ADDR_MC_STATE_1] = 0x52;
+
+        Self {
+            registers,
+            tx_fifo: 0,
+            tx_sent: 0,
+            rx_fifo: VecDeque::new(),
+            pending_rx_packets: VecDeque::new(),
+            rssi_noise: VecDeque::new(),
+            csma_active: false,
+            lock_fails: false,
+            strict_irq_pin: false,
+            irq_latency_ns: 0,
+            irq_visible_at_ns: 0,
+            counters: Counters::default(),
+            header: None,
+            transaction_bytes: 0,
+        }
+    }
+
+    fn read_u32(&self, address: usize) -> u32 {
+        u32::from_be_bytes(self.registers[address..address + 4].try_into().unwrap())
+    }
+
+    fn raise_irq(&mut self, irq: u32) {
+        let status = self.read_u32(ADDR_IRQ_STATUS) | irq;
+        self.registers[ADDR_IRQ_STATUS..ADDR_IRQ_STATUS + 4].copy_from_slice(&status.to_be_bytes());
+        self.irq_visible_at_ns = self.counters.time_ns + self.irq_latency_ns;
+    }
+
+    fn state(&self) -> u8 {
+        self.registers[ADDR_MC_STATE_0] >> 1
+    }
+
+    fn set_state(&mut self, state: u8) {
+        self.registers[ADDR_MC_STATE_0] = (state << 1) | 1;
+    }
+
+    fn low_duty_cycle(&self) -> bool {
+        self.registers[ADDR_PROTOCOL_1] & (1 << 7) != 0
+    }
+
+    /// The amount of payload bytes of the packet to send. The address fields are not in the fifo.
+    fn tx_payload_len(&self) -> usize {
+        let packet_len = u16::from_be_bytes([
+            self.registers[ADDR_PCKT_LEN],
+            self.registers[ADDR_PCKT_LEN + 1],
+        ]) as usize;
+
+        let address_len = if self.registers[ADDR_PCKT_CTRL_3] >> 6 == 3 {
+            2
+        } else if self.registers[ADDR_PCKT_CTRL_4] & (1 << 3) != 0 {
+            1
+        } else {
+            0
+        };
+
+        packet_len.saturating_sub(address_len)
+    }
+
+    /// Send out what's in the tx fifo
+    fn drain_tx_fifo(&mut self) {
+        self.tx_sent += self.tx_fifo;
+        self.tx_fifo = 0;
+
+        if self.tx_sent >= self.tx_payload_len() {
+            self.set_state(STATE_READY);
+            self.raise_irq(IRQ_TX_DATA_SENT);
+        } else {
+            self.raise_irq(IRQ_TX_FIFO_ALMOST_EMPTY);
+        }
+    }
+
+    /// The channel assessment ends with the last queued RSSI sample. A busy channel gives up on the packet.
+    fn end_csma(&mut self) {
+        self.csma_active = false;
+
+        if self.registers[ADDR_RSSI_LEVEL_RUN] > self.registers[ADDR_RSSI_TH] {
+            self.set_state(STATE_READY);
+            self.raise_irq(IRQ_MAX_BO_CCA_REACH);
+        } else {
+            self.set_state(STATE_TX);
+            self.tx_sent = 0;
+            self.drain_tx_fifo();
+        }
+    }
+
+    /// Move received data into the rx fifo
+    fn fill_rx_fifo(&mut self) {
+        let Some((packet, bad_crc)) = self.pending_rx_packets.front_mut() else {
+            return;
+        };
+        let bad_crc = *bad_crc;
+
+        let len = (FIFO_SIZE - self.rx_fifo.len()).min(packet.len());
+        self.rx_fifo.extend(packet.drain(..len));
+
+        // Direct through fifo has no packet end, the stream just keeps coming
+        let direct = (self.registers[ADDR_PCKT_CTRL_3] >> 4) & 0b11 == 1;
+        if direct {
+            if packet.is_empty() {
+                self.pending_rx_packets.pop_front();
+            }
+            self.raise_irq(IRQ_RX_FIFO_ALMOST_FULL);
+        } else if packet.is_empty() {
+            self.pending_rx_packets.pop_front();
+            self.set_state(STATE_READY);
+            if !bad_crc {
+                self.raise_irq(IRQ_RX_DATA_READY);
+            } else if self.registers[ADDR_PCKT_FLT_OPTIONS] & 1 != 0
+                && self.registers[ADDR_PROTOCOL_1] & (1 << 0) != 0
+            {
+                self.rx_fifo.clear();
+                self.raise_irq(IRQ_CRC_ERROR | IRQ_RX_DATA_DISCARDED);
+            } else {
+                self.raise_irq(IRQ_CRC_ERROR | IRQ_RX_DATA_READY);
+            }
+        } else {
+            self.raise_irq(IRQ_RX_FIFO_ALMOST_FULL);
+        }
+    }
+
+    fn command(&mut self, command: u8) {
+        match command {
+            // TX with CSMA/CA, which listens to the channel first
+            0x60 if self.registers[ADDR_PROTOCOL_1] & (1 << 2) != 0 => {
+                self.set_state(STATE_RX);
+                self.csma_active = true;
+            }
+            // TX of a PN9 sequence, which goes on until aborted
+            0x60 if (self.registers[ADDR_PCKT_CTRL_1] >> 2) & 0b11 == 3 => self.set_state(STATE_TX),
+            // TX
+            0x60 => {
+                self.set_state(STATE_TX);
+                self.tx_sent = 0;
+                self.drain_tx_fifo();
+            }
+            // RX
+            0x61 => {
+                self.set_state(STATE_RX);
+                if let Some((packet, _)) = self.pending_rx_packets.front() {
+                    let len = (packet.len() as u16).to_be_bytes();
+                    self.registers[ADDR_RX_PCKT_LEN..ADDR_RX_PCKT_LEN + 2].copy_from_slice(&len);
+                    self.raise_irq(IRQ_VALID_PREAMBLE | IRQ_RSSI_ABOVE_TH);
+                } else if self.registers[ADDR_PROTOCOL_2] & 0b1010_0000 != 0
+                    && self.registers[ADDR_TIMERS_5] != 0
+                {
+                    // Nobody is sending, so a timer that's stopped by a preamble or carrier runs out
+                    self.set_state(STATE_READY);
+                    self.raise_irq(IRQ_RX_TIMEOUT | IRQ_RX_DATA_DISCARDED);
+                }
+                self.fill_rx_fifo();
+            }
+            // LOCKRX | LOCKTX
+            0x65 | 0x66 if self.lock_fails => self.set_state(STATE_LOCKST),
+            0x65 | 0x66 => self.set_state(STATE_LOCKON),
+            // READY | ABORT
+            0x62 | 0x67 => {
+                self.set_state(STATE_READY);
+                self.csma_active = false;
+            }
+            // STANDBY
+            0x63 => self.set_state(STATE_STANDBY),
+            // SLEEP, with fifo retention
+            0x64 => self.set_state(STATE_SLEEP_B),
+            // FLUSH_RX_FIFO
+            0x71 => self.rx_fifo.clear(),
+            // FLUSH_TX_FIFO
+            0x72 => self.tx_fifo = 0,
+            _ => {}
+        }
+    }
+
+    fn write(&mut self, address: u8, data: &[u8]) {
+        if address == ADDR_FIFO {
+            self.tx_fifo = (self.tx_fifo + data.len()).min(FIFO_SIZE);
+
+            if self.state() == STATE_TX {
+                self.counters.fifo_refills += 1;
+                self.drain_tx_fifo();
+            }
+            return;
+        }
+
+        let address = address as usize;
+        self.registers[address..address + data.len()].copy_from_slice(data);
+    }
+
+    fn read(&mut self, address: u8, data: &mut [u8]) {
+        if address == ADDR_FIFO {
+            for byte in data.iter_mut() {
+                *byte = self.rx_fifo.pop_front().unwrap_or_default();
+            }
+
+            if self.state() == STATE_RX {
+                self.fill_rx_fifo();
+            }
+            return;
+        }
+
+        let address = address as usize;
+        if address == ADDR_RSSI_LEVEL_RUN && self.state() == STATE_RX {
+            if let Some(rssi) = self.rssi_noise.pop_front() {
+                self.registers[ADDR_RSSI_LEVEL_RUN] = rssi;
+            }
+        }
+        if address == ADDR_RSSI_LEVEL_RUN && self.csma_active && self.rssi_noise.is_empty() {
+            self.end_csma();
+        }
+        self.registers[ADDR_TX_FIFO_STATUS] = self.tx_fifo as u8;
+        self.registers[ADDR_RX_FIFO_STATUS] = self.rx_fifo.len() as u8;
+
+        if address == ADDR_IRQ_STATUS
+            && self.low_duty_cycle()
+            && self.state() == STATE_SLEEP_B
+            && self.read_u32(ADDR_IRQ_STATUS) == 0
+        {
+            // Waiting for something to happen lets the wake-up timer expire
+            self.command(0x61);
+        }
+
+        data.copy_from_slice(&self.registers[address..address + data.len()]);
+
+        if address == ADDR_IRQ_STATUS {
+            // Reading the status clears it
+            self.registers[ADDR_IRQ_STATUS..ADDR_IRQ_STATUS + 4].fill(0);
+        }
+    }
+
+    /// Handle bytes written over spi. The first write of a transaction is the header.
+    fn spi_write(&mut self, data: &[u8]) {
+        self.transaction_bytes += data.len();
+        match self.header {
+            None => {
+                self.header = Some((data[0], data[1]));
+                if data[0] == 0b1000_0000 {
+                    self.command(data[1]);
+                }
+            }
+            Some((_, address)) => self.write(address, data),
+        }
+    }
+
+    /// Handle bytes read over spi
+    fn spi_read(&mut self, data: &mut [u8]) {
+        self.transaction_bytes += data.len();
+        let (_, address) = self.header.expect("Read without header");
+        self.read(address, data);
+    }
+
+    /// Finish the spi transaction in progress, like when the chip select is deasserted
+    fn end_transaction(&mut self) {
+        let bytes = core::mem::take(&mut self.transaction_bytes);
+        self.header = None;
+
+        self.counters.spi_transactions += 1;
+        self.counters.spi_bytes += bytes as u32;
+        self.counters.time_ns += bytes as u64 * 8 * 1_000_000_000 / SPI_FREQUENCY;
+    }
+}
+
+/// The simulated radio. Hand out the spi, pins and delay to the driver.
+#[derive(Clone)]
+pub struct Simulator(Rc<RefCell<SimState>>);
+
+impl Simulator {
+    pub fn new() -> Self {
+        Self(Rc::new(RefCell::new(SimState::new())))
+    }
+
+    pub fn spi(&self) -> SimSpi {
+        SimSpi(self.clone())
+    }
+
+    pub fn sdn(&self) -> SimPin {
+        SimPin
+    }
+
+    pub fn irq_pin(&self) -> SimIrqPin {
+        SimIrqPin(self.clone())
+    }
+
+    pub fn delay(&self) -> SimDelay {
+        SimDelay(self.clone())
+    }
+
+    /// Get the counters since the last reset
+    pub fn counters(&self) -> Counters {
+        self.0.borrow().counters
+    }
+
+    pub fn reset_counters(&self) {
+        self.0.borrow_mut().counters = Counters::default();
+    }
+
+    /// Let the radio receive the given payload when it's in RX. Packets are received in the order they're queued.
+    pub fn queue_rx_packet(&self, payload: &[u8]) {
+        self.0
+            .borrow_mut()
+            .pending_rx_packets
+            .push_back((payload.to_vec(), false));
+    }
+
+    /// Like [Self::queue_rx_packet], but the packet arrives with a bad CRC.
+    /// It's discarded when the packet filter checks the CRC.
+    pub fn queue_rx_packet_with_bad_crc(&self, payload: &[u8]) {
+        self.0
+            .borrow_mut()
+            .pending_rx_packets
+            .push_back((payload.to_vec(), true));
+    }
+
+    /// Let the running RSSI take on the given values, one per read while in RX. It keeps the last value after that.
+    ///
+    /// With CSMA/CA on, the channel assessment lasts until the last value is read.
+    /// The packet is sent if that value is at or below the RSSI threshold.
+    pub fn queue_rssi_noise(&self, samples: impl IntoIterator<Item = u8>) {
+        self.0.borrow_mut().rssi_noise.extend(samples);
+    }
+
+    /// Let the RX fifo overflow, like when the driver doesn't service it in time
+    pub fn overflow_rx_fifo(&self) {
+        self.0.borrow_mut().raise_irq(IRQ_RX_FIFO_ERROR);
+    }
+
+    /// Only complete waits on the irq pin when an interrupt is pending, instead of always.
+    /// Then waiting for an event the simulator doesn't model (like an automatic ack being sent) never completes.
+    pub fn strict_irq_pin(&self) {
+        self.0.borrow_mut().strict_irq_pin = true;
+    }
+
+    /// Let raised interrupts only show on the irq pin after the given simulated time has passed,
+    /// like the time it takes to send out the fifo. The irq status register shows them right away.
+    pub fn irq_latency(&self, ns: u64) {
+        self.0.borrow_mut().irq_latency_ns = ns;
+    }
+
+    /// Let the TX fifo underflow, like when the driver doesn't refill it in time
+    pub fn underflow_tx_fifo(&self) {
+        self.0.borrow_mut().raise_irq(IRQ_TX_FIFO_ERROR);
+    }
+
+    /// Let the RX timer expire. Like the radio, this also discards the (empty) packet.
+    /// With low duty cycle, the radio goes to sleep until the next wake-up instead of to ready.
+    pub fn expire_rx_timer(&self) {
+        let mut state = self.0.borrow_mut();
+        if state.low_duty_cycle() {
+            state.set_state(STATE_SLEEP_B);
+        } else {
+            state.set_state(STATE_READY);
+        }
+        state.raise_irq(IRQ_RX_TIMEOUT | IRQ_RX_DATA_DISCARDED);
+    }
+
+    /// Drive a gpio of the radio high. When it's set up as a command input, the command is given.
+    pub fn raise_gpio(&self, pin: u8) {
+        let mut state = self.0.borrow_mut();
+        let gpio_conf = state.registers[pin as usize];
+
+        // Input mode with the TX or RX command selected
+        match (gpio_conf & 0b11, gpio_conf >> 3) {
+            (0b01, 0) => state.command(0x60),
+            (0b01, 1) => state.command(0x61),
+            _ => {}
+        }
+    }
+
+    /// Let the RCO calibration never finish, like with a broken oscillator
+    pub fn stall_rco_calibration(&self) {
+        self.0.borrow_mut().registers[ADDR_MC_STATE_1] &= !(1 << 4);
+    }
+
+    /// Reset the radio like a dip in the supply would, so all registers are back at their reset values
+    pub fn brown_out(&self) {
+        self.0.borrow_mut().registers = SimState::new().registers;
+    }
+
+    /// Let the synthesizer fail to lock, so the radio ends up in LOCKST
+    pub fn fail_synth_lock(&self) {
+        self.0.borrow_mut().lock_fails = true;
+    }
+
+    /// Read a register of the simulated radio
+    pub fn register(&self, address: u8) -> u8 {
+        self.0.borrow().registers[address as usize]
+    }
+
+    /// The interrupts that are enabled in the irq mask
+    pub fn irq_mask(&self) -> u32 {
+        self.0.borrow().read_u32(ADDR_IRQ_MASK)
+    }
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct SimSpi(Simulator);
+
+impl spi::ErrorType for SimSpi {
+    type Error = Infallible;
+}
+
+impl SpiDevice for SimSpi {
+    fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Self::Error> {
+        let mut state = self.0 .0.borrow_mut();
+
+        for operation in operations.iter_mut() {
+            match operation {
+                Operation::Write(data) => state.spi_write(data),
+                Operation::Read(data) => state.spi_read(data),
+                _ => unimplemented!(),
+            }
+        }
+
+        state.end_transaction();
+
+        Ok(())
+    }
+}
+
+/// An spi bus shared by multiple simulated radios, like on a board with more than one radio.
+/// The bus talks to the radio of which the chip select is low.
+///
+/// Use it with the shared bus implementations of `embedded-hal-bus`.
+pub struct SimBus {
+    radios: Vec<Simulator>,
+    selected: Rc<Cell<Option<usize>>>,
+}
+
+impl SimBus {
+    pub fn new(radios: &[&Simulator]) -> Self {
+        Self {
+            radios: radios.iter().map(|radio| (*radio).clone()).collect(),
+            selected: Rc::new(Cell::new(None)),
+        }
+    }
+
+    /// Get the chip select pin of the radio with the given index
+    pub fn cs(&self, index: usize) -> SimCs {
+        SimCs {
+            index,
+            radios: self.radios.clone(),
+            selected: self.selected.clone(),
+        }
+    }
+
+    fn selected(&self) -> &Simulator {
+        let index = self.selected.get().expect("Bus used without a chip select");
+        &self.radios[index]
+    }
+}
+
+impl spi::ErrorType for SimBus {
+    type Error = Infallible;
+}
+
+impl SpiBus for SimBus {
+    fn read(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        self.selected().0.borrow_mut().spi_read(words);
+        Ok(())
+    }
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        self.selected().0.borrow_mut().spi_write(words);
+        Ok(())
+    }
+
+    fn transfer(&mut self, _read: &mut [u8], _write: &[u8]) -> Result<(), Self::Error> {
+        unimplemented!()
+    }
+
+    fn transfer_in_place(&mut self, _words: &mut [u8]) -> Result<(), Self::Error> {
+        unimplemented!()
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// The chip select pin of a radio on a [SimBus]
+pub struct SimCs {
+    index: usize,
+    radios: Vec<Simulator>,
+    selected: Rc<Cell<Option<usize>>>,
+}
+
+impl ErrorType for SimCs {
+    type Error = Infallible;
+}
+
+impl OutputPin for SimCs {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        assert_eq!(
+            self.selected.get(),
+            None,
+            "Multiple radios selected on the same bus"
+        );
+        self.selected.set(Some(self.index));
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        if self.selected.get() == Some(self.index) {
+            self.selected.set(None);
+            self.radios[self.index].0.borrow_mut().end_transaction();
+        }
+        Ok(())
+    }
+}
+
+/// A pin that does nothing, used for the shutdown pin
+pub struct SimPin;
+
+impl ErrorType for SimPin {
+    type Error = Infallible;
+}
+
+impl OutputPin for SimPin {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// The irq pin of the radio. Since the simulation is instant, an event is always ready when waited on,
+/// unless [Simulator::strict_irq_pin] is used.
+pub struct SimIrqPin(Simulator);
+
+impl SimIrqPin {
+    async fn wait_for_event(&mut self) -> Result<(), Infallible> {
+        if self.0 .0.borrow().strict_irq_pin && !self.is_low()? {
+            // Nothing happens in the simulation unless the driver does something
+            core::future::pending::<()>().await;
+        }
+
+        Ok(())
+    }
+}
+
+impl ErrorType for SimIrqPin {
+    type Error = Infallible;
+}
+
+impl InputPin for SimIrqPin {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        self.is_low().map(|low| !low)
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        let state = self.0 .0.borrow();
+        Ok(
+            state.read_u32(ADDR_IRQ_STATUS) & state.read_u32(ADDR_IRQ_MASK) != 0
+                && state.counters.time_ns >= state.irq_visible_at_ns,
+        )
+    }
+}
+
+impl Wait for SimIrqPin {
+    async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_event().await
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_event().await
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_event().await
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_event().await
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_event().await
+    }
+}
+
+/// A delay that only adds to the simulated time
+pub struct SimDelay(Simulator);
+
+impl DelayNs for SimDelay {
+    async fn delay_ns(&mut self, ns: u32) {
+        self.0 .0.borrow_mut().counters.time_ns += ns as u64;
+    }
+}
+
+/// The driver running on the simulator
+pub type Radio<State> = S2lp<State, SimSpi, SimPin, SimIrqPin, SimDelay>;
+
+/// A driver on the simulator in shutdown, with the irq on gpio 0
+pub fn new_radio(sim: &Simulator) -> Radio<Shutdown> {
+    S2lp::new(
+        sim.spi(),
+        sim.sdn(),
+        sim.irq_pin(),
+        GpioNumber::Gpio0,
+        sim.delay(),
+    )
+}
+
+/// A driver on the simulator that's initialized with the default config
+pub async fn ready_radio(sim: &Simulator) -> Radio<Ready<Uninitialized>> {
+    new_radio(sim).init(Config::default()).await.unwrap()
+}
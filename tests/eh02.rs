@@ -0,0 +1,151 @@
+#![cfg(all(feature = "embedded-hal-02", feature = "basic"))]
+
+mod common;
+
+use std::{cell::RefCell, convert::Infallible};
+
+use common::{SimBus, SimCs, SimDelay, SimIrqPin, Simulator};
+use embedded_hal::{digital::InputPin, spi::SpiBus};
+use embedded_hal_02::{
+    blocking::{
+        delay::DelayUs,
+        spi::{Transfer, Write},
+    },
+    digital::v2 as digital_02,
+};
+use embedded_hal_async::delay::DelayNs;
+use s2lp::{
+    eh02::{Eh02Delay, Eh02IrqPin, Eh02OutputPin, Eh02Spi},
+    ll::{CrcMode, LenWid},
+    packet_format::{
+        Basic, BasicConfig, BasicTxMetaData, PacketFilteringOptions, PostambleLength,
+        PreamblePattern, SyncWord,
+    },
+    states::{shutdown::Config, tx::TxResult},
+    GpioNumber, S2lp,
+};
+
+/// The simulated spi bus behind the embedded-hal 0.2 traits
+struct Bus(SimBus);
+
+impl Transfer<u8> for Bus {
+    type Error = Infallible;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
+        // The adapter only transfers to read
+        self.0.read(words)?;
+        Ok(words)
+    }
+}
+
+impl Write<u8> for Bus {
+    type Error = Infallible;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        self.0.write(words)
+    }
+}
+
+struct Cs(SimCs);
+
+impl digital_02::OutputPin for Cs {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        embedded_hal::digital::OutputPin::set_low(&mut self.0)
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        embedded_hal::digital::OutputPin::set_high(&mut self.0)
+    }
+}
+
+struct Sdn;
+
+impl digital_02::OutputPin for Sdn {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// The 0.2 pins are read through a shared reference
+struct Irq(RefCell<SimIrqPin>);
+
+impl digital_02::InputPin for Irq {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        self.0.borrow_mut().is_high()
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        self.is_high().map(|high| !high)
+    }
+}
+
+/// A blocking delay that moves the simulated time forward
+struct Delay(SimDelay);
+
+impl DelayUs<u32> for Delay {
+    fn delay_us(&mut self, us: u32) {
+        embassy_futures::block_on(DelayNs::delay_us(&mut self.0, us));
+    }
+}
+
+#[futures_test::test]
+async fn send_refilled_packet() {
+    let sim = Simulator::new();
+    // The packet takes a while to go out, so the interrupts don't show right away
+    sim.irq_latency(50_000);
+    let bus = SimBus::new(&[&sim]);
+    let cs = bus.cs(0);
+
+    let radio = S2lp::new(
+        Eh02Spi::new(Bus(bus), Cs(cs)),
+        Eh02OutputPin(Sdn),
+        Eh02IrqPin(Irq(RefCell::new(sim.irq_pin()))),
+        GpioNumber::Gpio0,
+        Eh02Delay(Delay(sim.delay())),
+    )
+    .init(Config::default())
+    .await
+    .unwrap()
+    .set_format::<Basic>(&BasicConfig {
+        preamble_length: 32,
+        preamble_pattern: PreamblePattern::Pattern0,
+        sync_length: 32,
+        sync_pattern: SyncWord::msb_first(0x12345678),
+        include_address: false,
+        packet_length_encoding: LenWid::Bytes2,
+        postamble_length: PostambleLength::NONE,
+        crc_mode: CrcMode::CrcPoly0X07,
+        packet_filter: PacketFilteringOptions::default(),
+    })
+    .unwrap();
+
+    sim.reset_counters();
+    let mut tx = radio
+        .send_packet(
+            &BasicTxMetaData {
+                destination_address: None,
+            },
+            &[0xAB; 128 * 3],
+        )
+        .unwrap();
+    let report = tx.wait_with_report().await.unwrap();
+    assert_eq!(report.result, TxResult::Ok);
+    assert_eq!(report.fifo_refills, 2);
+    let Ok(_radio) = tx.finish() else {
+        unreachable!()
+    };
+
+    // Every interrupt is seen shortly after it shows, not after the TX watchdog
+    let time_ns = sim.counters().time_ns;
+    assert!(time_ns < 1_000_000, "Sending took {time_ns} ns");
+}
@@ -0,0 +1,219 @@
+//! Regression suite for the amount of spi traffic and (simulated) time the common operations take.
+//!
+//! The bounds are set at what the driver currently does. If a change makes an operation more
+//! expensive, the test fails and the bound needs a conscious bump.
+//! A failing check prints all the numbers of the operation.
+//!
+//! The `sanity-checks` feature adds reads on purpose, so the bounds don't apply with it.
+
+#![cfg(all(feature = "basic", not(feature = "sanity-checks")))]
+
+mod common;
+
+use common::{new_radio, ready_radio, Counters, Radio, Simulator};
+use s2lp::{
+    ll::{CrcMode, LenWid},
+    packet_format::{
+        Basic, BasicConfig, BasicTxMetaData, PacketFilteringOptions, PostambleLength,
+        PreamblePattern, SyncWord,
+    },
+    states::{shutdown::Config, tx::TxResult, Ready},
+};
+
+// The current costs. Lower them when an optimization lands.
+const INIT_TRANSACTIONS: u32 = 32;
+const INIT_BYTES: u32 = 109;
+const INIT_TIME_NS: u64 = 110_000;
+const SET_FORMAT_TRANSACTIONS: u32 = 24;
+const SET_FORMAT_BYTES: u32 = 76;
+const SET_FORMAT_TIME_NS: u64 = 76_000;
+const RECONFIGURE_TRANSACTIONS: u32 = 2;
+const RECONFIGURE_BYTES: u32 = 62;
+const RECONFIGURE_TIME_NS: u64 = 62_000;
+const SEND_PACKET_TRANSACTIONS: u32 = 17;
+const SEND_PACKET_BYTES: u32 = 95;
+const SEND_PACKET_TIME_NS: u64 = 95_000;
+const REFILL_TRANSACTIONS: u32 = 3;
+const REFILL_BYTES: u32 = 11;
+const REFILL_TIME_NS: u64 = 11_000;
+
+fn basic_config() -> BasicConfig {
+    BasicConfig {
+        preamble_length: 128,
+        preamble_pattern: PreamblePattern::Pattern0,
+        sync_length: 32,
+        sync_pattern: SyncWord::msb_first(0x12345678),
+        include_address: true,
+        packet_length_encoding: LenWid::Bytes2,
+        postamble_length: PostambleLength::NONE,
+        crc_mode: CrcMode::CrcPoly0X1021,
+        packet_filter: PacketFilteringOptions::default(),
+    }
+}
+
+async fn basic_radio(sim: &Simulator) -> Radio<Ready<Basic>> {
+    ready_radio(sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap()
+}
+
+fn check(name: &str, counters: Counters, max_transactions: u32, max_bytes: u32, max_time_ns: u64) {
+    assert!(
+        counters.spi_transactions <= max_transactions,
+        "{name} uses {} spi transactions, expected at most {max_transactions}: {counters:?}",
+        counters.spi_transactions
+    );
+    assert!(
+        counters.spi_bytes <= max_bytes,
+        "{name} uses {} spi bytes, expected at most {max_bytes}: {counters:?}",
+        counters.spi_bytes
+    );
+    assert!(
+        counters.time_ns <= max_time_ns,
+        "{name} takes {} ns, expected at most {max_time_ns}: {counters:?}",
+        counters.time_ns
+    );
+}
+
+#[futures_test::test]
+async fn init() {
+    let sim = Simulator::new();
+    let radio = new_radio(&sim);
+
+    sim.reset_counters();
+    let _radio = radio.init(Config::default()).await.unwrap();
+
+    check(
+        "init",
+        sim.counters(),
+        INIT_TRANSACTIONS,
+        INIT_BYTES,
+        INIT_TIME_NS,
+    );
+}
+
+#[futures_test::test]
+async fn set_format() {
+    let sim = Simulator::new();
+    let radio = ready_radio(&sim).await;
+
+    sim.reset_counters();
+    let _radio = radio.set_format::<Basic>(&basic_config()).unwrap();
+
+    check(
+        "set_format",
+        sim.counters(),
+        SET_FORMAT_TRANSACTIONS,
+        SET_FORMAT_BYTES,
+        SET_FORMAT_TIME_NS,
+    );
+}
+
+#[futures_test::test]
+async fn reconfigure_preamble_length() {
+    let sim = Simulator::new();
+    let radio = basic_radio(&sim).await;
+
+    sim.reset_counters();
+    let _radio = radio
+        .reconfigure::<Basic>(&BasicConfig {
+            preamble_length: 64,
+            ..basic_config()
+        })
+        .unwrap();
+
+    check(
+        "reconfigure",
+        sim.counters(),
+        RECONFIGURE_TRANSACTIONS,
+        RECONFIGURE_BYTES,
+        RECONFIGURE_TIME_NS,
+    );
+}
+
+#[futures_test::test]
+async fn send_small_packet() {
+    let sim = Simulator::new();
+    let radio = basic_radio(&sim).await;
+
+    sim.reset_counters();
+    let mut tx = radio
+        .send_packet(
+            &BasicTxMetaData {
+                destination_address: Some(0x42),
+            },
+            &[0xAB; 32],
+        )
+        .unwrap();
+    assert_eq!(tx.wait().await.unwrap(), TxResult::Ok);
+    let Ok(_) = tx.finish() else { unreachable!() };
+
+    // The session restored the irq mask it had before
+    assert_eq!(sim.irq_mask(), 0);
+
+    let counters = sim.counters();
+    assert_eq!(counters.fifo_refills, 0);
+    check(
+        "send_packet",
+        counters,
+        SEND_PACKET_TRANSACTIONS,
+        SEND_PACKET_BYTES,
+        SEND_PACKET_TIME_NS,
+    );
+}
+
+#[futures_test::test]
+async fn fifo_refill() {
+    let sim = Simulator::new();
+
+    // Measure a packet that just fits and one that needs two refills. The difference is the refill cost.
+    let radio = basic_radio(&sim).await;
+    sim.reset_counters();
+    let mut tx = radio
+        .send_packet(
+            &BasicTxMetaData {
+                destination_address: Some(0x42),
+            },
+            &[0xAB; 128],
+        )
+        .unwrap();
+    assert_eq!(tx.wait().await.unwrap(), TxResult::Ok);
+    let Ok(radio) = tx.finish() else {
+        unreachable!()
+    };
+    let single = sim.counters();
+
+    sim.reset_counters();
+    let mut tx = radio
+        .send_packet(
+            &BasicTxMetaData {
+                destination_address: Some(0x42),
+            },
+            &[0xAB; 128 * 3],
+        )
+        .unwrap();
+    let report = tx.wait_with_report().await.unwrap();
+    assert_eq!(report.result, TxResult::Ok);
+    assert_eq!(report.fifo_refills, 2);
+    assert_eq!(report.retransmissions, 0);
+    let Ok(_) = tx.finish() else { unreachable!() };
+    let refilled = sim.counters();
+
+    assert_eq!(refilled.fifo_refills, 2);
+
+    let per_refill = Counters {
+        spi_transactions: (refilled.spi_transactions - single.spi_transactions) / 2,
+        // Don't count the payload itself
+        spi_bytes: (refilled.spi_bytes - single.spi_bytes - 256) / 2,
+        fifo_refills: 1,
+        time_ns: (refilled.time_ns - single.time_ns - 256 * 1000) / 2,
+    };
+    check(
+        "fifo refill",
+        per_refill,
+        REFILL_TRANSACTIONS,
+        REFILL_BYTES,
+        REFILL_TIME_NS,
+    );
+}
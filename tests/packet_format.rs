@@ -0,0 +1,434 @@
+#![cfg(all(
+    feature = "basic",
+    feature = "fixed-length",
+    feature = "wmbus",
+    feature = "uart-ota",
+    feature = "raw-fifo"
+))]
+
+mod common;
+
+use common::{new_radio, ready_radio, Simulator};
+// The prelude has everything, except the packet format of the modem status
+use s2lp::{ll::PacketFormat, prelude::*};
+
+fn config() -> FixedLengthConfig {
+    FixedLengthConfig {
+        preamble_length: 32,
+        preamble_pattern: PreamblePattern::Pattern0,
+        sync_length: 16,
+        sync_pattern: SyncWord::msb_first(0x1234),
+        packet_length: 4,
+        postamble_length: PostambleLength::NONE,
+        crc_mode: CrcMode::CrcPoly0X07,
+        packet_filter: PacketFilteringOptions::default(),
+    }
+}
+
+#[futures_test::test]
+async fn software_matching_discards_other_frames() {
+    let sim = Simulator::new();
+    let radio = ready_radio(&sim)
+        .await
+        .set_format::<FixedLength>(&config())
+        .unwrap();
+
+    sim.queue_rx_packet(&[0x01, 0x02, 0x03, 0x04]);
+    sim.queue_rx_packet(&[0xAB, 0x05, 0x06, 0x07]);
+
+    let mut buffer = [0; 4];
+    let (radio, rx_result) = radio
+        .receive_matching(&mut buffer, RxMode::default(), |frame| {
+            frame.starts_with(&[0xAB])
+        })
+        .await
+        .unwrap();
+
+    assert!(matches!(rx_result, RxResult::Ok { packet_size: 4, .. }));
+    assert_eq!(buffer, [0xAB, 0x05, 0x06, 0x07]);
+
+    // The payload must be exactly the fixed length
+    assert!(matches!(
+        radio.send_packet(&FixedLengthTxMetaData, &[0; 3]),
+        Err(Error::BadConfig { .. })
+    ));
+}
+
+#[futures_test::test]
+async fn format_config_readback() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&BasicConfig {
+            preamble_length: 64,
+            preamble_pattern: PreamblePattern::Pattern2,
+            sync_length: 32,
+            sync_pattern: SyncWord::msb_first(0x12345678),
+            include_address: true,
+            packet_length_encoding: LenWid::Bytes2,
+            postamble_length: PostambleLength::pairs(4),
+            crc_mode: CrcMode::CrcPoly0X1021,
+            packet_filter: PacketFilteringOptions::default(),
+        })
+        .unwrap();
+
+    let status = radio.current_format_config().unwrap();
+
+    assert_eq!(status.packet_format, PacketFormat::Basic);
+    assert_eq!(status.preamble_length, 64);
+    assert_eq!(status.preamble_pattern, PreamblePattern::Pattern2);
+    assert_eq!(status.sync_length, 32);
+    assert_eq!(status.sync_pattern, 0x12345678);
+    assert!(!status.fixed_length);
+    assert_eq!(status.packet_length_encoding, LenWid::Bytes2);
+    assert!(status.address_field);
+    assert_eq!(status.postamble_length, PostambleLength::pairs(4));
+    assert_eq!(status.crc_mode, CrcMode::CrcPoly0X1021);
+    assert!(status.whitening);
+    assert!(!status.fec);
+}
+
+#[futures_test::test]
+async fn sync_less_reception() {
+    let sync_less_config = |preamble_quality_threshold| BasicConfig {
+        preamble_length: 64,
+        preamble_pattern: PreamblePattern::Pattern0,
+        sync_length: 0,
+        sync_pattern: SyncWord::msb_first(0),
+        include_address: false,
+        packet_length_encoding: LenWid::Bytes1,
+        postamble_length: PostambleLength::NONE,
+        crc_mode: CrcMode::CrcPoly0X07,
+        packet_filter: PacketFilteringOptions {
+            preamble_quality_threshold,
+            ..Default::default()
+        },
+    };
+
+    let sim = Simulator::new();
+    let radio = ready_radio(&sim).await;
+
+    // Without a sync word, something has to qualify the preamble
+    assert!(matches!(
+        radio.set_format::<Basic>(&sync_less_config(0)),
+        Err(Error::BadConfig { .. })
+    ));
+
+    let sim = Simulator::new();
+    let radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&sync_less_config(2))
+        .unwrap();
+
+    // SQI_EN off, PQI_TH 2
+    assert_eq!(sim.register(0x37), 2 << 1);
+
+    sim.queue_rx_packet(&[0x01, 0x02, 0x03]);
+
+    let mut buffer = [0; 8];
+    let mut rx = radio
+        .start_receive(
+            &mut buffer,
+            RxMode::Normal {
+                timeout: Some(RxTimeout {
+                    timeout: Duration::from_millis(10),
+                    mask: RxTimeoutMask::Sqi,
+                }),
+            },
+        )
+        .unwrap();
+
+    // The RX timer is stopped by the PQI instead of the SQI
+    assert_eq!(sim.register(0x39) & 0b1110_0000, 0b0010_0000);
+
+    assert!(matches!(
+        rx.wait().await.unwrap(),
+        RxResult::Ok { packet_size: 3, .. }
+    ));
+    assert_eq!(&buffer[..3], &[0x01, 0x02, 0x03]);
+}
+
+#[futures_test::test]
+async fn sync_word_byte_order() {
+    for (sync_pattern, expected_registers) in [
+        (SyncWord::msb_first(0x12345678), [0x78, 0x56, 0x34, 0x12]),
+        (SyncWord::lsb_first(0x12345678), [0x12, 0x34, 0x56, 0x78]),
+    ] {
+        let sim = Simulator::new();
+        let _radio = ready_radio(&sim)
+            .await
+            .set_format::<FixedLength>(&FixedLengthConfig {
+                sync_length: 32,
+                sync_pattern,
+                ..config()
+            })
+            .unwrap();
+
+        // SYNC3..SYNC0
+        let registers = [0x33, 0x34, 0x35, 0x36].map(|address| sim.register(address));
+        assert_eq!(registers, expected_registers);
+    }
+}
+
+#[futures_test::test]
+async fn reconfigure_matches_set_format() {
+    let basic_config = |preamble_length, crc_mode| BasicConfig {
+        preamble_length,
+        preamble_pattern: PreamblePattern::Pattern0,
+        sync_length: 32,
+        sync_pattern: SyncWord::msb_first(0x12345678),
+        include_address: true,
+        packet_length_encoding: LenWid::Bytes2,
+        postamble_length: PostambleLength::NONE,
+        crc_mode,
+        packet_filter: PacketFilteringOptions::default(),
+    };
+
+    let reconfigured_sim = Simulator::new();
+    let _radio = ready_radio(&reconfigured_sim)
+        .await
+        .set_format::<FixedLength>(&config())
+        .unwrap()
+        .reconfigure::<Basic>(&basic_config(32, CrcMode::CrcPoly0X07))
+        .unwrap()
+        .reconfigure::<Basic>(&basic_config(64, CrcMode::CrcPoly0X1021))
+        .unwrap();
+
+    let fresh_sim = Simulator::new();
+    let _radio = ready_radio(&fresh_sim)
+        .await
+        .set_format::<Basic>(&basic_config(64, CrcMode::CrcPoly0X1021))
+        .unwrap();
+
+    // PCKT_LEN is left alone, variable length formats write it when sending
+    for address in (0..0x80).filter(|address| !(0x31..=0x32).contains(address)) {
+        assert_eq!(
+            reconfigured_sim.register(address),
+            fresh_sim.register(address),
+            "Register {address:#04X}"
+        );
+    }
+}
+
+#[futures_test::test]
+async fn automatic_filtering_only_with_filters() {
+    for (packet_filter, expected) in [
+        (PacketFilteringOptions::default(), true),
+        (
+            PacketFilteringOptions {
+                discard_bad_crc: false,
+                ..Default::default()
+            },
+            false,
+        ),
+        (
+            PacketFilteringOptions {
+                automatic_filtering: false,
+                ..Default::default()
+            },
+            false,
+        ),
+    ] {
+        let sim = Simulator::new();
+        let _radio = ready_radio(&sim)
+            .await
+            .set_format::<FixedLength>(&FixedLengthConfig {
+                packet_filter,
+                ..config()
+            })
+            .unwrap();
+
+        // PROTOCOL1.AUTO_PCKT_FLT
+        assert_eq!(sim.register(0x3A) & 0x01 != 0, expected);
+    }
+}
+
+#[futures_test::test]
+async fn basic_length_limit_with_address() {
+    // The address byte counts in the 1-byte length field, so a 255 byte payload doesn't fit
+    for (payload_len, fits) in [(254, true), (255, false)] {
+        let sim = Simulator::new();
+        let radio = ready_radio(&sim)
+            .await
+            .set_format::<Basic>(&BasicConfig {
+                preamble_length: 32,
+                preamble_pattern: PreamblePattern::Pattern0,
+                sync_length: 32,
+                sync_pattern: SyncWord::msb_first(0x12345678),
+                include_address: true,
+                packet_length_encoding: LenWid::Bytes1,
+                postamble_length: PostambleLength::NONE,
+                crc_mode: CrcMode::CrcPoly0X07,
+                packet_filter: PacketFilteringOptions::default(),
+            })
+            .unwrap();
+
+        let payload = [0; 255];
+        let result = radio.send_packet(
+            &BasicTxMetaData {
+                destination_address: Some(0xAA),
+            },
+            &payload[..payload_len],
+        );
+
+        if fits {
+            result.unwrap().wait().await.unwrap();
+            // PCKT_LEN1..PCKT_LEN0
+            assert_eq!([sim.register(0x31), sim.register(0x32)], [0, 255]);
+        } else {
+            assert!(matches!(result, Err(Error::BufferTooLarge)));
+        }
+    }
+}
+
+#[futures_test::test]
+async fn wmbus_modes() {
+    for mode in [
+        WMBusMode::S {
+            long_preamble: true,
+        },
+        WMBusMode::S {
+            long_preamble: false,
+        },
+        WMBusMode::T,
+        WMBusMode::C,
+    ] {
+        assert!(mode.radio_config(50_000_000).try_compile().is_ok());
+    }
+
+    let sim = Simulator::new();
+    let radio = new_radio(&sim)
+        .init(WMBusMode::T.radio_config(50_000_000))
+        .await
+        .unwrap()
+        .set_format::<WMBus>(&WMBusConfig {
+            mode: WMBusMode::T,
+            frame_length: 12,
+            postamble_length: PostambleLength::pairs(2),
+        })
+        .unwrap();
+
+    // 3-out-of-6 coding, fixed length
+    assert_eq!(sim.register(0x2F), 0b0000_0100);
+    // A 10 bit sync word after 19 preamble pairs
+    assert_eq!(sim.register(0x2B), 10 << 2);
+    assert_eq!(sim.register(0x2C), 19);
+    // No CRC and no whitening
+    assert_eq!(sim.register(0x30) & 0b1111_0000, 0);
+
+    let frame = [11, 0x44, 0x2D, 0x2C, 1, 2, 3, 4, 5, 6, 7, 8];
+    let mut tx = radio.send_packet(&WMBusTxMetaData, &frame).unwrap();
+    tx.wait().await.unwrap();
+    let Ok(radio) = tx.finish() else {
+        unreachable!()
+    };
+
+    sim.queue_rx_packet(&frame);
+    let mut buffer = [0; 12];
+    let mut rx = radio.start_receive(&mut buffer, RxMode::default()).unwrap();
+    assert!(matches!(
+        rx.wait().await.unwrap(),
+        RxResult::Ok {
+            packet_size: 12,
+            ..
+        }
+    ));
+    assert_eq!(rx.packet(), &frame);
+    let Ok(radio) = rx.finish() else {
+        unreachable!()
+    };
+
+    // The payload must be exactly the frame length
+    assert!(matches!(
+        radio.send_packet(&WMBusTxMetaData, &[0; 11]),
+        Err(Error::BadConfig { .. })
+    ));
+}
+
+#[futures_test::test]
+async fn uart_over_the_air() {
+    let sim = Simulator::new();
+    let radio = ready_radio(&sim)
+        .await
+        .set_format::<UartOta>(&UartOtaConfig {
+            preamble_length: 32,
+            preamble_pattern: PreamblePattern::Pattern0,
+            sync_length: 16,
+            sync_pattern: SyncWord::msb_first(0x1234),
+            packet_length_encoding: LenWid::Bytes1,
+            postamble_length: PostambleLength::NONE,
+            crc_mode: CrcMode::CrcPoly0X07,
+            packet_filter: PacketFilteringOptions::default(),
+            start_bit: false,
+            stop_bit: true,
+        })
+        .unwrap();
+
+    assert_eq!(sim.register(0x2E) >> 6, 2);
+    // A high stop bit, a low start bit and a variable length
+    assert_eq!(sim.register(0x2F), 0b0001_0001);
+    // No whitening
+    assert_eq!(sim.register(0x30) & (1 << 4), 0);
+
+    let mut tx = radio.send_packet(&UartOtaTxMetaData, b"hello").unwrap();
+    tx.wait().await.unwrap();
+    let Ok(radio) = tx.finish() else {
+        unreachable!()
+    };
+    assert_eq!(sim.register(0x32), 5);
+
+    sim.queue_rx_packet(b"world");
+    let mut buffer = [0; 16];
+    let mut rx = radio.start_receive(&mut buffer, RxMode::default()).unwrap();
+    assert!(matches!(
+        rx.wait().await.unwrap(),
+        RxResult::Ok { packet_size: 5, .. }
+    ));
+    assert_eq!(rx.packet(), b"world");
+    let Ok(_radio) = rx.finish() else {
+        unreachable!()
+    };
+}
+
+#[futures_test::test]
+async fn raw_fifo() {
+    let sim = Simulator::new();
+    let radio = ready_radio(&sim)
+        .await
+        .set_format::<RawFifo>(&RawFifoConfig)
+        .unwrap();
+
+    // Direct through fifo for both directions and no whitening
+    assert_eq!((sim.register(0x2E) >> 4) & 0b11, 1);
+    assert_eq!(sim.register(0x30) & 0b0001_1100, 0b0000_0100);
+
+    let mut tx = radio.send_packet(&RawFifoTxMetaData, &[0x55; 40]).unwrap();
+    assert_eq!(tx.wait().await.unwrap(), TxResult::Ok);
+    let Ok(radio) = tx.finish() else {
+        unreachable!()
+    };
+    assert_eq!(sim.register(0x32), 40);
+
+    // The stream is captured until the buffer is full
+    sim.queue_rx_packet(&[0xA5; 100]);
+    let mut buffer = [0; 48];
+    let mut rx = radio.start_receive(&mut buffer, RxMode::default()).unwrap();
+    assert!(matches!(
+        rx.wait().await.unwrap(),
+        RxResult::Ok {
+            packet_size: 48,
+            ..
+        }
+    ));
+    assert_eq!(rx.packet(), [0xA5; 48]);
+    let Ok(radio) = rx.finish() else {
+        unreachable!()
+    };
+    assert_eq!(sim.register(0x8E) >> 1, 0);
+
+    let mut buffer = [0; 16];
+    assert!(matches!(
+        radio.start_receive_detached(&mut buffer, RxMode::default()),
+        Err(Error::BadConfig { .. })
+    ));
+}
@@ -0,0 +1,2048 @@
+#![cfg(all(feature = "basic", feature = "stack"))]
+
+mod common;
+
+use std::cell::RefCell;
+
+use common::{new_radio, ready_radio, SimBus, Simulator};
+use embedded_hal_bus::spi::{NoDelay, RefCellDevice};
+use s2lp::{
+    beacon::RxWindow,
+    command::Command,
+    crypto::{CryptoError, LinkCipher, PayloadCipher, ReplayFilter},
+    csma::{SoftCsma, SoftCsmaConfig},
+    diagnostics::FatalError,
+    ll::{CcaPeriod, CrcMode, GpioSelectInput, LenWid, State},
+    mac::{AddressTable, GatewayConfig, Peer, PeerSettings},
+    mirror::{MirrorCapture, MIRROR_DEPTH, MIRROR_HEAD_LEN},
+    packet_format::{
+        Basic, BasicConfig, BasicTxMetaData, PacketFilteringOptions, PostambleLength,
+        PreamblePattern, Stack, StackConfig, StackTxMetaData, SyncWord,
+    },
+    queue::TxQueue,
+    raw::GpioTxPins,
+    rssi::Rssi,
+    states::{
+        addressable::{FifoGpioMux, GpioFunction},
+        ready::{CsmaCaMode, FilterVerdicts, Fsk4SymbolMapping, LockDirection, PaRamp},
+        rx::{RxMode, RxResult, RxTimeout, RxTimeoutMask},
+        shutdown::{
+            CompiledConfig, Config, DataRate, InitStep, InitWarning, ModulationType,
+            RcoCalibrationWait,
+        },
+        tx::{TxResult, TxStep, Wakeup, TX_WATCHDOG},
+    },
+    time::Duration,
+    Error, GpioNumber, IrqTrigger, S2lp, DEFAULT_LABEL, IRQ_EDGE_RECHECK,
+};
+
+fn basic_config() -> BasicConfig {
+    BasicConfig {
+        preamble_length: 32,
+        preamble_pattern: PreamblePattern::Pattern0,
+        sync_length: 32,
+        sync_pattern: SyncWord::msb_first(0x12345678),
+        include_address: false,
+        packet_length_encoding: LenWid::Bytes2,
+        postamble_length: PostambleLength::NONE,
+        crc_mode: CrcMode::CrcPoly0X07,
+        packet_filter: PacketFilteringOptions::default(),
+    }
+}
+
+#[futures_test::test]
+async fn lock_test() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim).await;
+
+    assert!(radio.test_lock(LockDirection::Tx).await.unwrap());
+    assert!(radio.test_lock(LockDirection::Rx).await.unwrap());
+
+    // Back in ready
+    assert_eq!(sim.register(0x8E) >> 1, 0x00);
+}
+
+#[futures_test::test]
+async fn strobe_commands() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim).await;
+
+    assert_eq!(radio.strobe(Command::Sleep).await.unwrap(), State::SleepB);
+    // A lock can't be started from sleep
+    assert_eq!(
+        radio.strobe(Command::LockTx).await,
+        Err(Error::IllegalCommand {
+            command: Command::LockTx,
+            state: State::SleepB
+        })
+    );
+    assert_eq!(radio.strobe(Command::Ready).await.unwrap(), State::Ready);
+
+    assert_eq!(radio.strobe(Command::LockTx).await.unwrap(), State::Lockon);
+    assert_eq!(radio.strobe(Command::Abort).await.unwrap(), State::Ready);
+    // Nothing to abort in ready
+    assert!(matches!(
+        radio.strobe(Command::Abort).await,
+        Err(Error::IllegalCommand { .. })
+    ));
+
+    // The commands that leave the typestate have their own methods
+    for command in [Command::Tx, Command::Rx, Command::Standby, Command::Reset] {
+        assert_eq!(
+            radio.strobe(command).await,
+            Err(Error::IllegalCommand {
+                command,
+                state: State::Ready
+            })
+        );
+    }
+
+    assert_eq!(
+        radio.strobe(Command::FlushTxFifo).await.unwrap(),
+        State::Ready
+    );
+}
+
+const COMPILED_CONFIG: CompiledConfig = Config {
+    xtal_frequency: 26_000_000,
+    base_frequency: 433_000_000,
+    modulation: ModulationType::Gfsk2Bt1,
+    datarate: DataRate::bits_per_sec(4_800),
+    frequency_deviation: 5_000,
+    bandwidth: 20_000,
+}
+.compile();
+
+#[futures_test::test]
+async fn compiled_config_matches_runtime_config() {
+    let runtime_sim = Simulator::new();
+    let _radio = new_radio(&runtime_sim)
+        .init(Config {
+            xtal_frequency: 26_000_000,
+            base_frequency: 433_000_000,
+            modulation: ModulationType::Gfsk2Bt1,
+            datarate: DataRate::bits_per_sec(4_800),
+            frequency_deviation: 5_000,
+            bandwidth: 20_000,
+        })
+        .await
+        .unwrap();
+
+    let compiled_sim = Simulator::new();
+    let _radio = new_radio(&compiled_sim)
+        .init_compiled(&COMPILED_CONFIG)
+        .await
+        .unwrap();
+
+    for address in 0..0x80 {
+        assert_eq!(
+            runtime_sim.register(address),
+            compiled_sim.register(address),
+            "Register {address:#04X}"
+        );
+    }
+}
+
+#[futures_test::test]
+async fn init_warnings() {
+    let sim = Simulator::new();
+    let (_, warnings) = new_radio(&sim)
+        .init_with_warnings(Config {
+            bandwidth: 101_000,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+    // No filter in the table has exactly this bandwidth
+    assert!(warnings.iter().any(|warning| matches!(
+        warning,
+        InitWarning::BandwidthFromTable {
+            requested: 101_000,
+            actual,
+        } if *actual != 101_000
+    )));
+
+    // Whatever is rounded, it's rounded to a close value
+    for warning in COMPILED_CONFIG.warnings() {
+        let (InitWarning::DataRateRounded { requested, actual }
+        | InitWarning::FrequencyDeviationRounded { requested, actual }
+        | InitWarning::BandwidthFromTable { requested, actual }) = warning;
+        assert!(requested.abs_diff(actual) < requested / 10, "{warning:?}");
+    }
+}
+
+#[futures_test::test]
+async fn rco_calibration_timeout() {
+    let sim = Simulator::new();
+    sim.stall_rco_calibration();
+
+    let mut radio = new_radio(&sim);
+    radio.set_rco_calibration_wait(RcoCalibrationWait {
+        timeout: Duration::from_micros(100),
+        retries: 2,
+    });
+
+    sim.reset_counters();
+    let result = radio.init(Config::default()).await;
+
+    // Three attempts of 100 µs each
+    assert_eq!(
+        result.err(),
+        Some(Error::RcoCalibrationTimeout {
+            waited: Duration::from_micros(300)
+        })
+    );
+    // Init gives up instead of hanging, the spi traffic adds a bit to the waited time
+    assert!(sim.counters().time_ns < 1_000_000);
+}
+
+#[test]
+fn four_level_datarate_is_programmed_in_symbols() {
+    let config = |modulation, datarate| Config {
+        modulation,
+        datarate,
+        ..Config::default()
+    };
+
+    assert_eq!(
+        config(ModulationType::Fsk4, DataRate::bits_per_sec(76_800)).try_compile(),
+        config(ModulationType::Fsk4, DataRate::symbols_per_sec(38_400)).try_compile(),
+    );
+    assert_eq!(
+        config(ModulationType::Fsk2, DataRate::bits_per_sec(38_400)).try_compile(),
+        config(ModulationType::Fsk2, DataRate::symbols_per_sec(38_400)).try_compile(),
+    );
+    assert!(config(ModulationType::Fsk4, DataRate::bits_per_sec(38_401))
+        .try_compile()
+        .is_err());
+    assert_eq!(
+        DataRate::symbols_per_sec(38_400).bit_rate(ModulationType::Gfsk4Bt1),
+        76_800
+    );
+}
+
+#[futures_test::test]
+async fn rx_overflow_while_detached() {
+    let sim = Simulator::new();
+    let radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    sim.queue_rx_packet(&[0xAB; 200]);
+
+    let mut buffer = [0; 256];
+    let rx = radio.start_receive(&mut buffer, RxMode::default()).unwrap();
+    let (mut rx, spi) = rx.take_spi();
+
+    rx.wait_for_irq().await.unwrap();
+    sim.overflow_rx_fifo();
+
+    let mut rx = rx.reattach_spi(spi).unwrap();
+    assert_eq!(rx.wait().await.unwrap(), RxResult::OverflowWhileDetached);
+}
+
+#[futures_test::test]
+async fn direct_fifo_access() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim).await;
+
+    // Only what fits is written and a full fifo doesn't block
+    assert_eq!(radio.write_tx_fifo(&[0xAB; 200]).unwrap(), 128);
+    assert_eq!(radio.write_tx_fifo(&[0xAB; 10]).unwrap(), 0);
+    assert_eq!(radio.tx_fifo_len().unwrap(), 128);
+
+    radio.flush_tx_fifo().unwrap();
+    assert_eq!(radio.tx_fifo_len().unwrap(), 0);
+
+    // An empty fifo doesn't block either
+    let mut buffer = [0; 16];
+    assert_eq!(radio.read_rx_fifo(&mut buffer).unwrap(), 0);
+}
+
+#[futures_test::test]
+async fn rx_timeout_event_without_polling() {
+    let sim = Simulator::new();
+    let radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    let mut buffer = [0; 16];
+    let mut rx = radio.start_receive(&mut buffer, RxMode::default()).unwrap();
+    assert!(!rx.has_event().unwrap());
+
+    sim.expire_rx_timer();
+    assert!(rx.has_event().unwrap());
+    assert_eq!(rx.wait().await.unwrap(), RxResult::Timeout);
+
+    // The result stays visible
+    assert!(rx.has_event().unwrap());
+    assert!(rx.finish().is_ok());
+}
+
+#[futures_test::test]
+async fn low_duty_cycle_receive() {
+    let sim = Simulator::new();
+    let radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    let mut buffer = [0; 16];
+    let mut rx = radio
+        .start_receive(
+            &mut buffer,
+            RxMode::LowDutyCycle {
+                wake_up_interval: Duration::from_millis(100),
+                timeout: RxTimeout {
+                    timeout: Duration::from_millis(1),
+                    mask: RxTimeoutMask::Sqi,
+                },
+            },
+        )
+        .unwrap();
+    assert_ne!(sim.register(0x3A) & (1 << 7), 0);
+    // 100 ms of the 34.7 kHz RCO
+    assert_eq!(sim.register(0x39) & 0b11, 0);
+    assert_eq!(sim.register(0x48), 13);
+    assert_eq!(sim.register(0x49), 247);
+
+    // Nobody is sending in the first window, but the radio wakes up again for the packet
+    sim.expire_rx_timer();
+    sim.queue_rx_packet(&[1, 2, 3, 4]);
+    let result = rx.wait().await.unwrap();
+    assert!(matches!(result, RxResult::Ok { packet_size: 4, .. }));
+    assert_eq!(rx.packet(), [1, 2, 3, 4]);
+
+    // The cycling is stopped
+    let _radio = rx.finish().ok().unwrap();
+    assert_eq!(sim.register(0x3A) & (1 << 7), 0);
+    assert_eq!(sim.register(0x8E) >> 1, 0);
+}
+
+#[futures_test::test]
+async fn sniff_selects_rx_fifo_mux() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    assert_eq!(radio.fifo_gpio_mux().unwrap(), FifoGpioMux::Tx);
+
+    let mut buffer = [0; 16];
+    let mut rx = radio
+        .start_receive(
+            &mut buffer,
+            RxMode::Sniff {
+                timeout: RxTimeout {
+                    timeout: Duration::from_millis(10),
+                    mask: RxTimeoutMask::Rssi,
+                },
+            },
+        )
+        .unwrap();
+
+    // FIFO_GPIO_OUT_MUX_SEL and FAST_CS_TERM_EN
+    assert_ne!(sim.register(0x39) & 1 << 2, 0);
+    assert_ne!(sim.register(0x3A) & 1 << 4, 0);
+
+    sim.expire_rx_timer();
+    assert_eq!(rx.wait().await.unwrap(), RxResult::Timeout);
+    let Ok(mut radio) = rx.finish() else {
+        unreachable!()
+    };
+    assert_eq!(radio.fifo_gpio_mux().unwrap(), FifoGpioMux::Rx);
+
+    // A normal receive doesn't terminate on the carrier sense
+    let rx = radio.start_receive(&mut buffer, RxMode::default()).unwrap();
+    assert_eq!(sim.register(0x3A) & 1 << 4, 0);
+    let mut radio = rx.abort().unwrap();
+
+    radio.set_fifo_gpio_mux(FifoGpioMux::Tx).unwrap();
+    assert_eq!(sim.register(0x39) & 1 << 2, 0);
+}
+
+#[futures_test::test]
+async fn mirror_capture() {
+    let sim = Simulator::new();
+    let radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    // Nothing is captured by default
+    sim.queue_rx_packet(&[0xAA; 8]);
+    let mut buffer = [0; 64];
+    let mut rx = radio.start_receive(&mut buffer, RxMode::default()).unwrap();
+    assert!(matches!(rx.wait().await.unwrap(), RxResult::Ok { .. }));
+    let Ok(mut radio) = rx.finish() else {
+        unreachable!()
+    };
+    assert!(radio.mirror_capture().is_none());
+
+    let capture = Box::leak(Box::new(MirrorCapture::new(100)));
+    assert_eq!(capture.head_len(), MIRROR_HEAD_LEN);
+    assert!(radio.set_mirror_capture(Some(capture)).is_none());
+
+    for i in 0..MIRROR_DEPTH as u8 + 1 {
+        sim.queue_rx_packet(&[i; 32]);
+    }
+    let mut rx = radio.start_receive(&mut buffer, RxMode::default()).unwrap();
+    rx.set_restart_after_packet(true);
+    for _ in 0..MIRROR_DEPTH + 1 {
+        assert!(matches!(rx.wait().await.unwrap(), RxResult::Ok { .. }));
+    }
+    let mut radio = rx.abort().unwrap();
+
+    // The oldest frame is dropped
+    let capture = radio.mirror_capture().unwrap();
+    assert_eq!(capture.captured(), MIRROR_DEPTH as u32 + 1);
+    assert_eq!(capture.frames().count(), MIRROR_DEPTH);
+    for (i, frame) in capture.frames().enumerate() {
+        assert_eq!(frame.packet_size, 32);
+        assert_eq!(frame.head, [i as u8 + 1; MIRROR_HEAD_LEN]);
+    }
+
+    radio.mirror_capture_mut().unwrap().clear();
+    let capture = radio.set_mirror_capture(None).unwrap();
+    assert!(capture.last().is_none());
+    assert_eq!(capture.captured(), 0);
+}
+
+#[futures_test::test]
+async fn command_pins() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    // The command inputs can't be set up behind the back of the driver
+    assert!(matches!(
+        radio.set_gpio_function(
+            GpioNumber::Gpio2,
+            GpioFunction::Input {
+                select: GpioSelectInput::TxCommand,
+            },
+        ),
+        Err(Error::BadConfig { .. })
+    ));
+    let gpio_2 = sim.register(0x02);
+
+    let tx_meta_data = BasicTxMetaData {
+        destination_address: None,
+    };
+    let mut tx = radio
+        .arm_tx_on_pin(GpioNumber::Gpio2, &tx_meta_data, &[0xAB; 16])
+        .unwrap();
+    // Nothing happens until the pin goes high
+    assert_eq!(sim.register(0x8E) >> 1, 0);
+    sim.raise_gpio(2);
+    assert_eq!(tx.wait().await.unwrap(), TxResult::Ok);
+    let Ok(radio) = tx.finish() else {
+        unreachable!()
+    };
+    // The pin is back to what it was
+    assert_eq!(sim.register(0x02), gpio_2);
+
+    sim.queue_rx_packet(&[0xCD; 16]);
+    let mut buffer = [0; 32];
+    let mut rx = radio
+        .arm_rx_on_pin(GpioNumber::Gpio3, &mut buffer, RxMode::default())
+        .unwrap();
+    assert!(!rx.has_event().unwrap());
+    sim.raise_gpio(3);
+    assert!(matches!(
+        rx.wait().await.unwrap(),
+        RxResult::Ok {
+            packet_size: 16,
+            ..
+        }
+    ));
+    assert_eq!(rx.packet(), [0xCD; 16]);
+    let Ok(radio) = rx.finish() else {
+        unreachable!()
+    };
+    assert_eq!(sim.register(0x03) & 0b11, 0b00);
+
+    // The irq pin can't give commands
+    assert!(matches!(
+        radio.arm_tx_on_pin(GpioNumber::Gpio0, &tx_meta_data, &[0xAB; 16]),
+        Err(Error::BadConfig { .. })
+    ));
+}
+
+#[futures_test::test]
+async fn two_radios_on_a_shared_bus() {
+    let sim_a = Simulator::new();
+    let sim_b = Simulator::new();
+    let bus = SimBus::new(&[&sim_a, &sim_b]);
+    let (cs_a, cs_b) = (bus.cs(0), bus.cs(1));
+    let bus = RefCell::new(bus);
+
+    let mut radio_a = S2lp::new(
+        RefCellDevice::new(&bus, cs_a, NoDelay).unwrap(),
+        sim_a.sdn(),
+        sim_a.irq_pin(),
+        GpioNumber::Gpio0,
+        sim_a.delay(),
+    );
+    let mut radio_b = S2lp::new(
+        RefCellDevice::new(&bus, cs_b, NoDelay).unwrap(),
+        sim_b.sdn(),
+        sim_b.irq_pin(),
+        GpioNumber::Gpio0,
+        sim_b.delay(),
+    );
+    assert_eq!(radio_a.label(), DEFAULT_LABEL);
+    radio_a.set_label("radio-a");
+    radio_b.set_label("radio-b");
+
+    let radio_a = radio_a
+        .init(Config::default())
+        .await
+        .unwrap()
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+    let radio_b = radio_b
+        .init(Config {
+            base_frequency: 868_300_000,
+            ..Config::default()
+        })
+        .await
+        .unwrap()
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+    assert_eq!(radio_a.label(), "radio-a");
+    assert_eq!(radio_b.label(), "radio-b");
+
+    // Each radio only got its own config
+    let synt = |sim: &Simulator| (0x05..=0x08).map(|a| sim.register(a)).collect::<Vec<_>>();
+    assert_ne!(synt(&sim_a), synt(&sim_b));
+
+    // Radio b receives while radio a transmits
+    sim_b.queue_rx_packet(&[0xCD; 16]);
+    let mut buffer = [0; 32];
+    let mut rx = radio_b
+        .start_receive(&mut buffer, RxMode::default())
+        .unwrap();
+
+    let mut tx = radio_a
+        .send_packet(
+            &BasicTxMetaData {
+                destination_address: None,
+            },
+            &[0xAB; 16],
+        )
+        .unwrap();
+    assert_eq!(tx.wait().await.unwrap(), TxResult::Ok);
+
+    assert!(matches!(
+        rx.wait().await.unwrap(),
+        RxResult::Ok {
+            packet_size: 16,
+            ..
+        }
+    ));
+    let Ok(_) = rx.finish() else { unreachable!() };
+    assert_eq!(buffer[..16], [0xCD; 16]);
+
+    // Both radios are back in ready
+    assert_eq!(sim_a.register(0x8E) >> 1, 0x00);
+    assert_eq!(sim_b.register(0x8E) >> 1, 0x00);
+}
+
+#[futures_test::test]
+async fn receive_in_a_window() {
+    let sim = Simulator::new();
+    let radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    let window = RxWindow {
+        opens_at: Duration::from_millis(1_005),
+        length: Duration::from_millis(2),
+    };
+
+    sim.queue_rx_packet(&[0xAB; 8]);
+    sim.reset_counters();
+
+    let mut buffer = [0; 16];
+    let mut rx = radio
+        .receive_window(&mut buffer, window, Duration::from_millis(1_000))
+        .await
+        .unwrap();
+
+    // The radio waited for the window to open
+    assert!(sim.counters().time_ns >= 5_000_000);
+    assert!(matches!(
+        rx.wait().await.unwrap(),
+        RxResult::Ok { packet_size: 8, .. }
+    ));
+}
+
+#[futures_test::test]
+async fn channel_activity_detection() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    let irq_mask = sim.irq_mask();
+    let window = Duration::from_micros(300);
+
+    assert!(!radio.detect_activity(window, 4).await.unwrap());
+
+    sim.queue_rx_packet(&[0xAB; 8]);
+    assert!(radio.detect_activity(window, 4).await.unwrap());
+
+    // Back in ready with the irq mask restored
+    assert_eq!(sim.register(0x8E) >> 1, 0x00);
+    assert_eq!(sim.irq_mask(), irq_mask);
+
+    assert!(radio.detect_activity(window, 0).await.is_err());
+}
+
+#[futures_test::test]
+async fn scan_for_packet() {
+    let sim = Simulator::new();
+    let radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    let qi = sim.register(0x37);
+    let window = Duration::from_micros(300);
+    let mut buffer = [0; 16];
+
+    // Nothing on the air, so the window runs out
+    let (radio, result) = radio.scan_for_packet(&mut buffer, window, 4).await.unwrap();
+    assert_eq!(result, RxResult::Timeout);
+    // The timer is stopped by the preamble only
+    assert_eq!(sim.register(0x39) >> 5, 0b001);
+    assert_eq!(sim.register(0x37), qi);
+
+    sim.queue_rx_packet(&[0xAB; 8]);
+    let (radio, result) = radio.scan_for_packet(&mut buffer, window, 4).await.unwrap();
+    assert!(matches!(result, RxResult::Ok { packet_size: 8, .. }));
+    assert_eq!(buffer[..8], [0xAB; 8]);
+    assert_eq!(sim.register(0x8E) >> 1, 0x00);
+
+    assert!(radio.scan_for_packet(&mut buffer, window, 0).await.is_err());
+}
+
+#[futures_test::test]
+async fn configuration_canary() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim).await;
+    assert!(radio.is_configured().unwrap());
+    radio.check_configured().unwrap();
+    // The LDC reload counter is left alone, since the LDC reload command loads it into the wake-up timer
+    assert_eq!(sim.register(0x4B), 0);
+
+    sim.brown_out();
+    assert!(!radio.is_configured().unwrap());
+    assert!(matches!(
+        radio.check_configured(),
+        Err(Error::ConfigurationLost)
+    ));
+}
+
+#[futures_test::test]
+async fn transition_timings() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim).await;
+
+    let timings = radio.timings().unwrap();
+    assert!(timings.ready_to_tx > Duration::ZERO);
+    assert!(timings.rx_to_tx > timings.ready_to_tx);
+
+    // The simulated radio locks right away, so only the turn-on after the lock remains
+    let measured = radio.measure_timings().await.unwrap();
+    assert!(measured.ready_to_rx < timings.ready_to_rx);
+    assert_eq!(sim.register(0x8E) >> 1, 0x00);
+}
+
+#[futures_test::test]
+async fn diagnostics_on_failed_lock() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim).await;
+    assert!(radio.last_diagnostics().is_none());
+
+    sim.fail_synth_lock();
+    assert!(matches!(radio.calibrate_vco().await, Err(Error::BadState)));
+
+    let diagnostics = radio.last_diagnostics().unwrap();
+    assert_eq!(diagnostics.error, FatalError::BadState);
+    // The failed lock is aborted before the snapshot
+    assert_eq!(diagnostics.mc_state_0.state(), Ok(State::Ready));
+    assert_eq!(diagnostics.tx_fifo_len, 0);
+    assert_eq!(diagnostics.rx_fifo_len, 0);
+    assert_eq!(
+        diagnostics.irq_mask.iter().count(),
+        sim.irq_mask().count_ones() as usize
+    );
+}
+
+#[futures_test::test]
+async fn switch_band() {
+    let band_868 = Config {
+        base_frequency: 868_000_000,
+        ..Config::default()
+    }
+    .compile();
+    let band_433 = Config {
+        base_frequency: 433_920_000,
+        ..Config::default()
+    }
+    .compile();
+
+    let sim = Simulator::new();
+    let mut radio = new_radio(&sim).init_compiled(&band_868).await.unwrap();
+    assert_eq!(radio.band_profile().unwrap(), band_868.band_profile());
+    // The band select is off for the high band
+    assert_eq!(sim.register(0x05) & (1 << 4), 0);
+
+    assert!(radio.switch_band(&band_433.band_profile()).await.unwrap());
+    assert_eq!(radio.band_profile().unwrap(), band_433.band_profile());
+    assert_ne!(sim.register(0x05) & (1 << 4), 0);
+    assert_eq!(sim.register(0x8E) >> 1, 0);
+
+    // Without a lock, the radio stays on the previous band
+    sim.fail_synth_lock();
+    assert!(!radio.switch_band(&band_868.band_profile()).await.unwrap());
+    assert_eq!(radio.band_profile().unwrap(), band_433.band_profile());
+}
+
+#[futures_test::test]
+async fn node_address() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    radio.set_node_address(0x42).unwrap();
+    assert_eq!(sim.register(0x45), 0x42);
+    assert_eq!(radio.node_address().unwrap(), 0x42);
+}
+
+#[futures_test::test]
+async fn fsk4_symbol_mapping() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    assert_eq!(sim.register(0x2E) & 0x08, 0);
+    radio
+        .set_fsk4_symbol_mapping(Fsk4SymbolMapping::Swapped)
+        .unwrap();
+    assert_eq!(sim.register(0x2E) & 0x08, 0x08);
+
+    // Setting a format resets it
+    let _radio = radio.reconfigure::<Basic>(&basic_config()).unwrap();
+    assert_eq!(sim.register(0x2E) & 0x08, 0);
+}
+
+#[futures_test::test]
+async fn software_csma() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    let mut csma = SoftCsma::new(SoftCsmaConfig::default(), || 1);
+
+    assert!(radio
+        .channel_clear(Duration::from_micros(250))
+        .await
+        .unwrap());
+    assert!(radio.csma_backoff(&mut csma).await.unwrap());
+
+    // Someone is sending during every assessment
+    for _ in 0..=SoftCsmaConfig::default().max_backoffs {
+        sim.queue_rx_packet(&[0xAB; 8]);
+    }
+    sim.reset_counters();
+    assert!(!radio.csma_backoff(&mut csma).await.unwrap());
+    // Waited one unit backoff for every attempt
+    assert!(sim.counters().time_ns >= 5_000_000);
+    assert_eq!(sim.register(0x8E) >> 1, 0x00);
+}
+
+#[futures_test::test]
+async fn tx_progress() {
+    let sim = Simulator::new();
+    let radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    let mut tx = radio
+        .send_packet(
+            &BasicTxMetaData {
+                destination_address: None,
+            },
+            &[0xAB; 300],
+        )
+        .unwrap();
+    // The first part is written before the transmission starts
+    let initial = tx.bytes_queued();
+    assert!(initial < 300);
+
+    let mut refills = Vec::new();
+    assert_eq!(
+        tx.wait_with_progress(|queued| refills.push(queued))
+            .await
+            .unwrap(),
+        TxResult::Ok
+    );
+    assert!(!refills.is_empty());
+    assert_eq!(initial + refills.iter().sum::<usize>(), 300);
+    assert_eq!(tx.bytes_queued(), 300);
+}
+
+#[test]
+fn delay_free_init_and_tx() {
+    let sim = Simulator::new();
+    let mut pending = new_radio(&sim)
+        .init_stepped(&Config::default().compile())
+        .unwrap();
+
+    // The reset pulse, the startup delay and a first RCO poll
+    let mut waits = Vec::new();
+    let radio = loop {
+        waits.push(pending.wait_time());
+        match pending.resume().unwrap() {
+            InitStep::Pending(next) => pending = next,
+            InitStep::Done(radio) => break radio,
+        }
+    };
+    assert_eq!(
+        waits,
+        [
+            Duration::from_micros(1),
+            Duration::from_millis(2),
+            Duration::from_micros(10)
+        ]
+    );
+
+    let radio = radio.set_format::<Basic>(&basic_config()).unwrap();
+    sim.reset_counters();
+
+    let mut tx = radio
+        .send_packet(
+            &BasicTxMetaData {
+                destination_address: None,
+            },
+            &[0xAB; 300],
+        )
+        .unwrap();
+
+    // A lost interrupt is caught by the watchdog
+    assert_eq!(
+        tx.resume(Wakeup::Timeout).unwrap(),
+        TxStep::WaitForIrq {
+            timeout: TX_WATCHDOG
+        }
+    );
+
+    let result = loop {
+        match tx.resume(Wakeup::Irq).unwrap() {
+            TxStep::WaitForIrq { .. } => {}
+            TxStep::Done(result) => break result,
+        }
+    };
+    assert_eq!(result, TxResult::Ok);
+    assert_eq!(tx.bytes_queued(), 300);
+    assert_eq!(
+        tx.resume(Wakeup::Irq).unwrap(),
+        TxStep::Done(TxResult::TxAlreadyDone)
+    );
+
+    // The driver never used the delay, only the spi took time
+    let spi_ns = sim.counters().spi_bytes as u64 * 1_000;
+    assert_eq!(sim.counters().time_ns, spi_ns);
+}
+
+#[futures_test::test]
+async fn inter_packet_gap() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+    radio.set_inter_packet_gap(Duration::from_millis(5));
+
+    let meta_data = BasicTxMetaData {
+        destination_address: None,
+    };
+
+    sim.reset_counters();
+    let mut tx = radio.send_packet(&meta_data, &[0xAB; 10]).unwrap();
+    assert_eq!(tx.wait().await.unwrap(), TxResult::Ok);
+    // The gap is part of the wait
+    assert!(sim.counters().time_ns >= 5_000_000);
+
+    // Waiting again doesn't add another gap
+    sim.reset_counters();
+    assert_eq!(tx.wait().await.unwrap(), TxResult::TxAlreadyDone);
+    assert!(sim.counters().time_ns < 5_000_000);
+
+    let Ok(mut radio) = tx.finish() else {
+        unreachable!()
+    };
+    assert_eq!(radio.inter_packet_gap(), Duration::from_millis(5));
+
+    radio.set_inter_packet_gap(Duration::ZERO);
+    sim.reset_counters();
+    let mut tx = radio.send_packet(&meta_data, &[0xAB; 10]).unwrap();
+    assert_eq!(tx.wait().await.unwrap(), TxResult::Ok);
+    assert!(sim.counters().time_ns < 5_000_000);
+}
+
+#[futures_test::test]
+async fn fifo_size_boundaries() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    const IRQ_TX_FIFO_ALMOST_EMPTY: u32 = 1 << 8;
+
+    for len in [127, 128, 129] {
+        let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+
+        let mut tx = radio
+            .send_packet(
+                &BasicTxMetaData {
+                    destination_address: None,
+                },
+                &payload,
+            )
+            .unwrap();
+        // Only a payload that doesn't fit needs the refill interrupt
+        assert_eq!(
+            sim.irq_mask() & IRQ_TX_FIFO_ALMOST_EMPTY != 0,
+            len > 128,
+            "{len}"
+        );
+        assert_eq!(tx.wait().await.unwrap(), TxResult::Ok, "{len}");
+        assert_eq!(tx.bytes_queued(), len);
+        let Ok(ready) = tx.finish() else {
+            unreachable!()
+        };
+
+        // A buffer of exactly the packet size
+        let mut buffer = vec![0; len];
+        sim.queue_rx_packet(&payload);
+        let mut rx = ready
+            .start_receive(&mut buffer, RxMode::Normal { timeout: None })
+            .unwrap();
+        let result = rx.wait().await.unwrap();
+        assert!(
+            matches!(result, RxResult::Ok { packet_size, .. } if packet_size == len),
+            "{len}: {result:?}"
+        );
+        assert_eq!(rx.packet(), payload);
+        let Ok(ready) = rx.finish() else {
+            unreachable!()
+        };
+
+        // One byte short
+        let mut buffer = vec![0; len - 1];
+        sim.queue_rx_packet(&payload);
+        let mut rx = ready
+            .start_receive(&mut buffer, RxMode::Normal { timeout: None })
+            .unwrap();
+        assert!(
+            matches!(rx.wait().await.unwrap(), RxResult::TooBigForBuffer),
+            "{len}"
+        );
+        let Ok(ready) = rx.finish() else {
+            unreachable!()
+        };
+        radio = ready;
+    }
+}
+
+#[futures_test::test]
+async fn ranging_primitives() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    assert!(matches!(
+        radio.set_sync_strobe(GpioNumber::Gpio0),
+        Err(Error::BadConfig { .. })
+    ));
+    radio.set_sync_strobe(GpioNumber::Gpio2).unwrap();
+    // Sync word detected
+    assert_eq!(sim.register(0x02) >> 3, 15);
+
+    // One bit at 38.4 kbps
+    let resolution = radio.sync_strobe_resolution_ns().unwrap();
+    assert!((26_000..26_100).contains(&resolution), "{resolution}");
+
+    let whitening = sim.register(0x30) & (1 << 4);
+    let mut scratch = [0; 64];
+    let (_radio, result) = radio
+        .send_pn9_sequence(
+            &BasicTxMetaData {
+                destination_address: None,
+            },
+            &mut scratch,
+        )
+        .await
+        .unwrap();
+    assert_eq!(result, TxResult::Ok);
+    assert_eq!(scratch[0], 0xFF);
+    assert_eq!(sim.register(0x30) & (1 << 4), whitening);
+}
+
+#[futures_test::test]
+async fn stack_ack_timeout() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim)
+        .await
+        .set_format::<Stack>(&StackConfig {
+            preamble_length: 32,
+            preamble_pattern: PreamblePattern::Pattern0,
+            sync_length: 32,
+            sync_pattern: SyncWord::msb_first(0x12345678),
+            packet_length_encoding: LenWid::Bytes1,
+            postamble_length: PostambleLength::NONE,
+            crc_mode: CrcMode::CrcPoly0X07,
+            packet_filter: PacketFilteringOptions::default(),
+            auto_ack: false,
+            max_retransmissions: 3,
+        })
+        .unwrap();
+
+    radio.set_node_address(0x05).unwrap();
+
+    let mut tx = radio
+        .send_packet(
+            &StackTxMetaData {
+                destination_address: 0x42,
+                source_address: Some(0x17),
+                request_ack: true,
+                ack_timeout: Duration::from_millis(5),
+            },
+            &[0xAB; 8],
+        )
+        .unwrap();
+
+    // The RX timer is armed for the ack wait (TIMERS4 holds the prescaler, which is 0 after reset)
+    assert_ne!(sim.register(0x47), 0);
+    // The source address is the node address for this packet
+    assert_eq!(sim.register(0x45), 0x17);
+
+    assert_eq!(tx.wait().await.unwrap(), TxResult::Ok);
+    let Ok(mut radio) = tx.finish() else {
+        unreachable!()
+    };
+    assert_eq!(radio.node_address().unwrap(), 0x05);
+}
+
+#[futures_test::test]
+async fn send_clears_ack_payload() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim)
+        .await
+        .set_format::<Stack>(&StackConfig {
+            preamble_length: 32,
+            preamble_pattern: PreamblePattern::Pattern0,
+            sync_length: 32,
+            sync_pattern: SyncWord::msb_first(0x12345678),
+            packet_length_encoding: LenWid::Bytes1,
+            postamble_length: PostambleLength::NONE,
+            crc_mode: CrcMode::CrcPoly0X07,
+            packet_filter: PacketFilteringOptions::default(),
+            auto_ack: true,
+            max_retransmissions: 3,
+        })
+        .unwrap();
+
+    radio.set_ack_payload(&[1, 2, 3]).unwrap();
+    // PROTOCOL1.PIGGYBACKING
+    assert_ne!(sim.register(0x3A) & (1 << 5), 0);
+
+    let mut tx = radio
+        .send_packet(
+            &StackTxMetaData {
+                destination_address: 0x42,
+                source_address: None,
+                request_ack: false,
+                ack_timeout: Duration::from_millis(5),
+            },
+            &[0xAB; 8],
+        )
+        .unwrap();
+    assert_eq!(tx.wait().await.unwrap(), TxResult::Ok);
+    let Ok(radio) = tx.finish() else {
+        unreachable!()
+    };
+
+    // The ack of the next reception is a plain one, not one with the payload that went out with the packet
+    let mut buffer = [0; 16];
+    let rx = radio.start_receive(&mut buffer, RxMode::default()).unwrap();
+    assert_eq!(sim.register(0x3A) & (1 << 5), 0);
+    let _radio = rx.abort().unwrap();
+}
+
+#[futures_test::test]
+async fn send_from_queue() {
+    let sim = Simulator::new();
+    let radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&BasicConfig {
+            packet_length_encoding: LenWid::Bytes1,
+            ..basic_config()
+        })
+        .unwrap();
+
+    let mut queue = TxQueue::<Basic, 2, 300>::new();
+    let meta_data = BasicTxMetaData {
+        destination_address: None,
+    };
+    queue.push(meta_data.clone(), &[0xAB; 8]).unwrap();
+    queue.push(meta_data, &[0xCD; 300]).unwrap();
+
+    let (radio, result) = radio.send_from_queue(&mut queue).await.unwrap();
+    assert_eq!(result, Some(TxResult::Ok));
+    assert_eq!(queue.len(), 1);
+
+    // The length doesn't fit in the length field. The packet isn't lost.
+    assert!(matches!(
+        radio.send_from_queue(&mut queue).await,
+        Err(Error::BufferTooLarge)
+    ));
+    assert_eq!(queue.len(), 1);
+    assert_eq!(queue.peek().unwrap().payload.len(), 300);
+}
+
+/// Not a real cipher: it xors with the nonce and the tag is the last nonce byte
+struct XorCipher;
+
+impl PayloadCipher for XorCipher {
+    const NONCE_SIZE: usize = 8;
+    const TAG_SIZE: usize = 1;
+
+    fn encrypt(
+        &mut self,
+        nonce: &[u8],
+        buffer: &mut [u8],
+        tag: &mut [u8],
+    ) -> Result<(), CryptoError> {
+        buffer.iter_mut().for_each(|byte| *byte ^= nonce[0]);
+        tag[0] = nonce[7];
+        Ok(())
+    }
+
+    fn decrypt(&mut self, nonce: &[u8], buffer: &mut [u8], tag: &[u8]) -> Result<(), CryptoError> {
+        if tag[0] != nonce[7] {
+            return Err(CryptoError::Authentication);
+        }
+        buffer.iter_mut().for_each(|byte| *byte ^= nonce[0]);
+        Ok(())
+    }
+}
+
+#[futures_test::test]
+async fn receive_encrypted_packet() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    let sender_prefix = [0x5A; 4];
+    let mut sender = LinkCipher::new(XorCipher, &sender_prefix).unwrap();
+    let mut sealed = [0; 16];
+    let sealed = sender.seal(b"hello", &mut sealed).unwrap();
+    // Once as sent, once replayed
+    sim.queue_rx_packet(sealed);
+    sim.queue_rx_packet(sealed);
+
+    let mut receiver = LinkCipher::new(XorCipher, &[0xA5; 4]).unwrap();
+    let mut replay_filter = ReplayFilter::<4>::new();
+
+    let mut buffer = [0; 32];
+    for expected in [Ok(&b"hello"[..]), Err(CryptoError::Replayed)] {
+        let (ready, result, payload) = radio
+            .receive_encrypted_packet(
+                &mut buffer,
+                RxMode::default(),
+                &mut receiver,
+                &mut replay_filter,
+                |_| Some((0x17, &sender_prefix[..])),
+            )
+            .await
+            .unwrap();
+        radio = ready;
+        assert!(matches!(
+            result,
+            RxResult::Ok {
+                packet_size: 10,
+                ..
+            }
+        ));
+        assert_eq!(payload, Some(expected));
+    }
+
+    // Nothing is received
+    let (_radio, result, payload) = radio
+        .receive_encrypted_packet(
+            &mut buffer,
+            RxMode::Normal {
+                timeout: Some(RxTimeout {
+                    timeout: Duration::from_millis(10),
+                    mask: RxTimeoutMask::Pqi,
+                }),
+            },
+            &mut receiver,
+            &mut replay_filter,
+            |_| Some((0x17, &sender_prefix[..])),
+        )
+        .await
+        .unwrap();
+    assert_eq!(result, RxResult::Timeout);
+    assert_eq!(payload, None);
+}
+
+#[futures_test::test]
+async fn receive_until_silence() {
+    let sim = Simulator::new();
+    let radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    sim.queue_rx_packet(&[1; 8]);
+    sim.queue_rx_packet(&[2; 4]);
+
+    let mut buffer = [0; 16];
+    let mut rx = radio.start_receive(&mut buffer, RxMode::default()).unwrap();
+    rx.set_restart_after_packet(true);
+
+    for expected in [&[1; 8][..], &[2; 4]] {
+        assert!(matches!(rx.wait().await.unwrap(), RxResult::Ok { .. }));
+        assert_eq!(rx.packet(), expected);
+    }
+
+    // The receiver is still on
+    assert_eq!(sim.register(0x8E) >> 1, 0x30);
+    sim.expire_rx_timer();
+    assert_eq!(rx.wait().await.unwrap(), RxResult::Timeout);
+    assert!(rx.finish().is_ok());
+}
+
+#[futures_test::test]
+async fn detached_rx_size_limit() {
+    let sim = Simulator::new();
+    let radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    sim.queue_rx_packet(&[0xAB; 100]);
+
+    let mut buffer = [0; 128];
+    let (mut rx, spi) = radio
+        .start_receive_detached(&mut buffer, RxMode::default())
+        .unwrap();
+    // No wake-ups for the fifo, only for the end of the reception
+    assert_eq!(sim.irq_mask() & (1 << 9), 0);
+
+    rx.wait_for_irq().await.unwrap();
+    let mut rx = rx.reattach_spi(spi).unwrap();
+    assert!(matches!(
+        rx.wait().await.unwrap(),
+        RxResult::Ok {
+            packet_size: 100,
+            ..
+        }
+    ));
+    let Ok(radio) = rx.finish() else {
+        unreachable!()
+    };
+
+    let mut buffer = [0; 129];
+    assert!(matches!(
+        radio.start_receive_detached(&mut buffer, RxMode::default()),
+        Err(Error::BadConfig { .. })
+    ));
+}
+
+#[futures_test::test]
+async fn fifo_chunking() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    assert!(matches!(
+        radio.set_fifo_chunk_size(0),
+        Err(Error::BadConfig { .. })
+    ));
+    assert!(matches!(
+        radio.set_fifo_chunk_size(129),
+        Err(Error::BadConfig { .. })
+    ));
+    radio.set_fifo_chunk_size(48).unwrap();
+
+    let payload: Vec<u8> = (0..200).collect();
+    sim.queue_rx_packet(&payload);
+
+    // The end of the packet doesn't fall on a chunk boundary, but all of it is read
+    let mut buffer = [0; 256];
+    let mut rx = radio.start_receive(&mut buffer, RxMode::default()).unwrap();
+    assert!(matches!(
+        rx.wait().await.unwrap(),
+        RxResult::Ok {
+            packet_size: 200,
+            ..
+        }
+    ));
+    assert_eq!(rx.packet(), &payload[..]);
+    let Ok(radio) = rx.finish() else {
+        unreachable!()
+    };
+
+    // Taking the spi out, like in a low power cycle, keeps the chunk size
+    let (radio, spi) = radio.take_spi();
+    let radio = radio.give_spi(spi);
+
+    let mut tx = radio
+        .send_packet(
+            &BasicTxMetaData {
+                destination_address: None,
+            },
+            &[0xAB; 300],
+        )
+        .unwrap();
+
+    let mut refills = Vec::new();
+    assert_eq!(
+        tx.wait_with_progress(|queued| refills.push(queued))
+            .await
+            .unwrap(),
+        TxResult::Ok
+    );
+    let (last, refills) = refills.split_last().unwrap();
+    assert!(refills.iter().all(|queued| queued % 48 == 0));
+    assert!(*last <= 128);
+}
+
+#[cfg(feature = "metrics")]
+#[futures_test::test]
+async fn metrics_survive_taking_the_spi() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+    radio.reset_metrics();
+
+    let mut tx = radio
+        .send_packet(
+            &BasicTxMetaData {
+                destination_address: None,
+            },
+            &[0xAB; 8],
+        )
+        .unwrap();
+    assert_eq!(tx.wait().await.unwrap(), TxResult::Ok);
+    let Ok(radio) = tx.finish() else {
+        unreachable!()
+    };
+    let metrics = radio.metrics();
+    assert_ne!(metrics.spi_transactions, 0);
+    assert_ne!(metrics.irqs_serviced, 0);
+
+    let (radio, spi) = radio.take_spi();
+    let radio = radio.give_spi(spi);
+    assert_eq!(radio.metrics(), metrics);
+}
+
+#[futures_test::test]
+async fn rearm_after_a_failed_reception() {
+    let sim = Simulator::new();
+    let radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    let idle_irq_mask = sim.irq_mask();
+    let mut buffer = [0; 16];
+    let mut rx = radio.start_receive(&mut buffer, RxMode::default()).unwrap();
+    let rx_irq_mask = sim.irq_mask();
+
+    sim.expire_rx_timer();
+    assert_eq!(rx.wait().await.unwrap(), RxResult::Timeout);
+    assert_eq!(sim.irq_mask(), idle_irq_mask);
+
+    sim.queue_rx_packet(&[0xCD; 8]);
+    rx.rearm().unwrap();
+    assert_eq!(sim.irq_mask(), rx_irq_mask);
+    assert!(matches!(
+        rx.wait().await.unwrap(),
+        RxResult::Ok { packet_size: 8, .. }
+    ));
+    assert_eq!(rx.packet(), &[0xCD; 8]);
+
+    // Rearming while still receiving starts over as well
+    rx.rearm().unwrap();
+    assert_eq!(sim.register(0x8E) >> 1, 0x30);
+    sim.expire_rx_timer();
+    assert_eq!(rx.wait().await.unwrap(), RxResult::Timeout);
+    assert!(rx.finish().is_ok());
+}
+
+#[futures_test::test]
+async fn stop_after_current() {
+    let sim = Simulator::new();
+    let radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    // Nothing is coming in, so the receiver stops right away
+    let mut buffer = [0; 256];
+    let rx = radio.start_receive(&mut buffer, RxMode::default()).unwrap();
+    let (radio, result) = rx
+        .stop_after_current(Duration::from_millis(100))
+        .await
+        .unwrap();
+    assert_eq!(result, None);
+    assert_eq!(sim.register(0x8E) >> 1, 0x00);
+
+    // A packet that's coming in is received completely
+    sim.queue_rx_packet(&[0x5A; 200]);
+    let mut rx = radio.start_receive(&mut buffer, RxMode::default()).unwrap();
+    rx.set_restart_after_packet(true);
+    let (radio, result) = rx
+        .stop_after_current(Duration::from_millis(100))
+        .await
+        .unwrap();
+    assert!(matches!(
+        result,
+        Some(RxResult::Ok {
+            packet_size: 200,
+            ..
+        })
+    ));
+    assert_eq!(buffer[..200], [0x5A; 200]);
+
+    // And so is a packet that's being sent
+    let tx = radio
+        .send_packet(
+            &BasicTxMetaData {
+                destination_address: None,
+            },
+            &[0xAB; 300],
+        )
+        .unwrap();
+    let (radio, result) = tx
+        .stop_after_current(Duration::from_millis(100))
+        .await
+        .unwrap();
+    assert_eq!(result, Some(TxResult::Ok));
+    assert_eq!(sim.register(0x8E) >> 1, 0x00);
+
+    // A fifo error ends it as well, and the session registers are restored
+    let irq_mask = sim.irq_mask();
+    let ant_select_conf = sim.register(0x1F);
+    let tx = radio
+        .send_packet(
+            &BasicTxMetaData {
+                destination_address: None,
+            },
+            &[0xAB; 300],
+        )
+        .unwrap();
+    assert_ne!(sim.irq_mask(), irq_mask);
+    sim.underflow_tx_fifo();
+    let (_radio, result) = tx
+        .stop_after_current(Duration::from_millis(100))
+        .await
+        .unwrap();
+    assert_eq!(result, Some(TxResult::FifoError));
+    assert_eq!(sim.irq_mask(), irq_mask);
+    assert_eq!(sim.register(0x1F), ant_select_conf);
+}
+
+#[cfg(feature = "alloc")]
+#[futures_test::test]
+async fn receive_vec() {
+    let sim = Simulator::new();
+    let radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    // The vector grows with the packet instead of being allocated at the max length up front
+    let payload: Vec<u8> = (0..300).map(|i| i as u8).collect();
+    sim.queue_rx_packet(&payload);
+    let (radio, result, packet) = radio.receive_vec(RxMode::default(), 4096).await.unwrap();
+    assert!(matches!(
+        result,
+        RxResult::Ok {
+            packet_size: 300,
+            ..
+        }
+    ));
+    assert_eq!(packet, payload);
+    assert!(packet.capacity() < 1024);
+
+    sim.queue_rx_packet(&payload);
+    let (_radio, result, packet) = radio.receive_vec(RxMode::default(), 200).await.unwrap();
+    assert_eq!(result, RxResult::TooBigForBuffer);
+    assert!(packet.is_empty());
+}
+
+#[futures_test::test]
+async fn gateway_applies_peer_settings() {
+    let sim = Simulator::new();
+    let radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&BasicConfig {
+            include_address: true,
+            ..basic_config()
+        })
+        .unwrap();
+
+    let mut peers = AddressTable::<&str, 2>::new();
+    peers
+        .insert(
+            7,
+            Peer {
+                endpoint: "sensor",
+                settings: PeerSettings {
+                    tx_power_dbm: Some(-10),
+                    max_retransmissions: Some(3),
+                },
+            },
+        )
+        .unwrap();
+    let config = GatewayConfig {
+        rx_mode: RxMode::default(),
+        ack_turnaround: Duration::from_micros(100),
+    };
+    let mut buffer = [0; 32];
+
+    // An ack to an unknown peer leaves the settings alone
+    let pa_power = sim.register(0x5A);
+    sim.queue_rx_packet(&[1, 2, 3]);
+    let (radio, received) = radio
+        .gateway_receive(
+            &mut buffer,
+            &[0xAC],
+            |_| BasicTxMetaData {
+                destination_address: Some(8),
+            },
+            &peers,
+            &config,
+        )
+        .await
+        .unwrap();
+    assert_eq!(received.ack_result, Some(TxResult::Ok));
+    assert_eq!(sim.register(0x5A), pa_power);
+    assert_eq!(sim.register(0x3B) >> 4, 0);
+
+    // The settings of a known peer are applied before the ack is sent to it
+    sim.queue_rx_packet(&[1, 2, 3]);
+    let (mut radio, received) = radio
+        .gateway_receive(
+            &mut buffer,
+            &[0xAC],
+            |_| BasicTxMetaData {
+                destination_address: Some(7),
+            },
+            &peers,
+            &config,
+        )
+        .await
+        .unwrap();
+    assert_eq!(received.ack_result, Some(TxResult::Ok));
+    assert_eq!(sim.register(0x3B) >> 4, 3);
+    assert_ne!(sim.register(0x5A), pa_power);
+    let peer_power = sim.register(0x5A);
+    radio.set_tx_power(-10).unwrap();
+    assert_eq!(sim.register(0x5A), peer_power);
+}
+
+#[futures_test::test]
+async fn auto_ack_timeout() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+    radio
+        .ll()
+        .protocol_0()
+        .modify(|reg| reg.set_auto_ack(true))
+        .unwrap();
+
+    // The simulator never sends the ack, so the wait for it gives up instead of hanging
+    sim.strict_irq_pin();
+    sim.queue_rx_packet(&[1, 2, 3]);
+    let mut buffer = [0; 16];
+    let mut rx = radio.start_receive(&mut buffer, RxMode::default()).unwrap();
+    assert!(matches!(rx.wait().await, Err(Error::AckTimeout)));
+    assert_eq!(rx.packet(), &[1, 2, 3]);
+
+    let _radio = rx.abort().unwrap();
+    assert_eq!(sim.register(0x8E) >> 1, 0x00);
+}
+
+#[futures_test::test]
+async fn missed_irq_edge() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+    radio.set_irq_trigger(IrqTrigger::Edge);
+
+    // The interrupt comes in after the wait started, but its edge is never seen
+    sim.strict_irq_pin();
+    sim.irq_latency(1_000_000);
+    sim.queue_rx_packet(&[1, 2, 3]);
+    let mut buffer = [0; 16];
+    let mut rx = radio.start_receive(&mut buffer, RxMode::default()).unwrap();
+    assert!(matches!(
+        rx.wait().await.unwrap(),
+        RxResult::Ok { packet_size: 3, .. }
+    ));
+    assert!(sim.counters().time_ns >= IRQ_EDGE_RECHECK.as_micros() as u64 * 1000);
+}
+
+#[futures_test::test]
+async fn promiscuous_receive() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&BasicConfig {
+            include_address: true,
+            packet_filter: PacketFilteringOptions {
+                source_address: Some(5),
+                ..PacketFilteringOptions::DEFAULT
+            },
+            ..basic_config()
+        })
+        .unwrap();
+    let filter_options = sim.register(0x40);
+    let protocol_1 = sim.register(0x3A);
+
+    // A packet for another node with a bad CRC is received anyway
+    let mut buffer = [0; 16];
+    sim.queue_rx_packet_with_bad_crc(&[0x11; 6]);
+    let (ready, result, verdicts) = radio
+        .receive_promiscuous(&mut buffer, RxMode::default())
+        .await
+        .unwrap();
+    radio = ready;
+    assert!(matches!(result, RxResult::Ok { packet_size: 6, .. }));
+    assert_eq!(
+        verdicts,
+        Some(FilterVerdicts {
+            crc_ok: Some(false),
+            address_match: Some(false),
+            would_be_discarded: true,
+        })
+    );
+    assert_eq!(sim.register(0x40), filter_options);
+    assert_eq!(sim.register(0x3A), protocol_1);
+
+    // Without it, the filter drops the packet
+    sim.queue_rx_packet_with_bad_crc(&[0x11; 6]);
+    let mut rx = radio.start_receive(&mut buffer, RxMode::default()).unwrap();
+    assert_eq!(rx.wait().await.unwrap(), RxResult::CrcError);
+}
+
+#[cfg(feature = "sanity-checks")]
+#[futures_test::test]
+async fn sanity_checks() {
+    for radio_in_rx in [false, true] {
+        let sim = Simulator::new();
+        let mut radio = ready_radio(&sim)
+            .await
+            .set_format::<Basic>(&basic_config())
+            .unwrap();
+
+        // Changed behind the back of the driver
+        if radio_in_rx {
+            radio.ll().rx().dispatch().unwrap();
+        } else {
+            radio
+                .ll()
+                .pckt_ctrl_3()
+                .modify(|reg| reg.set_pckt_frmt(s2lp::ll::PacketFormat::Stack))
+                .unwrap();
+        }
+
+        let result = radio.send_packet(
+            &BasicTxMetaData {
+                destination_address: None,
+            },
+            &[1, 2, 3],
+        );
+        assert!(matches!(result, Err(Error::SanityCheck { .. })));
+    }
+}
+
+#[futures_test::test]
+async fn tx_power_ramp() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    // 2 bit periods at 38.4 kbps is ~52 µs
+    let ramp = PaRamp::for_ramp_time(10, Duration::from_micros(50), 38_400);
+    assert_eq!(ramp.step_width, 2);
+    assert_eq!(ramp.ramp_time(38_400), Duration::from_micros(52));
+    // Too long ramps are clamped
+    assert_eq!(
+        PaRamp::for_ramp_time(10, Duration::from_millis(1), 38_400).step_width,
+        4
+    );
+
+    assert!(matches!(
+        radio.set_tx_power_ramp(PaRamp {
+            target_dbm: 10,
+            step_width: 5
+        }),
+        Err(Error::BadConfig { .. })
+    ));
+
+    radio.set_tx_power_ramp(ramp).unwrap();
+    // PA_POWER0: ramp on, step width 2, all 8 slots
+    assert_eq!(sim.register(0x62) & 0b0111_1111, 0b0010_1111);
+    // PA_POWER8 is the target, PA_POWER1 the bottom of the ramp, with rising power (lower levels) in between
+    let levels: Vec<u8> = (0x5A..=0x61).map(|address| sim.register(address)).collect();
+    assert_eq!(levels[0], 4);
+    assert!(levels.windows(2).all(|pair| pair[0] < pair[1]));
+
+    radio.set_tx_power(10).unwrap();
+    assert_eq!(sim.register(0x62) & 0b0010_0000, 0);
+}
+
+#[futures_test::test]
+async fn random_bytes_from_rssi_noise() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+    let irq_mask = sim.irq_mask();
+
+    // Pairs: (0, 1) -> 0, (1, 1) dropped, (1, 0) -> 1
+    sim.queue_rssi_noise([
+        0x40, 0x41, 0x41, 0x41, 0x41, 0x40, 0x40, 0x41, 0x41, 0x40, 0x40, 0x41, 0x41, 0x40, 0x40,
+        0x41, 0x41, 0x40,
+    ]);
+    let mut buffer = [0; 1];
+    radio.random_bytes(&mut buffer).await.unwrap();
+    assert_eq!(buffer, [0b0101_0101]);
+    assert_eq!(sim.register(0x8E) >> 1, 0);
+    assert_eq!(sim.irq_mask(), irq_mask);
+
+    // A constant RSSI gives nothing
+    assert!(matches!(
+        radio.random_bytes(&mut buffer).await,
+        Err(Error::NoEntropy)
+    ));
+}
+
+#[futures_test::test]
+async fn csma_prng_seeded_from_rssi_noise() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    // 16 (1, 0) pairs give a seed of all ones
+    sim.queue_rssi_noise([1, 0].repeat(16));
+    radio
+        .set_csma_ca_seeded(CsmaCaMode::Backoff {
+            cca_period: CcaPeriod::Bits64,
+            num_cca_periods: 4,
+            max_backoffs: 3,
+            backoff_prescaler: 8,
+            custom_prng_seed: None,
+        })
+        .await
+        .unwrap();
+    assert_eq!([sim.register(0x4C), sim.register(0x4D)], [0xFF, 0xFF]);
+    // SEED_RELOAD
+    assert_ne!(sim.register(0x3A) & 1 << 3, 0);
+
+    // A custom seed is kept and no entropy is needed for it
+    radio
+        .set_csma_ca_seeded(CsmaCaMode::Backoff {
+            cca_period: CcaPeriod::Bits64,
+            num_cca_periods: 4,
+            max_backoffs: 3,
+            backoff_prescaler: 8,
+            custom_prng_seed: Some(0x1234),
+        })
+        .await
+        .unwrap();
+    assert_eq!([sim.register(0x4C), sim.register(0x4D)], [0x12, 0x34]);
+}
+
+#[futures_test::test]
+async fn tx_power_calibration() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+    let mod_2 = sim.register(0x10);
+
+    // A board that loses 1 dB compared to the datasheet curve
+    let detector_sim = sim.clone();
+    let mut detector = move || {
+        let level = detector_sim.register(0x5A) as i16;
+        (2566 - level * 100) * 10 / 211 - 10
+    };
+
+    let calibration = radio
+        .calibrate_tx_power(8, 3, Duration::from_micros(100), &mut detector)
+        .await
+        .unwrap();
+    assert!(calibration.converged);
+    assert!((77..=83).contains(&calibration.measured));
+    // More power than the datasheet level for 8 dBm
+    assert!(calibration.pa_level < 8);
+    assert_eq!(sim.register(0x5A), calibration.pa_level);
+    assert_eq!(sim.register(0x10), mod_2);
+    assert_eq!(sim.register(0x8E) >> 1, 0);
+
+    // 14 dBm can't be reached on this board
+    let calibration = radio
+        .calibrate_tx_power(14, 3, Duration::from_micros(100), &mut detector)
+        .await
+        .unwrap();
+    assert!(!calibration.converged);
+    assert_eq!(calibration.pa_level, 1);
+
+    // A detector that swings around the target never converges. The last measured level is left set.
+    let mut swing = 0;
+    let mut detector = move || {
+        swing += 1;
+        if swing % 2 == 0 {
+            130
+        } else {
+            30
+        }
+    };
+    let calibration = radio
+        .calibrate_tx_power(8, 3, Duration::from_micros(100), &mut detector)
+        .await
+        .unwrap();
+    assert!(!calibration.converged);
+    assert_eq!(sim.register(0x5A), calibration.pa_level);
+
+    assert!(matches!(
+        radio.set_pa_level(91),
+        Err(Error::BadConfig { .. })
+    ));
+}
+
+#[futures_test::test]
+async fn gpio_tx() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    assert!(matches!(
+        radio.start_gpio_tx(GpioTxPins {
+            clock: GpioNumber::Gpio0,
+            data: GpioNumber::Gpio1,
+        }),
+        Err(Error::BadConfig { .. })
+    ));
+
+    let pins = GpioTxPins {
+        clock: GpioNumber::Gpio2,
+        data: GpioNumber::Gpio3,
+    };
+    radio.start_gpio_tx(pins).unwrap();
+    // Clock output, data input and the tx source set to the gpio
+    assert_eq!(sim.register(0x02) & 0b11, 0b10);
+    assert_eq!(sim.register(0x03) & 0b11, 0b01);
+    assert_eq!((sim.register(0x30) >> 2) & 0b11, 2);
+
+    radio.stop_gpio_tx(pins).unwrap();
+    assert_eq!(sim.register(0x02) & 0b11, 0);
+    assert_eq!(sim.register(0x03) & 0b11, 0);
+    assert_eq!((sim.register(0x30) >> 2) & 0b11, 0);
+    assert_eq!(sim.register(0x8E) >> 1, 0);
+}
+
+#[futures_test::test]
+async fn gpio_keying() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    assert!(matches!(
+        radio.start_gpio_keying(GpioNumber::Gpio0),
+        Err(Error::BadConfig { .. })
+    ));
+
+    radio.start_gpio_keying(GpioNumber::Gpio1).unwrap();
+    // Data input with the tx source set to the gpio
+    assert_eq!(sim.register(0x01) & 0b11, 0b01);
+    assert_eq!(sim.register(0x01) >> 3, 2);
+    assert_eq!((sim.register(0x30) >> 2) & 0b11, 2);
+
+    radio.stop_gpio_keying(GpioNumber::Gpio1).unwrap();
+    assert_eq!(sim.register(0x01) & 0b11, 0);
+    assert_eq!((sim.register(0x30) >> 2) & 0b11, 0);
+    assert_eq!(sim.register(0x8E) >> 1, 0);
+}
+
+#[futures_test::test]
+async fn test_tx() {
+    let sim = Simulator::new();
+    let radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    let test_tx = radio.start_pn9_tx().unwrap();
+    assert_eq!((sim.register(0x30) >> 2) & 0b11, 3);
+    // The transmission doesn't end by itself
+    assert_eq!(sim.register(0x8E) >> 1, 0x5C);
+
+    let radio = test_tx.abort().unwrap();
+    assert_eq!((sim.register(0x30) >> 2) & 0b11, 0);
+    assert_eq!(sim.register(0x8E) >> 1, 0);
+
+    // The carrier has the modulation turned off
+    let mod_2 = sim.register(0x10);
+    let test_tx = radio.start_cw().unwrap();
+    assert_eq!(sim.register(0x10) >> 4, 7);
+    assert_eq!(sim.register(0x8E) >> 1, 0x5C);
+
+    let _radio = test_tx.abort().unwrap();
+    assert_eq!(sim.register(0x10), mod_2);
+    assert_eq!(sim.register(0x8E) >> 1, 0);
+}
+
+#[futures_test::test]
+async fn detach_and_recover() {
+    let sim = Simulator::new();
+    let config = Config::default().compile();
+    let radio = new_radio(&sim)
+        .init_compiled(&config)
+        .await
+        .unwrap()
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    let mut buffer = [0; 128];
+    let parts = radio
+        .start_receive(&mut buffer, RxMode::default())
+        .unwrap()
+        .detach();
+    // The receiver keeps going without a driver
+    assert_eq!(sim.register(0x8E) >> 1, 0x30);
+
+    let radio = S2lp::recover_orphaned(parts, &config).await.unwrap();
+    assert_eq!(sim.register(0x8E) >> 1, 0);
+    assert_eq!(sim.irq_mask(), 0);
+
+    let radio = radio.set_format::<Basic>(&basic_config()).unwrap();
+    let mut tx = radio
+        .send_packet(
+            &BasicTxMetaData {
+                destination_address: None,
+            },
+            &[1, 2, 3],
+        )
+        .unwrap();
+    assert_eq!(tx.wait().await.unwrap(), TxResult::Ok);
+    let Ok(_radio) = tx.finish() else {
+        unreachable!()
+    };
+}
+
+#[futures_test::test]
+async fn cca_rssi_stats() {
+    let sim = Simulator::new();
+    let mut radio = ready_radio(&sim)
+        .await
+        .set_format::<Basic>(&basic_config())
+        .unwrap();
+
+    radio.set_rssi_threshold(Rssi::from_dbm(-90)).unwrap();
+    radio
+        .set_csma_ca(CsmaCaMode::Backoff {
+            cca_period: CcaPeriod::Bits64,
+            num_cca_periods: 1,
+            max_backoffs: 3,
+            backoff_prescaler: 2,
+            custom_prng_seed: None,
+        })
+        .unwrap();
+
+    let meta = BasicTxMetaData {
+        destination_address: None,
+    };
+
+    // The channel clears up
+    sim.queue_rssi_noise([
+        Rssi::from_dbm(-80).register(),
+        Rssi::from_dbm(-100).register(),
+        Rssi::from_dbm(-96).register(),
+    ]);
+    let mut tx = radio.send_packet(&meta, &[1, 2, 3]).unwrap();
+    let (result, stats) = tx.wait_with_cca_stats().await.unwrap();
+    assert_eq!(result, TxResult::Ok);
+    let stats = stats.unwrap();
+    assert_eq!(stats.samples, 3);
+    assert_eq!(stats.min, Rssi::from_dbm(-100));
+    assert_eq!(stats.max, Rssi::from_dbm(-80));
+    assert_eq!(stats.average(), Rssi::from_dbm(-92));
+    let Ok(radio) = tx.finish() else {
+        unreachable!()
+    };
+
+    // The channel stays busy
+    sim.queue_rssi_noise([
+        Rssi::from_dbm(-70).register(),
+        Rssi::from_dbm(-60).register(),
+    ]);
+    let mut tx = radio.send_packet(&meta, &[1, 2, 3]).unwrap();
+    let (result, stats) = tx.wait_with_cca_stats().await.unwrap();
+    assert_eq!(result, TxResult::MaxBackoffReached);
+    assert_eq!(stats.unwrap().samples, 2);
+    let Ok(_radio) = tx.finish() else {
+        unreachable!()
+    };
+}
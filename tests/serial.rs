@@ -0,0 +1,83 @@
+#![cfg(feature = "basic")]
+
+mod common;
+
+use common::{ready_radio, Simulator};
+use embedded_io_async::{Read, Write};
+use s2lp::{
+    ll::{CrcMode, LenWid},
+    packet_format::{
+        Basic, BasicConfig, PacketFilteringOptions, PostambleLength, PreamblePattern, SyncWord,
+    },
+    serial::{SerialConfig, SerialLink},
+};
+
+async fn serial_link(
+    sim: &Simulator,
+) -> SerialLink<common::SimSpi, common::SimPin, common::SimIrqPin, common::SimDelay, 64> {
+    let radio = ready_radio(sim)
+        .await
+        .set_format::<Basic>(&BasicConfig {
+            preamble_length: 32,
+            preamble_pattern: PreamblePattern::Pattern0,
+            sync_length: 32,
+            sync_pattern: SyncWord::msb_first(0x12345678),
+            include_address: false,
+            packet_length_encoding: LenWid::Bytes1,
+            postamble_length: PostambleLength::NONE,
+            crc_mode: CrcMode::CrcPoly0X1021,
+            packet_filter: PacketFilteringOptions::default(),
+        })
+        .unwrap();
+
+    SerialLink::new(radio, SerialConfig::default())
+}
+
+#[futures_test::test]
+async fn write_is_acknowledged() {
+    let sim = Simulator::new();
+    let mut link = serial_link(&sim).await;
+
+    // Ack of sequence 0
+    sim.queue_rx_packet(&[0x80]);
+    assert_eq!(link.write(b"hello").await.unwrap(), 5);
+
+    // Only a frame worth of data is written at once
+    sim.queue_rx_packet(&[0x81]);
+    assert_eq!(link.write(&[0xAB; 200]).await.unwrap(), 127);
+}
+
+#[futures_test::test]
+async fn read_drops_retransmissions() {
+    let sim = Simulator::new();
+    let mut link = serial_link(&sim).await;
+
+    let mut buffer = [0; 16];
+
+    sim.queue_rx_packet(&[0x00, b'h', b'i']);
+    assert_eq!(link.read(&mut buffer).await.unwrap(), 2);
+    assert_eq!(&buffer[..2], b"hi");
+
+    // The ack got lost, so the same frame comes again
+    sim.queue_rx_packet(&[0x00, b'h', b'i']);
+    sim.queue_rx_packet(&[0x01, b'!']);
+    assert_eq!(link.read(&mut buffer).await.unwrap(), 1);
+    assert_eq!(&buffer[..1], b"!");
+    assert_eq!(link.available(), 0);
+}
+
+#[futures_test::test]
+async fn data_received_while_writing_is_buffered() {
+    let sim = Simulator::new();
+    let mut link = serial_link(&sim).await;
+
+    // The other side sends data instead of the ack. That's acked and then our frame is sent again.
+    sim.queue_rx_packet(&[0x00, b'x', b'y']);
+    sim.queue_rx_packet(&[0x80]);
+    assert_eq!(link.write(b"hello").await.unwrap(), 5);
+    assert_eq!(link.available(), 2);
+
+    let mut buffer = [0; 16];
+    assert_eq!(link.read(&mut buffer).await.unwrap(), 2);
+    assert_eq!(&buffer[..2], b"xy");
+}